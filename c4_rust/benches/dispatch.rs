@@ -0,0 +1,329 @@
+//! Interpreter dispatch benchmarks
+//!
+//! Compares the decoded `Instr` loop in `VirtualMachine` with the previous
+//! design, which matched raw code words against a chain of
+//! `i if i == Opcode::X as usize` guards and bounds-checked every operand
+//! fetch. That loop is reproduced below for the opcodes the benchmark
+//! programs use. Run with `cargo bench`.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use c4_rust::types::{InstructionSet, Opcode};
+use c4_rust::vm::{VirtualMachine, STACK_BASE};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const STACK_WORDS: usize = 64 * 1024;
+const ROUNDS: usize = 10;
+
+const PROGRAMS: [(&str, &str); 3] = [
+    ("fib", r#"
+        int fib(int n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+        int main() { return fib(24) & 255; }
+    "#),
+    ("loops", r#"
+        int main() {
+            int i = 0, j, total = 0;
+            while (i < 600) {
+                j = 0;
+                while (j < 600) { total = total + (i ^ j) % 7; j++; }
+                i++;
+            }
+            return total & 255;
+        }
+    "#),
+    ("sieve", r#"
+        int main() {
+            int flags[8192], i, j, count = 0, pass = 0;
+            while (pass < 20) {
+                i = 0;
+                while (i < 8192) { flags[i] = 1; i++; }
+                count = 0;
+                i = 2;
+                while (i < 8192) {
+                    if (flags[i]) {
+                        count++;
+                        j = i + i;
+                        while (j < 8192) { flags[j] = 0; j = j + i; }
+                    }
+                    i++;
+                }
+                pass++;
+            }
+            return count & 255;
+        }
+    "#),
+];
+
+/// Compile a benchmark program, returning its code, data and entry point
+fn compile(source: &str, instruction_set: InstructionSet) -> (Vec<i64>, Vec<u8>, usize) {
+    let object = common::compile_with(source, |parser| parser.set_instruction_set(instruction_set));
+    (object.code, object.data, object.entry.expect("main"))
+}
+
+/// Best wall-clock time of several runs
+fn best_of(mut run: impl FnMut() -> i64) -> (Duration, i64) {
+    let mut best = Duration::MAX;
+    let mut result = 0;
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        result = black_box(run());
+        best = best.min(start.elapsed());
+    }
+    (best, result)
+}
+
+fn main() {
+    println!("{:<8} {:>12} {:>12} {:>12} {:>9}", "program", "guard chain", "decoded", "+fused", "speedup");
+
+    for (name, source) in PROGRAMS {
+        let (code, data, entry) = compile(source, InstructionSet::C4);
+        let (legacy_time, legacy_result) = best_of(|| {
+            legacy::run(&code, &data, entry, STACK_WORDS).expect("legacy run")
+        });
+        let (decoded_time, decoded_result) = best_of(|| {
+            let mut vm = VirtualMachine::new(code.clone(), data.clone(), STACK_WORDS, false);
+            vm.run(entry, &[]).expect("decoded run")
+        });
+
+        let (code, data, entry) = compile(source, InstructionSet::Extended);
+        let (fused_time, fused_result) = best_of(|| {
+            let mut vm = VirtualMachine::new(code.clone(), data.clone(), STACK_WORDS, false);
+            vm.run(entry, &[]).expect("fused run")
+        });
+
+        assert_eq!(legacy_result, decoded_result, "{}: results differ", name);
+        assert_eq!(legacy_result, fused_result, "{}: results differ", name);
+        println!("{:<8} {:>10.2}ms {:>10.2}ms {:>10.2}ms {:>8.2}x",
+                 name,
+                 legacy_time.as_secs_f64() * 1e3,
+                 decoded_time.as_secs_f64() * 1e3,
+                 fused_time.as_secs_f64() * 1e3,
+                 legacy_time.as_secs_f64() / fused_time.as_secs_f64());
+    }
+}
+
+/// The word-matching interpreter loop that decoding replaced
+mod legacy {
+    use super::{Opcode, STACK_BASE};
+
+    pub fn run(code: &[i64], data: &[u8], entry: usize, stack_words: usize) -> Result<i64, String> {
+        let mut code = code.to_vec();
+        let trampoline = code.len();
+        code.push(Opcode::PSH as i64);
+        code.push(Opcode::EXIT as i64);
+
+        let mut vm = Legacy {
+            pc: entry,
+            sp: stack_words,
+            bp: stack_words,
+            ax: 0,
+            code,
+            stack: vec![0; stack_words],
+            data: data.to_vec(),
+            debug: false,
+            cycle: 0,
+        };
+        vm.push(0)?;
+        vm.push(0)?;
+        vm.push(trampoline as i64)?;
+        vm.run()
+    }
+
+    struct Legacy {
+        pc: usize,
+        sp: usize,
+        bp: usize,
+        ax: i64,
+        code: Vec<i64>,
+        stack: Vec<i64>,
+        data: Vec<u8>,
+        debug: bool,
+        cycle: i64,
+    }
+
+    impl Legacy {
+        fn run(&mut self) -> Result<i64, String> {
+            loop {
+                self.cycle += 1;
+                if self.pc >= self.code.len() {
+                    return Err(format!("Program counter out of bounds: {}", self.pc));
+                }
+                let op = self.code[self.pc];
+                if self.debug {
+                    println!("{:4}> {}", self.cycle, op);
+                }
+
+                match op as usize {
+                    i if i == Opcode::LEA as usize => {
+                        let offset = self.operand()?;
+                        self.ax = STACK_BASE + (self.bp as i64 + offset) * 8;
+                        self.pc += 2;
+                    },
+                    i if i == Opcode::IMM as usize => {
+                        self.ax = self.operand()?;
+                        self.pc += 2;
+                    },
+                    i if i == Opcode::JMP as usize => {
+                        self.pc = self.jump_target()?;
+                    },
+                    i if i == Opcode::JSR as usize => {
+                        let target = self.jump_target()?;
+                        self.push((self.pc + 2) as i64)?;
+                        self.pc = target;
+                    },
+                    i if i == Opcode::BZ as usize => {
+                        if self.ax == 0 {
+                            self.pc = self.jump_target()?;
+                        } else {
+                            self.pc += 2;
+                        }
+                    },
+                    i if i == Opcode::BNZ as usize => {
+                        if self.ax != 0 {
+                            self.pc = self.jump_target()?;
+                        } else {
+                            self.pc += 2;
+                        }
+                    },
+                    i if i == Opcode::ENT as usize => {
+                        let locals = self.operand()?;
+                        self.push(self.bp as i64)?;
+                        self.bp = self.sp;
+                        if locals < 0 || locals as usize > self.sp {
+                            return Err("Stack overflow".to_string());
+                        }
+                        self.sp -= locals as usize;
+                        self.pc += 2;
+                    },
+                    i if i == Opcode::ADJ as usize => {
+                        let words = self.operand()?;
+                        let sp = self.sp as i64 + words;
+                        if sp < 0 || sp as usize > self.stack.len() {
+                            return Err("Stack out of bounds".to_string());
+                        }
+                        self.sp = sp as usize;
+                        self.pc += 2;
+                    },
+                    i if i == Opcode::LEV as usize => {
+                        self.sp = self.bp;
+                        self.bp = self.pop()? as usize;
+                        self.pc = self.pop()? as usize;
+                    },
+                    i if i == Opcode::LI as usize => {
+                        self.ax = self.load_int(self.ax)?;
+                        self.pc += 1;
+                    },
+                    i if i == Opcode::LC as usize => {
+                        self.ax = self.read_byte(self.ax)? as i8 as i64;
+                        self.pc += 1;
+                    },
+                    i if i == Opcode::SI as usize => {
+                        let addr = self.pop()?;
+                        self.store_int(addr, self.ax)?;
+                        self.pc += 1;
+                    },
+                    i if i == Opcode::PSH as usize => {
+                        self.push(self.ax)?;
+                        self.pc += 1;
+                    },
+                    i if i == Opcode::OR as usize => { self.ax |= self.pop()?; self.pc += 1; },
+                    i if i == Opcode::XOR as usize => { self.ax ^= self.pop()?; self.pc += 1; },
+                    i if i == Opcode::AND as usize => { self.ax &= self.pop()?; self.pc += 1; },
+                    i if i == Opcode::EQ as usize => { self.ax = (self.pop()? == self.ax) as i64; self.pc += 1; },
+                    i if i == Opcode::NE as usize => { self.ax = (self.pop()? != self.ax) as i64; self.pc += 1; },
+                    i if i == Opcode::LT as usize => { self.ax = (self.pop()? < self.ax) as i64; self.pc += 1; },
+                    i if i == Opcode::GT as usize => { self.ax = (self.pop()? > self.ax) as i64; self.pc += 1; },
+                    i if i == Opcode::LE as usize => { self.ax = (self.pop()? <= self.ax) as i64; self.pc += 1; },
+                    i if i == Opcode::GE as usize => { self.ax = (self.pop()? >= self.ax) as i64; self.pc += 1; },
+                    i if i == Opcode::SHL as usize => { self.ax = self.pop()?.wrapping_shl(self.ax as u32); self.pc += 1; },
+                    i if i == Opcode::SHR as usize => { self.ax = self.pop()?.wrapping_shr(self.ax as u32); self.pc += 1; },
+                    i if i == Opcode::ADD as usize => { self.ax = self.pop()?.wrapping_add(self.ax); self.pc += 1; },
+                    i if i == Opcode::SUB as usize => { self.ax = self.pop()?.wrapping_sub(self.ax); self.pc += 1; },
+                    i if i == Opcode::MUL as usize => { self.ax = self.pop()?.wrapping_mul(self.ax); self.pc += 1; },
+                    i if i == Opcode::DIV as usize => {
+                        let lhs = self.pop()?;
+                        if self.ax == 0 {
+                            return Err("Division by zero".to_string());
+                        }
+                        self.ax = lhs.wrapping_div(self.ax);
+                        self.pc += 1;
+                    },
+                    i if i == Opcode::MOD as usize => {
+                        let lhs = self.pop()?;
+                        if self.ax == 0 {
+                            return Err("Division by zero in modulo".to_string());
+                        }
+                        self.ax = lhs.wrapping_rem(self.ax);
+                        self.pc += 1;
+                    },
+                    i if i == Opcode::EXIT as usize => return Ok(self.ax),
+                    _ => return Err(format!("Unsupported opcode: {}", op)),
+                }
+            }
+        }
+
+        fn operand(&self) -> Result<i64, String> {
+            self.code.get(self.pc + 1).copied().ok_or_else(|| "Unexpected end of code".to_string())
+        }
+
+        fn jump_target(&self) -> Result<usize, String> {
+            let target = self.operand()?;
+            if target < 0 || target as usize >= self.code.len() {
+                return Err(format!("Jump target out of bounds: {}", target));
+            }
+            Ok(target as usize)
+        }
+
+        fn push(&mut self, value: i64) -> Result<(), String> {
+            if self.sp == 0 {
+                return Err("Stack overflow".to_string());
+            }
+            self.sp -= 1;
+            self.stack[self.sp] = value;
+            Ok(())
+        }
+
+        fn pop(&mut self) -> Result<i64, String> {
+            let value = *self.stack.get(self.sp).ok_or_else(|| "Stack underflow".to_string())?;
+            self.sp += 1;
+            Ok(value)
+        }
+
+        fn read_byte(&self, addr: i64) -> Result<u8, String> {
+            if addr >= STACK_BASE {
+                let offset = (addr - STACK_BASE) as usize;
+                if let Some(word) = self.stack.get(offset / 8) {
+                    return Ok(word.to_le_bytes()[offset % 8]);
+                }
+            } else if addr >= 0 && (addr as usize) < self.data.len() {
+                return Ok(self.data[addr as usize]);
+            }
+            Err(format!("Memory access out of bounds: {}", addr))
+        }
+
+        fn load_int(&self, addr: i64) -> Result<i64, String> {
+            if addr >= STACK_BASE && (addr - STACK_BASE) % 8 == 0 {
+                if let Some(&value) = self.stack.get(((addr - STACK_BASE) / 8) as usize) {
+                    return Ok(value);
+                }
+            }
+            let mut bytes = [0; 8];
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                *byte = self.read_byte(addr + i as i64)?;
+            }
+            Ok(i64::from_le_bytes(bytes))
+        }
+
+        fn store_int(&mut self, addr: i64, value: i64) -> Result<(), String> {
+            if addr >= STACK_BASE && (addr - STACK_BASE) % 8 == 0 {
+                if let Some(slot) = self.stack.get_mut(((addr - STACK_BASE) / 8) as usize) {
+                    *slot = value;
+                    return Ok(());
+                }
+            }
+            Err(format!("Unsupported store to {}", addr))
+        }
+    }
+}
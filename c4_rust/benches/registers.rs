@@ -0,0 +1,132 @@
+//! Register machine benchmarks
+//!
+//! Runs each program on the stack VM and on the register machine
+//! (`--vm=reg`), comparing the instructions each executes and the best
+//! wall-clock time of several runs. Run with `cargo bench --bench registers`.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use c4_rust::host::MemoryHost;
+use c4_rust::regvm::RegisterVm;
+use c4_rust::vm::{VirtualMachine, VmLimits};
+use common::compile;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const STACK_WORDS: usize = 64 * 1024;
+const ROUNDS: usize = 10;
+
+const PROGRAMS: [(&str, &str); 5] = [
+    ("fib", r#"
+        int fib(int n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+        int main() { return fib(24) & 255; }
+    "#),
+    ("loops", r#"
+        int main() {
+            int i = 0, j, total = 0;
+            while (i < 600) {
+                j = 0;
+                while (j < 600) { total = total + (i ^ j) % 7; j++; }
+                i++;
+            }
+            return total & 255;
+        }
+    "#),
+    ("sieve", r#"
+        int main() {
+            int flags[8192], i, j, count = 0, pass = 0;
+            while (pass < 20) {
+                i = 0;
+                while (i < 8192) { flags[i] = 1; i++; }
+                count = 0;
+                i = 2;
+                while (i < 8192) {
+                    if (flags[i]) {
+                        count++;
+                        j = i + i;
+                        while (j < 8192) { flags[j] = 0; j = j + i; }
+                    }
+                    i++;
+                }
+                pass++;
+            }
+            return count & 255;
+        }
+    "#),
+    ("sort", r#"
+        int main() {
+            int *a, n = 1500, i, j, t, seed = 12345;
+            a = malloc(n * sizeof(int));
+            i = 0;
+            while (i < n) { seed = (seed * 1103515245 + 12345) & 2147483647; a[i] = seed % 10000; i++; }
+            i = 0;
+            while (i < n) {
+                j = 0;
+                while (j < n - 1 - i) {
+                    if (a[j] > a[j + 1]) { t = a[j]; a[j] = a[j + 1]; a[j + 1] = t; }
+                    j++;
+                }
+                i++;
+            }
+            return a[n / 2] & 255;
+        }
+    "#),
+    ("strings", r#"
+        int length(char *s) { char *p; p = s; while (*p) p++; return p - s; }
+        int main() {
+            char *buf; int i, total = 0;
+            buf = malloc(1024);
+            i = 0;
+            while (i < 1000) { buf[i] = 'a' + i % 26; i++; }
+            buf[i] = 0;
+            i = 0;
+            while (i < 300) { total = total + length(buf + i); i++; }
+            return total & 255;
+        }
+    "#),
+];
+
+/// Best wall-clock time of several runs, with the result and the number
+/// of instructions executed
+fn best_of(mut run: impl FnMut() -> (i64, i64)) -> (Duration, i64, i64) {
+    let mut best = Duration::MAX;
+    let (mut result, mut cycles) = (0, 0);
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        (result, cycles) = black_box(run());
+        best = best.min(start.elapsed());
+    }
+    (best, result, cycles)
+}
+
+fn main() {
+    println!("{:<8} {:>12} {:>12} {:>7} {:>10} {:>10} {:>8}",
+             "program", "stack instrs", "reg instrs", "ratio", "stack", "registers", "speedup");
+
+    let limits = VmLimits { stack_size: STACK_WORDS, ..VmLimits::default() };
+    for (name, source) in PROGRAMS {
+        let program = compile(source);
+        let entry = program.entry.expect("main");
+        let (stack_time, stack_result, stack_cycles) = best_of(|| {
+            let mut vm = VirtualMachine::with_limits(program.code.clone(), program.data.clone(), limits, false);
+            vm.set_host(Box::new(MemoryHost::new()));
+            (vm.run(entry, &[]).expect("stack run"), vm.cycle())
+        });
+        let (reg_time, reg_result, reg_cycles) = best_of(|| {
+            let mut machine = RegisterVm::compile(&program, limits, false).expect("translation");
+            machine.set_host(Box::new(MemoryHost::new()));
+            (machine.run(&[]).expect("register run"), machine.cycle())
+        });
+
+        assert_eq!(stack_result, reg_result, "{}: results differ", name);
+        println!("{:<8} {:>12} {:>12} {:>6.2}x {:>8.2}ms {:>8.2}ms {:>7.2}x",
+                 name,
+                 stack_cycles,
+                 reg_cycles,
+                 stack_cycles as f64 / reg_cycles as f64,
+                 stack_time.as_secs_f64() * 1e3,
+                 reg_time.as_secs_f64() * 1e3,
+                 stack_time.as_secs_f64() / reg_time.as_secs_f64());
+    }
+}
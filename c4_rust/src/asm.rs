@@ -0,0 +1,581 @@
+use crate::debug_info::DebugInfo;
+use crate::error::CompilerError;
+use crate::object::{ObjectFile, ObjectImport, ObjectSymbol, Relocation, RelocationKind, SymbolKind};
+use crate::types::{Opcode, Type};
+use std::collections::{HashMap, HashSet};
+
+/// Segment that statements are assembled into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Text,
+    Data,
+}
+
+/// Address a label stands for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Label {
+    Code(usize),
+    Data(usize),
+    /// Defined in another unit
+    Extern,
+}
+
+/// An operand: a number, or a label plus an offset
+#[derive(Debug, Clone, Copy)]
+enum Operand<'a> {
+    Number(i64),
+    Label(&'a str, i64),
+}
+
+/// A label reference to resolve once every label is defined
+struct Fixup<'a> {
+    /// Code word that receives the address
+    pos: usize,
+    /// Instruction the operand belongs to
+    op: Opcode,
+    name: &'a str,
+    offset: i64,
+    /// Where the reference was written, for errors
+    line: usize,
+    text: &'a str,
+}
+
+/// Assemble bytecode assembly text into an object file
+///
+/// The syntax is the disassembler's listing format, so listings can be
+/// edited and reassembled:
+///
+/// ```text
+/// .entry main          ; where execution starts
+/// .text
+/// main:                ; labels end with a colon
+///     IMM   msg        ; data labels give data addresses
+///     PSH
+///     PRTF
+///     ADJ   1
+///     JMP   .done      ; code labels give jump targets
+/// .done:
+///     EXIT
+/// .data
+/// msg:
+///     .string "hi\n"   ; also .byte 1, 2 and .word 42
+/// ```
+///
+/// A number at the start of a line is the address column of a listing and
+/// is ignored. Labels starting with `.` are local; the others become the
+/// object's symbols. `name+offset` addresses into a label, and `.word` in
+/// `.text` emits a raw code word. `.extern name` declares a symbol another
+/// unit defines; it is imported as a function if it is jumped to and as a
+/// global otherwise.
+pub fn assemble(source: &str) -> Result<ObjectFile, CompilerError> {
+    let mut asm = Assembler {
+        lines: source.lines().collect(),
+        section: Section::Text,
+        code: Vec::new(),
+        data: Vec::new(),
+        labels: HashMap::new(),
+        order: Vec::new(),
+        fixups: Vec::new(),
+        relocations: Vec::new(),
+        imports: Vec::new(),
+        entry: None,
+        words: HashSet::new(),
+    };
+    for number in 0..asm.lines.len() {
+        asm.statement(number + 1)?;
+    }
+    asm.finish()
+}
+
+/// State of an assembly in progress
+struct Assembler<'a> {
+    lines: Vec<&'a str>,
+    section: Section,
+    code: Vec<i64>,
+    data: Vec<u8>,
+    labels: HashMap<&'a str, Label>,
+    /// Labels in definition order, for the symbol table
+    order: Vec<&'a str>,
+    fixups: Vec<Fixup<'a>>,
+    relocations: Vec<Relocation>,
+    /// `.extern` symbols in order of first use
+    imports: Vec<ObjectImport>,
+    /// The `.entry` operand and where it was written
+    entry: Option<(Operand<'a>, usize, &'a str)>,
+    /// Data addresses where a `.word` directive starts
+    words: HashSet<usize>,
+}
+
+impl<'a> Assembler<'a> {
+    /// Assemble one line
+    fn statement(&mut self, number: usize) -> Result<(), CompilerError> {
+        let mut rest = strip_comment(self.lines[number - 1]).trim();
+
+        // Labels, possibly several, possibly followed by a statement
+        while let Some(colon) = rest.find(':').filter(|&colon| is_identifier(&rest[..colon])) {
+            self.define(number, &rest[..colon])?;
+            rest = rest[colon + 1..].trim_start();
+        }
+
+        // The address column of a listing
+        let (mut word, mut args) = split_word(rest);
+        if !word.is_empty() && word.bytes().all(|b| b.is_ascii_digit()) {
+            (word, args) = split_word(args);
+        }
+        if word.is_empty() {
+            return Ok(());
+        }
+
+        match word {
+            ".text" | ".data" => {
+                self.no_operand(number, word, args)?;
+                self.section = if word == ".text" { Section::Text } else { Section::Data };
+            },
+            ".entry" => {
+                let operand = self.operand(number, args)?;
+                self.entry = Some((operand, number, args));
+            },
+            ".extern" => {
+                for name in self.list(number, word, args)? {
+                    if !is_identifier(name) {
+                        return Err(self.error(number, name, format!("Invalid symbol name '{}'", name)));
+                    }
+                    if self.labels.insert(name, Label::Extern).is_some() {
+                        return Err(self.error(number, name, format!("Label '{}' is already defined", name)));
+                    }
+                }
+            },
+            ".word" => {
+                for item in self.list(number, word, args)? {
+                    let value = self.number(number, item)?;
+                    match self.section {
+                        Section::Text => self.code.push(value),
+                        Section::Data => {
+                            self.words.insert(self.data.len());
+                            self.data.extend_from_slice(&value.to_le_bytes());
+                        },
+                    }
+                }
+            },
+            ".byte" => {
+                self.in_data(number, word)?;
+                for item in self.list(number, word, args)? {
+                    let value = self.number(number, item)?;
+                    if !(-128..=255).contains(&value) {
+                        return Err(self.error(number, item, format!("Byte value {} out of range", value)));
+                    }
+                    self.data.push(value as u8);
+                }
+            },
+            ".string" => {
+                self.in_data(number, word)?;
+                let bytes = unquote(args)
+                    .ok_or_else(|| self.error(number, args, "Expected a quoted string".to_string()))?;
+                self.data.extend_from_slice(&bytes);
+                self.data.push(0);
+            },
+            _ if word.starts_with('.') => {
+                return Err(self.error(number, word, format!("Unknown directive '{}'", word)));
+            },
+            _ => self.instruction(number, word, args)?,
+        }
+
+        Ok(())
+    }
+
+    /// Assemble an instruction and its operand
+    fn instruction(&mut self, number: usize, word: &'a str, args: &'a str) -> Result<(), CompilerError> {
+        let op = Opcode::ALL.iter().copied()
+            .find(|op| op.to_string().eq_ignore_ascii_case(word))
+            .ok_or_else(|| self.error(number, word, format!("Unknown instruction '{}'", word)))?;
+        if self.section != Section::Text {
+            return Err(self.error(number, word, format!("{} must be in .text", op.to_string())));
+        }
+
+        self.code.push(op as i64);
+        if !op.has_operand() {
+            return self.no_operand(number, word, args);
+        }
+        if args.is_empty() {
+            return Err(self.error(number, word, format!("{} needs an operand", op.to_string())));
+        }
+
+        let pos = self.code.len();
+        match self.operand(number, args)? {
+            Operand::Number(value) => {
+                self.code.push(value);
+                // Jump targets are code addresses however they are written
+                if op.is_jump() {
+                    self.relocations.push(Relocation { offset: pos, kind: RelocationKind::Code });
+                }
+            },
+            Operand::Label(name, offset) => {
+                self.code.push(0);
+                self.fixups.push(Fixup { pos, op, name, offset, line: number, text: args });
+            },
+        }
+        Ok(())
+    }
+
+    /// Record a label at the current address
+    fn define(&mut self, number: usize, name: &'a str) -> Result<(), CompilerError> {
+        let label = match self.section {
+            Section::Text => Label::Code(self.code.len()),
+            Section::Data => Label::Data(self.data.len()),
+        };
+        if self.labels.insert(name, label).is_some() {
+            return Err(self.error(number, name, format!("Label '{}' is already defined", name)));
+        }
+        self.order.push(name);
+        Ok(())
+    }
+
+    /// Resolve labels and build the object file
+    fn finish(mut self) -> Result<ObjectFile, CompilerError> {
+        for fixup in std::mem::take(&mut self.fixups) {
+            let label = self.labels.get(fixup.name).copied().ok_or_else(|| {
+                self.error(fixup.line, fixup.text, format!("Undefined label '{}'", fixup.name))
+            })?;
+            let (addr, kind) = match (label, fixup.op.is_jump()) {
+                (Label::Code(addr), true) => (addr, RelocationKind::Code),
+                (Label::Data(addr), false) => (addr, RelocationKind::Data),
+                (Label::Extern, is_jump) => {
+                    let kind = if is_jump { SymbolKind::Function } else { SymbolKind::Global };
+                    let index = match self.imports.iter().position(|import| import.name == fixup.name) {
+                        Some(index) if self.imports[index].kind == kind => index,
+                        Some(_) => {
+                            let message = format!("'{}' is used both as a function and as a global", fixup.name);
+                            return Err(self.error(fixup.line, fixup.text, message));
+                        },
+                        None => {
+                            self.imports.push(ObjectImport { name: fixup.name.to_string(), kind });
+                            self.imports.len() - 1
+                        },
+                    };
+                    // The linker adds the symbol's address to the offset
+                    (0, RelocationKind::Import(index))
+                },
+                (Label::Code(_), false) => {
+                    let message = format!("'{}' is a code label, but {} does not jump", fixup.name, fixup.op.to_string());
+                    return Err(self.error(fixup.line, fixup.text, message));
+                },
+                (Label::Data(_), true) => {
+                    let message = format!("'{}' is a data label, but {} needs a code address", fixup.name, fixup.op.to_string());
+                    return Err(self.error(fixup.line, fixup.text, message));
+                },
+            };
+            self.code[fixup.pos] = addr as i64 + fixup.offset;
+            self.relocations.push(Relocation { offset: fixup.pos, kind });
+        }
+        self.relocations.sort_by_key(|reloc| reloc.offset);
+
+        let entry = match self.entry {
+            Some((Operand::Number(addr), line, text)) => {
+                let addr = usize::try_from(addr)
+                    .map_err(|_| self.error(line, text, format!("Invalid entry point {}", addr)))?;
+                Some(addr)
+            },
+            Some((Operand::Label(name, offset), line, text)) => match self.labels.get(name) {
+                Some(&Label::Code(addr)) => Some((addr as i64 + offset) as usize),
+                Some(Label::Data(_) | Label::Extern) => {
+                    return Err(self.error(line, text, format!("Entry point '{}' is not a code label", name)));
+                },
+                None => return Err(self.error(line, text, format!("Undefined label '{}'", name))),
+            },
+            None => None,
+        };
+
+        // Globals laid out as words are ints; anything else is treated as char data
+        let symbols = self.order.iter()
+            .filter(|name| !name.starts_with('.'))
+            .map(|&name| match self.labels[name] {
+                Label::Code(addr) => ObjectSymbol {
+                    name: name.to_string(),
+                    kind: SymbolKind::Function,
+                    typ: Type::INT,
+                    value: addr as i64,
+                },
+                Label::Data(addr) => ObjectSymbol {
+                    name: name.to_string(),
+                    kind: SymbolKind::Global,
+                    typ: if self.words.contains(&addr) { Type::INT } else { Type::CHAR },
+                    value: addr as i64,
+                },
+                Label::Extern => unreachable!("extern labels are not in definition order"),
+            })
+            .collect();
+
+        Ok(ObjectFile {
+            code: self.code,
+            data: self.data,
+            entry,
+            symbols,
+            imports: self.imports,
+            relocations: self.relocations,
+            debug: DebugInfo::default(),
+        })
+    }
+
+    /// Parse an instruction or `.entry` operand
+    fn operand(&self, number: usize, text: &'a str) -> Result<Operand<'a>, CompilerError> {
+        if let Some(value) = parse_number(text) {
+            return Ok(Operand::Number(value));
+        }
+        let (name, offset) = match text.find(['+', '-']) {
+            Some(sign) => (text[..sign].trim_end(), parse_number(&text[sign..])),
+            None => (text, Some(0)),
+        };
+        match offset {
+            Some(offset) if is_identifier(name) => Ok(Operand::Label(name, offset)),
+            _ => Err(self.error(number, text, format!("Invalid operand '{}'", text))),
+        }
+    }
+
+    /// Parse a number operand of a directive
+    fn number(&self, number: usize, text: &'a str) -> Result<i64, CompilerError> {
+        parse_number(text).ok_or_else(|| self.error(number, text, format!("Expected a number, found '{}'", text)))
+    }
+
+    /// Split a comma-separated directive operand list
+    fn list(&self, number: usize, word: &'a str, args: &'a str) -> Result<Vec<&'a str>, CompilerError> {
+        if args.is_empty() {
+            return Err(self.error(number, word, format!("{} needs at least one value", word)));
+        }
+        Ok(args.split(',').map(str::trim).collect())
+    }
+
+    /// Reject operands on statements that take none
+    fn no_operand(&self, number: usize, word: &'a str, args: &'a str) -> Result<(), CompilerError> {
+        if args.is_empty() {
+            Ok(())
+        } else {
+            Err(self.error(number, args, format!("{} takes no operand", word)))
+        }
+    }
+
+    /// Reject data directives outside `.data`
+    fn in_data(&self, number: usize, word: &'a str) -> Result<(), CompilerError> {
+        if self.section == Section::Data {
+            Ok(())
+        } else {
+            Err(self.error(number, word, format!("{} must be in .data", word)))
+        }
+    }
+
+    /// Build an error pointing at `at`, a slice of line `number`
+    fn error(&self, number: usize, at: &str, message: String) -> CompilerError {
+        let line = self.lines[number - 1];
+        let column = (at.as_ptr() as usize).saturating_sub(line.as_ptr() as usize).min(line.len()) + 1;
+        CompilerError::assembly_error(&message, number, column, Some(line))
+    }
+}
+
+/// Remove a `;` comment, ignoring semicolons inside string literals
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &line[..i],
+            _ => {},
+        }
+    }
+    line
+}
+
+/// Split off the first whitespace-separated word
+fn split_word(text: &str) -> (&str, &str) {
+    match text.find(char::is_whitespace) {
+        Some(end) => (&text[..end], text[end..].trim()),
+        None => (text, ""),
+    }
+}
+
+/// Whether `text` is a valid label name
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+/// Parse a decimal or `0x` hexadecimal integer with an optional sign
+fn parse_number(text: &str) -> Option<i64> {
+    let text = text.trim();
+    let (sign, digits) = match text.as_bytes().first()? {
+        b'-' => ("-", text[1..].trim_start()),
+        b'+' => ("", text[1..].trim_start()),
+        _ => ("", text),
+    };
+    match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(&format!("{}{}", sign, hex), 16).ok(),
+        None if digits.starts_with(|c: char| c.is_ascii_digit()) => format!("{}{}", sign, digits).parse().ok(),
+        None => None,
+    }
+}
+
+/// Decode a double-quoted string literal with C escapes
+///
+/// Accepts what the disassembler's `quote` produces: `\n`, `\t`, `\r`,
+/// `\0`, `\"`, `\\` and `\xNN`.
+fn unquote(text: &str) -> Option<Vec<u8>> {
+    let body = text.strip_prefix('"')?.strip_suffix('"')?;
+    let mut bytes = Vec::with_capacity(body.len());
+    let mut rest = body.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        rest = tail;
+        if b == b'"' {
+            return None;
+        }
+        if b != b'\\' {
+            bytes.push(b);
+            continue;
+        }
+        let (&escape, tail) = rest.split_first()?;
+        rest = tail;
+        bytes.push(match escape {
+            b'n' => b'\n',
+            b't' => b'\t',
+            b'r' => b'\r',
+            b'0' => 0,
+            b'"' => b'"',
+            b'\\' => b'\\',
+            b'x' => {
+                let hex = std::str::from_utf8(rest.get(..2)?).ok()?;
+                rest = &rest[2..];
+                u8::from_str_radix(hex, 16).ok()?
+            },
+            _ => return None,
+        });
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::Disassembler;
+    use crate::parser::Parser;
+    use crate::vm::VirtualMachine;
+
+    #[test]
+    fn test_assemble_and_run() {
+        let object = assemble(r#"
+            .entry main
+            .text
+            double:
+                ENT   0
+                LEA   2
+                LI
+                PSH
+                IMM   2
+                MUL
+                LEV
+            main:
+                ENT   0
+                IMM   msg+1       ; skip the leading space
+                PSH
+                IMM   21          ; argument
+                PSH
+                JSR   double
+                ADJ   1
+                PSH
+                PRTF
+                ADJ   2
+                IMM   count
+                LI
+                EXIT
+            .data
+            msg:
+                .string " %d;\n"
+                .byte 0, 0
+            count:
+                .word 0x2a
+        "#).unwrap();
+
+        assert_eq!(object.entry, Some(10));
+        assert_eq!(object.code[12], Opcode::IMM as i64);
+        assert_eq!(&object.data[..6], b" %d;\n\0");
+        assert_eq!(object.symbols.iter().map(|sym| sym.name.as_str()).collect::<Vec<_>>(),
+                   ["double", "main", "msg", "count"]);
+        assert_eq!(object.symbols[3].typ, Type::INT);
+        assert!(object.relocations.contains(&Relocation { offset: 19, kind: RelocationKind::Code }));
+        assert!(object.relocations.contains(&Relocation { offset: 13, kind: RelocationKind::Data }));
+
+        let mut vm = VirtualMachine::new(object.code, object.data, 1024, false);
+        assert_eq!(vm.run(object.entry.unwrap(), &[]).unwrap(), 42);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut parser = Parser::new(r#"
+            char *name;
+            int counts;
+            int main() {
+                int i;
+                i = 0;
+                while (i < 3) { counts = counts + i; i++; }
+                if (counts > 2) printf("a \"quoted\"; %d\n", counts);
+                else printf("\ttab\n");
+                return counts;
+            }
+        "#.to_string(), false);
+        parser.init().unwrap();
+        parser.parse().unwrap();
+        parser.optimize();
+        parser.fuse();
+        let original = ObjectFile::from_parser(&parser).unwrap();
+
+        let listing = Disassembler::from_object(&original).disassemble();
+        let assembled = assemble(&listing).unwrap();
+        assert_eq!(assembled.code, original.code);
+        assert_eq!(assembled.data, original.data);
+        assert_eq!(assembled.entry, original.entry);
+        assert_eq!(assembled.relocations, original.relocations);
+        assert_eq!(Disassembler::from_object(&assembled).disassemble(), listing);
+    }
+
+    #[test]
+    fn test_extern_round_trip() {
+        let mut parser = Parser::new("extern int g; int f(int n); int main() { return f(g + 1); }".to_string(), false);
+        parser.set_separate_compilation(true);
+        parser.init().unwrap();
+        parser.parse().unwrap();
+        let original = ObjectFile::from_parser(&parser).unwrap();
+
+        let listing = Disassembler::from_object(&original).disassemble();
+        assert!(listing.contains(".extern g\n.extern f\n"), "{}", listing);
+        let assembled = assemble(&listing).unwrap();
+        assert_eq!(assembled.code, original.code);
+        assert_eq!(assembled.imports, original.imports);
+        assert_eq!(assembled.relocations, original.relocations);
+    }
+
+    #[test]
+    fn test_errors() {
+        let cases = [
+            ("FOO", 1, 1, "Unknown instruction"),
+            ("  IMM", 1, 3, "needs an operand"),
+            ("PSH 1", 1, 5, "takes no operand"),
+            ("JMP nowhere", 1, 5, "Undefined label 'nowhere'"),
+            ("x:\nx:", 2, 1, "already defined"),
+            (".data\nIMM 1", 2, 1, "must be in .text"),
+            (".string \"a\"", 1, 1, "must be in .data"),
+            (".data\n.byte 300", 2, 7, "out of range"),
+            (".data\nd: .word 1\n.text\nJSR d", 4, 5, "is a data label"),
+            ("f: IMM f", 1, 8, "is a code label"),
+            (".entry 1x", 1, 8, "Invalid operand"),
+        ];
+        for (source, line, column, expected) in cases {
+            match assemble(source) {
+                Err(CompilerError::AssemblyError { message, location: Some(loc), .. }) => {
+                    assert!(message.contains(expected), "{:?}: {}", source, message);
+                    assert_eq!((loc.line, loc.column), (line, column), "{:?}: {}", source, message);
+                },
+                other => panic!("{:?} should fail, got {:?}", source, other),
+            }
+        }
+    }
+}
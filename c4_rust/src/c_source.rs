@@ -0,0 +1,395 @@
+use crate::error::CompilerError;
+use crate::instr::{self, Instr};
+use crate::object::{ObjectFile, RelocationKind, SymbolKind};
+use crate::vm::DEFAULT_STACK_SIZE;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+/// Declarations and the runtime every translated program starts with
+///
+/// The system calls behave as the VM's do: `printf` takes every argument
+/// as a 64-bit word, `malloc` returns zeroed memory, `memcmp` returns the
+/// difference of the first bytes that differ, and runtime errors print the
+/// VM's message and exit with status 1.
+const PRELUDE: &str = r#"#define _POSIX_C_SOURCE 200809L
+#include <fcntl.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+
+/* A word of C4 memory at an address held in a register */
+#define AT(type, address) (*(type *)(intptr_t)(address))
+#define ADDRESS(pointer) ((int64_t)(intptr_t)(pointer))
+
+static int64_t ax, *sp, *bp;
+static int64_t stack[STACK_WORDS];
+
+void rt_fail(const char *message) {
+    fflush(stdout);
+    fprintf(stderr, "%s\n", message);
+    exit(1);
+}
+
+void rt_enter(int64_t locals) {
+    if (sp - stack < locals + 1) rt_fail("Stack overflow");
+    *--sp = ADDRESS(bp);
+    bp = sp;
+    sp -= locals;
+}
+
+int64_t rt_div(int64_t a, int64_t b) {
+    if (b == 0) rt_fail("Division by zero");
+    return b == -1 ? (int64_t)(0 - (uint64_t)a) : a / b;
+}
+
+int64_t rt_mod(int64_t a, int64_t b) {
+    if (b == 0) rt_fail("Division by zero in modulo");
+    return b == -1 ? 0 : a % b;
+}
+
+int64_t rt_open(int64_t path, int64_t flags) {
+    return open((const char *)(intptr_t)path, (int)flags, 0644);
+}
+
+int64_t rt_read(int64_t fd, int64_t buf, int64_t count) {
+    return read((int)fd, (void *)(intptr_t)buf, count < 0 ? 0 : (size_t)count);
+}
+
+int64_t rt_close(int64_t fd) {
+    return close((int)fd) == 0 ? 0 : -1;
+}
+
+int64_t rt_malloc(int64_t size) {
+    return size < 0 ? 0 : ADDRESS(calloc(1, size ? (size_t)size : 1));
+}
+
+int64_t rt_memset(int64_t dst, int64_t value, int64_t count) {
+    if (count > 0) memset((void *)(intptr_t)dst, (int)value, (size_t)count);
+    return dst;
+}
+
+int64_t rt_memcmp(int64_t a, int64_t b, int64_t count) {
+    int64_t i;
+    for (i = 0; i < count; i++) {
+        int64_t d = (int64_t)AT(unsigned char, a + i) - AT(unsigned char, b + i);
+        if (d) return d;
+    }
+    return 0;
+}
+
+static void rt_repeat(char c, uint64_t n) {
+    while (n-- > 0) putchar(c);
+}
+
+/* printf with 64-bit arguments, taken from args down for count words */
+int64_t rt_printf(const char *fmt, const int64_t *args, int64_t count) {
+    int64_t written = 0;
+#define NEXT_ARG() (count-- > 0 ? *args-- : 0)
+    while (*fmt) {
+        int left = 0, zero = 0, plus = 0, space = 0, numeric = 0;
+        uint64_t width = 0, zeros = 0, pad, len;
+        int64_t precision = -1, v;
+        const char *sign = "", *body;
+        char digits[32], conv;
+
+        if (*fmt != '%') {
+            putchar(*fmt++);
+            written++;
+            continue;
+        }
+        for (fmt++; ; fmt++) {
+            if (*fmt == '-') left = 1;
+            else if (*fmt == '0') zero = 1;
+            else if (*fmt == '+') plus = 1;
+            else if (*fmt == ' ') space = 1;
+            else break;
+        }
+        if (*fmt == '*') {
+            v = NEXT_ARG();
+            left |= v < 0;
+            width = v < 0 ? 0 - (uint64_t)v : (uint64_t)v;
+            fmt++;
+        }
+        for (; *fmt >= '0' && *fmt <= '9'; fmt++) width = width * 10 + (uint64_t)(*fmt - '0');
+        if (*fmt == '.') {
+            precision = 0;
+            if (*++fmt == '*') {
+                v = NEXT_ARG();
+                precision = v < 0 ? 0 : v;
+                fmt++;
+            }
+            for (; *fmt >= '0' && *fmt <= '9'; fmt++) precision = precision * 10 + (*fmt - '0');
+        }
+        while (*fmt == 'l') fmt++;
+        if (!*fmt) {
+            putchar('%');
+            written++;
+            break;
+        }
+
+        conv = *fmt++;
+        body = digits;
+        switch (conv) {
+        case 'd': case 'i':
+            v = NEXT_ARG();
+            sign = v < 0 ? "-" : plus ? "+" : space ? " " : "";
+            sprintf(digits, "%llu", (unsigned long long)(v < 0 ? 0 - (uint64_t)v : (uint64_t)v));
+            numeric = 1;
+            break;
+        case 'u': sprintf(digits, "%llu", (unsigned long long)NEXT_ARG()); numeric = 1; break;
+        case 'x': sprintf(digits, "%llx", (unsigned long long)NEXT_ARG()); numeric = 1; break;
+        case 'X': sprintf(digits, "%llX", (unsigned long long)NEXT_ARG()); numeric = 1; break;
+        case 'o': sprintf(digits, "%llo", (unsigned long long)NEXT_ARG()); numeric = 1; break;
+        case 'p': sign = "0x"; sprintf(digits, "%llx", (unsigned long long)NEXT_ARG()); break;
+        case 'c': digits[0] = (char)NEXT_ARG(); digits[1] = 0; break;
+        case 's': body = (const char *)(intptr_t)NEXT_ARG(); break;
+        case '%': strcpy(digits, "%"); break;
+        default: digits[0] = '%'; digits[1] = conv; digits[2] = 0; break;
+        }
+
+        len = conv == 'c' ? 1 : strlen(body);
+        if (conv == 's' && precision >= 0 && (uint64_t)precision < len) len = (uint64_t)precision;
+        if (numeric && precision == 0 && strcmp(body, "0") == 0) len = 0;
+        if (numeric && precision >= 0 && (uint64_t)precision > len) zeros = (uint64_t)precision - len;
+        len += strlen(sign) + zeros;
+        pad = width > len ? width - len : 0;
+        if (!left && !(zero && numeric && precision < 0)) rt_repeat(' ', pad);
+        fputs(sign, stdout);
+        if (!left && zero && numeric && precision < 0) rt_repeat('0', pad);
+        rt_repeat('0', zeros);
+        fwrite(body, 1, len - strlen(sign) - zeros, stdout);
+        if (left) rt_repeat(' ', pad);
+        written += len + pad;
+    }
+#undef NEXT_ARG
+    return written;
+}
+"#;
+
+/// Translate a linked program to a standalone C99 source file
+///
+/// The VM's registers and stack become C variables and every function a
+/// C function, so frames keep their layout: `JSR` pushes a return address
+/// and calls, `LEV` unwinds the frame and returns, and jumps within a
+/// function become `goto`s. Each statement is commented with the
+/// instruction it came from. The data segment is copied into an array of
+/// words at startup, and `main` pushes `argc` and `argv` as the VM does.
+/// The result compiles with `cc -std=c99 -Wall` on a platform with 64-bit
+/// pointers.
+///
+/// Programs that call native functions, that still need linking, or that
+/// jump or call into the middle of a function are rejected.
+pub fn emit(program: &ObjectFile) -> Result<String, CompilerError> {
+    if let Some(import) = program.imports.first() {
+        return Err(CompilerError::codegen_error(&format!("'{}' is not defined (link the program first)", import.name), None));
+    }
+    let entry = program.entry
+        .ok_or_else(|| CompilerError::codegen_error("main() not defined", None))?;
+    let code = instr::decode(&program.code)?;
+
+    let functions: BTreeMap<usize, String> = program.symbols.iter()
+        .filter(|sym| sym.kind == SymbolKind::Function)
+        .map(|sym| (sym.value as usize, format!("c4_{}", sym.name)))
+        .collect();
+    let Some(main) = functions.get(&entry) else {
+        return Err(CompilerError::codegen_error("main() is not a function", Some(entry)));
+    };
+
+    let mut emitter = Emitter {
+        out: String::new(),
+        data_relocations: program.relocations.iter()
+            .filter(|reloc| reloc.kind == RelocationKind::Data)
+            .map(|reloc| reloc.offset)
+            .collect(),
+    };
+    emitter.line("/* Generated by c4_rust from C4 bytecode */");
+    emitter.line(&format!("#define STACK_WORDS {}", DEFAULT_STACK_SIZE));
+    emitter.out.push_str(PRELUDE);
+    emitter.data(&program.data);
+    emitter.line("");
+    for name in functions.values() {
+        emitter.line(&format!("void {}(void);", name));
+    }
+
+    let ends = functions.keys().skip(1).copied().chain([code.len()]);
+    for ((&start, name), end) in functions.iter().zip(ends) {
+        // Only the targets of this function's jumps get labels, as C
+        // warns about unused ones
+        let mut labels = BTreeSet::new();
+        for (pc, &instr) in code.iter().enumerate().take(end).skip(start) {
+            match instr {
+                Instr::Jmp(target) | Instr::Bz(target) | Instr::Bnz(target) => {
+                    if !(start..end).contains(&target) {
+                        return Err(CompilerError::codegen_error("jump out of the function", Some(pc)));
+                    }
+                    labels.insert(target);
+                },
+                Instr::Jsr(target) | Instr::Tsr(target) if !functions.contains_key(&target) => {
+                    return Err(CompilerError::codegen_error("call into the middle of a function", Some(pc)));
+                },
+                _ => {},
+            }
+        }
+
+        emitter.line(&format!("\nvoid {}(void) {{", name));
+        for (pc, &instr) in code.iter().enumerate().take(end).skip(start) {
+            if labels.contains(&pc) {
+                emitter.line(&format!("L{}:", pc));
+            }
+            let statement = emitter.statement(pc, instr, &functions)?;
+            if !statement.is_empty() {
+                emitter.line(&format!("    {:<44} /* {} */", statement, instr));
+            }
+        }
+        emitter.line("}");
+    }
+    emitter.main(main, program.data.is_empty());
+
+    Ok(emitter.out)
+}
+
+struct Emitter {
+    out: String,
+    /// Operand words holding data addresses
+    data_relocations: BTreeSet<usize>,
+}
+
+impl Emitter {
+    fn line(&mut self, text: &str) {
+        writeln!(self.out, "{}", text).unwrap();
+    }
+
+    /// The data segment, as bytes copied at startup into words so that
+    /// loads of any size may read it
+    fn data(&mut self, data: &[u8]) {
+        self.line("");
+        self.line(&format!("static int64_t data[{}];", data.len().div_ceil(8).max(1)));
+        if data.is_empty() {
+            return;
+        }
+        self.line("static const unsigned char data_init[] = {");
+        for chunk in data.chunks(16) {
+            let bytes: Vec<String> = chunk.iter().map(|byte| byte.to_string()).collect();
+            self.line(&format!("    {},", bytes.join(", ")));
+        }
+        self.line("};");
+    }
+
+    /// A C `main` that calls the program's `main` as the VM would, with
+    /// `argc` and `argv` pushed in order and exiting with its result
+    fn main(&mut self, main: &str, no_data: bool) {
+        self.line("\nint main(int argc, char **argv) {");
+        if !no_data {
+            self.line("    memcpy(data, data_init, sizeof data_init);");
+        }
+        self.line("    sp = stack + STACK_WORDS;");
+        self.line("    *--sp = argc;");
+        self.line("    *--sp = ADDRESS(argv);");
+        self.line("    *--sp = 0;");
+        self.line(&format!("    {}();", main));
+        self.line("    return (int)ax;");
+        self.line("}");
+    }
+
+    /// The operand of the instruction at `pc` as a C expression
+    fn value(&self, pc: usize, value: i64) -> String {
+        if self.data_relocations.contains(&(pc + 1)) {
+            format!("ADDRESS((char *)data + {})", value)
+        } else if value == i64::MIN {
+            "INT64_MIN".to_string()
+        } else if i32::try_from(value).is_ok() {
+            value.to_string()
+        } else {
+            format!("INT64_C({})", value)
+        }
+    }
+
+    /// The C statement for one instruction; operand words have none
+    fn statement(&self, pc: usize, instr: Instr, functions: &BTreeMap<usize, String>) -> Result<String, CompilerError> {
+        // Arithmetic wraps, as in the VM, by going through unsigned words
+        let wrapping = |op: &str| format!("ax = (int64_t)((uint64_t)*sp++ {} (uint64_t)ax);", op);
+        let arg = |argc: usize, i: usize| format!("sp[{}]", argc - 1 - i);
+
+        Ok(match instr {
+            Instr::Lea(offset) => format!("ax = ADDRESS(bp + {});", offset),
+            Instr::Imm(value) => format!("ax = {};", self.value(pc, value)),
+            Instr::Jmp(target) => format!("goto L{};", target),
+            Instr::Jsr(target) => format!("*--sp = {}; {}();", pc + 2, functions[&target]),
+            Instr::Bz(target) => format!("if (!ax) goto L{};", target),
+            Instr::Bnz(target) => format!("if (ax) goto L{};", target),
+            Instr::Ent(locals) => format!("rt_enter({});", locals),
+            Instr::Adj(words) => format!("sp += {};", words),
+            Instr::Lev => "sp = bp; bp = (int64_t *)(intptr_t)*sp++; sp++; return;".to_string(),
+            Instr::Tsr(target) => format!("sp = bp; bp = (int64_t *)(intptr_t)*sp++; {}(); return;", functions[&target]),
+            Instr::Li => "ax = AT(int64_t, ax);".to_string(),
+            Instr::Lc => "ax = AT(signed char, ax);".to_string(),
+            Instr::Si => "AT(int64_t, *sp++) = ax;".to_string(),
+            Instr::Sc => "ax = AT(signed char, *sp++) = (signed char)ax;".to_string(),
+            Instr::Psh => "*--sp = ax;".to_string(),
+            Instr::Or => "ax = *sp++ | ax;".to_string(),
+            Instr::Xor => "ax = *sp++ ^ ax;".to_string(),
+            Instr::And => "ax = *sp++ & ax;".to_string(),
+            Instr::Eq => "ax = *sp++ == ax;".to_string(),
+            Instr::Ne => "ax = *sp++ != ax;".to_string(),
+            Instr::Lt => "ax = *sp++ < ax;".to_string(),
+            Instr::Gt => "ax = *sp++ > ax;".to_string(),
+            Instr::Le => "ax = *sp++ <= ax;".to_string(),
+            Instr::Ge => "ax = *sp++ >= ax;".to_string(),
+            Instr::Shl => "ax = (int64_t)((uint64_t)*sp++ << (ax & 63));".to_string(),
+            Instr::Shr => "ax = *sp++ >> (ax & 63);".to_string(),
+            Instr::Add => wrapping("+"),
+            Instr::Sub => wrapping("-"),
+            Instr::Mul => wrapping("*"),
+            Instr::Div => "ax = rt_div(*sp++, ax);".to_string(),
+            Instr::Mod => "ax = rt_mod(*sp++, ax);".to_string(),
+            Instr::Open => format!("ax = rt_open({}, {});", arg(2, 0), arg(2, 1)),
+            Instr::Read => format!("ax = rt_read({}, {}, {});", arg(3, 0), arg(3, 1), arg(3, 2)),
+            Instr::Clos => format!("ax = rt_close({});", arg(1, 0)),
+            Instr::Prtf(argc) => format!("ax = rt_printf((const char *)(intptr_t)sp[{}], sp + {}, {});", argc - 1, argc as i64 - 2, argc - 1),
+            Instr::Malc => format!("ax = rt_malloc({});", arg(1, 0)),
+            Instr::Free => format!("free((void *)(intptr_t){});", arg(1, 0)),
+            Instr::Mset => format!("ax = rt_memset({}, {}, {});", arg(3, 0), arg(3, 1), arg(3, 2)),
+            Instr::Mcmp => format!("ax = rt_memcmp({}, {}, {});", arg(3, 0), arg(3, 1), arg(3, 2)),
+            Instr::Exit => "exit((int)ax);".to_string(),
+            Instr::Neg => "ax = (int64_t)(0 - (uint64_t)ax);".to_string(),
+            Instr::Lli(offset) => format!("ax = bp[{}];", offset),
+            Instr::Llc(offset) => format!("ax = AT(signed char, bp + {});", offset),
+            Instr::Pshl(offset) => format!("*--sp = ax = bp[{}];", offset),
+            Instr::Pshi(value) => format!("*--sp = ax = {};", self.value(pc, value)),
+            Instr::Addi(value) => format!("ax = (int64_t)((uint64_t)ax + (uint64_t){});", self.value(pc, value)),
+            Instr::Subi(value) => format!("ax = (int64_t)((uint64_t)ax - (uint64_t){});", self.value(pc, value)),
+            Instr::Muli(value) => format!("ax = (int64_t)((uint64_t)ax * (uint64_t){});", self.value(pc, value)),
+            Instr::Natv(_) => {
+                return Err(CompilerError::codegen_error("native functions cannot be called from compiled code", Some(pc)));
+            },
+            Instr::Operand => String::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compile, compile_with};
+
+    #[test]
+    fn test_emit() {
+        let program = compile(r#"
+            int main() { int i; i = 0; while (i < 3) i = i + 1; printf("%d\n", i); return 0; }
+        "#);
+        let c = emit(&program).unwrap();
+        assert!(c.contains("void c4_main(void) {"), "{}", c);
+        assert!(c.contains("goto L"));
+        assert!(c.contains("ADDRESS((char *)data + 0)"));
+        assert!(c.contains("static const unsigned char data_init[] = {\n    37, 100, 10, 0"));
+        assert!(c.contains("    c4_main();"));
+    }
+
+    #[test]
+    fn test_emit_rejects_unlinked_programs() {
+        let object = compile_with("int f(); int main() { return f(); }", |parser| parser.set_separate_compilation(true));
+        assert!(matches!(emit(&object), Err(CompilerError::CodegenError { .. })));
+    }
+}
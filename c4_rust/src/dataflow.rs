@@ -0,0 +1,606 @@
+use crate::instr::Instr;
+use crate::ir::{Function, Inst, Program, Reloc, Terminator};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Rounds of propagation and dead store removal before settling for what
+/// has been found
+const MAX_ROUNDS: usize = 8;
+
+/// New frame offsets of each function's locals, by function name; locals
+/// missing from a map were removed, and parameters never move
+pub type FrameMaps = BTreeMap<String, BTreeMap<i64, i64>>;
+
+/// How a frame slot is loaded and stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Width {
+    Int,
+    Char,
+}
+
+/// A load from or store to a frame slot, by instruction index in its block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    /// `at` reads the slot: `LI`/`LC` after the `LEA` at `lea`, or a fused
+    /// `LLI`, `LLC` or `PSHL`
+    Load { slot: i64, width: Width, at: usize, lea: Option<usize> },
+    /// `LEA slot` at `lea`, pushed by the `PSH` at `psh` and written by the
+    /// `SI`/`SC` at `at`
+    Store { slot: i64, width: Width, lea: usize, psh: usize, at: usize },
+}
+
+impl Access {
+    fn lea(&self) -> Option<usize> {
+        match *self {
+            Access::Load { lea, .. } => lea,
+            Access::Store { lea, .. } => Some(lea),
+        }
+    }
+}
+
+/// The frame slots a function accesses, and how
+struct Frame {
+    /// Accesses of each block, in order
+    accesses: Vec<Vec<Access>>,
+    /// Slots only ever loaded and stored directly, at one width
+    slots: BTreeMap<i64, Width>,
+    /// Every slot in use, including those reachable through an address
+    /// that escaped
+    used: BTreeSet<i64>,
+    /// Slots whose address escaped
+    escaped: BTreeSet<i64>,
+}
+
+impl Frame {
+    /// Find the accesses of every slot by following `LEA` addresses
+    /// through `ax` and the stack
+    ///
+    /// A slot whose address is used any other way escapes: it may be read
+    /// or written through a pointer, so it is left alone, along with the
+    /// slots above it, since it may be the start of an array.
+    fn analyze(func: &Function) -> Frame {
+        let mut accesses = Vec::new();
+        let mut escaped = BTreeSet::new();
+        let mut referenced = BTreeSet::new();
+        for block in &func.blocks {
+            let mut list = Vec::new();
+            // The slot whose address is in ax, and the LEA that put it there
+            let mut ax: Option<(i64, usize)> = None;
+            // Stack entries holding slot addresses, with their LEA and PSH
+            let mut stack: Vec<Option<(i64, usize, usize)>> = Vec::new();
+            for (i, inst) in block.insts.iter().enumerate() {
+                let instr = inst.instr;
+                match (instr, ax) {
+                    (Instr::Lea(slot), _) => {
+                        referenced.insert(slot);
+                    },
+                    (Instr::Lli(slot) | Instr::Pshl(slot), _) => {
+                        referenced.insert(slot);
+                        list.push(Access::Load { slot, width: Width::Int, at: i, lea: None });
+                    },
+                    (Instr::Llc(slot), _) => {
+                        referenced.insert(slot);
+                        list.push(Access::Load { slot, width: Width::Char, at: i, lea: None });
+                    },
+                    (Instr::Li, Some((slot, lea))) => {
+                        list.push(Access::Load { slot, width: Width::Int, at: i, lea: Some(lea) });
+                    },
+                    (Instr::Lc, Some((slot, lea))) => {
+                        list.push(Access::Load { slot, width: Width::Char, at: i, lea: Some(lea) });
+                    },
+                    _ => {},
+                }
+
+                let (pops, pushes) = instr.stack_effect();
+                for n in 0..pops {
+                    if let Some((slot, lea, psh)) = stack.pop().flatten() {
+                        match instr {
+                            Instr::Si if n == 0 => list.push(Access::Store { slot, width: Width::Int, lea, psh, at: i }),
+                            Instr::Sc if n == 0 => list.push(Access::Store { slot, width: Width::Char, lea, psh, at: i }),
+                            _ => {
+                                escaped.insert(slot);
+                            },
+                        }
+                    }
+                }
+                for _ in 0..pushes {
+                    let tag = if instr == Instr::Psh { ax.map(|(slot, lea)| (slot, lea, i)) } else { None };
+                    stack.push(tag);
+                }
+
+                ax = match instr {
+                    Instr::Lea(slot) => Some((slot, i)),
+                    Instr::Psh | Instr::Adj(_) => ax,
+                    Instr::Li | Instr::Lc => None,
+                    _ => {
+                        if let (Some((slot, _)), true) = (ax, reads_ax(instr)) {
+                            escaped.insert(slot);
+                        }
+                        None
+                    },
+                };
+            }
+            escaped.extend(stack.into_iter().flatten().map(|(slot, _, _)| slot));
+            escaped.extend(ax.map(|(slot, _)| slot));
+            accesses.push(list);
+        }
+
+        let mut tainted = escaped.clone();
+        for &slot in escaped.iter().filter(|&&slot| slot < 0) {
+            tainted.extend(slot..0);
+        }
+        let mut widths: BTreeMap<i64, Option<Width>> = BTreeMap::new();
+        for access in accesses.iter().flatten() {
+            let (slot, width) = match *access {
+                Access::Load { slot, width, .. } | Access::Store { slot, width, .. } => (slot, width),
+            };
+            let entry = widths.entry(slot).or_insert(Some(width));
+            if *entry != Some(width) {
+                *entry = None;
+            }
+        }
+        let slots = widths.into_iter()
+            .filter(|(slot, _)| !tainted.contains(slot))
+            .filter_map(|(slot, width)| width.map(|width| (slot, width)))
+            .collect();
+        let used = referenced.union(&tainted).copied().collect();
+        Frame { accesses, slots, used, escaped }
+    }
+}
+
+/// Whether an instruction replaces `ax` without reading it
+pub(crate) fn clobbers_ax(instr: Instr) -> bool {
+    matches!(instr,
+        Instr::Imm(_) | Instr::Lea(_) | Instr::Lli(_) | Instr::Llc(_) | Instr::Pshl(_) | Instr::Pshi(_)
+        | Instr::Jsr(_) | Instr::Natv(_) | Instr::Open | Instr::Read | Instr::Clos | Instr::Prtf(_)
+        | Instr::Malc | Instr::Free | Instr::Mset | Instr::Mcmp)
+}
+
+/// Whether an instruction reads `ax`, other than `PSH` and `ADJ`, which
+/// pass it on
+fn reads_ax(instr: Instr) -> bool {
+    !clobbers_ax(instr) && !matches!(instr, Instr::Psh | Instr::Adj(_))
+}
+
+/// What is known about a slot on entry to a block
+#[derive(Debug, Clone, PartialEq)]
+enum Fact {
+    Const(i64, Option<Reloc>),
+    /// Holds the same value as another slot
+    Copy(i64),
+}
+
+/// What is known about `ax`
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Unknown,
+    Const(i64, Option<Reloc>),
+    /// The current value of a slot
+    Slot(i64),
+}
+
+type Facts = BTreeMap<i64, Fact>;
+
+/// Whether the address of a parameter or local may be used other than
+/// to load or store it directly, so the slot can be reached through a
+/// pointer
+pub fn address_escapes(func: &Function) -> bool {
+    !Frame::analyze(func).escaped.is_empty()
+}
+
+/// Run the dataflow optimizations over every function
+///
+/// Unreachable blocks are removed, chains of jumps are merged into single
+/// blocks, constants and copies stored in locals
+/// are propagated to their loads (folding branches on constants), stores
+/// that are never read are removed, and the frame shrinks to the locals
+/// still in use. Only slots whose address never escapes are touched, so
+/// pointers and arrays keep working.
+pub fn optimize(program: &mut Program) -> FrameMaps {
+    program.functions.iter_mut()
+        .map(|func| (func.name.clone(), optimize_function(func)))
+        .collect()
+}
+
+fn optimize_function(func: &mut Function) -> BTreeMap<i64, i64> {
+    remove_unreachable(func);
+    for _ in 0..MAX_ROUNDS {
+        let mut changed = propagate(func);
+        changed |= remove_unreachable(func);
+        changed |= merge_blocks(func);
+        changed |= remove_dead_stores(func);
+        if !changed {
+            break;
+        }
+    }
+    shrink_frame(func)
+}
+
+/// Remove blocks that cannot be reached from the entry block
+pub fn remove_unreachable(func: &mut Function) -> bool {
+    let mut reachable = vec![false; func.blocks.len()];
+    let mut work = vec![0];
+    while let Some(id) = work.pop() {
+        if id < reachable.len() && !reachable[id] {
+            reachable[id] = true;
+            work.extend(func.blocks[id].terminator.successors());
+        }
+    }
+    if reachable.iter().all(|&reached| reached) {
+        return false;
+    }
+
+    let mut ids = vec![0; func.blocks.len()];
+    let mut next = 0;
+    for (id, &reached) in reachable.iter().enumerate() {
+        ids[id] = next;
+        next += reached as usize;
+    }
+    let mut index = 0;
+    func.blocks.retain(|_| {
+        index += 1;
+        reachable[index - 1]
+    });
+    for block in &mut func.blocks {
+        block.terminator = match block.terminator {
+            Terminator::Jump(to) => Terminator::Jump(ids[to]),
+            Terminator::Branch { zero, nonzero } => Terminator::Branch { zero: ids[zero], nonzero: ids[nonzero] },
+            terminator => terminator,
+        };
+    }
+    true
+}
+
+/// Append each block that is only entered by a jump from one other block
+/// to that block
+pub fn merge_blocks(func: &mut Function) -> bool {
+    let mut merged = false;
+    loop {
+        let preds = func.predecessors();
+        let found = func.blocks.iter().enumerate().find_map(|(id, block)| match block.terminator {
+            Terminator::Jump(succ) if succ != id && succ != 0 && preds[succ].len() == 1 => Some((id, succ)),
+            _ => None,
+        });
+        let Some((id, succ)) = found else { return merged };
+        let next = func.blocks[succ].clone();
+        let block = &mut func.blocks[id];
+        block.insts.extend(next.insts);
+        block.terminator = next.terminator;
+        block.terminator_origin = next.terminator_origin;
+        // The appended block is now unreachable
+        func.blocks[succ].terminator = Terminator::Return;
+        remove_unreachable(func);
+        merged = true;
+    }
+}
+
+/// Replace loads of slots holding a known constant or a copy of another
+/// slot, and turn branches on constants into jumps
+pub fn propagate(func: &mut Function) -> bool {
+    let frame = Frame::analyze(func);
+    let ints: BTreeSet<i64> = frame.slots.iter()
+        .filter(|(_, width)| **width == Width::Int)
+        .map(|(slot, _)| *slot)
+        .collect();
+
+    // Facts on entry to each block, None until a path reaches it
+    let mut entry: Vec<Option<Facts>> = vec![None; func.blocks.len()];
+    entry[0] = Some(Facts::new());
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for id in 0..func.blocks.len() {
+            let Some(facts) = entry[id].clone() else { continue };
+            let (facts, _, _) = transfer(func, &frame, &ints, id, facts);
+            for succ in func.blocks[id].terminator.successors() {
+                let merged = match &entry[succ] {
+                    None => facts.clone(),
+                    Some(known) => known.iter()
+                        .filter(|(slot, fact)| facts.get(slot) == Some(fact))
+                        .map(|(slot, fact)| (*slot, fact.clone()))
+                        .collect(),
+                };
+                if entry[succ].as_ref() != Some(&merged) {
+                    entry[succ] = Some(merged);
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    let mut rewrote = false;
+    for (id, facts) in entry.into_iter().enumerate() {
+        let Some(facts) = facts else { continue };
+        let (_, loads, ax) = transfer(func, &frame, &ints, id, facts);
+        let shared: BTreeSet<usize> = frame.accesses[id].iter()
+            .filter_map(|access| match access {
+                Access::Store { lea, .. } => Some(*lea),
+                _ => None,
+            })
+            .collect();
+
+        let block = &mut func.blocks[id];
+        let mut edits: BTreeMap<usize, Option<Inst>> = BTreeMap::new();
+        for (access, value) in loads {
+            let Access::Load { slot, at, lea, .. } = access else { continue };
+            let inst = &block.insts[at];
+            // The LEA of a LEA; LI pair, if nothing else needs it
+            let pair = lea.filter(|&lea| lea + 1 == at && !shared.contains(&lea));
+            match (value, inst.instr) {
+                (Value::Const(n, reloc), Instr::Lli(_)) => {
+                    edits.insert(at, Some(Inst { instr: Instr::Imm(n), reloc, origin: inst.origin }));
+                },
+                (Value::Const(n, reloc), Instr::Pshl(_)) => {
+                    edits.insert(at, Some(Inst { instr: Instr::Pshi(n), reloc, origin: inst.origin }));
+                },
+                (Value::Const(n, reloc), Instr::Li) => match pair {
+                    Some(lea) => {
+                        let origin = block.insts[lea].origin;
+                        edits.insert(lea, Some(Inst { instr: Instr::Imm(n), reloc, origin }));
+                        edits.insert(at, None);
+                    },
+                    None => {
+                        edits.insert(at, Some(Inst { instr: Instr::Imm(n), reloc, origin: inst.origin }));
+                    },
+                },
+                (Value::Slot(other), Instr::Lli(_)) if other != slot => {
+                    edits.insert(at, Some(Inst { instr: Instr::Lli(other), ..inst.clone() }));
+                },
+                (Value::Slot(other), Instr::Pshl(_)) if other != slot => {
+                    edits.insert(at, Some(Inst { instr: Instr::Pshl(other), ..inst.clone() }));
+                },
+                (Value::Slot(other), Instr::Li) if other != slot => {
+                    if let Some(lea) = pair {
+                        edits.insert(lea, Some(Inst { instr: Instr::Lea(other), ..block.insts[lea].clone() }));
+                    }
+                },
+                _ => {},
+            }
+        }
+        if !edits.is_empty() {
+            rewrote = true;
+            apply(&mut block.insts, edits);
+        }
+
+        if let (Terminator::Branch { zero, nonzero }, Value::Const(n, None)) = (block.terminator, ax) {
+            block.terminator = Terminator::Jump(if n != 0 { nonzero } else { zero });
+            rewrote = true;
+        }
+    }
+    rewrote
+}
+
+/// Run a block's instructions over the facts on entry to it, returning
+/// the facts on exit, each load of a tracked slot with what it reads, and
+/// what is left in `ax`
+fn transfer(
+    func: &Function,
+    frame: &Frame,
+    ints: &BTreeSet<i64>,
+    id: usize,
+    mut facts: Facts,
+) -> (Facts, Vec<(Access, Value)>, Value) {
+    let accesses: BTreeMap<usize, Access> = frame.accesses[id].iter()
+        .map(|access| match *access {
+            Access::Load { at, .. } | Access::Store { at, .. } => (at, *access),
+        })
+        .collect();
+    let mut loads = Vec::new();
+    let mut ax = Value::Unknown;
+    // Constants on the expression stack, for folding operators
+    let mut stack: Vec<Option<i64>> = Vec::new();
+    for (i, inst) in func.blocks[id].insts.iter().enumerate() {
+        let instr = inst.instr;
+        match accesses.get(&i) {
+            Some(&access @ Access::Load { slot, .. }) if ints.contains(&slot) => {
+                ax = match facts.get(&slot) {
+                    Some(Fact::Const(n, reloc)) => Value::Const(*n, reloc.clone()),
+                    Some(Fact::Copy(other)) => Value::Slot(*other),
+                    None => Value::Slot(slot),
+                };
+                loads.push((access, ax.clone()));
+            },
+            Some(&Access::Store { slot, .. }) if ints.contains(&slot) => {
+                facts.remove(&slot);
+                facts.retain(|_, fact| *fact != Fact::Copy(slot));
+                match &ax {
+                    Value::Const(n, reloc) => {
+                        facts.insert(slot, Fact::Const(*n, reloc.clone()));
+                    },
+                    Value::Slot(other) if *other != slot => {
+                        facts.insert(slot, Fact::Copy(*other));
+                    },
+                    _ => {},
+                }
+            },
+            _ => {
+                let constant = match ax {
+                    Value::Const(n, None) => Some(n),
+                    _ => None,
+                };
+                ax = match (instr, constant) {
+                    (Instr::Imm(n) | Instr::Pshi(n), _) => Value::Const(n, inst.reloc.clone()),
+                    (Instr::Psh | Instr::Adj(_), _) => ax,
+                    (Instr::Addi(k), Some(n)) => Value::Const(n.wrapping_add(k), None),
+                    (Instr::Subi(k), Some(n)) => Value::Const(n.wrapping_sub(k), None),
+                    (Instr::Muli(k), Some(n)) => Value::Const(n.wrapping_mul(k), None),
+                    (_, Some(rhs)) => stack.last().copied().flatten()
+                        .zip(instr.opcode())
+                        .and_then(|(lhs, op)| op.fold(lhs, rhs))
+                        .map_or(Value::Unknown, |n| Value::Const(n, None)),
+                    _ => Value::Unknown,
+                };
+            },
+        }
+
+        let (pops, pushes) = instr.stack_effect();
+        let popped: Vec<Option<i64>> = (0..pops).map(|_| stack.pop().flatten()).collect();
+        if pops > 0 && pushes == pops {
+            stack.extend(popped.into_iter().rev());
+        } else {
+            for _ in 0..pushes {
+                stack.push(match ax {
+                    Value::Const(n, None) => Some(n),
+                    _ => None,
+                });
+            }
+        }
+    }
+    (facts, loads, ax)
+}
+
+/// Remove stores to slots that are never read afterwards
+pub fn remove_dead_stores(func: &mut Function) -> bool {
+    let frame = Frame::analyze(func);
+
+    // Slots live on entry to each block, found by iterating backwards
+    let mut live_in: Vec<BTreeSet<i64>> = vec![BTreeSet::new(); func.blocks.len()];
+    let live_out = |live_in: &[BTreeSet<i64>], id: usize| -> BTreeSet<i64> {
+        let mut live: BTreeSet<i64> = func.blocks[id].terminator.successors().iter()
+            .flat_map(|&succ| live_in[succ].iter().copied())
+            .collect();
+        // A tail call passes its arguments in the parameter slots
+        if let Terminator::TailCall(_) = func.blocks[id].terminator {
+            live.extend(frame.slots.keys().filter(|&&slot| slot >= 2));
+        }
+        live
+    };
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for id in (0..func.blocks.len()).rev() {
+            let mut live = live_out(&live_in, id);
+            for access in frame.accesses[id].iter().rev() {
+                match *access {
+                    Access::Load { slot, .. } => {
+                        live.insert(slot);
+                    },
+                    Access::Store { slot, .. } => {
+                        live.remove(&slot);
+                    },
+                }
+            }
+            if live != live_in[id] {
+                live_in[id] = live;
+                changed = true;
+            }
+        }
+    }
+
+    let mut dead = Vec::new();
+    for id in 0..func.blocks.len() {
+        let mut live = live_out(&live_in, id);
+        let mut lea_uses: BTreeMap<usize, usize> = BTreeMap::new();
+        for lea in frame.accesses[id].iter().filter_map(Access::lea) {
+            *lea_uses.entry(lea).or_default() += 1;
+        }
+
+        let insts = &func.blocks[id].insts;
+        let mut edits = BTreeMap::new();
+        for access in frame.accesses[id].iter().rev() {
+            match *access {
+                Access::Load { slot, .. } => {
+                    live.insert(slot);
+                },
+                Access::Store { slot, width, lea, psh, at } => {
+                    // SC leaves the truncated value in ax, so it can only go
+                    // if ax is replaced right after
+                    let ax_dead = width == Width::Int
+                        || insts.get(at + 1).is_some_and(|next| clobbers_ax(next.instr));
+                    if frame.slots.contains_key(&slot) && !live.contains(&slot) && psh == lea + 1
+                        && lea_uses[&lea] == 1 && ax_dead {
+                        edits.insert(lea, None);
+                        edits.insert(psh, None);
+                        edits.insert(at, None);
+                    }
+                    live.remove(&slot);
+                },
+            }
+        }
+        if !edits.is_empty() {
+            dead.push((id, edits));
+        }
+    }
+    let removed = !dead.is_empty();
+    for (id, edits) in dead {
+        apply(&mut func.blocks[id].insts, edits);
+    }
+    removed
+}
+
+/// Pack the locals still in use next to each other, so `ENT` reserves no
+/// more than it needs, and return their new offsets
+pub fn shrink_frame(func: &mut Function) -> BTreeMap<i64, i64> {
+    let frame = Frame::analyze(func);
+    let map: BTreeMap<i64, i64> = frame.used.iter().rev()
+        .filter(|&&slot| slot < 0)
+        .enumerate()
+        .map(|(index, &slot)| (slot, -(index as i64) - 1))
+        .collect();
+    func.frame = map.len() as i64;
+    for inst in func.blocks.iter_mut().flat_map(|block| block.insts.iter_mut()) {
+        inst.instr = match inst.instr {
+            Instr::Lea(slot) if slot < 0 => Instr::Lea(map[&slot]),
+            Instr::Lli(slot) if slot < 0 => Instr::Lli(map[&slot]),
+            Instr::Llc(slot) if slot < 0 => Instr::Llc(map[&slot]),
+            Instr::Pshl(slot) if slot < 0 => Instr::Pshl(map[&slot]),
+            instr => instr,
+        };
+    }
+    map
+}
+
+/// Replace (`Some`) or delete (`None`) instructions by index
+fn apply(insts: &mut Vec<Inst>, mut edits: BTreeMap<usize, Option<Inst>>) {
+    let old = std::mem::take(insts);
+    for (i, inst) in old.into_iter().enumerate() {
+        match edits.remove(&i) {
+            Some(Some(replacement)) => insts.push(replacement),
+            Some(None) => {},
+            None => insts.push(inst),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::vm::VirtualMachine;
+
+    fn optimized(source: &str) -> (Program, FrameMaps) {
+        let mut parser = Parser::new(source.to_string(), false);
+        parser.init().unwrap();
+        parser.parse().unwrap();
+        let mut program = Program::from_parser(&parser).unwrap();
+        let frames = optimize(&mut program);
+        program.verify().unwrap();
+        (program, frames)
+    }
+
+    fn run(program: &Program) -> i64 {
+        let lowered = program.to_code();
+        let main = lowered.functions.iter().find(|(name, _)| name == "main").unwrap().1;
+        let mut vm = VirtualMachine::new(lowered.code, Vec::new(), 64 * 1024, false);
+        vm.run(main, &[]).unwrap()
+    }
+
+    #[test]
+    fn test_propagation_folds_everything_known() {
+        let (program, frames) = optimized(
+            "int main() { int a, b, c; a = 4; b = a; c = b * 2; if (c == 8) return b + c; return 0; }");
+        let main = &program.functions[0];
+        assert_eq!(main.frame, 0);
+        assert_eq!(main.blocks.len(), 1);
+        assert!(frames["main"].is_empty());
+        assert_eq!(run(&program), 12);
+    }
+
+    #[test]
+    fn test_escaped_locals_are_kept() {
+        let (program, frames) = optimized(
+            "int main() { int x, dead, *p; dead = 7; x = 1; p = &x; *p = 2; return x; }");
+        assert_eq!(program.functions[0].frame, 2);
+        assert_eq!(frames["main"], BTreeMap::from([(-1, -1), (-3, -2)]));
+        assert_eq!(run(&program), 2);
+    }
+}
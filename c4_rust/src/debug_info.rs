@@ -0,0 +1,186 @@
+use crate::error::{SourceLocation, StackFrame};
+use crate::types::Type;
+
+/// The source position whose code starts at a code address
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineEntry {
+    pub pc: usize,
+    pub location: SourceLocation,
+}
+
+/// A parameter or local variable of a function
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalVariable {
+    pub name: String,
+    pub typ: Type,
+    /// Word offset from the frame's `bp` (parameters are positive)
+    pub offset: i64,
+    /// Number of elements if the variable is an array
+    pub array_len: Option<usize>,
+    /// Code addresses where the variable is in scope
+    pub start: usize,
+    pub end: usize,
+}
+
+/// The code of one function
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionRange {
+    pub name: String,
+    /// Address of the function's first instruction
+    pub start: usize,
+    /// Address just past its last instruction
+    pub end: usize,
+    /// Source file the function came from, once known (the linker fills
+    /// it in from the unit's name)
+    pub file: Option<String>,
+    /// Parameters and locals, in declaration order
+    pub locals: Vec<LocalVariable>,
+}
+
+impl FunctionRange {
+    /// The variable a name refers to at `pc`, the innermost if several
+    /// are in scope
+    pub fn local(&self, name: &str, pc: usize) -> Option<&LocalVariable> {
+        self.locals.iter().rev()
+            .find(|var| var.name == name && var.start <= pc && pc < var.end)
+    }
+
+    /// The variables in scope at `pc`, leaving out shadowed ones
+    pub fn locals_at(&self, pc: usize) -> Vec<&LocalVariable> {
+        let mut visible: Vec<&LocalVariable> = Vec::new();
+        for var in self.locals.iter().filter(|var| var.start <= pc && pc < var.end) {
+            visible.retain(|other| other.name != var.name);
+            visible.push(var);
+        }
+        visible
+    }
+
+    /// Apply `moved` to every code address
+    fn move_code(&mut self, moved: impl Fn(usize) -> usize) {
+        self.start = moved(self.start);
+        self.end = moved(self.end);
+        for var in &mut self.locals {
+            var.start = moved(var.start);
+            var.end = moved(var.end);
+        }
+    }
+}
+
+/// Tables mapping code addresses back to the source
+///
+/// `lines` is sorted by address; an address belongs to the last entry at
+/// or before it. The parser adds an entry where each statement's code
+/// starts, so locations are as precise as statements are.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DebugInfo {
+    pub lines: Vec<LineEntry>,
+    pub functions: Vec<FunctionRange>,
+}
+
+impl DebugInfo {
+    /// Record that the code from `pc` on comes from `location`
+    ///
+    /// A later entry at the same address replaces the earlier one, since a
+    /// statement that generated no code does not own any.
+    pub fn add_line(&mut self, pc: usize, location: SourceLocation) {
+        match self.lines.last_mut() {
+            Some(last) if last.pc == pc => last.location = location,
+            Some(last) if last.location == location => {},
+            _ => self.lines.push(LineEntry { pc, location }),
+        }
+    }
+
+    /// Source position of the code at `pc`
+    pub fn location(&self, pc: usize) -> Option<SourceLocation> {
+        let index = self.lines.partition_point(|entry| entry.pc <= pc);
+        index.checked_sub(1).map(|index| self.lines[index].location)
+    }
+
+    /// The function containing `pc`
+    pub fn function(&self, pc: usize) -> Option<&FunctionRange> {
+        self.functions.iter().find(|func| func.start <= pc && pc < func.end)
+    }
+
+    /// Describe the code at `pc` as a backtrace frame
+    pub fn frame(&self, pc: usize) -> StackFrame {
+        let function = self.function(pc);
+        StackFrame {
+            pc,
+            function: function.map(|func| func.name.clone()),
+            file: function.and_then(|func| func.file.clone()),
+            location: self.location(pc),
+        }
+    }
+
+    /// Follow a code rewrite, given its map from old to new addresses
+    pub fn remap(&mut self, map: &[usize]) {
+        let lines = std::mem::take(&mut self.lines);
+        for entry in lines {
+            self.add_line(map[entry.pc], entry.location);
+        }
+        for func in &mut self.functions {
+            func.move_code(|pc| map[pc]);
+        }
+    }
+
+    /// Account for code removed from `start..end`
+    pub fn remove_code(&mut self, start: usize, end: usize) {
+        let removed = end - start;
+        let mut lines = std::mem::take(&mut self.lines);
+        for entry in &mut lines {
+            if entry.pc >= end {
+                entry.pc -= removed;
+            } else if entry.pc > start {
+                entry.pc = start;
+            }
+        }
+        for entry in lines {
+            self.add_line(entry.pc, entry.location);
+        }
+        let moved = |pc: usize| if pc >= end { pc - removed } else { pc.min(start) };
+        for func in &mut self.functions {
+            func.move_code(moved);
+        }
+    }
+
+    /// Move the tables to code placed at `base`, from the file `file`
+    pub fn rebase(&mut self, base: usize, file: &str) {
+        for entry in &mut self.lines {
+            entry.pc += base;
+        }
+        for func in &mut self.functions {
+            func.move_code(|pc| pc + base);
+            func.file.get_or_insert_with(|| file.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_and_remap() {
+        let mut info = DebugInfo::default();
+        info.add_line(0, SourceLocation::new(1, 1));
+        info.add_line(4, SourceLocation::new(2, 5));
+        info.add_line(4, SourceLocation::new(3, 5)); // replaces the empty statement
+        info.add_line(9, SourceLocation::new(4, 1));
+        info.functions.push(FunctionRange { name: "main".to_string(), start: 0, end: 12, file: None, locals: Vec::new() });
+
+        assert_eq!(info.location(3), Some(SourceLocation::new(1, 1)));
+        assert_eq!(info.location(8), Some(SourceLocation::new(3, 5)));
+        assert_eq!(info.function(11).map(|func| func.name.as_str()), Some("main"));
+        assert_eq!(info.function(12), None);
+
+        // Dropping 2..6 merges the entry at 4 into the one at 2
+        info.remove_code(2, 6);
+        assert_eq!(info.lines.iter().map(|entry| entry.pc).collect::<Vec<_>>(), [0, 2, 5]);
+        assert_eq!(info.location(2), Some(SourceLocation::new(3, 5)));
+
+        info.rebase(100, "a.c");
+        let frame = info.frame(105);
+        assert_eq!((frame.function.as_deref(), frame.file.as_deref()), (Some("main"), Some("a.c")));
+        assert_eq!(frame.location, Some(SourceLocation::new(4, 1)));
+    }
+}
@@ -0,0 +1,897 @@
+use crate::error::{CompilerError, StackFrame};
+use crate::instr::{self, Instr};
+use crate::object::{ObjectFile, ObjectSymbol, SymbolKind};
+use crate::types::Type;
+use crate::vm::{Frame, VirtualMachine, VmLimits};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "\
+run, r                  start the program
+continue, c             run until a breakpoint, a watchpoint or the end
+stepi, si [n]           execute n instructions
+step, s [n]             run to the next source line, entering calls
+next, n [n]             run to the next source line, stepping over calls
+finish                  run until the current function returns
+break, b <where>        stop at a function, a line, file:line or *pc
+watch <what>            stop when a variable, or the word at *address, changes
+delete, d [n]           delete breakpoint or watchpoint n, or all of them
+info breakpoints        list breakpoints and watchpoints
+info registers          show pc, sp, bp and ax
+info locals             show the variables in scope in the selected frame
+backtrace, bt           list the active calls
+frame, f [n]            show or select a frame; also up and down
+print, p <expr>         show a variable, &variable, *pointer, $register or number
+x[/<n><w|b|s|i>] <expr> examine memory as words, bytes, a string or instructions
+list, l [line]          show source around a line
+quit, q                 leave the debugger";
+
+/// Whether the program has started, and whether it can go on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    NotStarted,
+    Running,
+    Finished,
+}
+
+/// How far a resumed program runs, unless something stops it first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Resume {
+    /// To the end
+    Continue,
+    /// This many instructions
+    Instructions(usize),
+    /// To the start of this many source lines, entering calls
+    Step(usize),
+    /// To the start of this many source lines in this function or a caller
+    Next(usize),
+    /// Until the current function returns
+    Finish,
+}
+
+struct Breakpoint {
+    id: usize,
+    pc: usize,
+}
+
+struct Watchpoint {
+    id: usize,
+    /// What the user asked to watch
+    expr: String,
+    addr: i64,
+    typ: Type,
+    value: i64,
+}
+
+/// A variable's storage
+struct Variable {
+    addr: i64,
+    typ: Type,
+    array_len: Option<usize>,
+}
+
+/// The result of evaluating an expression
+struct Value {
+    value: i64,
+    /// How to show it; plain numbers have no type
+    typ: Option<Type>,
+    array_len: Option<usize>,
+}
+
+/// Interactive debugger for a linked program
+///
+/// Commands are read a line at a time (see [`Debugger::execute`]), so a
+/// session can be scripted by piping commands into it. Their names follow
+/// gdb: breakpoints are set on functions, source lines or code addresses,
+/// the program is advanced by instruction, line, line-in-this-function or
+/// to the end of the current call, and stopped programs can be inspected
+/// through registers, frames, variables and memory. The program's own
+/// output goes to stdout as usual; reading stdin from the program competes
+/// with the commands.
+pub struct Debugger {
+    vm: VirtualMachine,
+    /// Decoded code, for checking addresses before the program starts
+    program: Vec<Instr>,
+    entry: usize,
+    args: Vec<String>,
+    globals: Vec<ObjectSymbol>,
+    /// Source lines by file name
+    sources: HashMap<String, Vec<String>>,
+    state: State,
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    next_id: usize,
+    /// Index of the selected frame, counting from the innermost
+    frame: usize,
+    /// Repeated when an empty line is entered
+    last_command: String,
+}
+
+impl Debugger {
+    /// Prepare to debug a program
+    ///
+    /// # Arguments
+    ///
+    /// * `program` - A linked program with an entry point
+    /// * `args` - Command line arguments to pass to the program
+    /// * `limits` - Resources the program may use
+    pub fn new(program: ObjectFile, args: Vec<String>, limits: VmLimits) -> Result<Self, CompilerError> {
+        let entry = program.entry
+            .ok_or_else(|| CompilerError::vm_error("main() not defined", None, None))?;
+        let decoded = instr::decode(&program.code)?;
+        let globals = program.symbols.into_iter()
+            .filter(|sym| sym.kind == SymbolKind::Global)
+            .collect();
+
+        let mut vm = VirtualMachine::with_limits(program.code, program.data, limits, false);
+        vm.set_debug_info(program.debug);
+
+        Ok(Debugger {
+            vm,
+            program: decoded,
+            entry,
+            args,
+            globals,
+            sources: HashMap::new(),
+            state: State::NotStarted,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            next_id: 1,
+            frame: 0,
+            last_command: String::new(),
+        })
+    }
+
+    /// Add the source of a file, for showing lines
+    pub fn with_source(mut self, file: &str, source: &str) -> Self {
+        self.sources.insert(file.to_string(), source.lines().map(str::to_string).collect());
+        self
+    }
+
+    /// Read and execute commands until the input ends or `quit`
+    ///
+    /// With `prompt`, a prompt is written before each command.
+    pub fn run_script(&mut self, input: impl BufRead, out: &mut dyn Write, prompt: bool) -> io::Result<()> {
+        let mut lines = input.lines();
+        loop {
+            if prompt {
+                write!(out, "(c4db) ")?;
+                out.flush()?;
+            }
+            let Some(line) = lines.next() else {
+                break;
+            };
+            if !self.execute(&line?, out)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Execute one command, writing its output to `out`
+    ///
+    /// An empty line repeats the previous command. Returns false once the
+    /// user asks to quit.
+    pub fn execute(&mut self, line: &str, out: &mut dyn Write) -> io::Result<bool> {
+        let line = match line.trim() {
+            "" => self.last_command.clone(),
+            line => {
+                self.last_command = line.to_string();
+                line.to_string()
+            },
+        };
+        let (word, arg) = line.split_once(char::is_whitespace).unwrap_or((&line, ""));
+        let (command, format) = word.split_once('/').unwrap_or((word, ""));
+        let arg = arg.trim();
+
+        match command {
+            "" => {},
+            "r" | "run" if self.state == State::Running => {
+                writeln!(out, "The program is already running; use continue")?;
+            },
+            "r" | "run" | "c" | "continue" => self.resume(Resume::Continue, out)?,
+            "si" | "stepi" => match count(arg) {
+                Ok(n) => self.resume(Resume::Instructions(n), out)?,
+                Err(message) => writeln!(out, "{}", message)?,
+            },
+            "s" | "step" => match count(arg) {
+                Ok(n) => self.resume(Resume::Step(n), out)?,
+                Err(message) => writeln!(out, "{}", message)?,
+            },
+            "n" | "next" => match count(arg) {
+                Ok(n) => self.resume(Resume::Next(n), out)?,
+                Err(message) => writeln!(out, "{}", message)?,
+            },
+            "finish" => self.resume(Resume::Finish, out)?,
+            "b" | "break" => self.add_breakpoint(arg, out)?,
+            "watch" => self.add_watchpoint(arg, out)?,
+            "d" | "delete" => self.delete(arg, out)?,
+            "i" | "info" => match arg {
+                "b" | "break" | "breakpoints" | "watchpoints" => self.info_breakpoints(out)?,
+                "r" | "registers" => self.info_registers(out)?,
+                "locals" => self.info_locals(out)?,
+                "f" | "frame" => self.show_frame(out)?,
+                _ => writeln!(out, "Unknown info command '{}'", arg)?,
+            },
+            "bt" | "backtrace" | "where" => self.backtrace(out)?,
+            "f" | "frame" => self.select_frame(arg, out)?,
+            "up" => self.select_frame(&(self.frame + 1).to_string(), out)?,
+            "down" => match self.frame.checked_sub(1) {
+                Some(frame) => self.select_frame(&frame.to_string(), out)?,
+                None => writeln!(out, "Bottom (innermost) frame selected; you cannot go down")?,
+            },
+            "p" | "print" => match self.eval(arg) {
+                Ok(value) => writeln!(out, "{} = {}", arg, self.show_value(&value))?,
+                Err(message) => writeln!(out, "{}", message)?,
+            },
+            "x" => self.examine(format, arg, out)?,
+            "l" | "list" => self.list(arg, out)?,
+            "h" | "help" => writeln!(out, "{}", HELP)?,
+            "q" | "quit" => return Ok(false),
+            _ => writeln!(out, "Unknown command '{}'; try help", command)?,
+        }
+        Ok(true)
+    }
+
+    /// Run the program until `mode` is satisfied or something stops it
+    fn resume(&mut self, mode: Resume, out: &mut dyn Write) -> io::Result<()> {
+        match self.state {
+            State::NotStarted if mode == Resume::Continue => {
+                if let Err(err) = self.vm.start(self.entry, &self.args) {
+                    self.state = State::Finished;
+                    return write!(out, "{}", err);
+                }
+                self.state = State::Running;
+
+                // A breakpoint on the entry point stops before anything runs
+                if let Some(id) = self.breakpoint_at(self.entry) {
+                    writeln!(out, "Breakpoint {}, {}", id, self.current_frame())?;
+                    return self.show_source(out);
+                }
+            },
+            State::Running => {},
+            _ => return writeln!(out, "The program is not being run."),
+        }
+
+        self.frame = 0;
+        let start_bp = self.vm.registers().bp;
+        if mode == Resume::Finish {
+            writeln!(out, "Run till exit from {}", self.current_frame())?;
+        }
+        let mut remaining = match mode {
+            Resume::Instructions(n) | Resume::Step(n) | Resume::Next(n) => n,
+            Resume::Continue | Resume::Finish => 0,
+        };
+
+        loop {
+            match self.vm.step() {
+                Ok(None) => {},
+                Ok(Some(exit_code)) => {
+                    self.state = State::Finished;
+                    return writeln!(out, "Program exited with code {}", exit_code);
+                },
+                Err(err) => {
+                    self.state = State::Finished;
+                    return write!(out, "{}", err);
+                },
+            }
+
+            let regs = self.vm.registers();
+            if let Some((id, expr, old, new)) = self.changed_watchpoint() {
+                writeln!(out, "Watchpoint {}: {}", id, expr)?;
+                writeln!(out, "Old value = {}", old)?;
+                writeln!(out, "New value = {}", new)?;
+                writeln!(out, "{}", self.current_frame())?;
+                return self.show_source(out);
+            }
+            if let Some(id) = self.breakpoint_at(regs.pc) {
+                writeln!(out, "Breakpoint {}, {}", id, self.current_frame())?;
+                return self.show_source(out);
+            }
+
+            let reached = match mode {
+                Resume::Continue => false,
+                Resume::Instructions(_) => true,
+                Resume::Step(_) => self.is_statement_start(regs.pc),
+                Resume::Next(_) => regs.bp >= start_bp && self.is_statement_start(regs.pc),
+                Resume::Finish => regs.bp > start_bp,
+            };
+            if !reached {
+                continue;
+            }
+            if mode == Resume::Finish {
+                writeln!(out, "Value returned: {}", regs.ax)?;
+            } else {
+                remaining -= 1;
+                if remaining > 0 {
+                    continue;
+                }
+            }
+
+            writeln!(out, "{}", self.current_frame())?;
+            if let Resume::Instructions(_) = mode {
+                if let Some(instr) = self.vm.instruction(regs.pc) {
+                    writeln!(out, "{:6}  {}", regs.pc, instr)?;
+                }
+                return Ok(());
+            }
+            return self.show_source(out);
+        }
+    }
+
+    /// The breakpoint at `pc`, if any
+    fn breakpoint_at(&self, pc: usize) -> Option<usize> {
+        self.breakpoints.iter().find(|bp| bp.pc == pc).map(|bp| bp.id)
+    }
+
+    /// Update watched values, returning the first that changed
+    fn changed_watchpoint(&mut self) -> Option<(usize, String, i64, i64)> {
+        for wp in &mut self.watchpoints {
+            let Ok(value) = read_typed(&self.vm, wp.addr, wp.typ) else {
+                continue;
+            };
+            if value != wp.value {
+                let old = std::mem::replace(&mut wp.value, value);
+                return Some((wp.id, wp.expr.clone(), old, value));
+            }
+        }
+        None
+    }
+
+    /// Whether a source line's code starts at `pc`
+    ///
+    /// Function entries are skipped, so stepping into a call stops at its
+    /// first statement rather than before its frame is set up.
+    fn is_statement_start(&self, pc: usize) -> bool {
+        let info = self.vm.debug_info();
+        info.lines.binary_search_by_key(&pc, |entry| entry.pc).is_ok() &&
+            !info.functions.iter().any(|func| func.start == pc)
+    }
+
+    /// The active calls, or an error message if there are none
+    fn frames(&self) -> Result<Vec<Frame>, String> {
+        match self.state {
+            State::Running => Ok(self.vm.frames()),
+            _ => Err("The program is not being run.".to_string()),
+        }
+    }
+
+    /// Describe the current pc
+    fn current_frame(&self) -> StackFrame {
+        self.vm.debug_info().frame(self.vm.registers().pc)
+    }
+
+    /// Show the source line of a frame, if the source is known
+    fn write_source_line(&self, frame: &StackFrame, out: &mut dyn Write) -> io::Result<()> {
+        let Some(loc) = frame.location else {
+            return Ok(());
+        };
+        if let Some(text) = self.source(frame.file.as_deref()).and_then(|lines| lines.get(loc.line - 1)) {
+            writeln!(out, "{}\t{}", loc.line, text)?;
+        }
+        Ok(())
+    }
+
+    /// Show the source line at the current pc
+    fn show_source(&self, out: &mut dyn Write) -> io::Result<()> {
+        self.write_source_line(&self.current_frame(), out)
+    }
+
+    /// Source lines of a file, or of the only file if the name is unknown
+    fn source(&self, file: Option<&str>) -> Option<&Vec<String>> {
+        match file {
+            Some(file) => self.sources.get(file),
+            None if self.sources.len() == 1 => self.sources.values().next(),
+            None => None,
+        }
+    }
+
+    /// Set a breakpoint on a function, a line, `file:line` or `*pc`
+    fn add_breakpoint(&mut self, arg: &str, out: &mut dyn Write) -> io::Result<()> {
+        let pc = match self.resolve(arg) {
+            Ok(pc) => pc,
+            Err(message) => return writeln!(out, "{}", message),
+        };
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.push(Breakpoint { id, pc });
+        writeln!(out, "Breakpoint {} at {}", id, self.vm.debug_info().frame(pc))
+    }
+
+    /// The code address a breakpoint location stands for
+    fn resolve(&self, arg: &str) -> Result<usize, String> {
+        let info = self.vm.debug_info();
+        if arg.is_empty() {
+            return Err("Argument required (function, line, file:line or *pc)".to_string());
+        }
+        if let Some(addr) = arg.strip_prefix('*') {
+            let pc = parse_number(addr.trim())
+                .and_then(|pc| usize::try_from(pc).ok())
+                .ok_or_else(|| format!("Invalid address '{}'", addr))?;
+            return match self.program.get(pc) {
+                Some(instr) if instr.opcode().is_some() => Ok(pc),
+                _ => Err(format!("No instruction at pc {}", pc)),
+            };
+        }
+
+        let (file, line) = match arg.rsplit_once(':') {
+            Some((file, line)) => (Some(file), line),
+            None => (None, arg),
+        };
+        if let Ok(line) = line.parse::<usize>() {
+            // The first code of the line, or of the next line with code
+            return info.lines.iter()
+                .filter(|entry| entry.location.line >= line)
+                .filter(|entry| file.is_none() ||
+                    info.function(entry.pc).and_then(|func| func.file.as_deref()) == file)
+                .min_by_key(|entry| (entry.location.line, entry.pc))
+                .map(|entry| entry.pc)
+                .ok_or_else(|| format!("No code at or after line {}", arg));
+        }
+
+        // A function stops at its first statement, once its frame exists
+        let func = info.functions.iter().find(|func| func.name == arg)
+            .ok_or_else(|| format!("No function '{}'", arg))?;
+        Ok(info.lines.iter()
+            .map(|entry| entry.pc)
+            .find(|&pc| pc > func.start && pc < func.end)
+            .unwrap_or(func.start))
+    }
+
+    /// Watch a variable, or the word at `*address`
+    fn add_watchpoint(&mut self, arg: &str, out: &mut dyn Write) -> io::Result<()> {
+        let target = match arg.strip_prefix('*') {
+            Some(addr) => self.eval(addr).map(|value| Variable { addr: value.value, typ: Type::INT, array_len: None }),
+            None => self.variable(arg),
+        };
+        let var = match target {
+            Ok(var) if var.array_len.is_none() => var,
+            Ok(_) => return writeln!(out, "Cannot watch a whole array; watch an element with *address"),
+            Err(message) => return writeln!(out, "{}", message),
+        };
+        let value = match read_typed(&self.vm, var.addr, var.typ) {
+            Ok(value) => value,
+            Err(_) => return writeln!(out, "Cannot access memory at {:#x}", var.addr),
+        };
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.watchpoints.push(Watchpoint { id, expr: arg.to_string(), addr: var.addr, typ: var.typ, value });
+        writeln!(out, "Watchpoint {}: {}", id, arg)
+    }
+
+    /// Delete one breakpoint or watchpoint, or all of them
+    fn delete(&mut self, arg: &str, out: &mut dyn Write) -> io::Result<()> {
+        if arg.is_empty() {
+            self.breakpoints.clear();
+            self.watchpoints.clear();
+            return Ok(());
+        }
+        let Ok(id) = arg.parse::<usize>() else {
+            return writeln!(out, "Invalid breakpoint number '{}'", arg);
+        };
+        let count = self.breakpoints.len() + self.watchpoints.len();
+        self.breakpoints.retain(|bp| bp.id != id);
+        self.watchpoints.retain(|wp| wp.id != id);
+        if self.breakpoints.len() + self.watchpoints.len() == count {
+            writeln!(out, "No breakpoint number {}", id)?;
+        }
+        Ok(())
+    }
+
+    fn info_breakpoints(&self, out: &mut dyn Write) -> io::Result<()> {
+        if self.breakpoints.is_empty() && self.watchpoints.is_empty() {
+            return writeln!(out, "No breakpoints or watchpoints.");
+        }
+        writeln!(out, "Num  Type        What")?;
+        let mut rows: Vec<(usize, String)> = self.breakpoints.iter()
+            .map(|bp| (bp.id, format!("breakpoint  {}", self.vm.debug_info().frame(bp.pc))))
+            .chain(self.watchpoints.iter()
+                .map(|wp| (wp.id, format!("watchpoint  {} ({:#x})", wp.expr, wp.addr))))
+            .collect();
+        rows.sort_by_key(|(id, _)| *id);
+        for (id, row) in rows {
+            writeln!(out, "{:<4} {}", id, row)?;
+        }
+        Ok(())
+    }
+
+    fn info_registers(&self, out: &mut dyn Write) -> io::Result<()> {
+        if let Err(message) = self.frames() {
+            return writeln!(out, "{}", message);
+        }
+        let regs = self.vm.registers();
+        writeln!(out, "pc  {:<18} {}", regs.pc, self.current_frame())?;
+        writeln!(out, "sp  {:#x}", regs.sp)?;
+        writeln!(out, "bp  {:#x}", regs.bp)?;
+        writeln!(out, "ax  {}", regs.ax)
+    }
+
+    /// Show the variables in scope in the selected frame
+    fn info_locals(&self, out: &mut dyn Write) -> io::Result<()> {
+        let frame = match self.selected_frame() {
+            Ok(frame) => frame,
+            Err(message) => return writeln!(out, "{}", message),
+        };
+        let Some(func) = self.vm.debug_info().function(frame.pc) else {
+            return writeln!(out, "No symbol table info available.");
+        };
+        let locals = func.locals_at(frame.pc);
+        if locals.is_empty() {
+            return writeln!(out, "No locals.");
+        }
+        for var in locals {
+            let value = self.variable_value(&Variable {
+                addr: frame.bp + var.offset * 8,
+                typ: var.typ,
+                array_len: var.array_len,
+            });
+            match value {
+                Ok(value) => writeln!(out, "{} = {}", var.name, self.show_value(&value))?,
+                Err(message) => writeln!(out, "{} = <{}>", var.name, message)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn backtrace(&self, out: &mut dyn Write) -> io::Result<()> {
+        let frames = match self.frames() {
+            Ok(frames) => frames,
+            Err(message) => return writeln!(out, "{}", message),
+        };
+        for (i, frame) in frames.iter().enumerate() {
+            let marker = if i == self.frame { '*' } else { ' ' };
+            writeln!(out, "{}#{:<2} {}", marker, i, self.vm.debug_info().frame(frame.pc))?;
+        }
+        Ok(())
+    }
+
+    /// Select a frame by number, or show the selected one
+    fn select_frame(&mut self, arg: &str, out: &mut dyn Write) -> io::Result<()> {
+        let frames = match self.frames() {
+            Ok(frames) => frames,
+            Err(message) => return writeln!(out, "{}", message),
+        };
+        if !arg.is_empty() {
+            match arg.parse::<usize>() {
+                Ok(n) if n < frames.len() => self.frame = n,
+                Ok(_) => return writeln!(out, "No frame {}; the outermost is #{}", arg, frames.len() - 1),
+                Err(_) => return writeln!(out, "Invalid frame number '{}'", arg),
+            }
+        }
+        self.show_frame(out)
+    }
+
+    fn show_frame(&self, out: &mut dyn Write) -> io::Result<()> {
+        let frame = match self.selected_frame() {
+            Ok(frame) => frame,
+            Err(message) => return writeln!(out, "{}", message),
+        };
+        let frame = self.vm.debug_info().frame(frame.pc);
+        writeln!(out, "#{:<2} {}", self.frame, frame)?;
+        self.write_source_line(&frame, out)
+    }
+
+    fn selected_frame(&self) -> Result<Frame, String> {
+        let frames = self.frames()?;
+        Ok(frames.get(self.frame).copied().unwrap_or(frames[0]))
+    }
+
+    /// Find a variable: a local of the selected frame, or a global
+    fn variable(&self, name: &str) -> Result<Variable, String> {
+        if let Ok(frame) = self.selected_frame() {
+            let local = self.vm.debug_info().function(frame.pc)
+                .and_then(|func| func.local(name, frame.pc));
+            if let Some(var) = local {
+                return Ok(Variable { addr: frame.bp + var.offset * 8, typ: var.typ, array_len: var.array_len });
+            }
+        }
+        self.globals.iter()
+            .find(|sym| sym.name == name)
+            .map(|sym| Variable { addr: sym.value, typ: sym.typ, array_len: None })
+            .ok_or_else(|| format!("No symbol \"{}\" in current context.", name))
+    }
+
+    /// The value of a variable (the address, for an array)
+    fn variable_value(&self, var: &Variable) -> Result<Value, String> {
+        let value = match var.array_len {
+            Some(_) => var.addr,
+            None => read_typed(&self.vm, var.addr, var.typ)
+                .map_err(|_| format!("Cannot access memory at {:#x}", var.addr))?,
+        };
+        Ok(Value { value, typ: Some(var.typ), array_len: var.array_len })
+    }
+
+    /// Evaluate `name`, `&name`, `*expr`, `$register` or a number
+    fn eval(&self, expr: &str) -> Result<Value, String> {
+        let expr = expr.trim();
+        if expr.is_empty() {
+            return Err("Argument required (expression to compute)".to_string());
+        }
+        if let Some(inner) = expr.strip_prefix('*') {
+            let pointer = self.eval(inner)?;
+            let typ = pointer.typ.map_or(Type::INT, Type::deref);
+            let value = read_typed(&self.vm, pointer.value, typ)
+                .map_err(|_| format!("Cannot access memory at {:#x}", pointer.value))?;
+            return Ok(Value { value, typ: Some(typ), array_len: None });
+        }
+        if let Some(name) = expr.strip_prefix('&') {
+            let var = self.variable(name.trim())?;
+            let typ = if var.array_len.is_some() { var.typ } else { var.typ.to_ptr() };
+            return Ok(Value { value: var.addr, typ: Some(typ), array_len: None });
+        }
+        if let Some(register) = expr.strip_prefix('$') {
+            self.frames()?;
+            let regs = self.vm.registers();
+            let value = match register {
+                "pc" => regs.pc as i64,
+                "sp" => regs.sp,
+                "bp" => regs.bp,
+                "ax" => regs.ax,
+                _ => return Err(format!("Unknown register ${}", register)),
+            };
+            return Ok(Value { value, typ: None, array_len: None });
+        }
+        if let Some(value) = parse_number(expr) {
+            return Ok(Value { value, typ: None, array_len: None });
+        }
+        self.variable_value(&self.variable(expr)?)
+    }
+
+    /// Format a value according to its type
+    fn show_value(&self, value: &Value) -> String {
+        let Some(typ) = value.typ else {
+            return value.value.to_string();
+        };
+        if let Some(len) = value.array_len {
+            let elem = typ.deref();
+            let items: Vec<String> = (0..len.min(16))
+                .map(|i| match read_typed(&self.vm, value.value + (i * elem.size()) as i64, elem) {
+                    Ok(item) => self.show_value(&Value { value: item, typ: Some(elem), array_len: None }),
+                    Err(_) => "?".to_string(),
+                })
+                .collect();
+            let more = if len > 16 { ", ..." } else { "" };
+            return format!("{{{}{}}}", items.join(", "), more);
+        }
+        match typ {
+            Type::CHAR => format!("{} '{}'", value.value, (value.value as u8).escape_ascii()),
+            Type::CPTR => match self.read_string(value.value, 32) {
+                Ok(text) => format!("{:#x} \"{}\"", value.value, text),
+                Err(_) => format!("{:#x}", value.value),
+            },
+            _ if typ.is_ptr() => format!("{:#x}", value.value),
+            _ => value.value.to_string(),
+        }
+    }
+
+    /// Read a string of at most `max` bytes, escaped for display
+    fn read_string(&self, addr: i64, max: usize) -> Result<String, CompilerError> {
+        let mut text = String::new();
+        for i in 0..max as i64 {
+            let byte = self.vm.read_memory(addr + i, 1)?[0];
+            if byte == 0 {
+                return Ok(text);
+            }
+            text.extend(std::ascii::escape_default(byte).map(char::from));
+        }
+        Ok(text + "...")
+    }
+
+    /// Examine memory: `x/<count><w|b|s|i> <expr>`
+    fn examine(&self, format: &str, arg: &str, out: &mut dyn Write) -> io::Result<()> {
+        let digits = format.bytes().take_while(u8::is_ascii_digit).count();
+        let count = format[..digits].parse::<usize>().unwrap_or(1);
+        let unit = format[digits..].chars().next().unwrap_or('w');
+        let addr = match self.eval(if arg.is_empty() && unit == 'i' { "$pc" } else { arg }) {
+            Ok(value) => value.value,
+            Err(message) => return writeln!(out, "{}", message),
+        };
+
+        match unit {
+            'w' | 'b' => {
+                let (size, per_line) = if unit == 'w' { (8, 4) } else { (1, 8) };
+                for row in 0..count.div_ceil(per_line) {
+                    let row_addr = addr + (row * per_line * size) as i64;
+                    write!(out, "{:#x}:", row_addr)?;
+                    for i in 0..per_line.min(count - row * per_line) {
+                        let item_addr = row_addr + (i * size) as i64;
+                        let item = if unit == 'w' {
+                            self.vm.read_int(item_addr)
+                        } else {
+                            self.vm.read_memory(item_addr, 1).map(|bytes| bytes[0] as i64)
+                        };
+                        match item {
+                            Ok(item) => write!(out, "  {}", item)?,
+                            Err(_) => return writeln!(out, "\nCannot access memory at {:#x}", item_addr),
+                        }
+                    }
+                    writeln!(out)?;
+                }
+            },
+            's' => {
+                let mut addr = addr;
+                for _ in 0..count {
+                    match self.read_string(addr, 256) {
+                        Ok(text) => writeln!(out, "{:#x}:  \"{}\"", addr, text)?,
+                        Err(_) => return writeln!(out, "Cannot access memory at {:#x}", addr),
+                    }
+                    while self.vm.read_memory(addr, 1).is_ok_and(|bytes| bytes[0] != 0) {
+                        addr += 1;
+                    }
+                    addr += 1;
+                }
+            },
+            'i' => {
+                let mut pc = addr.max(0) as usize;
+                for _ in 0..count {
+                    let Some(instr) = self.program.get(pc).filter(|instr| instr.opcode().is_some()) else {
+                        return writeln!(out, "No instruction at pc {}", pc);
+                    };
+                    let marker = if self.state == State::Running && pc == self.vm.registers().pc { "=>" } else { "  " };
+                    writeln!(out, "{}{:6}  {}", marker, pc, instr)?;
+                    pc += if instr.operand().is_some() { 2 } else { 1 };
+                }
+            },
+            other => writeln!(out, "Unknown format '{}'; use w, b, s or i", other)?,
+        }
+        Ok(())
+    }
+
+    /// Show ten source lines around a line (by default the current one)
+    fn list(&self, arg: &str, out: &mut dyn Write) -> io::Result<()> {
+        let pc = match self.selected_frame() {
+            Ok(frame) => frame.pc,
+            Err(_) => self.entry,
+        };
+        let frame = self.vm.debug_info().frame(pc);
+        let Some(lines) = self.source(frame.file.as_deref()) else {
+            return writeln!(out, "No source available.");
+        };
+        let center = match arg.parse::<usize>() {
+            Ok(line) => line,
+            Err(_) if arg.is_empty() => frame.location.map_or(1, |loc| loc.line),
+            Err(_) => return writeln!(out, "Invalid line number '{}'", arg),
+        };
+        let first = center.saturating_sub(5).max(1);
+        for (i, text) in lines.iter().enumerate().skip(first - 1).take(10) {
+            writeln!(out, "{}\t{}", i + 1, text)?;
+        }
+        Ok(())
+    }
+}
+
+/// Read a value of type `typ` (chars are one signed byte, the rest words)
+fn read_typed(vm: &VirtualMachine, addr: i64, typ: Type) -> Result<i64, CompilerError> {
+    match typ {
+        Type::CHAR => Ok(vm.read_memory(addr, 1)?[0] as i8 as i64),
+        _ => vm.read_int(addr),
+    }
+}
+
+/// Parse a decimal or `0x` hexadecimal number, possibly negative
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<i64>().ok()?,
+    };
+    Some(if negative { -value } else { value })
+}
+
+/// The repeat count of a stepping command (1 if not given)
+fn count(arg: &str) -> Result<usize, String> {
+    match arg {
+        "" => Ok(1),
+        _ => arg.parse::<usize>().ok().filter(|&n| n > 0)
+            .ok_or_else(|| format!("Invalid count '{}'", arg)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    const SOURCE: &str = "\
+int total;
+
+int add(int n) {
+    int old;
+    old = total;
+    total = total + n;
+    return old;
+}
+
+int main() {
+    int i;
+    char *s;
+    s = \"hi\";
+    i = 0;
+    while (i < 3) {
+        add(i * 10);
+        i++;
+    }
+    return total;
+}
+";
+
+    fn debugger() -> Debugger {
+        let mut parser = Parser::new(SOURCE.to_string(), false);
+        parser.init().unwrap();
+        parser.parse().unwrap();
+        parser.optimize();
+        parser.fuse();
+        let program = ObjectFile::from_parser(&parser).unwrap();
+        Debugger::new(program, vec!["prog".to_string()], VmLimits::default()).unwrap().with_source("prog.c", SOURCE)
+    }
+
+    /// Run a script and return what the debugger printed
+    fn session(script: &str) -> String {
+        let mut out = Vec::new();
+        debugger().run_script(script.as_bytes(), &mut out, false).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_breakpoints_and_inspection() {
+        let out = session("break add\nrun\nbt\nprint n\nup\ninfo locals\nprint *s\np &total\ndelete 1\nc\n");
+        let expected = [
+            "Breakpoint 1 at add at line 5:5",
+            "Breakpoint 1, add at line 5:5",
+            "5\t    old = total;",
+            "*#0  add at line 5:5",
+            " #1  main at line 16:9",
+            "n = 0",
+            "#1  main at line 16:9",
+            "i = 0",
+            "s = 0x",
+            "\"hi\"",
+            "*s = 104 'h'",
+            "&total = 0x0",
+            "Program exited with code 30",
+        ];
+        for line in expected {
+            assert!(out.contains(line), "missing {:?} in:\n{}", line, out);
+        }
+    }
+
+    #[test]
+    fn test_stepping() {
+        // Lines, stepping into a call, then out of it
+        let out = session("break 14\nrun\nnext\nnext\nstep\nfinish\nnext\nstepi\nstepi 2\n");
+        let stops: Vec<&str> = out.lines().filter(|line| line.contains('\t')).collect();
+        assert_eq!(stops[..5], [
+            "14\t    i = 0;",
+            "15\t    while (i < 3) {",
+            "16\t        add(i * 10);",
+            "5\t    old = total;",
+            "16\t        add(i * 10);",
+        ], "{}", out);
+        assert!(out.contains("Run till exit from add at line 5:5"), "{}", out);
+        assert!(out.contains("Value returned: 0"), "{}", out);
+        assert!(out.contains("17\t        i++;"), "{}", out);
+        assert_eq!(out.lines().filter(|line| line.starts_with("main at")).count(), 6, "{}", out);
+    }
+
+    #[test]
+    fn test_watchpoints_and_memory() {
+        let out = session("watch total\nc\nc\ninfo breakpoints\nbreak *0\nx/2w &total\nrun\nfoo\n");
+        let expected = [
+            "Watchpoint 1: total",
+            "Old value = 0\nNew value = 10",
+            "Old value = 10\nNew value = 30",
+            "1    watchpoint  total (0x0)",
+            "Breakpoint 2 at add at line 3:16 (pc 0)",
+            "0x0:  30  ",
+            "The program is already running; use continue",
+            "Unknown command 'foo'; try help",
+        ];
+        for line in expected {
+            assert!(out.contains(line), "missing {:?} in:\n{}", line, out);
+        }
+    }
+}
@@ -0,0 +1,399 @@
+use crate::object::{ObjectFile, RelocationKind, SymbolKind};
+use crate::symbol::SymbolTable;
+use crate::types::{Opcode, TokenType, Type};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+/// Longest string shown in an operand preview
+const PREVIEW_LEN: usize = 32;
+
+/// Static disassembler for code and data segments
+///
+/// Produces one line per instruction with its address, mnemonic and
+/// operand. Branch and call targets become labels (function names where
+/// known), operands that point into the data segment are shown by name
+/// with a preview of the string there, and the data segment follows as
+/// directives. The output is valid input for the assembler, so a program
+/// can be disassembled, edited and reassembled.
+pub struct Disassembler<'a> {
+    code: &'a [i64],
+    data: &'a [u8],
+    /// Function names by code address
+    functions: BTreeMap<usize, String>,
+    /// Global variable names and types by data address
+    globals: BTreeMap<usize, (String, Type)>,
+    /// Operand words holding data addresses
+    data_relocations: BTreeSet<usize>,
+    /// Imported symbol names, in import order
+    imports: Vec<String>,
+    /// Operand words holding the address of an import, by import index
+    import_relocations: BTreeMap<usize, usize>,
+    /// Entry point, if known
+    entry: Option<usize>,
+    /// Source lines, for interleaving
+    source: Vec<&'a str>,
+    /// Code addresses paired with the source line whose code starts there
+    lines: Vec<(usize, usize)>,
+}
+
+impl<'a> Disassembler<'a> {
+    /// Create a disassembler with no symbol information
+    pub fn new(code: &'a [i64], data: &'a [u8]) -> Self {
+        Disassembler {
+            code,
+            data,
+            functions: BTreeMap::new(),
+            globals: BTreeMap::new(),
+            data_relocations: BTreeSet::new(),
+            imports: Vec::new(),
+            import_relocations: BTreeMap::new(),
+            entry: None,
+            source: Vec::new(),
+            lines: Vec::new(),
+        }
+    }
+
+    /// Create a disassembler for an object file, using its symbols and
+    /// relocations
+    pub fn from_object(object: &'a ObjectFile) -> Self {
+        let mut disasm = Self::new(&object.code, &object.data);
+        for sym in &object.symbols {
+            match sym.kind {
+                SymbolKind::Function => {
+                    disasm.functions.insert(sym.value as usize, sym.name.clone());
+                },
+                SymbolKind::Global => {
+                    disasm.globals.insert(sym.value as usize, (sym.name.clone(), sym.typ));
+                },
+            }
+        }
+        disasm.data_relocations = object.relocations.iter()
+            .filter(|reloc| reloc.kind == RelocationKind::Data)
+            .map(|reloc| reloc.offset)
+            .collect();
+        disasm.imports = object.imports.iter().map(|import| import.name.clone()).collect();
+        disasm.import_relocations = object.relocations.iter()
+            .filter_map(|reloc| match reloc.kind {
+                RelocationKind::Import(index) => Some((reloc.offset, index)),
+                _ => None,
+            })
+            .collect();
+        disasm.entry = object.entry;
+        disasm
+    }
+
+    /// Name functions and globals from a symbol table
+    pub fn with_symbol_table(mut self, symbols: &SymbolTable) -> Self {
+        for sym in symbols.iter().filter(|sym| !sym.external) {
+            match sym.class {
+                TokenType::Fun => {
+                    self.functions.insert(sym.value as usize, sym.name.clone());
+                    if sym.name == "main" {
+                        self.entry = Some(sym.value as usize);
+                    }
+                },
+                TokenType::Glo => {
+                    self.globals.insert(sym.value as usize, (sym.name.clone(), sym.typ));
+                },
+                _ => {},
+            }
+        }
+        self
+    }
+
+    /// Mark which operand words hold data addresses
+    pub fn with_data_relocations(mut self, relocations: &[usize]) -> Self {
+        self.data_relocations = relocations.iter().copied().collect();
+        self
+    }
+
+    /// Interleave source lines
+    ///
+    /// `lines` pairs code addresses with the 1-based source line whose
+    /// code starts there, in address order. A line is shown once for each
+    /// run of code it produced.
+    pub fn with_source(mut self, source: &'a str, lines: &[(usize, usize)]) -> Self {
+        self.source = source.lines().collect();
+        self.lines = lines.to_vec();
+        self
+    }
+
+    /// Produce the listing
+    pub fn disassemble(&self) -> String {
+        let mut out = String::new();
+        let labels = self.code_labels();
+        let data_labels = self.data_labels();
+
+        if let Some(entry) = self.entry {
+            writeln!(out, ".entry {}", self.code_label(&labels, entry)).unwrap();
+        }
+        for name in &self.imports {
+            writeln!(out, ".extern {}", name).unwrap();
+        }
+        out.push_str(".text\n");
+
+        let mut next_line = 0;
+        let mut last_line = None;
+        let mut pc = 0;
+        while pc < self.code.len() {
+            // Source lines whose code starts here
+            while next_line < self.lines.len() && self.lines[next_line].0 <= pc {
+                let line = self.lines[next_line].1;
+                if last_line != Some(line) {
+                    let text = self.source.get(line.wrapping_sub(1)).map_or("", |text| text.trim());
+                    writeln!(out, "; {}: {}", line, text).unwrap();
+                    last_line = Some(line);
+                }
+                next_line += 1;
+            }
+
+            if let Some(label) = labels.get(&pc) {
+                writeln!(out, "{}:", label).unwrap();
+            }
+
+            match self.instruction_at(pc) {
+                Some((op, Some(value))) => {
+                    write!(out, "{:6}  {:<5} ", pc, op.to_string()).unwrap();
+                    self.write_operand(&mut out, pc, op, value, &labels, &data_labels);
+                    out.push('\n');
+                    pc += 2;
+                },
+                Some((op, None)) => {
+                    writeln!(out, "{:6}  {}", pc, op.to_string()).unwrap();
+                    pc += 1;
+                },
+                // Undecodable code is listed word by word
+                _ => {
+                    writeln!(out, "{:6}  .word {}", pc, self.code[pc]).unwrap();
+                    pc += 1;
+                },
+            }
+        }
+
+        if !self.data.is_empty() {
+            out.push_str(".data\n");
+            self.write_data(&mut out, &data_labels);
+        }
+
+        out
+    }
+
+    /// The opcode at `pc` and its operand, if the word is a known opcode
+    /// with all of its operand present
+    fn instruction_at(&self, pc: usize) -> Option<(Opcode, Option<i64>)> {
+        let op = Opcode::from_i64(self.code[pc])?;
+        if !op.has_operand() {
+            return Some((op, None));
+        }
+        self.code.get(pc + 1).map(|&value| (op, Some(value)))
+    }
+
+    /// Labels for code addresses: function names and branch targets
+    fn code_labels(&self) -> BTreeMap<usize, String> {
+        let mut labels: BTreeMap<usize, String> = self.functions.clone();
+        let mut pc = 0;
+        while pc < self.code.len() {
+            match self.instruction_at(pc) {
+                Some((op, Some(target))) => {
+                    let internal = !self.import_relocations.contains_key(&(pc + 1));
+                    if op.is_jump() && internal && target >= 0 && (target as usize) < self.code.len() {
+                        labels.entry(target as usize).or_insert_with(|| format!(".L{}", target));
+                    }
+                    pc += 2;
+                },
+                _ => pc += 1,
+            }
+        }
+        if let Some(entry) = self.entry {
+            labels.entry(entry).or_insert_with(|| format!(".L{}", entry));
+        }
+        labels
+    }
+
+    /// Name for a code address
+    fn code_label(&self, labels: &BTreeMap<usize, String>, addr: usize) -> String {
+        labels.get(&addr).cloned().unwrap_or_else(|| addr.to_string())
+    }
+
+    /// Labels for data addresses: globals and everything code refers to
+    fn data_labels(&self) -> BTreeMap<usize, String> {
+        let mut labels: BTreeMap<usize, String> = self.globals.iter()
+            .map(|(&addr, (name, _))| (addr, name.clone()))
+            .collect();
+        for &pos in &self.data_relocations {
+            if let Some(&addr) = self.code.get(pos) {
+                if addr >= 0 && (addr as usize) < self.data.len() && !self.inside_string(&labels, addr as usize) {
+                    labels.entry(addr as usize).or_insert_with(|| format!(".D{}", addr));
+                }
+            }
+        }
+        labels
+    }
+
+    /// Whether `addr` points into the middle of a string that starts at an
+    /// existing label, so a reference can be written as label+offset
+    fn inside_string(&self, labels: &BTreeMap<usize, String>, addr: usize) -> bool {
+        match labels.range(..addr).next_back() {
+            Some((&start, _)) => {
+                self.string_at(start).is_some_and(|text| start + text.len() >= addr)
+            },
+            None => false,
+        }
+    }
+
+    /// The printable NUL-terminated string starting at `addr`, if any
+    fn string_at(&self, addr: usize) -> Option<&'a [u8]> {
+        let rest = self.data.get(addr..)?;
+        let len = rest.iter().position(|&b| b == 0)?;
+        let text = &rest[..len];
+        let printable = text.iter().all(|&b| b.is_ascii_graphic() || matches!(b, b' ' | b'\n' | b'\t' | b'\r'));
+        (len > 0 && printable).then_some(text)
+    }
+
+    /// Write the operand of an instruction, if it has one
+    fn write_operand(&self, out: &mut String, pc: usize, op: Opcode, value: i64,
+                     labels: &BTreeMap<usize, String>, data_labels: &BTreeMap<usize, String>) {
+        if let Some(name) = self.import_relocations.get(&(pc + 1)).and_then(|&index| self.imports.get(index)) {
+            match value {
+                0 => out.push_str(name),
+                _ => write!(out, "{}{:+}", name, value).unwrap(),
+            }
+            return;
+        }
+
+        match op {
+            _ if op.is_jump() => {
+                match usize::try_from(value).ok().and_then(|target| labels.get(&target)) {
+                    Some(label) => out.push_str(label),
+                    None => write!(out, "{}", value).unwrap(),
+                }
+            },
+            _ => {
+                if !self.data_relocations.contains(&(pc + 1)) {
+                    write!(out, "{}", value).unwrap();
+                    return;
+                }
+
+                // A data address: name it relative to the closest label
+                match data_labels.range(..=value.max(0) as usize).next_back() {
+                    Some((&base, name)) if value >= 0 => {
+                        let offset = value as usize - base;
+                        if offset == 0 {
+                            out.push_str(name);
+                        } else {
+                            write!(out, "{}+{}", name, offset).unwrap();
+                        }
+                    },
+                    _ => write!(out, "{}", value).unwrap(),
+                }
+                if let Some(text) = usize::try_from(value).ok().and_then(|addr| self.string_at(addr)) {
+                    let shown = &text[..text.len().min(PREVIEW_LEN)];
+                    let more = if text.len() > PREVIEW_LEN { "..." } else { "" };
+                    write!(out, "  ; {}{}", quote(shown), more).unwrap();
+                }
+            },
+        }
+    }
+
+    /// Write the data segment as directives, split at every label
+    fn write_data(&self, out: &mut String, labels: &BTreeMap<usize, String>) {
+        let mut starts: Vec<usize> = labels.keys().copied().filter(|&addr| addr < self.data.len()).collect();
+        if starts.first() != Some(&0) {
+            starts.insert(0, 0);
+        }
+        starts.push(self.data.len());
+
+        for chunk in starts.windows(2) {
+            let (start, end) = (chunk[0], chunk[1]);
+            if let Some(name) = labels.get(&start) {
+                writeln!(out, "{}:", name).unwrap();
+            }
+
+            // Int and pointer globals are shown as words
+            let is_word = matches!(self.globals.get(&start), Some((_, typ)) if *typ != Type::CHAR);
+            let mut addr = start;
+            while addr < end {
+                if is_word && addr % 8 == 0 && addr + 8 <= end {
+                    let word = i64::from_le_bytes(self.data[addr..addr + 8].try_into().unwrap());
+                    writeln!(out, "{:6}  .word {}", addr, word).unwrap();
+                    addr += 8;
+                } else if let Some(text) = self.string_at(addr).filter(|text| addr + text.len() < end) {
+                    writeln!(out, "{:6}  .string {}", addr, quote(text)).unwrap();
+                    addr += text.len() + 1;
+                } else {
+                    // Bytes up to the next string, at most 16 per line
+                    let mut stop = addr + 1;
+                    while stop < end && stop - addr < 16 && self.string_at(stop).is_none_or(|_| self.data[stop - 1] != 0) {
+                        stop += 1;
+                    }
+                    let bytes: Vec<String> = self.data[addr..stop].iter().map(|b| b.to_string()).collect();
+                    writeln!(out, "{:6}  .byte {}", addr, bytes.join(", ")).unwrap();
+                    addr = stop;
+                }
+            }
+        }
+    }
+}
+
+/// Quote bytes as a C-style string literal
+pub fn quote(bytes: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for &b in bytes {
+        match b {
+            b'\n' => out.push_str("\\n"),
+            b'\t' => out.push_str("\\t"),
+            b'\r' => out.push_str("\\r"),
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b if b.is_ascii_graphic() || b == b' ' => out.push(b as char),
+            b => write!(out, "\\x{:02x}", b).unwrap(),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    #[test]
+    fn test_listing() {
+        let mut parser = Parser::new(r#"
+            int total;
+            int add(int a, int b) { return a + b; }
+            int main() {
+                int i = 0;
+                while (i < 3) { total = add(total, i); i++; }
+                printf("total=%d\n", total);
+                return 0;
+            }
+        "#.to_string(), false);
+        parser.init().unwrap();
+        parser.parse().unwrap();
+        parser.optimize();
+
+        let listing = Disassembler::new(parser.get_code(), parser.get_data())
+            .with_symbol_table(parser.get_symbol_table())
+            .with_data_relocations(parser.get_data_relocations())
+            .disassemble();
+
+        assert!(listing.starts_with(".entry main\n.text\n"), "{}", listing);
+        assert!(listing.contains("\nadd:\n"), "{}", listing);
+        assert!(listing.contains("JSR   add"), "{}", listing);
+        assert!(listing.contains("BZ    .L"), "{}", listing);
+        assert!(listing.contains("IMM   total"), "{}", listing);
+        assert!(listing.contains("; \"total=%d\\n\""), "{}", listing);
+        assert!(listing.contains("\ntotal:\n     0  .word 0\n.D8:\n     8  .string \"total=%d\\n\""), "{}", listing);
+    }
+
+    #[test]
+    fn test_source_and_bad_code() {
+        let code = [Opcode::IMM as i64, 1, 99, Opcode::EXIT as i64];
+        let listing = Disassembler::new(&code, &[])
+            .with_source("int x;\nreturn 1;\n", &[(0, 2)])
+            .disassemble();
+        assert_eq!(listing, ".text\n; 2: return 1;\n     0  IMM   1\n     2  .word 99\n     3  EXIT\n");
+    }
+}
@@ -0,0 +1,256 @@
+use crate::encoder::{Encoder, Label, Lowering};
+use crate::error::CompilerError;
+use crate::instr::Instr;
+use crate::object::{ObjectFile, SymbolKind};
+use crate::parser::Parser;
+use std::collections::HashMap;
+
+/// Source of the runtime linked into every executable
+const RUNTIME: &str = include_str!("elf_runtime.c");
+
+/// Address the executable is loaded at
+const BASE_ADDRESS: u64 = 0x40_0000;
+
+/// Page size, which segments are aligned to
+const PAGE_SIZE: u64 = 0x1000;
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const SECTION_HEADER_SIZE: usize = 64;
+
+/// Names of the sections, in `.shstrtab`
+const SECTION_NAMES: &[u8] = b"\0.text\0.data\0.shstrtab\0";
+
+/// Linux system call numbers
+const SYS_EXIT_GROUP: u8 = 231;
+
+/// The system call stub
+const SYSCALL: Label = Label::Stub("c4rt_syscall");
+
+/// Write a linked program as a statically linked Linux x86-64 executable
+///
+/// The program is translated to machine code much as the assembly backend
+/// ([`crate::x86_64`]) does, without needing an assembler or linker. Its
+/// system calls go to a small runtime, written in C4 and compiled along
+/// with it, that implements `printf`, `malloc`, `open` and the others on
+/// top of raw Linux system calls and behaves like the VM (`printf` formats
+/// 64-bit values). The file has a `.text` and a `.data` section, each in its
+/// own segment; `_start` passes `argc` and `argv` to `main` and exits with
+/// its result.
+///
+/// Division by zero traps, as in the assembly backend, and programs that
+/// call native functions or still need linking are rejected.
+pub fn emit(program: &ObjectFile) -> Result<Vec<u8>, CompilerError> {
+    if let Some(import) = program.imports.first() {
+        return Err(CompilerError::codegen_error(&format!("'{}' is not defined (link the program first)", import.name), None));
+    }
+    let entry = program.entry
+        .ok_or_else(|| CompilerError::codegen_error("main() not defined", None))?;
+    let runtime = compile_runtime()?;
+
+    // The runtime's data follows the program's, 8-byte aligned
+    let runtime_data = program.data.len().next_multiple_of(8);
+    let mut data = program.data.clone();
+    data.resize(runtime_data, 0);
+    data.extend_from_slice(&runtime.data);
+
+    let mut encoder = Encoder::default();
+    start(&mut encoder, Label::Code(0, entry));
+    syscall_stub(&mut encoder);
+    let mut lowering = Runtime {
+        functions: runtime.symbols.iter()
+            .filter(|sym| sym.kind == SymbolKind::Function)
+            .map(|sym| (sym.name.as_str(), sym.value as usize))
+            .collect(),
+    };
+    encoder.unit(0, program, 0, &mut lowering)?;
+    encoder.unit(1, &runtime, runtime_data, &mut lowering)?;
+
+    Ok(link(encoder, &data))
+}
+
+/// Compile the runtime, leaving `c4rt_syscall` to the stub
+fn compile_runtime() -> Result<ObjectFile, CompilerError> {
+    let mut parser = Parser::new(RUNTIME.to_string(), false);
+    parser.set_separate_compilation(true);
+    parser.init()?;
+    parser.parse()?;
+    parser.optimize();
+    parser.fuse();
+    ObjectFile::from_parser(&parser)
+}
+
+/// System calls become calls into the runtime, which is unit 1
+struct Runtime<'a> {
+    functions: HashMap<&'a str, usize>,
+}
+
+impl Lowering for Runtime<'_> {
+    fn system_call(&mut self, encoder: &mut Encoder, _pc: usize, instr: Instr) -> Result<(), CompilerError> {
+        let name = match instr {
+            Instr::Open => "c4rt_open",
+            Instr::Read => "c4rt_read",
+            Instr::Clos => "c4rt_close",
+            Instr::Prtf(argc) => {
+                encoder.bytes(&[0x68]);                      // push $argc
+                encoder.imm32(argc as i32);
+                encoder.branch(&[0xe8], Label::Code(1, self.functions["c4rt_printf"]));
+                encoder.bytes(&[0x48, 0x83, 0xc4, 0x08]);    // add $8, %rsp
+                return Ok(());
+            },
+            Instr::Malc => "c4rt_malloc",
+            Instr::Free => "c4rt_free",
+            Instr::Mset => "c4rt_memset",
+            Instr::Mcmp => "c4rt_memcmp",
+            _ => {
+                exit(encoder);
+                return Ok(());
+            },
+        };
+        encoder.branch(&[0xe8], Label::Code(1, self.functions[name]));
+        Ok(())
+    }
+
+    fn import(&mut self, pc: usize, name: &str) -> Result<Label, CompilerError> {
+        match name {
+            "c4rt_syscall" => Ok(SYSCALL),
+            _ => Err(CompilerError::codegen_error(&format!("'{}' is not defined", name), Some(pc))),
+        }
+    }
+}
+
+/// `_start`: call `main(argc, argv)` and exit with its result
+fn start(encoder: &mut Encoder, main: Label) {
+    encoder.bytes(&[0x48, 0x8b, 0x3c, 0x24]);       // mov (%rsp), %rdi
+    encoder.bytes(&[0x48, 0x8d, 0x74, 0x24, 0x08]); // lea 8(%rsp), %rsi
+    encoder.bytes(&[0x57, 0x56]);                   // push %rdi; push %rsi
+    encoder.branch(&[0xe8], main);                  // call main
+    exit(encoder);
+}
+
+/// Exit with the value in `%rax`
+fn exit(encoder: &mut Encoder) {
+    encoder.bytes(&[0x48, 0x89, 0xc7]);             // mov %rax, %rdi
+    encoder.bytes(&[0xb8, SYS_EXIT_GROUP, 0, 0, 0]); // mov $231, %eax
+    encoder.bytes(&[0x0f, 0x05]);                   // syscall
+}
+
+/// `c4rt_syscall(nr, a, b, c)`, called the C4 way with its arguments on
+/// the stack
+fn syscall_stub(encoder: &mut Encoder) {
+    encoder.place(SYSCALL);
+    encoder.bytes(&[0x48, 0x8b, 0x44, 0x24, 0x20]); // mov 32(%rsp), %rax
+    encoder.bytes(&[0x48, 0x8b, 0x7c, 0x24, 0x18]); // mov 24(%rsp), %rdi
+    encoder.bytes(&[0x48, 0x8b, 0x74, 0x24, 0x10]); // mov 16(%rsp), %rsi
+    encoder.bytes(&[0x48, 0x8b, 0x54, 0x24, 0x08]); // mov 8(%rsp), %rdx
+    encoder.bytes(&[0x0f, 0x05, 0xc3]);             // syscall; ret
+}
+
+/// Resolve the code against the data's address and wrap both in an ELF
+/// file
+fn link(encoder: Encoder, data: &[u8]) -> Vec<u8> {
+    let headers = ELF_HEADER_SIZE + 2 * PROGRAM_HEADER_SIZE;
+    let text_offset = headers.next_multiple_of(16);
+    let text_address = BASE_ADDRESS + text_offset as u64;
+    let data_offset = (text_offset + encoder.len()).next_multiple_of(PAGE_SIZE as usize);
+    let data_address = BASE_ADDRESS + data_offset as u64;
+    let names_offset = data_offset + data.len();
+    let sections_offset = (names_offset + SECTION_NAMES.len()).next_multiple_of(8);
+    let text = encoder.resolve(data_address);
+
+    let mut out = Vec::new();
+
+    // ELF header
+    out.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    out.extend_from_slice(&2u16.to_le_bytes());                     // e_type: executable
+    out.extend_from_slice(&0x3eu16.to_le_bytes());                  // e_machine: x86-64
+    out.extend_from_slice(&1u32.to_le_bytes());                     // e_version
+    out.extend_from_slice(&text_address.to_le_bytes());             // e_entry: _start
+    out.extend_from_slice(&(ELF_HEADER_SIZE as u64).to_le_bytes()); // e_phoff
+    out.extend_from_slice(&(sections_offset as u64).to_le_bytes()); // e_shoff
+    out.extend_from_slice(&0u32.to_le_bytes());                     // e_flags
+    out.extend_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+    out.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    out.extend_from_slice(&2u16.to_le_bytes());                     // e_phnum
+    out.extend_from_slice(&(SECTION_HEADER_SIZE as u16).to_le_bytes());
+    out.extend_from_slice(&4u16.to_le_bytes());                     // e_shnum
+    out.extend_from_slice(&3u16.to_le_bytes());                     // e_shstrndx
+
+    // Program headers: the headers and code, read-only and executable,
+    // then the data, read-write
+    let text_end = (text_offset + text.len()) as u64;
+    program_header(&mut out, 5, 0, BASE_ADDRESS, text_end);
+    program_header(&mut out, 6, data_offset as u64, data_address, data.len() as u64);
+
+    out.resize(text_offset, 0);
+    out.extend_from_slice(&text);
+    out.resize(data_offset, 0);
+    out.extend_from_slice(data);
+    out.extend_from_slice(SECTION_NAMES);
+    out.resize(sections_offset, 0);
+
+    // Section headers: null, .text, .data, .shstrtab
+    out.resize(out.len() + SECTION_HEADER_SIZE, 0);
+    section_header(&mut out, 1, 1, 0x6, text_address, text_offset, text.len(), 16);
+    section_header(&mut out, 7, 1, 0x3, data_address, data_offset, data.len(), 8);
+    section_header(&mut out, 13, 3, 0, 0, names_offset, SECTION_NAMES.len(), 1);
+    out
+}
+
+/// A loadable segment
+fn program_header(out: &mut Vec<u8>, flags: u32, offset: u64, address: u64, size: u64) {
+    out.extend_from_slice(&1u32.to_le_bytes());  // p_type: PT_LOAD
+    out.extend_from_slice(&flags.to_le_bytes());
+    out.extend_from_slice(&offset.to_le_bytes());
+    out.extend_from_slice(&address.to_le_bytes()); // p_vaddr
+    out.extend_from_slice(&address.to_le_bytes()); // p_paddr
+    out.extend_from_slice(&size.to_le_bytes());    // p_filesz
+    out.extend_from_slice(&size.to_le_bytes());    // p_memsz
+    out.extend_from_slice(&PAGE_SIZE.to_le_bytes());
+}
+
+#[allow(clippy::too_many_arguments)]
+fn section_header(out: &mut Vec<u8>, name: u32, kind: u32, flags: u64, address: u64, offset: usize, size: usize, align: u64) {
+    out.extend_from_slice(&name.to_le_bytes());
+    out.extend_from_slice(&kind.to_le_bytes());
+    out.extend_from_slice(&flags.to_le_bytes());
+    out.extend_from_slice(&address.to_le_bytes());
+    out.extend_from_slice(&(offset as u64).to_le_bytes());
+    out.extend_from_slice(&(size as u64).to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());  // sh_link
+    out.extend_from_slice(&0u32.to_le_bytes());  // sh_info
+    out.extend_from_slice(&align.to_le_bytes());
+    out.extend_from_slice(&0u64.to_le_bytes());  // sh_entsize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runtime_compiles() {
+        let runtime = compile_runtime().unwrap();
+        for name in ["c4rt_printf", "c4rt_malloc", "c4rt_open", "c4rt_read", "c4rt_close"] {
+            assert!(runtime.symbols.iter().any(|sym| sym.name == name), "runtime lacks {}", name);
+        }
+        assert_eq!(runtime.imports.len(), 1);
+        assert_eq!(runtime.imports[0].name, "c4rt_syscall");
+    }
+
+    #[test]
+    fn test_elf_header() {
+        let mut parser = Parser::new("int main() { return 42; }".to_string(), false);
+        parser.init().unwrap();
+        parser.parse().unwrap();
+        let elf = emit(&ObjectFile::from_parser(&parser).unwrap()).unwrap();
+
+        assert_eq!(&elf[..4], b"\x7fELF");
+        assert_eq!(u16::from_le_bytes([elf[18], elf[19]]), 0x3e);
+        let entry = u64::from_le_bytes(elf[24..32].try_into().unwrap());
+        assert_eq!(entry, BASE_ADDRESS + 176);
+        // _start begins by loading argc
+        assert_eq!(&elf[176..180], &[0x48, 0x8b, 0x3c, 0x24]);
+        let shoff = u64::from_le_bytes(elf[40..48].try_into().unwrap()) as usize;
+        assert_eq!(elf.len(), shoff + 4 * SECTION_HEADER_SIZE);
+    }
+}
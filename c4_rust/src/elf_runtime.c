@@ -0,0 +1,243 @@
+// Runtime linked into executables written by the ELF64 backend
+//
+// Compiled by c4_rust itself. The backend turns each system call opcode
+// into a call to the function of the same name here, so these follow the
+// VM's behaviour, and they reach Linux only through c4rt_syscall, which
+// the backend provides in machine code.
+
+int c4rt_syscall(int nr, int a, int b, int c);
+
+enum { SYS_READ = 0, SYS_WRITE = 1, SYS_OPEN = 2, SYS_CLOSE = 3, SYS_BRK = 12 };
+enum { OUT_SIZE = 4096, HEAP_CHUNK = 65536 };
+
+char c4rt_out[4096];
+int c4rt_out_len;
+int c4rt_written;
+int *c4rt_arg;
+int c4rt_args_left;
+int c4rt_heap;
+int c4rt_heap_end;
+
+// Write the output buffer to stdout
+int c4rt_flush() {
+  int done; int n;
+  done = 0;
+  while (done < c4rt_out_len) {
+    n = c4rt_syscall(SYS_WRITE, 1, (int)(c4rt_out + done), c4rt_out_len - done);
+    if (n <= 0) { c4rt_out_len = 0; return -1; }
+    done = done + n;
+  }
+  c4rt_out_len = 0;
+  return 0;
+}
+
+int c4rt_putc(int c) {
+  if (c4rt_out_len == OUT_SIZE) c4rt_flush();
+  c4rt_out[c4rt_out_len] = c;
+  c4rt_out_len = c4rt_out_len + 1;
+  c4rt_written = c4rt_written + 1;
+  return c;
+}
+
+int c4rt_puts(char *s, int n) {
+  while (n > 0) { c4rt_putc(*s); s = s + 1; n = n - 1; }
+  return 0;
+}
+
+int c4rt_repeat(int c, int n) {
+  while (n > 0) { c4rt_putc(c); n = n - 1; }
+  return 0;
+}
+
+// The next printf argument, or 0 once they run out
+int c4rt_next_arg() {
+  int v;
+  if (c4rt_args_left <= 0) return 0;
+  v = *c4rt_arg;
+  c4rt_arg = c4rt_arg - 1;
+  c4rt_args_left = c4rt_args_left - 1;
+  return v;
+}
+
+// Write the digits of v, taken as unsigned, so they end just before end;
+// returns how many there are. Halving first keeps the division signed.
+int c4rt_digits(char *end, int v, int base, char *digits) {
+  int n; int q;
+  n = 0;
+  while (n == 0 || v != 0) {
+    q = ((v >> 1) & ~(1 << 63)) / (base / 2);
+    end = end - 1;
+    *end = digits[v - q * base];
+    v = q;
+    n = n + 1;
+  }
+  return n;
+}
+
+// printf, called with the argument count pushed after the arguments
+int c4rt_printf(int argc) {
+  char *fmt; char *sign; char *body; char buf[80];
+  int left; int zero; int plus; int space; int width; int prec; int conv;
+  int v; int blen; int zeros; int pad; int numeric;
+
+  c4rt_arg = &argc + argc;
+  fmt = (char *)*c4rt_arg;
+  c4rt_arg = c4rt_arg - 1;
+  c4rt_args_left = argc - 1;
+  c4rt_written = 0;
+
+  while (*fmt) {
+    if (*fmt != '%') { c4rt_putc(*fmt); fmt = fmt + 1; }
+    else {
+      fmt = fmt + 1;
+
+      // Flags
+      left = 0; zero = 0; plus = 0; space = 0;
+      while (*fmt == '-' || *fmt == '0' || *fmt == '+' || *fmt == ' ') {
+        if (*fmt == '-') left = 1;
+        else if (*fmt == '0') zero = 1;
+        else if (*fmt == '+') plus = 1;
+        else space = 1;
+        fmt = fmt + 1;
+      }
+
+      // Width
+      width = 0;
+      if (*fmt == '*') {
+        width = c4rt_next_arg();
+        if (width < 0) { left = 1; width = -width; }
+        fmt = fmt + 1;
+      }
+      while (*fmt >= '0' && *fmt <= '9') { width = width * 10 + *fmt - '0'; fmt = fmt + 1; }
+
+      // Precision, -1 if there is none
+      prec = -1;
+      if (*fmt == '.') {
+        fmt = fmt + 1;
+        prec = 0;
+        if (*fmt == '*') {
+          prec = c4rt_next_arg();
+          if (prec < 0) prec = 0;
+          fmt = fmt + 1;
+        }
+        while (*fmt >= '0' && *fmt <= '9') { prec = prec * 10 + *fmt - '0'; fmt = fmt + 1; }
+      }
+
+      // Length modifiers are accepted but every argument is 64-bit
+      while (*fmt == 'l') fmt = fmt + 1;
+
+      if (*fmt == 0) { c4rt_putc('%'); return c4rt_flush() ? -1 : c4rt_written; }
+      conv = *fmt;
+      fmt = fmt + 1;
+
+      sign = "";
+      body = buf + 80;
+      blen = 0;
+      zeros = 0;
+      numeric = conv == 'd' || conv == 'i' || conv == 'u' || conv == 'x' || conv == 'X' || conv == 'o';
+      if (numeric) {
+        v = c4rt_next_arg();
+        if (conv == 'd' || conv == 'i') {
+          if (v < 0) { sign = "-"; v = -v; }
+          else if (plus) sign = "+";
+          else if (space) sign = " ";
+        }
+        if (prec != 0 || v != 0) {
+          if (conv == 'x') blen = c4rt_digits(body, v, 16, "0123456789abcdef");
+          else if (conv == 'X') blen = c4rt_digits(body, v, 16, "0123456789ABCDEF");
+          else if (conv == 'o') blen = c4rt_digits(body, v, 8, "01234567");
+          else blen = c4rt_digits(body, v, 10, "0123456789");
+        }
+        body = body - blen;
+        if (prec > blen) zeros = prec - blen;
+      }
+      else if (conv == 'p') {
+        sign = "0x";
+        blen = c4rt_digits(body, c4rt_next_arg(), 16, "0123456789abcdef");
+        body = body - blen;
+      }
+      else if (conv == 'c') { buf[0] = c4rt_next_arg(); body = buf; blen = 1; }
+      else if (conv == 's') {
+        body = (char *)c4rt_next_arg();
+        while (body[blen] && (prec < 0 || blen < prec)) blen = blen + 1;
+      }
+      else if (conv == '%') { body = "%"; blen = 1; }
+      else { buf[0] = '%'; buf[1] = conv; body = buf; blen = 2; }
+
+      v = 0;
+      while (sign[v]) v = v + 1;
+      pad = width - v - zeros - blen;
+      if (pad < 0) pad = 0;
+      if (left) {
+        c4rt_puts(sign, v); c4rt_repeat('0', zeros); c4rt_puts(body, blen); c4rt_repeat(' ', pad);
+      }
+      else if (zero && numeric && prec < 0) {
+        c4rt_puts(sign, v); c4rt_repeat('0', pad); c4rt_puts(body, blen);
+      }
+      else {
+        c4rt_repeat(' ', pad); c4rt_puts(sign, v); c4rt_repeat('0', zeros); c4rt_puts(body, blen);
+      }
+    }
+  }
+  if (c4rt_flush()) return -1;
+  return c4rt_written;
+}
+
+// Allocate from memory past the program break; like the VM, memory is
+// never reused, so it is always zeroed
+int c4rt_malloc(int n) {
+  int p;
+  if (n < 0) return 0;
+  if (c4rt_heap == 0) {
+    c4rt_heap = c4rt_syscall(SYS_BRK, 0, 0, 0);
+    c4rt_heap_end = c4rt_heap;
+  }
+  p = c4rt_heap;
+  n = (n + 7) & ~7;
+  if (p + n > c4rt_heap_end) {
+    c4rt_heap_end = c4rt_syscall(SYS_BRK, p + n + HEAP_CHUNK, 0, 0);
+    if (c4rt_heap_end < p + n) return 0;
+  }
+  c4rt_heap = p + n;
+  return p;
+}
+
+int c4rt_free(int p) {
+  return p;
+}
+
+int c4rt_memset(char *dst, int value, int n) {
+  int i;
+  i = 0;
+  while (i < n) { dst[i] = value; i = i + 1; }
+  return (int)dst;
+}
+
+int c4rt_memcmp(char *a, char *b, int n) {
+  int i;
+  i = 0;
+  while (i < n) {
+    if (a[i] != b[i]) return (a[i] & 255) - (b[i] & 255);
+    i = i + 1;
+  }
+  return 0;
+}
+
+int c4rt_open(char *path, int flags) {
+  int fd;
+  fd = c4rt_syscall(SYS_OPEN, (int)path, flags, 420);
+  if (fd < 0) return -1;
+  return fd;
+}
+
+int c4rt_read(int fd, char *buf, int n) {
+  if (n < 0) n = 0;
+  n = c4rt_syscall(SYS_READ, fd, (int)buf, n);
+  if (n < 0) return -1;
+  return n;
+}
+
+int c4rt_close(int fd) {
+  if (c4rt_syscall(SYS_CLOSE, fd, 0, 0) < 0) return -1;
+  return 0;
+}
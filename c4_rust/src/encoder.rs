@@ -0,0 +1,256 @@
+use crate::error::CompilerError;
+use crate::instr::{self, Instr};
+use crate::object::{ObjectFile, RelocationKind};
+use std::collections::{BTreeSet, HashMap};
+
+/// Where a call or jump goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Label {
+    /// An instruction of one of the units being translated
+    Code(usize, usize),
+    /// Machine code written by the backend itself
+    Stub(&'static str),
+}
+
+/// What a backend decides for itself when bytecode is translated
+///
+/// Everything else about the translation is shared: `AX` lives in `%rax`,
+/// the VM stack is the machine stack and `bp` is `%rbp`.
+pub(crate) trait Lowering {
+    /// Encode a system call opcode or `EXIT`, whose arguments are on the
+    /// stack and whose result goes in `%rax`
+    fn system_call(&mut self, encoder: &mut Encoder, pc: usize, instr: Instr) -> Result<(), CompilerError>;
+
+    /// Where a call to the undefined function `name` goes
+    fn import(&mut self, pc: usize, name: &str) -> Result<Label, CompilerError> {
+        Err(CompilerError::codegen_error(&format!("'{}' is not defined", name), Some(pc)))
+    }
+
+    /// Code run once `ENT` has made a frame
+    fn frame_entered(&mut self, _encoder: &mut Encoder) {}
+
+    /// Code run before `%rax` is divided by `%rcx`, for `DIV` or `MOD`
+    fn dividing(&mut self, _encoder: &mut Encoder, _modulo: bool) {}
+}
+
+/// Translates bytecode to x86-64 machine code
+#[derive(Default)]
+pub(crate) struct Encoder {
+    text: Vec<u8>,
+    labels: HashMap<Label, usize>,
+    /// rel32 fields and the labels they point at
+    branches: Vec<(usize, Label)>,
+    /// imm64 fields holding data addresses, with the offset into the data
+    data_refs: Vec<(usize, u64)>,
+}
+
+impl Encoder {
+    /// Size of the code so far
+    pub fn len(&self) -> usize {
+        self.text.len()
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.text.extend_from_slice(bytes);
+    }
+
+    pub fn imm32(&mut self, value: i32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    /// Put `label` at the current position
+    pub fn place(&mut self, label: Label) {
+        self.labels.insert(label, self.text.len());
+    }
+
+    /// An instruction ending in a rel32 to `label`
+    pub fn branch(&mut self, opcode: &[u8], label: Label) {
+        self.bytes(opcode);
+        self.branches.push((self.text.len(), label));
+        self.imm32(0);
+    }
+
+    /// Load a constant or a data address into `%rax` (0) or `%rcx` (1)
+    fn load(&mut self, register: u8, value: i64, is_data: bool, data_base: usize) {
+        if is_data {
+            self.bytes(&[0x48, 0xb8 + register]);
+            self.data_refs.push((self.text.len(), (data_base as i64 + value) as u64));
+            self.bytes(&[0; 8]);
+        } else if let Ok(value) = i32::try_from(value) {
+            self.bytes(&[0x48, 0xc7, 0xc0 + register]);
+            self.imm32(value);
+        } else {
+            self.bytes(&[0x48, 0xb8 + register]);
+            self.bytes(&value.to_le_bytes());
+        }
+    }
+
+    /// Pop the left operand into `%rcx` and combine it into `%rax`
+    fn binary(&mut self, op: &[u8]) {
+        self.bytes(&[0x59]);
+        self.bytes(op);
+    }
+
+    /// Pop the left operand, compare it with `%rax` and set `%rax` to the
+    /// result of condition code `cc`
+    fn compare(&mut self, cc: u8) {
+        self.bytes(&[0x59, 0x48, 0x39, 0xc1]);       // pop %rcx; cmp %rax, %rcx
+        self.bytes(&[0x0f, 0x90 + cc, 0xc0]);        // setcc %al
+        self.bytes(&[0x48, 0x0f, 0xb6, 0xc0]);       // movzbq %al, %rax
+    }
+
+    /// Pop the dividend into `%rax` with the divisor in `%rcx`, and divide
+    fn divide(&mut self, lowering: &mut dyn Lowering, modulo: bool) {
+        self.bytes(&[0x48, 0x89, 0xc1, 0x58]);       // mov %rax, %rcx; pop %rax
+        lowering.dividing(self, modulo);
+        self.bytes(&[0x48, 0x99, 0x48, 0xf7, 0xf9]); // cqto; idiv %rcx
+        if modulo {
+            self.bytes(&[0x48, 0x89, 0xd0]);         // mov %rdx, %rax
+        }
+    }
+
+    /// Translate one unit's code, whose data starts `data_base` bytes into
+    /// the data
+    pub fn unit(
+        &mut self,
+        unit: usize,
+        object: &ObjectFile,
+        data_base: usize,
+        lowering: &mut dyn Lowering,
+    ) -> Result<(), CompilerError> {
+        let code = instr::decode(&object.code)?;
+        let data_relocations: BTreeSet<usize> = object.relocations.iter()
+            .filter(|reloc| reloc.kind == RelocationKind::Data)
+            .map(|reloc| reloc.offset)
+            .collect();
+        let imports: HashMap<usize, &str> = object.relocations.iter()
+            .filter_map(|reloc| match reloc.kind {
+                RelocationKind::Import(index) => Some((reloc.offset, object.imports[index].name.as_str())),
+                _ => None,
+            })
+            .collect();
+        let words = |pc: usize, n: i64| {
+            i32::try_from(n * 8).map_err(|_| CompilerError::codegen_error("stack offset out of range", Some(pc)))
+        };
+
+        for (pc, &instr) in code.iter().enumerate() {
+            self.place(Label::Code(unit, pc));
+            let is_data = data_relocations.contains(&(pc + 1));
+            match instr {
+                Instr::Lea(offset) => {
+                    self.bytes(&[0x48, 0x8d, 0x85]);
+                    self.imm32(words(pc, offset)?);
+                },
+                Instr::Imm(value) => self.load(0, value, is_data, data_base),
+                Instr::Jmp(target) => self.branch(&[0xe9], Label::Code(unit, target)),
+                Instr::Jsr(target) => {
+                    let label = match imports.get(&(pc + 1)) {
+                        Some(name) => lowering.import(pc, name)?,
+                        None => Label::Code(unit, target),
+                    };
+                    self.branch(&[0xe8], label);
+                },
+                Instr::Bz(target) => {
+                    self.bytes(&[0x48, 0x85, 0xc0]);
+                    self.branch(&[0x0f, 0x84], Label::Code(unit, target));
+                },
+                Instr::Bnz(target) => {
+                    self.bytes(&[0x48, 0x85, 0xc0]);
+                    self.branch(&[0x0f, 0x85], Label::Code(unit, target));
+                },
+                Instr::Ent(locals) => {
+                    self.bytes(&[0x55, 0x48, 0x89, 0xe5]);   // push %rbp; mov %rsp, %rbp
+                    if locals != 0 {
+                        self.bytes(&[0x48, 0x81, 0xec]);      // sub $n, %rsp
+                        self.imm32(words(pc, locals)?);
+                    }
+                    lowering.frame_entered(self);
+                },
+                Instr::Adj(n) => {
+                    if n != 0 {
+                        self.bytes(&[0x48, 0x81, 0xc4]);      // add $n, %rsp
+                        self.imm32(words(pc, n)?);
+                    }
+                },
+                Instr::Lev => self.bytes(&[0xc9, 0xc3]),      // leave; ret
+                Instr::Tsr(target) => {
+                    self.bytes(&[0xc9]);                      // leave
+                    self.branch(&[0xe9], Label::Code(unit, target));
+                },
+                Instr::Li => self.bytes(&[0x48, 0x8b, 0x00]),
+                Instr::Lc => self.bytes(&[0x48, 0x0f, 0xbe, 0x00]),
+                Instr::Si => self.bytes(&[0x59, 0x48, 0x89, 0x01]),
+                Instr::Sc => self.bytes(&[0x59, 0x88, 0x01, 0x48, 0x0f, 0xbe, 0xc0]),
+                Instr::Psh => self.bytes(&[0x50]),
+                Instr::Or => self.binary(&[0x48, 0x09, 0xc8]),
+                Instr::Xor => self.binary(&[0x48, 0x31, 0xc8]),
+                Instr::And => self.binary(&[0x48, 0x21, 0xc8]),
+                Instr::Eq => self.compare(0x4),
+                Instr::Ne => self.compare(0x5),
+                Instr::Lt => self.compare(0xc),
+                Instr::Gt => self.compare(0xf),
+                Instr::Le => self.compare(0xe),
+                Instr::Ge => self.compare(0xd),
+                Instr::Shl => self.bytes(&[0x48, 0x89, 0xc1, 0x58, 0x48, 0xd3, 0xe0]),
+                Instr::Shr => self.bytes(&[0x48, 0x89, 0xc1, 0x58, 0x48, 0xd3, 0xf8]),
+                Instr::Add => self.binary(&[0x48, 0x01, 0xc8]),
+                Instr::Sub => self.binary(&[0x48, 0x29, 0xc1, 0x48, 0x89, 0xc8]),
+                Instr::Mul => self.binary(&[0x48, 0x0f, 0xaf, 0xc1]),
+                Instr::Div => self.divide(lowering, false),
+                Instr::Mod => self.divide(lowering, true),
+                Instr::Open | Instr::Read | Instr::Clos | Instr::Prtf(_) | Instr::Malc
+                | Instr::Free | Instr::Mset | Instr::Mcmp | Instr::Exit => {
+                    lowering.system_call(self, pc, instr)?;
+                },
+                Instr::Neg => self.bytes(&[0x48, 0xf7, 0xd8]),
+                Instr::Lli(offset) => {
+                    self.bytes(&[0x48, 0x8b, 0x85]);
+                    self.imm32(words(pc, offset)?);
+                },
+                Instr::Llc(offset) => {
+                    self.bytes(&[0x48, 0x0f, 0xbe, 0x85]);
+                    self.imm32(words(pc, offset)?);
+                },
+                Instr::Pshl(offset) => {
+                    self.bytes(&[0x48, 0x8b, 0x85]);
+                    self.imm32(words(pc, offset)?);
+                    self.bytes(&[0x50]);
+                },
+                Instr::Pshi(value) => {
+                    self.load(0, value, is_data, data_base);
+                    self.bytes(&[0x50]);
+                },
+                Instr::Addi(value) => {
+                    self.load(1, value, is_data, data_base);
+                    self.bytes(&[0x48, 0x01, 0xc8]);
+                },
+                Instr::Subi(value) => {
+                    self.load(1, value, is_data, data_base);
+                    self.bytes(&[0x48, 0x29, 0xc8]);
+                },
+                Instr::Muli(value) => {
+                    self.load(1, value, is_data, data_base);
+                    self.bytes(&[0x48, 0x0f, 0xaf, 0xc1]);
+                },
+                Instr::Natv(_) => {
+                    return Err(CompilerError::codegen_error("native functions cannot be called from compiled code", Some(pc)));
+                },
+                Instr::Operand => {},
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolve branches, and data addresses for data loaded at
+    /// `data_address`, returning the finished code
+    pub fn resolve(mut self, data_address: u64) -> Vec<u8> {
+        for &(pos, label) in &self.branches {
+            let rel = self.labels[&label] as i64 - (pos as i64 + 4);
+            self.text[pos..pos + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+        }
+        for &(pos, offset) in &self.data_refs {
+            self.text[pos..pos + 8].copy_from_slice(&(data_address + offset).to_le_bytes());
+        }
+        self.text
+    }
+}
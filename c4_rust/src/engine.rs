@@ -0,0 +1,192 @@
+use crate::error::CompilerError;
+use crate::host::MemoryHost;
+use crate::native::Natives;
+use crate::object::ObjectFile;
+use crate::parser::Parser;
+use crate::vm::{VirtualMachine, VmLimits};
+
+/// What a program run by an [`Engine`] did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Output {
+    pub exit_code: i64,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+impl Output {
+    /// Standard output as text, with invalid UTF-8 replaced
+    pub fn stdout_text(&self) -> String {
+        String::from_utf8_lossy(&self.stdout).into_owned()
+    }
+}
+
+/// Compile and run C4 programs from Rust
+///
+/// An engine holds the native functions programs may call and the limits
+/// they run under. Programs run against an in-memory host, so their output
+/// is captured rather than printed and they see no real files.
+///
+/// ```
+/// use c4_rust::engine::Engine;
+///
+/// let mut engine = Engine::new();
+/// engine.register_native("sqrt_i", 1, |_, args| Ok((args[0] as f64).sqrt() as i64));
+/// let output = engine.run("int main() { printf(\"%d\\n\", sqrt_i(49)); return 0; }").unwrap();
+/// assert_eq!(output.stdout_text(), "7\n");
+/// ```
+#[derive(Debug, Clone)]
+pub struct Engine {
+    natives: Natives,
+    limits: VmLimits,
+    optimize: bool,
+}
+
+impl Default for Engine {
+    /// No native functions, the default limits, and the peephole optimizer
+    /// enabled, as in the CLI
+    fn default() -> Self {
+        Self { natives: Natives::default(), limits: VmLimits::default(), optimize: true }
+    }
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make a Rust function callable from C4 source as `name`
+    ///
+    /// Every call must pass `arity` arguments, which the function receives
+    /// in call order along with the VM, so it can read and write program
+    /// memory. An `Err` stops the program with a runtime error. Programs
+    /// compiled before a function is registered cannot call it.
+    pub fn register_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        func: impl Fn(&mut VirtualMachine, &[i64]) -> Result<i64, String> + 'static,
+    ) -> &mut Self {
+        self.natives.register(name, arity, func);
+        self
+    }
+
+    /// Hold programs to `limits`
+    pub fn set_limits(&mut self, limits: VmLimits) -> &mut Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Enable or disable the peephole optimizer, which is enabled by default
+    pub fn set_optimize(&mut self, enabled: bool) -> &mut Self {
+        self.optimize = enabled;
+        self
+    }
+
+    /// Compile a program that can call this engine's native functions
+    pub fn compile(&self, source: &str) -> Result<ObjectFile, CompilerError> {
+        let mut parser = Parser::new(source.to_string(), false);
+        parser.set_natives(&self.natives);
+        parser.init()?;
+        parser.parse()?;
+        if self.optimize {
+            parser.optimize();
+        }
+        parser.fuse();
+        ObjectFile::from_parser(&parser)
+    }
+
+    /// Run a compiled program with `args` as its `argv` and `stdin` as its
+    /// standard input
+    pub fn execute(&self, program: &ObjectFile, args: &[String], stdin: &[u8]) -> Result<Output, CompilerError> {
+        let entry = program.entry
+            .ok_or_else(|| CompilerError::object_error("program has no main()", None))?;
+        let host = MemoryHost::new().with_stdin(stdin);
+
+        let mut vm = VirtualMachine::with_limits(program.code.clone(), program.data.clone(), self.limits, false);
+        vm.set_debug_info(program.debug.clone());
+        vm.set_host(Box::new(host.clone()));
+        vm.set_natives(self.natives.clone());
+        let exit_code = vm.run(entry, args)?;
+
+        Ok(Output { exit_code, stdout: host.stdout(), stderr: host.stderr() })
+    }
+
+    /// Compile and run a program without arguments or input
+    pub fn run(&self, source: &str) -> Result<Output, CompilerError> {
+        let program = self.compile(source)?;
+        self.execute(&program, &[], &[])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_natives() {
+        let mut engine = Engine::new();
+        engine
+            .register_native("sqrt_i", 1, |_, args| Ok((args[0] as f64).sqrt() as i64))
+            .register_native("clamp", 3, |_, args| Ok(args[0].clamp(args[1], args[2])))
+            .register_native("strlen_n", 1, |vm, args| {
+                vm.read_string(args[0]).map(|s| s.len() as i64).map_err(|err| err.to_string())
+            });
+
+        let output = engine.run(r#"
+            int main() {
+                printf("%d %d %d\n", sqrt_i(81), clamp(15, 0, 10), strlen_n("hello"));
+                return sqrt_i(16);
+            }
+        "#).unwrap();
+        assert_eq!(output.stdout_text(), "9 10 5\n");
+        assert_eq!(output.exit_code, 4);
+    }
+
+    #[test]
+    fn test_native_errors() {
+        let mut engine = Engine::new();
+        engine.register_native("checked_div", 2, |_, args| {
+            args[0].checked_div(args[1]).ok_or_else(|| "division by zero".to_string())
+        });
+
+        // Wrong argument counts are caught at compile time
+        assert!(engine.compile("int main() { return checked_div(1); }").is_err());
+
+        // Errors from the native stop the program
+        let err = engine.run("int main() { return checked_div(1, 0); }").unwrap_err();
+        assert!(err.to_string().contains("checked_div: division by zero"), "{}", err);
+    }
+
+    #[test]
+    fn test_optimizes_by_default() {
+        let source = "int main() { int a, b; a = 6; b = 7; return a * b; }";
+        let mut engine = Engine::new();
+        let default = engine.compile(source).unwrap();
+        let unoptimized = engine.set_optimize(false).compile(source).unwrap();
+        let optimized = engine.set_optimize(true).compile(source).unwrap();
+
+        assert_eq!(default.code, optimized.code);
+        assert!(default.code.len() < unoptimized.code.len());
+        assert_eq!(engine.run(source).unwrap().exit_code, 42);
+    }
+
+    #[test]
+    fn test_execute_with_input() {
+        let engine = Engine::new();
+        let program = engine.compile(r#"
+            int main(int argc, char **argv) {
+                char *buf;
+                int n;
+                buf = malloc(16);
+                n = read(0, buf, 15);
+                buf[n] = 0;
+                printf("%s %s", argv[1], buf);
+                return argc;
+            }
+        "#).unwrap();
+        let args = vec!["prog".to_string(), "hi".to_string()];
+        let output = engine.execute(&program, &args, b"there").unwrap();
+        assert_eq!(output.stdout_text(), "hi there");
+        assert_eq!(output.exit_code, 2);
+    }
+}
@@ -1,5 +1,6 @@
 use std::fmt;
 use std::io;
+use std::time::Duration;
 
 /// Source location information for error reporting
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -15,10 +16,97 @@ impl SourceLocation {
     pub fn new(line: usize, column: usize) -> Self {
         SourceLocation { line, column }
     }
-    
+}
+
+impl fmt::Display for SourceLocation {
     /// Format the location as "line:column"
-    pub fn to_string(&self) -> String {
-        format!("{}:{}", self.line, self.column)
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// A non-fatal diagnostic produced during compilation
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompilerWarning {
+    /// Description of the problem
+    pub message: String,
+    /// Where in the source it was found
+    pub location: Option<SourceLocation>,
+    /// The offending source line
+    pub source_line: Option<String>,
+}
+
+impl fmt::Display for CompilerWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Warning: {}", self.message)?;
+        
+        if let Some(loc) = &self.location {
+            writeln!(f, "  --> {}", loc)?;
+            
+            if let Some(line) = &self.source_line {
+                writeln!(f, "   |")?;
+                writeln!(f, "{} |", loc.line)?;
+                writeln!(f, "   | {}", line)?;
+                writeln!(f, "   | {}^",
+                       " ".repeat(loc.column.saturating_sub(1)))?;
+            }
+        }
+        
+        Ok(())
+    }
+}
+
+/// One call frame of a runtime error's backtrace
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackFrame {
+    /// Code address: the failing instruction, or the call in a caller
+    pub pc: usize,
+    /// Function containing the address, if known
+    pub function: Option<String>,
+    /// Source file of that function, if known
+    pub file: Option<String>,
+    /// Source position of the address, if known
+    pub location: Option<SourceLocation>,
+}
+
+impl fmt::Display for StackFrame {
+    /// Format the frame as "name at file:line:column (pc N)"
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.function.as_deref().unwrap_or("??"))?;
+        match (&self.file, &self.location) {
+            (Some(file), Some(loc)) => write!(f, " at {}:{}", file, loc)?,
+            (None, Some(loc)) => write!(f, " at line {}", loc)?,
+            (Some(file), None) => write!(f, " in {}", file)?,
+            (None, None) => {},
+        }
+        write!(f, " (pc {})", self.pc)
+    }
+}
+
+/// A resource limit that stopped a program (see `VmLimits`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceLimit {
+    /// Most instructions to execute
+    Cycles(u64),
+    /// Most bytes the data segment may grow by while running
+    Heap(usize),
+    /// Size of the stack in words
+    Stack(usize),
+    /// Longest the program may run
+    Time(Duration),
+    /// Most bytes of output
+    Output(usize),
+}
+
+impl fmt::Display for ResourceLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResourceLimit::Cycles(max) => write!(f, "executed more than {} instructions", max),
+            ResourceLimit::Heap(max) => write!(f, "heap grew past {} bytes", max),
+            ResourceLimit::Stack(max) => write!(f, "Stack overflow (stack of {} words)", max),
+            ResourceLimit::Time(max) => write!(f, "ran for more than {} ms", max.as_millis()),
+            ResourceLimit::Output(max) => write!(f, "wrote more than {} bytes of output", max),
+        }
     }
 }
 
@@ -55,6 +143,44 @@ pub enum CompilerError {
         message: String,
         instruction: Option<String>,
         cycle: Option<i64>,
+        /// Call stack when the error happened, innermost frame first
+        backtrace: Vec<StackFrame>,
+    },
+    
+    /// A program ran into one of its resource limits
+    LimitExceeded {
+        limit: ResourceLimit,
+        instruction: Option<String>,
+        cycle: Option<i64>,
+        /// Call stack when the limit was reached, innermost frame first
+        backtrace: Vec<StackFrame>,
+    },
+    
+    /// Malformed or incompatible object files
+    ObjectError {
+        message: String,
+        offset: Option<usize>,
+    },
+    
+    /// Linker errors (combining compiled units)
+    LinkError {
+        message: String,
+        /// Names of the units involved
+        units: Vec<String>,
+    },
+    
+    /// Assembler errors (bytecode assembly text)
+    AssemblyError {
+        message: String,
+        location: Option<SourceLocation>,
+        source_line: Option<String>,
+    },
+    
+    /// Code generation errors (bytecode a native backend cannot lower)
+    CodegenError {
+        message: String,
+        /// Code address of the offending instruction
+        pc: Option<usize>,
     },
     
     /// IO errors (file operations)
@@ -68,15 +194,14 @@ impl fmt::Display for CompilerError {
                 writeln!(f, "Lexer error: {}", message)?;
                 
                 if let Some(loc) = location {
-                    writeln!(f, "  --> {}", loc.to_string())?;
+                    writeln!(f, "  --> {}", loc)?;
                     
                     if let Some(line) = source_line {
                         writeln!(f, "   |")?;
                         writeln!(f, "{} |", loc.line)?;
                         writeln!(f, "   | {}", line)?;
-                        writeln!(f, "   | {}{}",
-                               " ".repeat(loc.column - 1),
-                               "^")?;
+                        writeln!(f, "   | {}^",
+                               " ".repeat(loc.column.saturating_sub(1)))?;
                     }
                 }
                 
@@ -86,15 +211,14 @@ impl fmt::Display for CompilerError {
                 writeln!(f, "Parser error: {}", message)?;
                 
                 if let Some(loc) = location {
-                    writeln!(f, "  --> {}", loc.to_string())?;
+                    writeln!(f, "  --> {}", loc)?;
                     
                     if let Some(line) = source_line {
                         writeln!(f, "   |")?;
                         writeln!(f, "{} |", loc.line)?;
                         writeln!(f, "   | {}", line)?;
-                        writeln!(f, "   | {}{}",
-                               " ".repeat(loc.column - 1),
-                               "^")?;
+                        writeln!(f, "   | {}^",
+                               " ".repeat(loc.column.saturating_sub(1)))?;
                     }
                 }
                 
@@ -108,15 +232,14 @@ impl fmt::Display for CompilerError {
                 writeln!(f, "Type error: {}", message)?;
                 
                 if let Some(loc) = location {
-                    writeln!(f, "  --> {}", loc.to_string())?;
+                    writeln!(f, "  --> {}", loc)?;
                     
                     if let Some(line) = source_line {
                         writeln!(f, "   |")?;
                         writeln!(f, "{} |", loc.line)?;
                         writeln!(f, "   | {}", line)?;
-                        writeln!(f, "   | {}{}",
-                               " ".repeat(loc.column - 1),
-                               "^")?;
+                        writeln!(f, "   | {}^",
+                               " ".repeat(loc.column.saturating_sub(1)))?;
                     }
                 }
                 
@@ -126,15 +249,54 @@ impl fmt::Display for CompilerError {
                 
                 Ok(())
             },
-            CompilerError::VMError { message, instruction, cycle } => {
+            CompilerError::VMError { message, instruction, cycle, backtrace } => {
                 writeln!(f, "VM error: {}", message)?;
+                write_runtime_context(f, instruction, cycle, backtrace)
+            },
+            CompilerError::LimitExceeded { limit, instruction, cycle, backtrace } => {
+                writeln!(f, "Limit exceeded: {}", limit)?;
+                write_runtime_context(f, instruction, cycle, backtrace)
+            },
+            CompilerError::ObjectError { message, offset } => {
+                writeln!(f, "Object file error: {}", message)?;
                 
-                if let Some(instr) = instruction {
-                    writeln!(f, "  When executing: {}", instr)?;
+                if let Some(pos) = offset {
+                    writeln!(f, "  At byte offset: {}", pos)?;
                 }
                 
-                if let Some(c) = cycle {
-                    writeln!(f, "  At cycle: {}", c)?;
+                Ok(())
+            },
+            CompilerError::LinkError { message, units } => {
+                writeln!(f, "Link error: {}", message)?;
+                
+                if !units.is_empty() {
+                    writeln!(f, "  In: {}", units.join(", "))?;
+                }
+                
+                Ok(())
+            },
+            CompilerError::AssemblyError { message, location, source_line } => {
+                writeln!(f, "Assembly error: {}", message)?;
+                
+                if let Some(loc) = location {
+                    writeln!(f, "  --> {}", loc)?;
+                    
+                    if let Some(line) = source_line {
+                        writeln!(f, "   |")?;
+                        writeln!(f, "{} |", loc.line)?;
+                        writeln!(f, "   | {}", line)?;
+                        writeln!(f, "   | {}^",
+                               " ".repeat(loc.column.saturating_sub(1)))?;
+                    }
+                }
+                
+                Ok(())
+            },
+            CompilerError::CodegenError { message, pc } => {
+                writeln!(f, "Code generation error: {}", message)?;
+                
+                if let Some(pc) = pc {
+                    writeln!(f, "  At code address: {}", pc)?;
                 }
                 
                 Ok(())
@@ -146,6 +308,36 @@ impl fmt::Display for CompilerError {
     }
 }
 
+/// Write where a runtime error happened: the innermost frame, the
+/// instruction and cycle, and the backtrace if there are callers
+fn write_runtime_context(
+    f: &mut fmt::Formatter<'_>,
+    instruction: &Option<String>,
+    cycle: &Option<i64>,
+    backtrace: &[StackFrame],
+) -> fmt::Result {
+    if let Some(frame) = backtrace.first().filter(|frame| frame.function.is_some() || frame.location.is_some()) {
+        writeln!(f, "  In {}", frame)?;
+    }
+    
+    if let Some(instr) = instruction {
+        writeln!(f, "  When executing: {}", instr)?;
+    }
+    
+    if let Some(c) = cycle {
+        writeln!(f, "  At cycle: {}", c)?;
+    }
+    
+    if backtrace.len() > 1 {
+        writeln!(f, "  Backtrace:")?;
+        for (i, frame) in backtrace.iter().enumerate() {
+            writeln!(f, "    #{} {}", i, frame)?;
+        }
+    }
+    
+    Ok(())
+}
+
 impl std::error::Error for CompilerError {}
 
 impl From<io::Error> for CompilerError {
@@ -226,12 +418,46 @@ impl CompilerError {
         }
     }
     
+    /// Create an object file error
+    pub fn object_error(message: &str, offset: Option<usize>) -> Self {
+        CompilerError::ObjectError {
+            message: message.to_string(),
+            offset,
+        }
+    }
+    
+    /// Create a link error involving the named units
+    pub fn link_error(message: &str, units: &[&str]) -> Self {
+        CompilerError::LinkError {
+            message: message.to_string(),
+            units: units.iter().map(|unit| unit.to_string()).collect(),
+        }
+    }
+    
+    /// Create an assembly error
+    pub fn assembly_error(message: &str, line: usize, column: usize, source_line: Option<&str>) -> Self {
+        CompilerError::AssemblyError {
+            message: message.to_string(),
+            location: Some(SourceLocation::new(line, column)),
+            source_line: source_line.map(|s| s.to_string()),
+        }
+    }
+    
+    /// Create a code generation error
+    pub fn codegen_error(message: &str, pc: Option<usize>) -> Self {
+        CompilerError::CodegenError {
+            message: message.to_string(),
+            pc,
+        }
+    }
+    
     /// Create a VM error
     pub fn vm_error(message: &str, instruction: Option<&str>, cycle: Option<i64>) -> Self {
         CompilerError::VMError {
             message: message.to_string(),
             instruction: instruction.map(|s| s.to_string()),
             cycle,
+            backtrace: Vec::new(),
         }
     }
 }
@@ -290,7 +516,7 @@ mod tests {
     fn test_vm_error() {
         let err = CompilerError::vm_error("Division by zero", Some("DIV"), Some(42));
         
-        if let CompilerError::VMError { message, instruction, cycle } = err {
+        if let CompilerError::VMError { message, instruction, cycle, .. } = err {
             assert_eq!(message, "Division by zero");
             assert_eq!(instruction, Some("DIV".to_string()));
             assert_eq!(cycle, Some(42));
@@ -0,0 +1,563 @@
+use crate::error::CompilerError;
+use crate::host::{Host, OsHost, STDERR, STDOUT};
+use crate::object::ObjectFile;
+use crate::vm::{VirtualMachine, VmLimits};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+
+/// Target description sent to the debugger: four 64-bit registers
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.c4.vm">
+    <reg name="pc" bitsize="64" type="code_ptr" regnum="0"/>
+    <reg name="sp" bitsize="64" type="data_ptr"/>
+    <reg name="bp" bitsize="64" type="data_ptr"/>
+    <reg name="ax" bitsize="64" type="int64"/>
+  </feature>
+</target>
+"#;
+
+/// Register descriptions answering lldb's `qRegisterInfo`, in register order
+const REGISTER_INFO: [&str; 4] = [
+    "name:pc;bitsize:64;offset:0;encoding:uint;format:hex;set:General Purpose Registers;generic:pc;",
+    "name:sp;bitsize:64;offset:8;encoding:uint;format:hex;set:General Purpose Registers;generic:sp;",
+    "name:bp;bitsize:64;offset:16;encoding:uint;format:hex;set:General Purpose Registers;generic:fp;",
+    "name:ax;bitsize:64;offset:24;encoding:sint;format:decimal;set:General Purpose Registers;",
+];
+
+/// Largest block a single memory read returns
+const MAX_READ: usize = 4096;
+
+/// Stop signal for breakpoints and steps
+const SIGTRAP: u8 = 5;
+
+/// Stop signal for runtime errors
+const SIGSEGV: u8 = 11;
+
+/// The operating system, except that the program's output is collected
+/// until it can be sent as console packets
+struct StubHost {
+    os: OsHost,
+    console: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Host for StubHost {
+    fn open(&mut self, path: &str, flags: i64) -> io::Result<i64> {
+        self.os.open(path, flags)
+    }
+
+    fn read(&mut self, fd: i64, buf: &mut [u8]) -> io::Result<usize> {
+        self.os.read(fd, buf)
+    }
+
+    fn write(&mut self, fd: i64, bytes: &[u8]) -> io::Result<()> {
+        match fd {
+            STDOUT | STDERR => {
+                self.console.borrow_mut().extend_from_slice(bytes);
+                Ok(())
+            },
+            _ => self.os.write(fd, bytes),
+        }
+    }
+
+    fn close(&mut self, fd: i64) -> io::Result<()> {
+        self.os.close(fd)
+    }
+}
+
+/// A packet's effect on the session
+enum Reply {
+    /// Send this packet
+    Packet(String),
+    /// Send this packet and end the session (after `D`)
+    Last(String),
+    /// Send nothing and end the session (after `k`)
+    Close,
+}
+
+/// GDB Remote Serial Protocol stub driving the VM
+///
+/// The program is started and stopped at its entry point; a debugger
+/// connected over any byte stream (see [`GdbStub::serve`]) can then read
+/// the registers `pc`, `sp`, `bp` and `ax` (described by a target
+/// description, or by `qRegisterInfo` for lldb), read and write data and
+/// stack memory, set software breakpoints, step single instructions and
+/// continue. Addresses follow the VM: `pc` is a code word index, and
+/// memory addresses are the ones pointers hold. The program's output is
+/// forwarded to the debugger's console.
+///
+/// Execution is synchronous, so a continued program that never reaches a
+/// breakpoint cannot be interrupted.
+pub struct GdbStub {
+    vm: VirtualMachine,
+    breakpoints: BTreeSet<usize>,
+    /// Output waiting to be sent to the debugger
+    console: Rc<RefCell<Vec<u8>>>,
+    /// The stop reply once the program has exited or failed
+    finished: Option<String>,
+    /// Exit code of the program, once it has exited
+    exit_code: Option<i64>,
+    /// Whether packets are acknowledged with `+`
+    ack: bool,
+    /// The last packet sent, resent when the debugger asks with `-`
+    last_reply: Option<String>,
+}
+
+impl GdbStub {
+    /// Start a program, stopped before its first instruction
+    ///
+    /// # Arguments
+    ///
+    /// * `program` - A linked program with an entry point
+    /// * `args` - Command line arguments to pass to the program
+    /// * `limits` - Resources the program may use
+    pub fn new(program: ObjectFile, args: &[String], limits: VmLimits) -> Result<Self, CompilerError> {
+        let entry = program.entry
+            .ok_or_else(|| CompilerError::vm_error("main() not defined", None, None))?;
+        let console = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = VirtualMachine::with_limits(program.code, program.data, limits, false);
+        vm.set_debug_info(program.debug);
+        vm.set_host(Box::new(StubHost { os: OsHost::new(), console: console.clone() }));
+        vm.start(entry, args)?;
+
+        Ok(GdbStub {
+            vm,
+            breakpoints: BTreeSet::new(),
+            console,
+            finished: None,
+            exit_code: None,
+            ack: true,
+            last_reply: None,
+        })
+    }
+
+    /// Exit code of the program, if it has run to the end
+    pub fn exit_code(&self) -> Option<i64> {
+        self.exit_code
+    }
+
+    /// Answer packets from `reader` on `writer` until the input ends, or
+    /// the debugger kills the program or detaches
+    pub fn serve(&mut self, mut reader: impl BufRead, mut writer: impl Write) -> io::Result<()> {
+        loop {
+            let mut byte = [0];
+            if reader.read(&mut byte)? == 0 {
+                return Ok(());
+            }
+            match byte[0] {
+                b'$' => {},
+                b'-' => {
+                    if let Some(reply) = &self.last_reply {
+                        writer.write_all(&frame(reply))?;
+                        writer.flush()?;
+                    }
+                    continue;
+                },
+                // Acknowledgements, and interrupts that arrive while stopped
+                _ => continue,
+            }
+
+            let mut packet = Vec::new();
+            reader.read_until(b'#', &mut packet)?;
+            let mut checksum = [0; 2];
+            reader.read_exact(&mut checksum)?;
+            if packet.pop() != Some(b'#') {
+                return Ok(());
+            }
+            let expected = std::str::from_utf8(&checksum).ok()
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+            if self.ack && expected != Some(sum(&packet)) {
+                writer.write_all(b"-")?;
+                writer.flush()?;
+                continue;
+            }
+            if self.ack {
+                writer.write_all(b"+")?;
+            }
+
+            let reply = self.handle(&unescape(&packet));
+
+            // Output the program wrote before stopping goes first
+            let output = std::mem::take(&mut *self.console.borrow_mut());
+            for chunk in output.chunks(MAX_READ / 2) {
+                writer.write_all(&frame(&format!("O{}", hex(chunk))))?;
+            }
+            match reply {
+                Reply::Packet(reply) => {
+                    writer.write_all(&frame(&reply))?;
+                    writer.flush()?;
+                    self.last_reply = Some(reply);
+                },
+                Reply::Last(reply) => {
+                    writer.write_all(&frame(&reply))?;
+                    writer.flush()?;
+                    return Ok(());
+                },
+                Reply::Close => {
+                    writer.flush()?;
+                    return Ok(());
+                },
+            }
+        }
+    }
+
+    /// Answer one packet
+    fn handle(&mut self, packet: &[u8]) -> Reply {
+        let text = String::from_utf8_lossy(packet);
+        let (command, args) = text.split_at(text.chars().next().map_or(0, char::len_utf8));
+        let reply = match command {
+            "?" => self.stop_reply(SIGTRAP),
+            "g" => (0..REGISTER_INFO.len())
+                .filter_map(|n| self.register(n))
+                .map(|value| hex(&value.to_le_bytes()))
+                .collect(),
+            "p" => match usize::from_str_radix(args, 16).ok().and_then(|n| self.register(n)) {
+                Some(value) => hex(&value.to_le_bytes()),
+                None => "E45".to_string(),
+            },
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "Z" | "z" => self.breakpoint(command == "Z", args),
+            "s" => self.resume(true),
+            "c" => self.resume(false),
+            "k" => return Reply::Close,
+            "D" => return Reply::Last("OK".to_string()),
+            "H" | "T" => "OK".to_string(),
+            "q" | "Q" => self.query(&text),
+            _ => String::new(),
+        };
+        Reply::Packet(reply)
+    }
+
+    /// Answer a general query or set packet
+    fn query(&mut self, packet: &str) -> String {
+        if let Some(annex) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, len)) = parse_range(annex, ',') else {
+                return "E00".to_string();
+            };
+            let start = (offset as usize).min(TARGET_XML.len());
+            let end = start.saturating_add(len).min(TARGET_XML.len());
+            let more = if end < TARGET_XML.len() { 'm' } else { 'l' };
+            return format!("{}{}", more, &TARGET_XML[start..end]);
+        }
+        if let Some(n) = packet.strip_prefix("qRegisterInfo") {
+            return usize::from_str_radix(n, 16).ok()
+                .and_then(|n| REGISTER_INFO.get(n))
+                .map_or("E45".to_string(), |info| info.to_string());
+        }
+        match packet.split(':').next().unwrap_or("") {
+            "qSupported" => format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+;swbreak+", MAX_READ * 2),
+            "QStartNoAckMode" => {
+                self.ack = false;
+                "OK".to_string()
+            },
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    /// Register `n`, in target description order
+    fn register(&self, n: usize) -> Option<i64> {
+        let regs = self.vm.registers();
+        [regs.pc as i64, regs.sp, regs.bp, regs.ax].get(n).copied()
+    }
+
+    /// `m addr,len`: as many of the bytes as can be read
+    fn read_memory(&self, args: &str) -> String {
+        let Some((addr, len)) = parse_range(args, ',') else {
+            return "E01".to_string();
+        };
+        let bytes: Vec<u8> = (0..len.min(MAX_READ) as i64)
+            .map_while(|i| self.vm.read_memory(addr + i, 1).ok().map(|byte| byte[0]))
+            .collect();
+        if bytes.is_empty() && len > 0 {
+            return "E14".to_string();
+        }
+        hex(&bytes)
+    }
+
+    /// `M addr,len:bytes`
+    fn write_memory(&mut self, args: &str) -> String {
+        let Some((range, data)) = args.split_once(':') else {
+            return "E01".to_string();
+        };
+        let (Some((addr, len)), Some(bytes)) = (parse_range(range, ','), unhex(data)) else {
+            return "E01".to_string();
+        };
+        if bytes.len() != len {
+            return "E01".to_string();
+        }
+        match self.vm.write_memory(addr, &bytes) {
+            Ok(()) => "OK".to_string(),
+            Err(_) => "E14".to_string(),
+        }
+    }
+
+    /// `Z0,addr,kind` inserts a breakpoint, `z0,addr,kind` removes one
+    ///
+    /// Hardware breakpoints (`Z1`) are treated the same way; watchpoints
+    /// are not supported.
+    fn breakpoint(&mut self, insert: bool, args: &str) -> String {
+        let mut fields = args.split(',');
+        let (Some(kind), Some(addr)) = (fields.next(), fields.next()) else {
+            return "E01".to_string();
+        };
+        if kind != "0" && kind != "1" {
+            return String::new();
+        }
+        let Some(pc) = usize::from_str_radix(addr, 16).ok() else {
+            return "E01".to_string();
+        };
+        if !insert {
+            self.breakpoints.remove(&pc);
+        } else if self.vm.instruction(pc).is_some() {
+            self.breakpoints.insert(pc);
+        } else {
+            return "E01".to_string();
+        }
+        "OK".to_string()
+    }
+
+    /// Execute one instruction, or run until a breakpoint or the end
+    fn resume(&mut self, single_step: bool) -> String {
+        if let Some(reply) = &self.finished {
+            return reply.clone();
+        }
+        loop {
+            match self.vm.step() {
+                Ok(None) => {},
+                Ok(Some(exit_code)) => {
+                    self.exit_code = Some(exit_code);
+                    self.finished = Some(format!("W{:02x}", exit_code as u8));
+                    return self.stop_reply(SIGTRAP);
+                },
+                Err(err) => {
+                    // The message reaches the console ahead of the stop reply
+                    self.console.borrow_mut().extend_from_slice(err.to_string().as_bytes());
+                    self.finished = Some(format!("X{:02x}", SIGSEGV));
+                    return self.stop_reply(SIGSEGV);
+                },
+            }
+            if single_step || self.breakpoints.contains(&self.vm.registers().pc) {
+                return self.stop_reply(SIGTRAP);
+            }
+        }
+    }
+
+    /// Why the program is stopped
+    fn stop_reply(&self, signal: u8) -> String {
+        match &self.finished {
+            Some(reply) => reply.clone(),
+            None => format!("S{:02x}", signal),
+        }
+    }
+}
+
+/// Frame a packet: `$payload#checksum`, escaping the special characters
+fn frame(payload: &str) -> Vec<u8> {
+    let mut body = Vec::with_capacity(payload.len());
+    for &byte in payload.as_bytes() {
+        if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+            body.extend([b'}', byte ^ 0x20]);
+        } else {
+            body.push(byte);
+        }
+    }
+    let mut packet = vec![b'$'];
+    packet.extend_from_slice(&body);
+    packet.extend(format!("#{:02x}", sum(&body)).bytes());
+    packet
+}
+
+/// Undo the `}` escapes of a received packet
+fn unescape(body: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(body.len());
+    let mut escaped = false;
+    for &byte in body {
+        match (escaped, byte) {
+            (false, b'}') => escaped = true,
+            (true, _) => {
+                bytes.push(byte ^ 0x20);
+                escaped = false;
+            },
+            _ => bytes.push(byte),
+        }
+    }
+    bytes
+}
+
+/// Modulo-256 sum of a packet body
+fn sum(body: &[u8]) -> u8 {
+    body.iter().fold(0, |sum: u8, &byte| sum.wrapping_add(byte))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Parse `addr<sep>len`, both in hex
+fn parse_range(text: &str, sep: char) -> Option<(i64, usize)> {
+    let (addr, len) = text.split_once(sep)?;
+    Some((u64::from_str_radix(addr, 16).ok()? as i64, usize::from_str_radix(len, 16).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::vm::STACK_BASE;
+    use std::io::Cursor;
+
+    /// In-process RSP client: frames requests and checks every reply
+    struct Client {
+        stub: GdbStub,
+        /// Console output received so far
+        console: String,
+    }
+
+    impl Client {
+        fn new(source: &str) -> Self {
+            let mut parser = Parser::new(source.to_string(), false);
+            parser.init().unwrap();
+            parser.parse().unwrap();
+            let program = ObjectFile::from_parser(&parser).unwrap();
+            Client { stub: GdbStub::new(program, &["prog".to_string()], VmLimits::default()).unwrap(), console: String::new() }
+        }
+
+        /// Send a packet and return the final reply, collecting console output
+        fn request(&mut self, packet: &str) -> String {
+            let ack = self.stub.ack;
+            let mut out = Vec::new();
+            self.stub.serve(Cursor::new(frame(packet)), &mut out).unwrap();
+
+            let mut text = out.as_slice();
+            if ack {
+                assert_eq!(text.first(), Some(&b'+'), "no acknowledgement for {}", packet);
+                text = &text[1..];
+            }
+            let mut replies = Vec::new();
+            while let Some(rest) = text.strip_prefix(b"$") {
+                let end = rest.iter().position(|&byte| byte == b'#').unwrap();
+                let checksum = std::str::from_utf8(&rest[end + 1..end + 3]).unwrap();
+                assert_eq!(u8::from_str_radix(checksum, 16).unwrap(), sum(&rest[..end]));
+                replies.push(String::from_utf8(unescape(&rest[..end])).unwrap());
+                text = &rest[end + 3..];
+            }
+            assert!(text.is_empty(), "trailing bytes after {}", packet);
+
+            let last = replies.pop().unwrap_or_default();
+            for output in replies {
+                let bytes = unhex(output.strip_prefix('O').unwrap()).unwrap();
+                self.console.push_str(&String::from_utf8(bytes).unwrap());
+            }
+            last
+        }
+
+        /// Read one register from a `g` reply
+        fn register(&mut self, n: usize) -> i64 {
+            let regs = self.request("g");
+            let bytes = unhex(&regs[n * 16..(n + 1) * 16]).unwrap();
+            i64::from_le_bytes(bytes.try_into().unwrap())
+        }
+    }
+
+    const SOURCE: &str = "\
+int counter;
+
+int bump(int n) {
+    counter = counter + n;
+    return counter;
+}
+
+int main() {
+    bump(2);
+    bump(3);
+    printf(\"%d\\n\", counter);
+    return counter;
+}
+";
+
+    #[test]
+    fn test_queries_and_registers() {
+        let mut client = Client::new(SOURCE);
+        assert!(client.request("qSupported:swbreak+;xmlRegisters=i386").contains("qXfer:features:read+"));
+        assert_eq!(client.request("?"), "S05");
+
+        // The description arrives in pieces
+        let mut xml = String::new();
+        loop {
+            let chunk = client.request(&format!("qXfer:features:read:target.xml:{:x},40", xml.len()));
+            xml.push_str(&chunk[1..]);
+            if chunk.starts_with('l') {
+                break;
+            }
+        }
+        assert_eq!(xml, TARGET_XML);
+        assert!(client.request("qRegisterInfo3").starts_with("name:ax;"));
+        assert_eq!(client.request("qRegisterInfo4"), "E45");
+
+        let entry = client.stub.vm.registers().pc as i64;
+        assert_eq!(client.register(0), entry);
+        assert_eq!(client.request("p0"), hex(&entry.to_le_bytes()));
+        assert!(client.register(1) > STACK_BASE);
+        assert_eq!(client.request("vMustReplyEmpty"), "");
+    }
+
+    #[test]
+    fn test_breakpoints_memory_and_exit() {
+        let mut client = Client::new(SOURCE);
+        assert_eq!(client.request("QStartNoAckMode"), "OK");
+        assert!(!client.stub.ack);
+
+        // Stop at bump's first instruction, twice
+        let bump = client.stub.vm.debug_info().functions[0].start;
+        assert_eq!(client.request(&format!("Z0,{:x},1", bump)), "OK");
+        assert_eq!(client.request("c"), "S05");
+        assert_eq!(client.register(0), bump as i64);
+
+        // The argument sits above the return address on the stack
+        let sp = client.register(1);
+        assert_eq!(client.request(&format!("m{:x},8", sp + 8)), hex(&2i64.to_le_bytes()));
+
+        // Change the global before the second call adds to it
+        assert_eq!(client.request("c"), "S05");
+        assert_eq!(client.request("m0,8"), hex(&2i64.to_le_bytes()));
+        assert_eq!(client.request(&format!("M0,8:{}", hex(&40i64.to_le_bytes()))), "OK");
+        assert_eq!(client.request(&format!("z0,{:x},1", bump)), "OK");
+
+        // A step runs exactly one instruction
+        assert_eq!(client.request("s"), "S05");
+        assert_eq!(client.register(0), bump as i64 + 2);
+
+        assert_eq!(client.request("c"), "W2b");
+        assert_eq!(client.console, "43\n");
+        assert_eq!(client.stub.exit_code(), Some(43));
+        assert_eq!(client.request("?"), "W2b");
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut client = Client::new("int main() { int z; z = 0; return 1 / z; }");
+        assert_eq!(client.request("Z0,ffffff,1"), "E01");
+        assert_eq!(client.request("m7fffffff00,4"), "E14");
+        assert_eq!(client.request("M0,2:zz"), "E01");
+        assert_eq!(client.request("c"), "X0b");
+        assert!(client.console.contains("Division by zero"), "{}", client.console);
+        assert_eq!(client.request("s"), "X0b");
+    }
+}
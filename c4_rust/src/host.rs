@@ -0,0 +1,396 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+
+/// `open` flags, with Linux's values as used by C programs
+pub const O_ACCMODE: i64 = 0o3;
+pub const O_WRONLY: i64 = 0o1;
+pub const O_RDWR: i64 = 0o2;
+pub const O_CREAT: i64 = 0o100;
+pub const O_TRUNC: i64 = 0o1000;
+pub const O_APPEND: i64 = 0o2000;
+
+/// Descriptor numbers of the standard streams
+pub const STDIN: i64 = 0;
+pub const STDOUT: i64 = 1;
+pub const STDERR: i64 = 2;
+
+/// The outside world, as seen by a running program
+///
+/// The VM's system calls go through a host: `open`, `read` and `close`
+/// map directly onto it, `printf` writes to [`STDOUT`], and `exit` (or a
+/// return from `main`) is reported to [`Host::exit`]. Descriptors 0, 1 and
+/// 2 are the standard streams; `open` hands out the others.
+pub trait Host {
+    /// Open a file, returning its descriptor
+    fn open(&mut self, path: &str, flags: i64) -> io::Result<i64>;
+
+    /// Read from a descriptor into `buf`, returning how many bytes were read
+    fn read(&mut self, fd: i64, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Write all of `bytes` to a descriptor
+    fn write(&mut self, fd: i64, bytes: &[u8]) -> io::Result<()>;
+
+    /// Close a descriptor
+    fn close(&mut self, fd: i64) -> io::Result<()>;
+
+    /// Called once when the program exits with `code`
+    fn exit(&mut self, _code: i64) {}
+}
+
+fn bad_descriptor(fd: i64) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("bad file descriptor {}", fd))
+}
+
+/// Open files by descriptor, numbered from 3
+struct FileTable<T> {
+    files: HashMap<i64, T>,
+    next_fd: i64,
+}
+
+impl<T> Default for FileTable<T> {
+    fn default() -> Self {
+        FileTable { files: HashMap::new(), next_fd: 3 }
+    }
+}
+
+impl<T> FileTable<T> {
+    fn insert(&mut self, file: T) -> i64 {
+        let fd = self.next_fd;
+        self.next_fd += 1;
+        self.files.insert(fd, file);
+        fd
+    }
+
+    fn get(&mut self, fd: i64) -> io::Result<&mut T> {
+        self.files.get_mut(&fd).ok_or_else(|| bad_descriptor(fd))
+    }
+
+    fn remove(&mut self, fd: i64) -> io::Result<T> {
+        self.files.remove(&fd).ok_or_else(|| bad_descriptor(fd))
+    }
+}
+
+/// The real operating system: files, stdin, stdout and stderr
+pub struct OsHost {
+    files: FileTable<File>,
+}
+
+impl OsHost {
+    pub fn new() -> Self {
+        OsHost { files: FileTable::default() }
+    }
+}
+
+impl Default for OsHost {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Host for OsHost {
+    fn open(&mut self, path: &str, flags: i64) -> io::Result<i64> {
+        let file = OpenOptions::new()
+            .read(flags & O_ACCMODE != O_WRONLY)
+            .write(flags & O_ACCMODE != 0)
+            .create(flags & O_CREAT != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .append(flags & O_APPEND != 0)
+            .open(path)?;
+        Ok(self.files.insert(file))
+    }
+
+    fn read(&mut self, fd: i64, buf: &mut [u8]) -> io::Result<usize> {
+        match fd {
+            STDIN => io::stdin().read(buf),
+            _ => self.files.get(fd)?.read(buf),
+        }
+    }
+
+    fn write(&mut self, fd: i64, bytes: &[u8]) -> io::Result<()> {
+        match fd {
+            STDOUT => {
+                let mut stdout = io::stdout();
+                stdout.write_all(bytes).and_then(|_| stdout.flush())
+            },
+            STDERR => io::stderr().write_all(bytes),
+            _ => self.files.get(fd)?.write_all(bytes),
+        }
+    }
+
+    fn close(&mut self, fd: i64) -> io::Result<()> {
+        self.files.remove(fd).map(drop)
+    }
+}
+
+/// Like [`OsHost`], but only files under one directory can be opened
+///
+/// Relative paths are taken from that directory, and paths that lead
+/// outside it, through `..` or symbolic links, are refused.
+pub struct SandboxHost {
+    root: PathBuf,
+    os: OsHost,
+}
+
+impl SandboxHost {
+    /// Allow access to the files under `root`, which must exist
+    pub fn new(root: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(SandboxHost { root: root.as_ref().canonicalize()?, os: OsHost::new() })
+    }
+
+    /// Where `path` leads, if that is inside the sandbox
+    fn resolve(&self, path: &str) -> io::Result<PathBuf> {
+        let denied = || io::Error::new(io::ErrorKind::PermissionDenied, format!("{} is outside the sandbox", path));
+        // Refuse paths that climb out before looking at the file system
+        let mut normal = PathBuf::new();
+        for component in self.root.join(path).components() {
+            match component {
+                Component::ParentDir => {
+                    normal.pop();
+                },
+                Component::CurDir => {},
+                other => normal.push(other),
+            }
+        }
+        let path = normal;
+        if !path.starts_with(&self.root) {
+            return Err(denied());
+        }
+
+        // A file that does not exist yet is checked through its directory.
+        // A dangling symbolic link is refused, since creating the file
+        // would follow it wherever it points
+        let resolved = match path.canonicalize() {
+            Ok(resolved) => resolved,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                if path.symlink_metadata().is_ok() {
+                    return Err(denied());
+                }
+                let name = match path.components().next_back() {
+                    Some(Component::Normal(name)) => name,
+                    _ => return Err(denied()),
+                };
+                let parent = path.parent().ok_or_else(denied)?;
+                parent.canonicalize()?.join(name)
+            },
+            Err(err) => return Err(err),
+        };
+        if resolved.starts_with(&self.root) {
+            Ok(resolved)
+        } else {
+            Err(denied())
+        }
+    }
+}
+
+impl Host for SandboxHost {
+    fn open(&mut self, path: &str, flags: i64) -> io::Result<i64> {
+        let resolved = self.resolve(path)?;
+        self.os.open(&resolved.to_string_lossy(), flags)
+    }
+
+    fn read(&mut self, fd: i64, buf: &mut [u8]) -> io::Result<usize> {
+        self.os.read(fd, buf)
+    }
+
+    fn write(&mut self, fd: i64, bytes: &[u8]) -> io::Result<()> {
+        self.os.write(fd, bytes)
+    }
+
+    fn close(&mut self, fd: i64) -> io::Result<()> {
+        self.os.close(fd)
+    }
+}
+
+/// An open in-memory file: its name and the read or write position
+struct OpenFile {
+    path: String,
+    pos: usize,
+    append: bool,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    stdin: Vec<u8>,
+    stdin_pos: usize,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    files: HashMap<String, Vec<u8>>,
+    open: FileTable<OpenFile>,
+    exit_code: Option<i64>,
+}
+
+/// A host kept entirely in memory, for tests and embedding
+///
+/// Standard input comes from a buffer, output is collected, and files live
+/// in a map from path to contents. Clones share the same state, so a clone
+/// kept outside the VM can inspect what the program did.
+#[derive(Clone, Default)]
+pub struct MemoryHost(Rc<RefCell<MemoryState>>);
+
+impl MemoryHost {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use `input` as standard input
+    pub fn with_stdin(self, input: impl Into<Vec<u8>>) -> Self {
+        self.0.borrow_mut().stdin = input.into();
+        self
+    }
+
+    /// Add a file
+    pub fn with_file(self, path: &str, contents: impl Into<Vec<u8>>) -> Self {
+        self.0.borrow_mut().files.insert(path.to_string(), contents.into());
+        self
+    }
+
+    /// Everything written to standard output so far
+    pub fn stdout(&self) -> Vec<u8> {
+        self.0.borrow().stdout.clone()
+    }
+
+    /// Everything written to standard error so far
+    pub fn stderr(&self) -> Vec<u8> {
+        self.0.borrow().stderr.clone()
+    }
+
+    /// Contents of a file
+    pub fn file(&self, path: &str) -> Option<Vec<u8>> {
+        self.0.borrow().files.get(path).cloned()
+    }
+
+    /// The code the program exited with, once it has
+    pub fn exit_code(&self) -> Option<i64> {
+        self.0.borrow().exit_code
+    }
+}
+
+impl Host for MemoryHost {
+    fn open(&mut self, path: &str, flags: i64) -> io::Result<i64> {
+        let mut state = self.0.borrow_mut();
+        let state = &mut *state;
+        match state.files.get_mut(path) {
+            Some(contents) if flags & O_TRUNC != 0 && flags & O_ACCMODE != 0 => contents.clear(),
+            Some(_) => {},
+            None if flags & O_CREAT != 0 => {
+                state.files.insert(path.to_string(), Vec::new());
+            },
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path))),
+        }
+        let file = OpenFile { path: path.to_string(), pos: 0, append: flags & O_APPEND != 0 };
+        Ok(state.open.insert(file))
+    }
+
+    fn read(&mut self, fd: i64, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.0.borrow_mut();
+        let state = &mut *state;
+        let (contents, pos) = match fd {
+            STDIN => (&state.stdin, &mut state.stdin_pos),
+            _ => {
+                let file = state.open.get(fd)?;
+                (&state.files[&file.path], &mut file.pos)
+            },
+        };
+        let n = buf.len().min(contents.len().saturating_sub(*pos));
+        buf[..n].copy_from_slice(&contents[*pos..*pos + n]);
+        *pos += n;
+        Ok(n)
+    }
+
+    fn write(&mut self, fd: i64, bytes: &[u8]) -> io::Result<()> {
+        let mut state = self.0.borrow_mut();
+        let state = &mut *state;
+        match fd {
+            STDOUT => state.stdout.extend_from_slice(bytes),
+            STDERR => state.stderr.extend_from_slice(bytes),
+            _ => {
+                let file = state.open.get(fd)?;
+                let contents = state.files.get_mut(&file.path).ok_or_else(|| bad_descriptor(fd))?;
+                if file.append {
+                    file.pos = contents.len();
+                }
+                let end = file.pos + bytes.len();
+                if contents.len() < end {
+                    contents.resize(end, 0);
+                }
+                contents[file.pos..end].copy_from_slice(bytes);
+                file.pos = end;
+            },
+        }
+        Ok(())
+    }
+
+    fn close(&mut self, fd: i64) -> io::Result<()> {
+        self.0.borrow_mut().open.remove(fd).map(drop)
+    }
+
+    fn exit(&mut self, code: i64) {
+        self.0.borrow_mut().exit_code = Some(code);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_host() {
+        let mut host = MemoryHost::new().with_stdin("abc").with_file("in.txt", "hello");
+        let mut buf = [0; 2];
+        assert_eq!(host.read(STDIN, &mut buf).unwrap(), 2);
+        assert_eq!(host.read(STDIN, &mut buf).unwrap(), 1);
+        assert_eq!(&buf[..1], b"c");
+
+        let fd = host.open("in.txt", 0).unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(host.read(fd, &mut buf).unwrap(), 5);
+        assert_eq!(host.read(fd, &mut buf).unwrap(), 0);
+        host.close(fd).unwrap();
+        assert!(host.read(fd, &mut buf).is_err());
+        assert!(host.open("missing.txt", 0).is_err());
+
+        let out = host.open("out.txt", O_WRONLY | O_CREAT).unwrap();
+        host.write(out, b"one ").unwrap();
+        host.write(out, b"two").unwrap();
+        host.write(STDOUT, b"printed").unwrap();
+        assert_eq!(host.file("out.txt").unwrap(), b"one two");
+        assert_eq!(host.stdout(), b"printed");
+    }
+
+    #[test]
+    fn test_sandbox_host() {
+        let root = std::env::temp_dir().join(format!("c4_sandbox_{}", std::process::id()));
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("sub/data.txt"), "inside").unwrap();
+
+        let mut host = SandboxHost::new(&root).unwrap();
+        let fd = host.open("sub/data.txt", 0).unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(host.read(fd, &mut buf).unwrap(), 6);
+        host.close(fd).unwrap();
+
+        // New files may be created inside, but nothing outside can be reached
+        let fd = host.open("sub/new.txt", O_WRONLY | O_CREAT).unwrap();
+        host.write(fd, b"x").unwrap();
+        host.close(fd).unwrap();
+        for path in ["../outside.txt", "sub/../../etc/passwd", "/etc/passwd"] {
+            let err = host.open(path, 0).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied, "{}", path);
+        }
+
+        // Nor through a link to a file that does not exist yet
+        #[cfg(unix)]
+        {
+            let outside = root.with_extension("escaped");
+            std::os::unix::fs::symlink(&outside, root.join("dangle")).unwrap();
+            let err = host.open("dangle", O_WRONLY | O_CREAT).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+            assert!(!outside.exists());
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
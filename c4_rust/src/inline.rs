@@ -0,0 +1,432 @@
+use crate::dataflow;
+use crate::instr::Instr;
+use crate::ir::{Block, BlockId, Function, Inst, Program, Terminator};
+use std::collections::BTreeSet;
+
+/// Largest function, in code words, that is copied into its callers
+pub const MAX_INLINE_WORDS: usize = 32;
+
+/// Code words inlining may add to any one function
+pub const INLINE_BUDGET: usize = 256;
+
+/// A call whose arguments are all evaluated in the block of its `JSR`
+struct Call {
+    /// Start address of the function called
+    callee: usize,
+    /// Expression stack depth before the arguments
+    depth: usize,
+    /// Index of the first instruction evaluating the arguments
+    start: usize,
+    /// Index of the push that completes each argument, first to last
+    pushes: Vec<usize>,
+    /// Index just past the call and the `ADJ` that drops its arguments
+    end: usize,
+}
+
+impl Call {
+    /// Find the call made by the `JSR` at index `jsr` of block `id`
+    ///
+    /// Returns `None` if the callee is in another unit, or if the
+    /// arguments cannot be told apart: some were pushed in an earlier
+    /// block, or the first of them reads what `ax` held before.
+    fn find(func: &Function, depths: &[Option<usize>], id: BlockId, jsr: usize) -> Option<Call> {
+        let insts = &func.blocks[id].insts;
+        let Instr::Jsr(callee) = insts[jsr].instr else { return None };
+        if insts[jsr].reloc.is_some() {
+            return None;
+        }
+        let (argc, end) = match insts.get(jsr + 1).map(|next| next.instr) {
+            Some(Instr::Adj(n)) if n > 0 => (n as usize, jsr + 2),
+            _ => (0, jsr + 1),
+        };
+
+        // Stack depth before each instruction, up to the JSR
+        let mut depth = vec![depths[id]?];
+        for inst in &insts[..jsr] {
+            let (pops, pushes) = inst.instr.stack_effect();
+            depth.push(depth[depth.len() - 1].checked_sub(pops)? + pushes);
+        }
+        let base = depth[jsr].checked_sub(argc)?;
+        let start = (0..jsr).rev().find(|&i| depth[i] < base).map_or(0, |i| i + 1);
+        if depth[start] != base {
+            return None;
+        }
+        let mut pushes = Vec::new();
+        for level in base..base + argc {
+            let push = (start..jsr).find(|&i| depth[i] == level && depth[i + 1..=jsr].iter().all(|&d| d > level))?;
+            if insts[push].instr.stack_effect() != (0, 1) {
+                return None;
+            }
+            pushes.push(push);
+        }
+        if argc > 0 && !dataflow::clobbers_ax(insts[start].instr) {
+            return None;
+        }
+        Some(Call { callee, depth: base, start, pushes, end })
+    }
+
+    /// Indices of the instructions evaluating argument `i`, its push
+    /// included
+    fn argument(&self, i: usize) -> std::ops::RangeInclusive<usize> {
+        let from = if i == 0 { self.start } else { self.pushes[i - 1] + 1 };
+        from..=self.pushes[i]
+    }
+
+    /// Evaluate the arguments into frame slots instead of onto the stack,
+    /// argument `i` into `slot(i)`
+    fn store_arguments(&self, insts: &[Inst], slot: impl Fn(usize) -> i64) -> Vec<Inst> {
+        let mut out = Vec::new();
+        for (i, &push) in self.pushes.iter().enumerate() {
+            out.push(Inst::new(Instr::Lea(slot(i))));
+            out.push(Inst::new(Instr::Psh));
+            out.extend(insts[*self.argument(i).start()..push].iter().cloned());
+            // A fused push still has to load the value
+            let pushed = &insts[push];
+            match pushed.instr {
+                Instr::Pshl(offset) => out.push(Inst { instr: Instr::Lli(offset), ..pushed.clone() }),
+                Instr::Pshi(value) => out.push(Inst { instr: Instr::Imm(value), ..pushed.clone() }),
+                _ => {},
+            }
+            out.push(Inst::new(Instr::Si));
+        }
+        out
+    }
+}
+
+/// Approximate size of a function in code words
+fn size(func: &Function) -> usize {
+    func.blocks.iter()
+        .map(|block| 1 + block.insts.iter().map(|inst| inst.instr.encode().len()).sum::<usize>())
+        .sum()
+}
+
+/// Start addresses of the functions a function calls directly
+fn callees(func: &Function) -> BTreeSet<usize> {
+    func.blocks.iter()
+        .flat_map(|block| &block.insts)
+        .filter_map(|inst| match inst.instr {
+            Instr::Jsr(target) if inst.reloc.is_none() => Some(target),
+            _ => None,
+        })
+        .chain(func.blocks.iter().filter_map(|block| match block.terminator {
+            Terminator::TailCall(target) => Some(target),
+            _ => None,
+        }))
+        .collect()
+}
+
+/// Start addresses of the functions that can end up calling themselves
+fn recursive(program: &Program) -> BTreeSet<usize> {
+    let mut found = BTreeSet::new();
+    for func in &program.functions {
+        let mut seen = BTreeSet::new();
+        let mut work: Vec<usize> = callees(func).into_iter().collect();
+        while let Some(start) = work.pop() {
+            if start == func.start {
+                found.insert(start);
+                break;
+            }
+            if seen.insert(start) {
+                if let Some(callee) = program.function_at(start) {
+                    work.extend(callees(callee));
+                }
+            }
+        }
+    }
+    found
+}
+
+/// Whether a function can be copied into a caller passing `argc`
+/// arguments: it reads no more parameters than that, and returns with an
+/// empty expression stack
+fn fits_call(callee: &Function, argc: usize) -> bool {
+    if callee.params.is_some_and(|params| params != argc) {
+        return false;
+    }
+    let reads_beyond = callee.blocks.iter().flat_map(|block| &block.insts).any(|inst| match inst.instr {
+        Instr::Lea(offset) | Instr::Lli(offset) | Instr::Llc(offset) | Instr::Pshl(offset) => {
+            offset > argc as i64 + 1
+        },
+        _ => false,
+    });
+    let Ok(depths) = callee.stack_depths() else { return false };
+    let returns_empty = callee.blocks.iter().zip(depths).all(|(block, depth)| {
+        let Some(mut depth) = depth else { return true };
+        for inst in &block.insts {
+            let (pops, pushes) = inst.instr.stack_effect();
+            depth = depth.saturating_sub(pops) + pushes;
+        }
+        match block.terminator {
+            Terminator::Return => depth == 0,
+            Terminator::TailCall(_) => false,
+            _ => true,
+        }
+    });
+    !reads_beyond && returns_empty
+}
+
+/// Renumber the blocks a terminator continues at
+fn retarget(terminator: Terminator, renumber: impl Fn(BlockId) -> BlockId) -> Terminator {
+    match terminator {
+        Terminator::Jump(to) => Terminator::Jump(renumber(to)),
+        Terminator::Branch { zero, nonzero } => Terminator::Branch { zero: renumber(zero), nonzero: renumber(nonzero) },
+        terminator => terminator,
+    }
+}
+
+/// Copy small functions into the places that call them
+///
+/// A function is inlined if it is at most [`MAX_INLINE_WORDS`] long and
+/// can never call itself, as long as its caller grows by no more than
+/// [`INLINE_BUDGET`] words in all. The arguments are stored in fresh slots at
+/// the bottom of the caller's frame, where the copy finds its parameters,
+/// and the copy's locals go next to them, so `ADJ` and the frame
+/// set up by `ENT` and `LEV` disappear along with the `JSR`. Returns
+/// whether any call was inlined.
+pub fn inline_calls(program: &mut Program) -> bool {
+    let recursive = recursive(program);
+    let mut inlined = false;
+    for index in 0..program.functions.len() {
+        let mut grown = 0;
+        while let Some((id, call, callee)) = next_inline(program, index, &recursive, INLINE_BUDGET - grown) {
+            grown += size(&callee);
+            inline(&mut program.functions[index], id, call, &callee);
+            inlined = true;
+        }
+    }
+    inlined
+}
+
+/// The first call in a function that can be inlined within the budget,
+/// with a copy of the function it calls
+fn next_inline(
+    program: &Program,
+    index: usize,
+    recursive: &BTreeSet<usize>,
+    budget: usize,
+) -> Option<(BlockId, Call, Function)> {
+    let func = &program.functions[index];
+    let depths = func.stack_depths().ok()?;
+    for (id, block) in func.blocks.iter().enumerate() {
+        // Outer calls first, so calls in their arguments are moved along
+        for jsr in (0..block.insts.len()).rev() {
+            let Instr::Jsr(target) = block.insts[jsr].instr else { continue };
+            let Some(callee) = program.function_at(target) else { continue };
+            let words = size(callee);
+            if recursive.contains(&target) || words > MAX_INLINE_WORDS || words > budget {
+                continue;
+            }
+            let Some(call) = Call::find(func, &depths, id, jsr) else { continue };
+            if fits_call(callee, call.pushes.len()) {
+                return Some((id, call, callee.clone()));
+            }
+        }
+    }
+    None
+}
+
+/// Replace the call in block `id` with a copy of `callee`
+///
+/// The block is split at the call: the first half stores the arguments
+/// and continues into the copy, whose returns continue at the second half.
+fn inline(func: &mut Function, id: BlockId, call: Call, callee: &Function) {
+    let base = func.frame;
+    let argc = call.pushes.len();
+    let arg = |i: usize| -(base + callee.frame + 1 + i as i64);
+    // Locals move below the caller's; the last argument is at bp + 2
+    let slot = |offset: i64| if offset < 0 { offset - base } else { arg(argc + 1 - offset as usize) };
+    func.frame = base + callee.frame + argc as i64;
+
+    let copied = callee.blocks.len();
+    let after = id + copied + 1;
+    for block in &mut func.blocks {
+        block.terminator = retarget(block.terminator, |to| if to > id { to + copied + 1 } else { to });
+    }
+    let block = &mut func.blocks[id];
+    let insts = std::mem::take(&mut block.insts);
+    let mut first = insts[..call.start].to_vec();
+    first.extend(call.store_arguments(&insts, arg));
+    let rest = Block {
+        insts: insts[call.end..].to_vec(),
+        terminator: block.terminator,
+        terminator_origin: block.terminator_origin,
+    };
+    block.insts = first;
+    block.terminator = Terminator::Jump(id + 1);
+    block.terminator_origin = None;
+
+    let copies = callee.blocks.iter().map(|block| Block {
+        insts: block.insts.iter()
+            .map(|inst| Inst {
+                instr: match inst.instr {
+                    Instr::Lea(offset) => Instr::Lea(slot(offset)),
+                    Instr::Lli(offset) => Instr::Lli(slot(offset)),
+                    Instr::Llc(offset) => Instr::Llc(slot(offset)),
+                    Instr::Pshl(offset) => Instr::Pshl(slot(offset)),
+                    instr => instr,
+                },
+                reloc: inst.reloc.clone(),
+                origin: None,
+            })
+            .collect(),
+        terminator: match block.terminator {
+            Terminator::Return => Terminator::Jump(after),
+            terminator => retarget(terminator, |to| to + id + 1),
+        },
+        terminator_origin: None,
+    });
+    func.blocks.splice(id + 1..id + 1, copies.chain([rest]));
+}
+
+/// Turn calls in tail position into jumps
+///
+/// A function that returns what a call to itself returns stores the new
+/// arguments over its parameters and jumps back to its first block, so
+/// the recursion runs in one frame. Other tail calls become `TSR` when
+/// `opcode` is set (the extended instruction set), which leaves the frame
+/// before jumping to the callee. Either way the arguments are stored over
+/// the parameters, by way of fresh locals if an argument reads a
+/// parameter an earlier one has replaced. Functions whose locals may be reached through a pointer, or
+/// whose parameter count is unknown, are left alone. Returns whether any
+/// call was turned into a jump.
+pub fn tail_calls(program: &mut Program, opcode: bool) -> bool {
+    let starts: BTreeSet<usize> = program.functions.iter().map(|func| func.start).collect();
+    let mut changed = false;
+    for func in &mut program.functions {
+        let Some(params) = func.params else { continue };
+        if dataflow::address_escapes(func) {
+            continue;
+        }
+        let Ok(depths) = func.stack_depths() else { continue };
+        let base = func.frame;
+        for id in 0..func.blocks.len() {
+            let insts = &func.blocks[id].insts;
+            if func.blocks[id].terminator != Terminator::Return {
+                continue;
+            }
+            let jsr = match insts.iter().rev().map(|inst| inst.instr).take(2).collect::<Vec<_>>()[..] {
+                [Instr::Jsr(_), ..] => insts.len() - 1,
+                [Instr::Adj(_), Instr::Jsr(_)] => insts.len() - 2,
+                _ => continue,
+            };
+            let Some(call) = Call::find(func, &depths, id, jsr) else { continue };
+            let argc = call.pushes.len();
+            if call.end != insts.len() || call.depth != 0 || argc > params {
+                continue;
+            }
+            let terminator = if call.callee == func.start {
+                Terminator::Jump(0)
+            } else if opcode && starts.contains(&call.callee) {
+                Terminator::TailCall(call.callee)
+            } else {
+                continue;
+            };
+
+            let param = |i: usize| 2 + (argc - 1 - i) as i64;
+            let clobbered = (1..argc).any(|j| insts[call.argument(j)].iter().any(|inst| match inst.instr {
+                Instr::Lea(offset) | Instr::Lli(offset) | Instr::Llc(offset) | Instr::Pshl(offset) => {
+                    (0..j).any(|i| param(i) == offset)
+                },
+                _ => false,
+            }));
+            let mut rewritten = insts[..call.start].to_vec();
+            if clobbered {
+                let temp = |i: usize| -(base + 1 + i as i64);
+                rewritten.extend(call.store_arguments(insts, temp));
+                for i in 0..argc {
+                    rewritten.extend([
+                        Inst::new(Instr::Lea(param(i))),
+                        Inst::new(Instr::Psh),
+                        Inst::new(Instr::Lea(temp(i))),
+                        Inst::new(Instr::Li),
+                        Inst::new(Instr::Si),
+                    ]);
+                }
+                func.frame = func.frame.max(base + argc as i64);
+            } else {
+                rewritten.extend(call.store_arguments(insts, param));
+            }
+            let block = &mut func.blocks[id];
+            block.insts = rewritten;
+            block.terminator = terminator;
+            // The jump is wider than the LEV it replaces
+            block.terminator_origin = None;
+            changed = true;
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::vm::VirtualMachine;
+
+    fn program(source: &str) -> Program {
+        let mut parser = Parser::new(source.to_string(), false);
+        parser.init().unwrap();
+        parser.parse().unwrap();
+        parser.optimize();
+        Program::from_parser(&parser).unwrap()
+    }
+
+    fn run(program: &Program, stack: usize) -> i64 {
+        program.verify().unwrap();
+        let lowered = program.to_code();
+        let main = lowered.functions.iter().find(|(name, _)| name == "main").unwrap().1;
+        let mut vm = VirtualMachine::new(lowered.code, Vec::new(), stack, false);
+        vm.run(main, &[]).unwrap()
+    }
+
+    fn calls(func: &Function) -> usize {
+        func.blocks.iter().flat_map(|block| &block.insts)
+            .filter(|inst| matches!(inst.instr, Instr::Jsr(_)))
+            .count()
+    }
+
+    #[test]
+    fn test_small_functions_are_inlined() {
+        let mut program = program(
+            "int sq(int x) { int y; y = x * x; return y; }
+             int sub(int a, int b) { return a - b; }
+             int fact(int n) { if (n < 2) return 1; return n * fact(n - 1); }
+             int main() { int k; k = 3; return sub(sq(k), sq(2)) + fact(3); }");
+        assert!(inline_calls(&mut program));
+        let main = program.functions.iter().find(|func| func.name == "main").unwrap();
+        // Only the recursive call is left
+        assert_eq!(calls(main), 1);
+        assert_eq!(main.frame, 1 + 2 + 2 * 2);
+        assert_eq!(run(&program, 64 * 1024), 11);
+    }
+
+    #[test]
+    fn test_tail_calls_reuse_the_frame() {
+        let source = "int sum(int n, int acc) { if (n == 0) return acc; return sum(n - 1, acc + n); }
+                      int swap(int a, int b) { if (a > b) return swap(b, a); return b - a; }
+                      int main() { return sum(100000, 0) + swap(9, 2); }";
+        let mut program = program(source);
+        assert!(tail_calls(&mut program, false));
+        for func in &program.functions {
+            assert_eq!(calls(func), if func.name == "main" { 2 } else { 0 }, "{}", func.name);
+        }
+        // 100000 frames would not fit in a 64 KiB stack
+        assert_eq!(run(&program, 64 * 1024), 5000050000 + 7);
+    }
+
+    #[test]
+    fn test_other_tail_calls_use_tsr() {
+        let source = "int add(int a, int b) { return a + b; }
+                      int twice(int x, int y) { int *p; if (x) return add(x, x); p = &y; return *p; }
+                      int inc(int x, int y) { return add(y, x + 1); }
+                      int main() { return inc(4, 0) + twice(2, 0); }";
+        let mut program = program(source);
+        assert!(tail_calls(&mut program, true));
+        let tail = |name: &str| program.functions.iter().find(|func| func.name == name).unwrap()
+            .blocks.iter().any(|block| matches!(block.terminator, Terminator::TailCall(_)));
+        assert!(tail("inc"));
+        // Its parameter's address escapes
+        assert!(!tail("twice"));
+        assert_eq!(run(&program, 64 * 1024), 9);
+    }
+}
@@ -0,0 +1,291 @@
+use crate::error::CompilerError;
+use crate::types::Opcode;
+use std::fmt;
+
+/// A decoded VM instruction with its operand inline
+///
+/// Decoding happens once when a program is loaded, so the interpreter never
+/// has to look up opcodes or fetch and bounds-check operand words. Jump and
+/// call targets are validated code addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instr {
+    Lea(i64),
+    Imm(i64),
+    Jmp(usize),
+    Jsr(usize),
+    Bz(usize),
+    Bnz(usize),
+    Ent(i64),
+    Adj(i64),
+    Lev,
+    Li,
+    Lc,
+    Si,
+    Sc,
+    Psh,
+    Or,
+    Xor,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Open,
+    Read,
+    Clos,
+    /// `printf`, with the argument count taken from the `ADJ` after it
+    Prtf(usize),
+    Malc,
+    Free,
+    Mset,
+    Mcmp,
+    Exit,
+    Neg,
+    Lli(i64),
+    Llc(i64),
+    Pshl(i64),
+    Pshi(i64),
+    Addi(i64),
+    Subi(i64),
+    Muli(i64),
+    /// A call to the native function with this index
+    Natv(usize),
+    /// A tail call: unwind the frame like `LEV`, then jump to the function
+    /// here, which returns to the caller's caller
+    Tsr(usize),
+    /// The operand word of the preceding instruction; never executed
+    Operand,
+}
+
+impl Instr {
+    /// The opcode this instruction was decoded from
+    ///
+    /// Returns `None` for operand words.
+    pub fn opcode(&self) -> Option<Opcode> {
+        Some(match self {
+            Instr::Lea(_) => Opcode::LEA, Instr::Imm(_) => Opcode::IMM,
+            Instr::Jmp(_) => Opcode::JMP, Instr::Jsr(_) => Opcode::JSR,
+            Instr::Bz(_) => Opcode::BZ, Instr::Bnz(_) => Opcode::BNZ,
+            Instr::Ent(_) => Opcode::ENT, Instr::Adj(_) => Opcode::ADJ,
+            Instr::Lev => Opcode::LEV, Instr::Li => Opcode::LI, Instr::Lc => Opcode::LC,
+            Instr::Si => Opcode::SI, Instr::Sc => Opcode::SC, Instr::Psh => Opcode::PSH,
+            Instr::Or => Opcode::OR, Instr::Xor => Opcode::XOR, Instr::And => Opcode::AND,
+            Instr::Eq => Opcode::EQ, Instr::Ne => Opcode::NE, Instr::Lt => Opcode::LT,
+            Instr::Gt => Opcode::GT, Instr::Le => Opcode::LE, Instr::Ge => Opcode::GE,
+            Instr::Shl => Opcode::SHL, Instr::Shr => Opcode::SHR, Instr::Add => Opcode::ADD,
+            Instr::Sub => Opcode::SUB, Instr::Mul => Opcode::MUL, Instr::Div => Opcode::DIV,
+            Instr::Mod => Opcode::MOD, Instr::Open => Opcode::OPEN, Instr::Read => Opcode::READ,
+            Instr::Clos => Opcode::CLOS, Instr::Prtf(_) => Opcode::PRTF,
+            Instr::Malc => Opcode::MALC, Instr::Free => Opcode::FREE,
+            Instr::Mset => Opcode::MSET, Instr::Mcmp => Opcode::MCMP,
+            Instr::Exit => Opcode::EXIT, Instr::Neg => Opcode::NEG,
+            Instr::Lli(_) => Opcode::LLI, Instr::Llc(_) => Opcode::LLC,
+            Instr::Pshl(_) => Opcode::PSHL, Instr::Pshi(_) => Opcode::PSHI,
+            Instr::Addi(_) => Opcode::ADDI, Instr::Subi(_) => Opcode::SUBI,
+            Instr::Muli(_) => Opcode::MULI, Instr::Natv(_) => Opcode::NATV,
+            Instr::Tsr(_) => Opcode::TSR,
+            Instr::Operand => return None,
+        })
+    }
+
+    /// The operand word, for instructions that have one
+    pub fn operand(&self) -> Option<i64> {
+        match *self {
+            Instr::Jmp(target) | Instr::Jsr(target) |
+            Instr::Bz(target) | Instr::Bnz(target) | Instr::Tsr(target) => Some(target as i64),
+            Instr::Natv(index) => Some(index as i64),
+            Instr::Lea(n) | Instr::Imm(n) | Instr::Ent(n) | Instr::Adj(n) |
+            Instr::Lli(n) | Instr::Llc(n) | Instr::Pshl(n) | Instr::Pshi(n) |
+            Instr::Addi(n) | Instr::Subi(n) | Instr::Muli(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    /// Words the instruction pops from and pushes onto the stack, not
+    /// counting the frame that `ENT` and `LEV` make and unwind
+    ///
+    /// Calls leave their arguments for the `ADJ` after them, so a system
+    /// call pops and pushes back its arguments.
+    pub fn stack_effect(&self) -> (usize, usize) {
+        match *self {
+            Instr::Psh | Instr::Pshl(_) | Instr::Pshi(_) => (0, 1),
+            Instr::Si | Instr::Sc | Instr::Or | Instr::Xor | Instr::And | Instr::Eq | Instr::Ne | Instr::Lt
+            | Instr::Gt | Instr::Le | Instr::Ge | Instr::Shl | Instr::Shr | Instr::Add | Instr::Sub
+            | Instr::Mul | Instr::Div | Instr::Mod => (1, 0),
+            Instr::Adj(n) => (n.max(0) as usize, 0),
+            Instr::Prtf(argc) => (argc, argc),
+            Instr::Open => (2, 2),
+            Instr::Read | Instr::Mset | Instr::Mcmp => (3, 3),
+            Instr::Clos | Instr::Malc | Instr::Free => (1, 1),
+            _ => (0, 0),
+        }
+    }
+
+    /// The code words the instruction is encoded as
+    pub fn encode(&self) -> Vec<i64> {
+        let opcode = self.opcode().map_or(0, |op| op as i64);
+        match self.operand() {
+            Some(operand) => vec![opcode, operand],
+            None => vec![opcode],
+        }
+    }
+}
+
+impl fmt::Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.opcode(), self.operand()) {
+            (Some(op), Some(n)) => write!(f, "{} {}", op.to_string(), n),
+            (Some(op), None) => write!(f, "{}", op.to_string()),
+            (None, _) => write!(f, "<operand>"),
+        }
+    }
+}
+
+/// Validate a code segment and decode it
+///
+/// The result has one entry per code word, so code addresses (jump
+/// targets, return addresses, function symbols) index it directly; operand
+/// words decode to [`Instr::Operand`]. Unknown opcodes, missing operands,
+/// jumps that leave the code or land inside an instruction, and `printf`
+/// calls without the `ADJ` that gives their argument count are rejected.
+pub fn decode(code: &[i64]) -> Result<Vec<Instr>, CompilerError> {
+    let error = |message: String| CompilerError::VMError {
+        message,
+        instruction: None,
+        cycle: None,
+        backtrace: Vec::new(),
+    };
+
+    // First pass: find instruction boundaries
+    let mut ops = vec![None; code.len()];
+    let mut pc = 0;
+    while pc < code.len() {
+        let op = Opcode::from_i64(code[pc])
+            .ok_or_else(|| error(format!("Unknown opcode {} at {}", code[pc], pc)))?;
+        if op.has_operand() && pc + 1 >= code.len() {
+            return Err(error(format!("Missing operand for {} at {}", op.to_string(), pc)));
+        }
+        ops[pc] = Some(op);
+        pc += if op.has_operand() { 2 } else { 1 };
+    }
+
+    let target = |pc: usize| -> Result<usize, CompilerError> {
+        let addr = code[pc + 1];
+        if addr < 0 || addr as usize >= code.len() {
+            return Err(error(format!("Jump target out of bounds: {} at {}", addr, pc)));
+        }
+        if ops[addr as usize].is_none() {
+            return Err(error(format!("Jump target {} at {} is not an instruction", addr, pc)));
+        }
+        Ok(addr as usize)
+    };
+
+    // Second pass: build instructions now that every target can be checked
+    let mut program = vec![Instr::Operand; code.len()];
+    for pc in 0..code.len() {
+        let Some(op) = ops[pc] else { continue };
+        let n = code.get(pc + 1).copied().unwrap_or(0);
+        program[pc] = match op {
+            Opcode::LEA => Instr::Lea(n),
+            Opcode::IMM => Instr::Imm(n),
+            Opcode::JMP => Instr::Jmp(target(pc)?),
+            Opcode::JSR => Instr::Jsr(target(pc)?),
+            Opcode::BZ => Instr::Bz(target(pc)?),
+            Opcode::BNZ => Instr::Bnz(target(pc)?),
+            Opcode::ENT => Instr::Ent(n),
+            Opcode::ADJ => Instr::Adj(n),
+            Opcode::LEV => Instr::Lev,
+            Opcode::LI => Instr::Li,
+            Opcode::LC => Instr::Lc,
+            Opcode::SI => Instr::Si,
+            Opcode::SC => Instr::Sc,
+            Opcode::PSH => Instr::Psh,
+            Opcode::OR => Instr::Or,
+            Opcode::XOR => Instr::Xor,
+            Opcode::AND => Instr::And,
+            Opcode::EQ => Instr::Eq,
+            Opcode::NE => Instr::Ne,
+            Opcode::LT => Instr::Lt,
+            Opcode::GT => Instr::Gt,
+            Opcode::LE => Instr::Le,
+            Opcode::GE => Instr::Ge,
+            Opcode::SHL => Instr::Shl,
+            Opcode::SHR => Instr::Shr,
+            Opcode::ADD => Instr::Add,
+            Opcode::SUB => Instr::Sub,
+            Opcode::MUL => Instr::Mul,
+            Opcode::DIV => Instr::Div,
+            Opcode::MOD => Instr::Mod,
+            Opcode::OPEN => Instr::Open,
+            Opcode::READ => Instr::Read,
+            Opcode::CLOS => Instr::Clos,
+            Opcode::PRTF => {
+                // As in C4.c, the argument count comes from the following ADJ
+                match (ops.get(pc + 1), code.get(pc + 2)) {
+                    (Some(Some(Opcode::ADJ)), Some(&count)) if count > 0 => Instr::Prtf(count as usize),
+                    _ => return Err(error(format!("printf at {} must be followed by ADJ", pc))),
+                }
+            },
+            Opcode::MALC => Instr::Malc,
+            Opcode::FREE => Instr::Free,
+            Opcode::MSET => Instr::Mset,
+            Opcode::MCMP => Instr::Mcmp,
+            Opcode::EXIT => Instr::Exit,
+            Opcode::NEG => Instr::Neg,
+            Opcode::LLI => Instr::Lli(n),
+            Opcode::LLC => Instr::Llc(n),
+            Opcode::PSHL => Instr::Pshl(n),
+            Opcode::PSHI => Instr::Pshi(n),
+            Opcode::ADDI => Instr::Addi(n),
+            Opcode::SUBI => Instr::Subi(n),
+            Opcode::MULI => Instr::Muli(n),
+            Opcode::NATV if n >= 0 => Instr::Natv(n as usize),
+            Opcode::NATV => return Err(error(format!("Invalid native function {} at {}", n, pc))),
+            Opcode::TSR => Instr::Tsr(target(pc)?),
+        };
+    }
+
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let code = vec![
+            Opcode::IMM as i64, 7,
+            Opcode::BZ as i64, 5,
+            Opcode::PSH as i64,
+            Opcode::EXIT as i64,
+        ];
+        let program = decode(&code).unwrap();
+        assert_eq!(program, vec![
+            Instr::Imm(7), Instr::Operand, Instr::Bz(5), Instr::Operand, Instr::Psh, Instr::Exit,
+        ]);
+        assert_eq!(program[2].to_string(), "BZ 5");
+    }
+
+    #[test]
+    fn test_decode_rejects_bad_code() {
+        let cases: [&[i64]; 4] = [
+            &[99],
+            &[Opcode::IMM as i64],
+            &[Opcode::JMP as i64, 1],
+            &[Opcode::PRTF as i64, Opcode::EXIT as i64],
+        ];
+        for code in cases {
+            assert!(decode(code).is_err(), "{:?} should be rejected", code);
+        }
+    }
+}
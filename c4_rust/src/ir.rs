@@ -0,0 +1,671 @@
+use crate::debug_info::DebugInfo;
+use crate::error::CompilerError;
+use crate::instr::{self, Instr};
+use crate::object::{ObjectFile, RelocationKind, SymbolKind};
+use crate::parser::Parser;
+use crate::types::TokenType;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Write};
+
+/// Index of a block within its function
+pub type BlockId = usize;
+
+/// What a relocated operand refers to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reloc {
+    /// A data segment address
+    Data,
+    /// The address of a symbol another unit defines, plus the operand
+    Symbol(String),
+}
+
+/// A straight-line instruction
+#[derive(Debug, Clone, PartialEq)]
+pub struct Inst {
+    /// The operation; a `JSR` target is the callee's [`Function::start`]
+    pub instr: Instr,
+    /// Set if the operand has to be relocated
+    pub reloc: Option<Reloc>,
+    /// Code address the instruction was lowered from, if any
+    pub origin: Option<usize>,
+}
+
+impl Inst {
+    /// An instruction with no relocation or origin
+    pub fn new(instr: Instr) -> Self {
+        Inst { instr, reloc: None, origin: None }
+    }
+}
+
+/// How control leaves a block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Terminator {
+    /// Continue at another block
+    Jump(BlockId),
+    /// Continue at `nonzero` if `ax` is non-zero, otherwise at `zero`
+    Branch { zero: BlockId, nonzero: BlockId },
+    /// Return to the caller (`LEV`)
+    Return,
+    /// Leave the frame and continue in the function starting at this
+    /// address, which returns to the caller (`TSR`); the arguments have
+    /// been stored over the parameters
+    TailCall(usize),
+    /// End the program with `ax` as the exit status
+    Exit,
+}
+
+impl Terminator {
+    /// The blocks control can continue at
+    pub fn successors(&self) -> Vec<BlockId> {
+        match *self {
+            Terminator::Jump(target) => vec![target],
+            Terminator::Branch { zero, nonzero } if zero == nonzero => vec![zero],
+            Terminator::Branch { zero, nonzero } => vec![nonzero, zero],
+            Terminator::Return | Terminator::TailCall(_) | Terminator::Exit => Vec::new(),
+        }
+    }
+}
+
+/// A basic block: instructions that run in order, then a terminator
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub insts: Vec<Inst>,
+    pub terminator: Terminator,
+    /// Code address of the terminating instruction, if it had one
+    pub terminator_origin: Option<usize>,
+}
+
+/// A function as a control flow graph
+///
+/// The `ENT` is implicit in `frame`; block 0 is the entry block, and the
+/// order of `blocks` is the order they are laid out in when lowered.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    /// Code address the function was lowered from, which calls refer to
+    pub start: usize,
+    /// Words of locals, the `ENT` operand
+    pub frame: i64,
+    /// Number of parameters, when the debug information records it
+    pub params: Option<usize>,
+    pub blocks: Vec<Block>,
+}
+
+impl Function {
+    /// The blocks each block is reached from
+    pub fn predecessors(&self) -> Vec<Vec<BlockId>> {
+        let mut preds = vec![Vec::new(); self.blocks.len()];
+        for (id, block) in self.blocks.iter().enumerate() {
+            for succ in block.terminator.successors() {
+                if let Some(list) = preds.get_mut(succ) {
+                    list.push(id);
+                }
+            }
+        }
+        preds
+    }
+
+    /// Expression stack depth on entry to each block, `None` for blocks
+    /// that cannot be reached
+    pub fn stack_depths(&self) -> Result<Vec<Option<usize>>, CompilerError> {
+        let mut depths = vec![None; self.blocks.len()];
+        if self.blocks.is_empty() {
+            return Ok(depths);
+        }
+        depths[0] = Some(0);
+        let mut work = vec![0];
+        while let Some(id) = work.pop() {
+            let block = &self.blocks[id];
+            let mut depth = depths[id].unwrap_or(0);
+            for inst in &block.insts {
+                let (pops, pushes) = inst.instr.stack_effect();
+                depth = depth.checked_sub(pops)
+                    .ok_or_else(|| CompilerError::codegen_error(
+                        &format!("{}: expression stack underflow in b{}", self.name, id), inst.origin))?
+                    + pushes;
+            }
+            for succ in block.terminator.successors() {
+                match depths.get(succ).copied() {
+                    None => return Err(CompilerError::codegen_error(
+                        &format!("{}: b{} jumps to missing block b{}", self.name, id, succ),
+                        block.terminator_origin)),
+                    Some(Some(known)) if known != depth => return Err(CompilerError::codegen_error(
+                        &format!("{}: b{} is entered with stack depths {} and {}", self.name, succ, known, depth),
+                        block.terminator_origin)),
+                    Some(Some(_)) => {},
+                    Some(None) => {
+                        depths[succ] = Some(depth);
+                        work.push(succ);
+                    },
+                }
+            }
+        }
+        Ok(depths)
+    }
+}
+
+/// A lowered program: code with the relocations and function addresses
+/// that go with it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lowered {
+    pub code: Vec<i64>,
+    /// Old code address to new, one longer than the old code; an address
+    /// that was dropped maps to the next one that survived
+    pub map: Vec<usize>,
+    /// Operand words that need relocating, in address order
+    pub relocations: Vec<(usize, Reloc)>,
+    /// New address of each function, in program order
+    pub functions: Vec<(String, usize)>,
+}
+
+/// Mid-level intermediate representation of a code segment
+///
+/// Each function is split into basic blocks with explicit successor
+/// edges, so passes can reason about control flow without decoding
+/// absolute addresses. Values still live on the VM's stack and in `ax`
+/// and frame slots; the instructions inside blocks are the VM's own,
+/// minus the ones that transfer control. Lowering back to code lays the
+/// blocks out in order, drops jumps to the next block and re-resolves
+/// every target, so a program that round-trips unchanged comes back word
+/// for word.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub functions: Vec<Function>,
+    /// Length of the code the program was lowered from
+    pub code_len: usize,
+}
+
+impl Program {
+    /// Build the graph of a code segment
+    ///
+    /// `functions` are the name and start address of every function, and
+    /// `relocations` the operand words that need relocating. Every
+    /// function has to start with `ENT`, and control may not leave it
+    /// except by `LEV`, `EXIT` or a call.
+    pub fn from_code(
+        code: &[i64],
+        functions: &[(String, usize)],
+        relocations: &BTreeMap<usize, Reloc>,
+    ) -> Result<Self, CompilerError> {
+        let decoded = instr::decode(code)?;
+        let mut starts: Vec<(usize, &str)> = functions.iter()
+            .map(|(name, start)| (*start, name.as_str()))
+            .collect();
+        starts.sort_unstable();
+        starts.dedup_by_key(|(start, _)| *start);
+        if let Some(&(first, _)) = starts.first() {
+            if first != 0 {
+                return Err(CompilerError::codegen_error("code before the first function", Some(0)));
+            }
+        } else if !code.is_empty() {
+            return Err(CompilerError::codegen_error("code outside any function", Some(0)));
+        }
+        let entries: BTreeSet<usize> = starts.iter().map(|(start, _)| *start).collect();
+
+        let mut program = Program { functions: Vec::new(), code_len: code.len() };
+        for (i, &(start, name)) in starts.iter().enumerate() {
+            let end = starts.get(i + 1).map_or(code.len(), |(next, _)| *next);
+            program.functions.push(lift(&decoded, name, start, end, &entries, relocations)?);
+        }
+        Ok(program)
+    }
+
+    /// Build the graph of the code a parser generated
+    pub fn from_parser(parser: &Parser) -> Result<Self, CompilerError> {
+        let functions: Vec<(String, usize)> = parser.get_symbol_table().iter()
+            .filter(|sym| sym.class == TokenType::Fun && !sym.external)
+            .map(|sym| (sym.name.clone(), sym.value as usize))
+            .collect();
+        let mut relocations: BTreeMap<usize, Reloc> = parser.get_data_relocations().iter()
+            .map(|&pos| (pos, Reloc::Data))
+            .collect();
+        for (pos, name) in parser.get_symbol_relocations() {
+            relocations.insert(*pos, Reloc::Symbol(name.clone()));
+        }
+        let mut program = Self::from_code(parser.get_code(), &functions, &relocations)?;
+        program.count_params(parser.get_debug_info());
+        Ok(program)
+    }
+
+    /// Build the graph of an object file's code
+    pub fn from_object(object: &ObjectFile) -> Result<Self, CompilerError> {
+        let functions: Vec<(String, usize)> = object.symbols.iter()
+            .filter(|sym| sym.kind == SymbolKind::Function)
+            .map(|sym| (sym.name.clone(), sym.value as usize))
+            .collect();
+        let relocations = object.relocations.iter()
+            .filter_map(|reloc| match reloc.kind {
+                RelocationKind::Code => None,
+                RelocationKind::Data => Some((reloc.offset, Reloc::Data)),
+                RelocationKind::Import(index) => object.imports.get(index)
+                    .map(|import| (reloc.offset, Reloc::Symbol(import.name.clone()))),
+            })
+            .collect();
+        let mut program = Self::from_code(&object.code, &functions, &relocations)?;
+        program.count_params(&object.debug);
+        Ok(program)
+    }
+
+    /// Take each function's parameter count from the debug information
+    fn count_params(&mut self, debug: &DebugInfo) {
+        for func in &mut self.functions {
+            func.params = debug.functions.iter()
+                .find(|range| range.start == func.start && range.name == func.name)
+                .map(|range| range.locals.iter().filter(|var| var.offset >= 2).count());
+        }
+    }
+
+    /// Find a function by the address calls refer to it by
+    pub fn function_at(&self, start: usize) -> Option<&Function> {
+        self.functions.iter().find(|func| func.start == start)
+    }
+
+    /// Check that the program is well formed
+    ///
+    /// Blocks must end in valid targets, calls must name functions, frame
+    /// slots must lie in the frame or the arguments, every `PRTF` must be
+    /// followed by its `ADJ`, relocations must be on operands, and each
+    /// block must be entered with the same stack depth on every path.
+    pub fn verify(&self) -> Result<(), CompilerError> {
+        let starts: BTreeSet<usize> = self.functions.iter().map(|func| func.start).collect();
+        for func in &self.functions {
+            let error = |message: String, origin: Option<usize>| {
+                Err(CompilerError::codegen_error(&format!("{}: {}", func.name, message), origin))
+            };
+            if func.blocks.is_empty() {
+                return error("function has no blocks".to_string(), None);
+            }
+            if func.frame < 0 {
+                return error(format!("negative frame size {}", func.frame), None);
+            }
+            for (id, block) in func.blocks.iter().enumerate() {
+                for (i, inst) in block.insts.iter().enumerate() {
+                    match inst.instr {
+                        Instr::Jmp(_) | Instr::Bz(_) | Instr::Bnz(_) | Instr::Ent(_)
+                        | Instr::Lev | Instr::Tsr(_) | Instr::Exit | Instr::Operand => {
+                            return error(format!("{} inside b{}", inst.instr, id), inst.origin);
+                        },
+                        Instr::Jsr(target) if inst.reloc.is_none() && !starts.contains(&target) => {
+                            return error(format!("call to {}, which is not a function", target), inst.origin);
+                        },
+                        Instr::Lea(offset) | Instr::Lli(offset) | Instr::Llc(offset) | Instr::Pshl(offset)
+                            if offset == 0 || offset == 1 || offset < -func.frame => {
+                            return error(format!("frame offset {} outside the frame", offset), inst.origin);
+                        },
+                        Instr::Prtf(argc)
+                            if block.insts.get(i + 1).map(|next| next.instr) != Some(Instr::Adj(argc as i64)) => {
+                            return error(format!("PRTF in b{} is not followed by ADJ {}", id, argc), inst.origin);
+                        },
+                        _ => {},
+                    }
+                    if inst.reloc.is_some() && !matches!(inst.instr,
+                        Instr::Imm(_) | Instr::Pshi(_) | Instr::Addi(_) | Instr::Subi(_) | Instr::Jsr(_)) {
+                        return error(format!("relocated operand on {}", inst.instr), inst.origin);
+                    }
+                }
+                if let Terminator::TailCall(target) = block.terminator {
+                    if !starts.contains(&target) {
+                        return error(format!("tail call to {}, which is not a function", target), block.terminator_origin);
+                    }
+                }
+                for succ in block.terminator.successors() {
+                    if succ >= func.blocks.len() {
+                        return error(format!("b{} jumps to missing block b{}", id, succ), block.terminator_origin);
+                    }
+                }
+            }
+            func.stack_depths()?;
+        }
+        Ok(())
+    }
+
+    /// Lower the program back to code
+    ///
+    /// Blocks are laid out in order, so a jump to the next block is
+    /// dropped and a branch takes whichever form needs no extra jump.
+    pub fn to_code(&self) -> Lowered {
+        // Lay out every block first, so calls and jumps can be resolved
+        let mut addresses: Vec<Vec<usize>> = Vec::new();
+        let mut starts = BTreeMap::new();
+        let mut pc = 0;
+        for func in &self.functions {
+            starts.insert(func.start, pc);
+            pc += 2;
+            let mut blocks = Vec::new();
+            for (id, block) in func.blocks.iter().enumerate() {
+                blocks.push(pc);
+                pc += block.insts.iter().map(|inst| inst.instr.encode().len()).sum::<usize>();
+                pc += terminator_len(&block.terminator, id + 1);
+            }
+            addresses.push(blocks);
+        }
+
+        let mut lowered = Lowered {
+            code: Vec::with_capacity(pc),
+            map: Vec::new(),
+            relocations: Vec::new(),
+            functions: Vec::new(),
+        };
+        let mut map = vec![None; self.code_len + 1];
+        let mut note = |origin: Option<usize>, width: usize, pc: usize| {
+            for old in origin.into_iter().flat_map(|origin| origin..origin + width) {
+                if let Some(slot) = map.get_mut(old) {
+                    slot.get_or_insert(pc);
+                }
+            }
+        };
+
+        for (func, blocks) in self.functions.iter().zip(&addresses) {
+            let start = lowered.code.len();
+            lowered.functions.push((func.name.clone(), start));
+            note(Some(func.start), 2, start);
+            lowered.code.extend(Instr::Ent(func.frame).encode());
+            for (id, block) in func.blocks.iter().enumerate() {
+                for inst in &block.insts {
+                    let instr = match inst.instr {
+                        Instr::Jsr(target) if inst.reloc.is_none() => {
+                            Instr::Jsr(starts.get(&target).copied().unwrap_or(target))
+                        },
+                        instr => instr,
+                    };
+                    let words = instr.encode();
+                    note(inst.origin, words.len(), lowered.code.len());
+                    if let Some(reloc) = &inst.reloc {
+                        lowered.relocations.push((lowered.code.len() + 1, reloc.clone()));
+                    }
+                    lowered.code.extend(words);
+                }
+
+                let pc = lowered.code.len();
+                let next = id + 1;
+                let target = |block: BlockId| blocks.get(block).copied().unwrap_or(0);
+                let instrs = match block.terminator {
+                    Terminator::Jump(to) if to == next => Vec::new(),
+                    Terminator::Jump(to) => vec![Instr::Jmp(target(to))],
+                    Terminator::Branch { zero, nonzero } if zero == nonzero => {
+                        if zero == next { Vec::new() } else { vec![Instr::Jmp(target(zero))] }
+                    },
+                    Terminator::Branch { zero, nonzero } if nonzero == next => vec![Instr::Bz(target(zero))],
+                    Terminator::Branch { zero, nonzero } if zero == next => vec![Instr::Bnz(target(nonzero))],
+                    Terminator::Branch { zero, nonzero } => {
+                        vec![Instr::Bz(target(zero)), Instr::Jmp(target(nonzero))]
+                    },
+                    Terminator::Return => vec![Instr::Lev],
+                    Terminator::TailCall(callee) => vec![Instr::Tsr(starts.get(&callee).copied().unwrap_or(callee))],
+                    Terminator::Exit => vec![Instr::Exit],
+                };
+                if !instrs.is_empty() {
+                    let width = match block.terminator {
+                        Terminator::Return | Terminator::Exit => 1,
+                        _ => 2,
+                    };
+                    note(block.terminator_origin, width, pc);
+                }
+                for instr in instrs {
+                    lowered.code.extend(instr.encode());
+                }
+            }
+        }
+
+        // Anything that vanished maps to whatever took its place
+        let mut next = lowered.code.len();
+        map[self.code_len] = Some(next);
+        lowered.map = vec![0; self.code_len + 1];
+        for old in (0..=self.code_len).rev() {
+            next = map[old].unwrap_or(next);
+            lowered.map[old] = next;
+        }
+        lowered
+    }
+
+    /// Render the control flow graph in Graphviz DOT format, one cluster
+    /// per function
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph cfg {\n    node [shape=box, fontname=monospace];\n");
+        for (index, func) in self.functions.iter().enumerate() {
+            let _ = writeln!(out, "    subgraph cluster_{} {{", index);
+            let _ = writeln!(out, "        label=\"{} (frame {})\";", escape(&func.name), func.frame);
+            for (id, block) in func.blocks.iter().enumerate() {
+                let mut label = format!("b{}:\\l", id);
+                for inst in &block.insts {
+                    let _ = write!(label, "{}\\l", escape(&self.inst_text(inst)));
+                }
+                let _ = write!(label, "{}\\l", escape(&self.terminator_text(&block.terminator)));
+                let _ = writeln!(out, "        f{}_b{} [label=\"{}\"];", index, id, label);
+            }
+            for (id, block) in func.blocks.iter().enumerate() {
+                match block.terminator {
+                    Terminator::Jump(to) => {
+                        let _ = writeln!(out, "        f{}_b{} -> f{}_b{};", index, id, index, to);
+                    },
+                    Terminator::Branch { zero, nonzero } => {
+                        let _ = writeln!(out, "        f{}_b{} -> f{}_b{} [label=\"nonzero\"];", index, id, index, nonzero);
+                        let _ = writeln!(out, "        f{}_b{} -> f{}_b{} [label=\"zero\"];", index, id, index, zero);
+                    },
+                    Terminator::Return | Terminator::TailCall(_) | Terminator::Exit => {},
+                }
+            }
+            out.push_str("    }\n");
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// An instruction with calls shown by name and relocated operands by
+    /// what they refer to
+    fn inst_text(&self, inst: &Inst) -> String {
+        let mnemonic = inst.instr.opcode().map_or("", |op| op.to_string());
+        match (&inst.reloc, inst.instr) {
+            (Some(Reloc::Symbol(name)), _) => format!("{} {}", mnemonic, name),
+            (Some(Reloc::Data), instr) => format!("{} .D{}", mnemonic, instr.operand().unwrap_or(0)),
+            (None, Instr::Jsr(target)) => match self.function_at(target) {
+                Some(callee) => format!("{} {}", mnemonic, callee.name),
+                None => inst.instr.to_string(),
+            },
+            _ => inst.instr.to_string(),
+        }
+    }
+
+    fn terminator_text(&self, terminator: &Terminator) -> String {
+        match *terminator {
+            Terminator::Jump(to) => format!("jump b{}", to),
+            Terminator::Branch { zero, nonzero } => format!("if ax goto b{} else b{}", nonzero, zero),
+            Terminator::Return => "return".to_string(),
+            Terminator::TailCall(callee) => match self.function_at(callee) {
+                Some(callee) => format!("tail call {}", callee.name),
+                None => format!("tail call {}", callee),
+            },
+            Terminator::Exit => "exit".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, func) in self.functions.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            writeln!(f, "function {} (frame {}):", func.name, func.frame)?;
+            let preds = func.predecessors();
+            for (id, block) in func.blocks.iter().enumerate() {
+                if preds[id].is_empty() {
+                    writeln!(f, "b{}:", id)?;
+                } else {
+                    let list: Vec<String> = preds[id].iter().map(|pred| format!("b{}", pred)).collect();
+                    writeln!(f, "b{}:  ; preds {}", id, list.join(", "))?;
+                }
+                for inst in &block.insts {
+                    writeln!(f, "    {}", self.inst_text(inst))?;
+                }
+                writeln!(f, "    {}", self.terminator_text(&block.terminator))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Split the function at `start..end` into blocks
+fn lift(
+    decoded: &[Instr],
+    name: &str,
+    start: usize,
+    end: usize,
+    entries: &BTreeSet<usize>,
+    relocations: &BTreeMap<usize, Reloc>,
+) -> Result<Function, CompilerError> {
+    let error = |message: String, pc: usize| {
+        Err(CompilerError::codegen_error(&format!("{}: {}", name, message), Some(pc)))
+    };
+    let Instr::Ent(frame) = decoded[start] else {
+        return error("function does not start with ENT".to_string(), start);
+    };
+    let body = start + 2;
+
+    // Blocks start after the ENT, at jump targets, and after terminators
+    let mut leaders = BTreeSet::from([body]);
+    let mut pc = body;
+    while pc < end {
+        let instr = decoded[pc];
+        let width = instr.encode().len();
+        match instr {
+            Instr::Jmp(target) | Instr::Bz(target) | Instr::Bnz(target) => {
+                if target < body || target >= end {
+                    return error(format!("jump to {} leaves the function", target), pc);
+                }
+                leaders.insert(target);
+                leaders.insert(pc + width);
+            },
+            Instr::Jsr(target) if !relocations.contains_key(&(pc + 1)) && !entries.contains(&target) => {
+                return error(format!("call to {}, which is not a function", target), pc);
+            },
+            Instr::Tsr(target) if relocations.contains_key(&(pc + 1)) || !entries.contains(&target) => {
+                return error(format!("tail call to {}, which is not a function", target), pc);
+            },
+            Instr::Lev | Instr::Tsr(_) | Instr::Exit => {
+                leaders.insert(pc + width);
+            },
+            Instr::Ent(_) => return error("ENT inside a function".to_string(), pc),
+            _ => {},
+        }
+        pc += width;
+    }
+    leaders.retain(|&leader| leader < end);
+    if body >= end {
+        return error("function has no body".to_string(), start);
+    }
+
+    let ids: BTreeMap<usize, BlockId> = leaders.iter().enumerate().map(|(id, &pc)| (pc, id)).collect();
+    let leaders: Vec<usize> = leaders.into_iter().collect();
+    let mut blocks = Vec::new();
+    for (id, &leader) in leaders.iter().enumerate() {
+        let block_end = leaders.get(id + 1).copied().unwrap_or(end);
+        let mut insts = Vec::new();
+        let mut terminator = None;
+        let mut pc = leader;
+        while pc < block_end {
+            let instr = decoded[pc];
+            let width = instr.encode().len();
+            let next = pc + width;
+            let fallthrough = || ids.get(&next).copied();
+            let ended = match instr {
+                Instr::Jmp(target) => Some(Terminator::Jump(ids[&target])),
+                Instr::Bz(target) => fallthrough().map(|next| Terminator::Branch { zero: ids[&target], nonzero: next }),
+                Instr::Bnz(target) => fallthrough().map(|next| Terminator::Branch { zero: next, nonzero: ids[&target] }),
+                Instr::Lev => Some(Terminator::Return),
+                Instr::Tsr(target) => Some(Terminator::TailCall(target)),
+                Instr::Exit => Some(Terminator::Exit),
+                _ => None,
+            };
+            if matches!(instr, Instr::Bz(_) | Instr::Bnz(_)) && ended.is_none() {
+                return error("branch falls off the end of the function".to_string(), pc);
+            }
+            if let Some(ended) = ended {
+                terminator = Some((ended, Some(pc)));
+                break;
+            }
+            insts.push(Inst { instr, reloc: relocations.get(&(pc + 1)).cloned(), origin: Some(pc) });
+            pc = next;
+        }
+        let (terminator, terminator_origin) = match terminator {
+            Some(terminator) => terminator,
+            None => match ids.get(&block_end) {
+                Some(&next) => (Terminator::Jump(next), None),
+                None => return error("control falls off the end of the function".to_string(), block_end),
+            },
+        };
+        blocks.push(Block { insts, terminator, terminator_origin });
+    }
+
+    Ok(Function { name: name.to_string(), start, frame, params: None, blocks })
+}
+
+/// Words a terminator lowers to when `next` is laid out after its block
+fn terminator_len(terminator: &Terminator, next: BlockId) -> usize {
+    match *terminator {
+        Terminator::Jump(to) => if to == next { 0 } else { 2 },
+        Terminator::Branch { zero, nonzero } if zero == nonzero => if zero == next { 0 } else { 2 },
+        Terminator::Branch { zero, nonzero } => if zero == next || nonzero == next { 2 } else { 4 },
+        Terminator::TailCall(_) => 2,
+        Terminator::Return | Terminator::Exit => 1,
+    }
+}
+
+/// Escape a string for a DOT label
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(source: &str) -> (Parser, Program) {
+        let mut parser = Parser::new(source.to_string(), false);
+        parser.init().unwrap();
+        parser.parse().unwrap();
+        parser.optimize();
+        parser.fuse();
+        let program = Program::from_parser(&parser).unwrap();
+        (parser, program)
+    }
+
+    #[test]
+    fn test_round_trip_is_identity() {
+        let (parser, program) = program(
+            "int f(int n) { int i; i = 0; while (i < n) { if (i & 1) printf(\"%d\\n\", i); i = i + 1; } return i; }
+             int main() { return f(4) + f(2); }");
+        program.verify().unwrap();
+        let lowered = program.to_code();
+        assert_eq!(lowered.code, parser.get_code());
+        for (pc, instr) in instr::decode(parser.get_code()).unwrap().iter().enumerate() {
+            if *instr != Instr::Operand {
+                assert_eq!(lowered.map[pc], pc);
+            }
+        }
+        assert_eq!(program.functions.len(), 2);
+        assert!(program.functions[0].blocks.len() >= 4);
+    }
+
+    #[test]
+    fn test_verify_rejects_bad_graphs() {
+        let (_, mut program) = program("int main() { int x; x = 1; if (x) x = 2; return x; }");
+        program.verify().unwrap();
+
+        let mut bad = program.clone();
+        bad.functions[0].blocks[0].terminator = Terminator::Jump(99);
+        assert!(bad.verify().is_err());
+
+        let mut bad = program.clone();
+        bad.functions[0].blocks[1].insts.push(Inst::new(Instr::Psh));
+        let err = bad.verify().unwrap_err().to_string();
+        assert!(err.contains("stack depths"), "{}", err);
+
+        bad = program.clone();
+        bad.functions[0].blocks[0].insts.push(Inst::new(Instr::Lea(-5)));
+        assert!(bad.verify().is_err());
+
+        program.functions[0].blocks[0].insts.insert(0, Inst::new(Instr::Jsr(1234)));
+        assert!(program.verify().unwrap_err().to_string().contains("not a function"));
+    }
+}
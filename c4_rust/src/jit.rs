@@ -0,0 +1,484 @@
+use crate::encoder::{Encoder, Label, Lowering};
+use crate::error::{CompilerError, ResourceLimit};
+use crate::host::{Host, OsHost, STDOUT};
+use crate::instr::Instr;
+use crate::object::ObjectFile;
+use crate::types::Opcode;
+use crate::vm::{self, VmLimits, DATA_LIMIT};
+use std::ffi::CStr;
+
+/// Stack kept free below the program's frames for the trampolines
+const TRAMPOLINE_STACK: usize = 256 << 10;
+
+/// Why a program stopped before returning, as stored in [`Context::stop`]
+const STOP_NONE: u64 = 0;
+const STOP_FAILED: u64 = 1;
+const STOP_STACK: u64 = 2;
+const STOP_DIVISION: u64 = 3;
+const STOP_MODULO: u64 = 4;
+
+/// Returns from the program to Rust, with the exit code in `%rax`
+const RETURN: Label = Label::Stub("return");
+
+/// A C4 program translated to x86-64 machine code and run in-process
+///
+/// Every function is translated up front, much as the ELF64 backend
+/// ([`crate::elf`]) does, into an executable buffer, and data relocations
+/// are resolved against a buffer holding the data segment and heap. The
+/// program runs on a stack of its own; its system calls go through
+/// trampolines into Rust that use a [`Host`] and format `printf` exactly as
+/// the VM does.
+///
+/// Compiling fails for programs the JIT cannot run like the VM: those that
+/// call native functions, still need linking, or are held to cycle or time
+/// limits, and any program off x86-64 Linux. Callers are expected to fall
+/// back to the interpreter then. Heap, stack and output limits are kept,
+/// and division by zero stops the program as it does on the VM, but other
+/// bad memory accesses are not checked: they crash the process.
+pub struct Jit {
+    code: Mapping,
+    data: Mapping,
+    data_len: usize,
+    limits: VmLimits,
+    host: Box<dyn Host>,
+}
+
+impl Jit {
+    /// Translate a linked program
+    pub fn compile(program: &ObjectFile, limits: VmLimits) -> Result<Jit, CompilerError> {
+        if let Some(import) = program.imports.first() {
+            return Err(CompilerError::codegen_error(&format!("'{}' is not defined (link the program first)", import.name), None));
+        }
+        if limits.max_cycles.is_some() || limits.timeout.is_some() {
+            return Err(CompilerError::codegen_error("cycle and time limits need the interpreter", None));
+        }
+        let entry = program.entry
+            .ok_or_else(|| CompilerError::codegen_error("main() not defined", None))?;
+
+        let mut data = Mapping::new(DATA_LIMIT)?;
+        data.bytes()[..program.data.len()].copy_from_slice(&program.data);
+
+        let mut encoder = Encoder::default();
+        enter(&mut encoder, Label::Code(0, entry));
+        stops(&mut encoder);
+        encoder.unit(0, program, 0, &mut Trampolines)?;
+        let text = encoder.resolve(data.address());
+
+        let mut code = Mapping::new(text.len())?;
+        code.bytes().copy_from_slice(&text);
+        code.make_executable()?;
+
+        Ok(Jit { code, data, data_len: program.data.len(), limits, host: Box::new(OsHost::new()) })
+    }
+
+    /// Route the program's system calls to `host` instead of the real
+    /// operating system
+    pub fn set_host(&mut self, host: Box<dyn Host>) {
+        self.host = host;
+    }
+
+    /// Run the program with `args` as its `argv`, returning its exit code
+    pub fn run(mut self, args: &[String]) -> Result<i64, CompilerError> {
+        // argv strings and the pointer array go after the data, as on the VM
+        let base = self.data.address();
+        let memory = self.data.bytes();
+        let mut len = self.data_len;
+        let mut argv = Vec::with_capacity(args.len() + 1);
+        for arg in args {
+            argv.push(base + len as u64);
+            memory[len..len + arg.len()].copy_from_slice(arg.as_bytes());
+            len += arg.len() + 1;
+        }
+        argv.push(0);
+        let argv_address = base + len.next_multiple_of(8) as u64;
+        len = len.next_multiple_of(8);
+        for ptr in argv {
+            memory[len..len + 8].copy_from_slice(&ptr.to_le_bytes());
+            len += 8;
+        }
+
+        // main's arguments are pushed on the program's stack
+        let mut stack = Mapping::new(TRAMPOLINE_STACK + self.limits.stack_size * 8)?;
+        let top = stack.address() + stack.bytes().len() as u64;
+        let stack_words = stack.bytes().len() / 8;
+        let words = stack.bytes().as_mut_ptr() as *mut u64;
+        // SAFETY: the mapping is page aligned and at least two words long
+        unsafe {
+            *words.add(stack_words - 1) = args.len() as u64;
+            *words.add(stack_words - 2) = argv_address;
+        }
+
+        let mut context = Context {
+            saved_rsp: 0,
+            stack_limit: stack.address() + TRAMPOLINE_STACK as u64,
+            stop: STOP_NONE,
+            host: self.host,
+            error: None,
+            data: base,
+            heap_start: len,
+            heap_next: len,
+            limits: self.limits,
+            output_bytes: 0,
+        };
+
+        // SAFETY: the code starts with the entry stub, which takes the
+        // context and the stack pointer as a C function would
+        let entry: extern "C" fn(*mut Context, u64) -> i64 = unsafe { std::mem::transmute(self.code.bytes().as_ptr()) };
+        let exit_code = entry(&mut context, top - 16);
+
+        match context.stop {
+            STOP_FAILED => Err(context.error.take().unwrap_or_else(|| CompilerError::vm_error("system call failed", None, None))),
+            STOP_STACK => Err(limit_error(ResourceLimit::Stack(self.limits.stack_size))),
+            STOP_DIVISION => Err(CompilerError::vm_error("Division by zero", None, None)),
+            STOP_MODULO => Err(CompilerError::vm_error("Division by zero in modulo", None, None)),
+            _ => {
+                context.host.exit(exit_code);
+                Ok(exit_code)
+            },
+        }
+    }
+}
+
+/// What the machine code and the trampolines share
+///
+/// The machine code keeps a pointer to it in `%r12` and uses the first
+/// three fields, at fixed offsets.
+#[repr(C)]
+struct Context {
+    /// `%rsp` of the Rust caller, restored on the way out
+    saved_rsp: u64,
+    /// Lowest `%rsp` a new frame may have
+    stack_limit: u64,
+    /// One of the `STOP_` codes
+    stop: u64,
+    host: Box<dyn Host>,
+    /// The error behind `STOP_FAILED`
+    error: Option<CompilerError>,
+    /// Address of the data segment
+    data: u64,
+    /// Data segment size when the program started
+    heap_start: usize,
+    /// Where the next allocation goes, from the start of the data segment
+    heap_next: usize,
+    limits: VmLimits,
+    output_bytes: usize,
+}
+
+impl Context {
+    /// Carry out a system call; `args[n]` is the argument pushed `n`th from
+    /// last, as `arg(n)` is on the VM
+    fn system_call(&mut self, opcode: Option<Opcode>, args: &[i64]) -> Result<i64, CompilerError> {
+        match opcode {
+            Some(Opcode::OPEN) => {
+                let path = read_cstring(args[1])?;
+                let path = std::str::from_utf8(&path)
+                    .map_err(|_| CompilerError::vm_error("Invalid path string", None, None))?;
+                Ok(self.host.open(path, args[0]).unwrap_or(-1))
+            },
+            Some(Opcode::READ) => {
+                let count = (args[0].max(0) as usize).min(DATA_LIMIT);
+                let mut bytes = vec![0; count];
+                match self.host.read(args[2], &mut bytes) {
+                    Ok(n) => {
+                        memory(args[1], n)?.copy_from_slice(&bytes[..n]);
+                        Ok(n as i64)
+                    },
+                    Err(_) => Ok(-1),
+                }
+            },
+            Some(Opcode::CLOS) => Ok(if self.host.close(args[0]).is_ok() { 0 } else { -1 }),
+            Some(Opcode::PRTF) => {
+                // The format string is the first (deepest) argument
+                let fmt = read_cstring(args[args.len() - 1])?;
+                let rest: Vec<i64> = args.iter().rev().skip(1).copied().collect();
+                let out = vm::format(&fmt, &rest, read_cstring)?;
+                self.output_bytes += out.len();
+                if let Some(max) = self.limits.max_output_bytes.filter(|&max| self.output_bytes > max) {
+                    return Err(limit_error(ResourceLimit::Output(max)));
+                }
+                self.host.write(STDOUT, &out).map_err(CompilerError::IOError)?;
+                Ok(out.len() as i64)
+            },
+            Some(Opcode::MALC) => {
+                // Memory is never reused, so it is still zeroed from mmap
+                let addr = self.heap_next.next_multiple_of(8);
+                let size = args[0];
+                if size < 0 || addr + size as usize > DATA_LIMIT {
+                    return Ok(0);
+                }
+                let end = addr + size as usize;
+                if let Some(max) = self.limits.max_heap_bytes.filter(|&max| end - self.heap_start > max) {
+                    return Err(limit_error(ResourceLimit::Heap(max)));
+                }
+                self.heap_next = end;
+                Ok((self.data + addr as u64) as i64)
+            },
+            Some(Opcode::FREE) => Ok(args[0]),
+            Some(Opcode::MSET) => {
+                memory(args[2], args[0].max(0) as usize)?.fill(args[1] as u8);
+                Ok(args[2])
+            },
+            Some(Opcode::MCMP) => {
+                let count = args[0].max(0) as usize;
+                let (a, b) = (memory(args[2], count)?, memory(args[1], count)?);
+                Ok(a.iter().zip(b.iter())
+                    .find(|(a, b)| a != b)
+                    .map_or(0, |(&a, &b)| a as i64 - b as i64))
+            },
+            _ => Err(CompilerError::vm_error("Unknown system call", None, None)),
+        }
+    }
+}
+
+/// Entry point for system calls from the machine code
+///
+/// `sp` points at the last argument pushed. Errors are left in the context
+/// for the machine code to notice.
+extern "C" fn system_call(context: *mut Context, opcode: i64, sp: *const i64, argc: i64) -> i64 {
+    // SAFETY: the machine code passes the context it was entered with and
+    // its own stack pointer, below which `argc` arguments were pushed
+    let (context, args) = unsafe { (&mut *context, std::slice::from_raw_parts(sp, argc as usize)) };
+    match context.system_call(Opcode::from_i64(opcode), args) {
+        Ok(value) => value,
+        Err(err) => {
+            context.error = Some(err);
+            context.stop = STOP_FAILED;
+            0
+        },
+    }
+}
+
+/// `len` bytes of program memory at `addr`
+///
+/// Only null pointers are caught; like the rest of the program, other bad
+/// pointers crash.
+fn memory<'a>(addr: i64, len: usize) -> Result<&'a mut [u8], CompilerError> {
+    if addr == 0 && len > 0 {
+        return Err(CompilerError::vm_error("Memory access out of bounds: 0", None, None));
+    }
+    // SAFETY: the program owns the memory its pointers point at
+    Ok(unsafe { std::slice::from_raw_parts_mut(addr as *mut u8, len) })
+}
+
+/// Read a NUL-terminated string from program memory
+fn read_cstring(addr: i64) -> Result<Vec<u8>, CompilerError> {
+    if addr == 0 {
+        return Err(CompilerError::vm_error("Memory access out of bounds: 0", None, None));
+    }
+    // SAFETY: as for `memory`
+    Ok(unsafe { CStr::from_ptr(addr as *const std::ffi::c_char) }.to_bytes().to_vec())
+}
+
+fn limit_error(limit: ResourceLimit) -> CompilerError {
+    CompilerError::LimitExceeded { limit, instruction: None, cycle: None, backtrace: Vec::new() }
+}
+
+/// System calls go through [`system_call`]; the program is checked for
+/// stack overflow and division by zero
+struct Trampolines;
+
+impl Lowering for Trampolines {
+    fn system_call(&mut self, encoder: &mut Encoder, _pc: usize, instr: Instr) -> Result<(), CompilerError> {
+        let (opcode, argc) = match instr {
+            Instr::Open => (Opcode::OPEN, 2),
+            Instr::Read => (Opcode::READ, 3),
+            Instr::Clos => (Opcode::CLOS, 1),
+            Instr::Prtf(argc) => (Opcode::PRTF, argc as i32),
+            Instr::Malc => (Opcode::MALC, 1),
+            Instr::Free => (Opcode::FREE, 1),
+            Instr::Mset => (Opcode::MSET, 3),
+            Instr::Mcmp => (Opcode::MCMP, 3),
+            _ => {
+                encoder.branch(&[0xe9], RETURN);                // jmp return
+                return Ok(());
+            },
+        };
+        encoder.bytes(&[0x4c, 0x89, 0xe7]);                     // mov %r12, %rdi
+        encoder.bytes(&[0xbe]);                                 // mov $opcode, %esi
+        encoder.imm32(opcode as i32);
+        encoder.bytes(&[0x48, 0x89, 0xe2]);                     // mov %rsp, %rdx
+        encoder.bytes(&[0xb9]);                                 // mov $argc, %ecx
+        encoder.imm32(argc);
+        encoder.bytes(&[0x48, 0x89, 0xe3]);                     // mov %rsp, %rbx
+        encoder.bytes(&[0x48, 0x83, 0xe4, 0xf0]);               // and $-16, %rsp
+        encoder.bytes(&[0x48, 0xb8]);                           // movabs $system_call, %rax
+        let trampoline: extern "C" fn(*mut Context, i64, *const i64, i64) -> i64 = system_call;
+        encoder.bytes(&(trampoline as usize as u64).to_le_bytes());
+        encoder.bytes(&[0xff, 0xd0]);                           // call *%rax
+        encoder.bytes(&[0x48, 0x89, 0xdc]);                     // mov %rbx, %rsp
+        encoder.bytes(&[0x49, 0x83, 0x7c, 0x24, 0x10, 0x00]);   // cmpq $0, 16(%r12)
+        encoder.branch(&[0x0f, 0x85], RETURN);                  // jne return
+        Ok(())
+    }
+
+    fn frame_entered(&mut self, encoder: &mut Encoder) {
+        encoder.bytes(&[0x49, 0x3b, 0x64, 0x24, 0x08]);         // cmp 8(%r12), %rsp
+        encoder.branch(&[0x0f, 0x82], Label::Stub("stack"));    // jb stack
+    }
+
+    fn dividing(&mut self, encoder: &mut Encoder, modulo: bool) {
+        let stop = if modulo { Label::Stub("modulo") } else { Label::Stub("division") };
+        encoder.bytes(&[0x48, 0x85, 0xc9]);                     // test %rcx, %rcx
+        encoder.branch(&[0x0f, 0x84], stop);                    // je stop
+        // Dividing by -1 becomes dividing the negation by 1, which cannot
+        // overflow
+        encoder.bytes(&[0x48, 0x83, 0xf9, 0xff, 0x75, 0x06]);   // cmp $-1, %rcx; jne 1f
+        encoder.bytes(&[0x48, 0xf7, 0xd9, 0x48, 0xf7, 0xd8]);   // neg %rcx; neg %rax; 1:
+    }
+}
+
+/// The entry stub, `extern "C" fn(*mut Context, u64) -> i64`: save the
+/// caller's registers, switch to the program's stack and call `main`, then
+/// fall into `return`, which undoes it all
+fn enter(encoder: &mut Encoder, main: Label) {
+    encoder.bytes(&[0x53, 0x55, 0x41, 0x54]);               // push %rbx; push %rbp; push %r12
+    encoder.bytes(&[0x41, 0x55, 0x41, 0x56, 0x41, 0x57]);   // push %r13; push %r14; push %r15
+    encoder.bytes(&[0x49, 0x89, 0xfc]);                     // mov %rdi, %r12
+    encoder.bytes(&[0x49, 0x89, 0x24, 0x24]);               // mov %rsp, (%r12)
+    encoder.bytes(&[0x48, 0x89, 0xf4]);                     // mov %rsi, %rsp
+    encoder.branch(&[0xe8], main);                          // call main
+    encoder.place(RETURN);
+    encoder.bytes(&[0x49, 0x8b, 0x24, 0x24]);               // mov (%r12), %rsp
+    encoder.bytes(&[0x41, 0x5f, 0x41, 0x5e, 0x41, 0x5d]);   // pop %r15; pop %r14; pop %r13
+    encoder.bytes(&[0x41, 0x5c, 0x5d, 0x5b, 0xc3]);         // pop %r12; pop %rbp; pop %rbx; ret
+}
+
+/// Stubs that record why the program stopped and return
+fn stops(encoder: &mut Encoder) {
+    for (label, code) in [("stack", STOP_STACK), ("division", STOP_DIVISION), ("modulo", STOP_MODULO)] {
+        encoder.place(Label::Stub(label));
+        encoder.bytes(&[0x49, 0xc7, 0x44, 0x24, 0x10]);     // movq $code, 16(%r12)
+        encoder.imm32(code as i32);
+        encoder.branch(&[0xe9], RETURN);                    // jmp return
+    }
+}
+
+/// Anonymous memory mapped for the program
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod sys {
+    pub const PROT_READ: i32 = 1;
+    pub const PROT_WRITE: i32 = 2;
+    pub const PROT_EXEC: i32 = 4;
+    pub const MAP_PRIVATE: i32 = 0x02;
+    pub const MAP_ANONYMOUS: i32 = 0x20;
+    pub const MAP_NORESERVE: i32 = 0x4000;
+
+    extern "C" {
+        pub fn mmap(addr: *mut u8, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut u8;
+        pub fn mprotect(addr: *mut u8, len: usize, prot: i32) -> i32;
+        pub fn munmap(addr: *mut u8, len: usize) -> i32;
+    }
+}
+
+impl Mapping {
+    /// Map `len` bytes of zeroed, writable memory, which is only backed by
+    /// real memory once used
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn new(len: usize) -> Result<Mapping, CompilerError> {
+        let len = len.max(1);
+        // SAFETY: a fresh anonymous mapping aliases nothing
+        let ptr = unsafe {
+            sys::mmap(
+                std::ptr::null_mut(),
+                len,
+                sys::PROT_READ | sys::PROT_WRITE,
+                sys::MAP_PRIVATE | sys::MAP_ANONYMOUS | sys::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        if ptr as isize == -1 {
+            return Err(CompilerError::IOError(std::io::Error::last_os_error()));
+        }
+        Ok(Mapping { ptr, len })
+    }
+
+    #[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+    fn new(_len: usize) -> Result<Mapping, CompilerError> {
+        Err(CompilerError::codegen_error("the JIT needs x86-64 Linux", None))
+    }
+
+    /// Make the memory read-only and executable
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn make_executable(&mut self) -> Result<(), CompilerError> {
+        // SAFETY: the mapping is ours and nothing holds a reference into it
+        if unsafe { sys::mprotect(self.ptr, self.len, sys::PROT_READ | sys::PROT_EXEC) } != 0 {
+            return Err(CompilerError::IOError(std::io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    #[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+    fn make_executable(&mut self) -> Result<(), CompilerError> {
+        unreachable!("no mappings off x86-64 Linux")
+    }
+
+    fn address(&self) -> u64 {
+        self.ptr as u64
+    }
+
+    fn bytes(&mut self) -> &mut [u8] {
+        // SAFETY: the mapping is `len` bytes long and lives as long as self
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: the mapping is ours and is not used after this
+        #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+        unsafe {
+            sys::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux", target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use crate::host::MemoryHost;
+    use crate::parser::Parser;
+
+    fn compile(source: &str, limits: VmLimits) -> Result<(Jit, MemoryHost), CompilerError> {
+        let mut parser = Parser::new(source.to_string(), false);
+        parser.init()?;
+        parser.parse()?;
+        let mut jit = Jit::compile(&ObjectFile::from_parser(&parser)?, limits)?;
+        let host = MemoryHost::new();
+        jit.set_host(Box::new(host.clone()));
+        Ok((jit, host))
+    }
+
+    #[test]
+    fn test_run() {
+        let (jit, host) = compile(r#"
+            int main(int argc, char **argv) { printf("%s %d\n", argv[0], argc); return 7; }
+        "#, VmLimits::default()).unwrap();
+        assert_eq!(jit.run(&["prog".to_string()]).unwrap(), 7);
+        assert_eq!(host.stdout(), b"prog 1\n");
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let (jit, _) = compile("int main() { int z; z = 0; return 1 / z; }", VmLimits::default()).unwrap();
+        assert!(jit.run(&[]).unwrap_err().to_string().contains("Division by zero"));
+
+        let limits = VmLimits { stack_size: 1024, ..VmLimits::default() };
+        let (jit, _) = compile("int f(int n) { return f(n + 1); } int main() { return f(0); }", limits).unwrap();
+        assert!(matches!(
+            jit.run(&[]).unwrap_err(),
+            CompilerError::LimitExceeded { limit: ResourceLimit::Stack(1024), .. }
+        ));
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let limits = VmLimits { max_cycles: Some(1000), ..VmLimits::default() };
+        assert!(compile("int main() { return 0; }", limits).is_err());
+    }
+}
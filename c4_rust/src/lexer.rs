@@ -1,5 +1,5 @@
 use crate::types::TokenType;
-use crate::error::CompilerError;
+use crate::error::{CompilerError, SourceLocation};
 use std::collections::HashMap;
 
 /// Represents the current token with its metadata
@@ -17,7 +17,7 @@ pub struct Token {
 pub struct Lexer {
     /// Source code
     source: String,
-    /// Current position in source
+    /// Current byte offset in source
     position: usize,
     /// Line start position (for error reporting)
     line_position: usize,
@@ -25,6 +25,8 @@ pub struct Lexer {
     line: usize,
     /// Current column position
     column: usize,
+    /// Where the current token starts
+    token_location: SourceLocation,
     
     /// Current token information
     current: Token,
@@ -56,6 +58,7 @@ impl Lexer {
             line_position: 0,
             line: 1,
             column: 1,
+            token_location: SourceLocation::new(1, 1),
             current: Token {
                 token_type: TokenType::Eof,
                 value: None,
@@ -84,22 +87,23 @@ impl Lexer {
         self.keywords.insert("sizeof".to_string(), TokenType::Sizeof);
         self.keywords.insert("while".to_string(), TokenType::While);
         self.keywords.insert("void".to_string(), TokenType::Void);
+        self.keywords.insert("extern".to_string(), TokenType::Extern);
     }
     
     /// Get the current character or None if at end of source
     fn current_char(&self) -> Option<char> {
-        self.source.chars().nth(self.position)
+        self.source.get(self.position..)?.chars().next()
     }
     
     /// Peek at the next character without advancing
     fn peek_char(&self) -> Option<char> {
-        self.source.chars().nth(self.position + 1)
+        self.source.get(self.position..)?.chars().nth(1)
     }
     
     /// Advance to the next character
     fn advance(&mut self) -> Option<char> {
         let current = self.current_char();
-        self.position += 1;
+        self.position += current.map_or(1, char::len_utf8);
         self.column += 1;
         
         // Reset column on newline
@@ -126,6 +130,7 @@ impl Lexer {
         }
         
         // Process the next token based on the current character
+        self.token_location = SourceLocation::new(self.line, self.column);
         let ch = self.current_char().unwrap();
         
         let token = match ch {
@@ -138,6 +143,10 @@ impl Lexer {
                     // Line comment
                     self.skip_line_comment()?;
                     return self.next_token(); // Recursively get the next token
+                } else if let Some('*') = self.current_char() {
+                    // Block comment
+                    self.skip_block_comment()?;
+                    return self.next_token();
                 } else {
                     Token {
                         token_type: TokenType::Div,
@@ -208,10 +217,11 @@ impl Lexer {
                         name: None,
                     }
                 } else {
-                    // We'll use Tilde for logical NOT (like C4.c)
+                    // We'll use Tilde for logical NOT (like C4.c); the value
+                    // tells the parser which of '!' and '~' was written
                     Token {
                         token_type: TokenType::Tilde,
-                        value: None,
+                        value: Some('!' as i64),
                         name: None,
                     }
                 }
@@ -476,6 +486,30 @@ impl Lexer {
         Ok(())
     }
     
+    /// Skip a block comment, keeping line numbers in sync
+    fn skip_block_comment(&mut self) -> Result<(), CompilerError> {
+        self.advance(); // Skip the '*'
+        
+        while let Some(ch) = self.current_char() {
+            if ch == '*' && self.peek_char() == Some('/') {
+                self.advance();
+                self.advance();
+                return Ok(());
+            }
+            if ch == '\n' {
+                self.line += 1;
+                self.line_position = self.position + 1;
+            }
+            self.advance();
+        }
+        
+        Err(CompilerError::LexerError {
+            message: format!("Unterminated block comment at line {}", self.line),
+            location: None,
+            source_line: None,
+        })
+    }
+    
     /// Read an identifier or keyword
     fn read_identifier(&mut self) -> Result<Token, CompilerError> {
         let start_pos = self.position;
@@ -527,7 +561,7 @@ impl Lexer {
                     
                     let hex_start = self.position;
                     while let Some(ch) = self.current_char() {
-                        if ch.is_digit(16) {
+                        if ch.is_ascii_hexdigit() {
                             self.advance();
                         } else {
                             break;
@@ -556,12 +590,12 @@ impl Lexer {
                         value: Some(value),
                         name: None,
                     });
-                } else if ch >= '0' && ch <= '7' {
+                } else if ('0'..='7').contains(&ch) {
                     // Octal
                     let oct_start = self.position - 1; // Include the leading 0
                     
                     while let Some(ch) = self.current_char() {
-                        if ch >= '0' && ch <= '7' {
+                        if ('0'..='7').contains(&ch) {
                             self.advance();
                         } else {
                             break;
@@ -602,7 +636,7 @@ impl Lexer {
         
         // Decimal number
         while let Some(ch) = self.current_char() {
-            if ch.is_digit(10) {
+            if ch.is_ascii_digit() {
                 self.advance();
             } else {
                 break;
@@ -715,6 +749,11 @@ impl Lexer {
         self.line
     }
     
+    /// Get where the current token starts
+    pub fn token_location(&self) -> SourceLocation {
+        self.token_location
+    }
+    
     /// Get the current column position
     pub fn column(&self) -> usize {
         self.column
@@ -722,10 +761,14 @@ impl Lexer {
     
     /// Get the current line content for error reporting
     pub fn get_current_line(&self) -> String {
-        if self.line <= self.source_lines.len() {
-            self.source_lines[self.line - 1].clone()
-        } else {
-            String::new()
-        }
+        self.source_line(self.line)
+    }
+
+    /// Get the content of a source line, numbered from 1
+    pub fn source_line(&self, line: usize) -> String {
+        line.checked_sub(1)
+            .and_then(|index| self.source_lines.get(index))
+            .cloned()
+            .unwrap_or_default()
     }
 }
\ No newline at end of file
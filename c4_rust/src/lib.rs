@@ -1,24 +1,49 @@
-/// C4 Compiler in Rust
-///
-/// This is a Rust implementation of the C4 compiler, originally written by Robert Swierczek.
-/// The compiler translates a subset of C into bytecode and includes a virtual machine
-/// to execute the compiled code.
-///
-/// The compiler supports:
-/// - char, int, and pointer types
-/// - if, while, return, and expression statements
-/// - Function definitions and calls
-/// - Basic operators: arithmetic, logical, bitwise
+//! C4 Compiler in Rust
+//!
+//! This is a Rust implementation of the C4 compiler, originally written by Robert Swierczek.
+//! The compiler translates a subset of C into bytecode and includes a virtual machine
+//! to execute the compiled code.
+//!
+//! The compiler supports:
+//! - char, int, and pointer types
+//! - if, while, return, and expression statements
+//! - Function definitions and calls
+//! - Basic operators: arithmetic, logical, bitwise
 
 // Export all modules
+pub mod asm;
+pub mod c_source;
+pub mod dataflow;
+pub mod debug_info;
+pub mod debugger;
+pub mod disasm;
+pub mod elf;
+mod encoder;
+pub mod engine;
 pub mod error;
+pub mod gdbstub;
+pub mod host;
+pub mod inline;
+pub mod instr;
+pub mod ir;
+pub mod jit;
 pub mod lexer;
+pub mod link;
+pub mod llvm;
+pub mod native;
+pub mod object;
 pub mod parser;
+pub mod peephole;
+pub mod regvm;
 pub mod symbol;
+#[cfg(test)]
+mod test_support;
 pub mod types;
 pub mod vm;
+pub mod wasm;
+pub mod x86_64;
 
 // Re-export commonly used types
 pub use parser::Parser;
-pub use types::{TokenType, Type, Opcode};
+pub use types::{TokenType, Type, Opcode, InstructionSet};
 pub use symbol::SymbolTable;
\ No newline at end of file
@@ -0,0 +1,223 @@
+use crate::debug_info::DebugInfo;
+use crate::error::CompilerError;
+use crate::object::{ObjectFile, ObjectSymbol, Relocation, RelocationKind, SymbolKind};
+use std::collections::HashMap;
+
+/// Linker combining separately compiled units into one program
+///
+/// Units are laid out in the order they were added: each unit's code
+/// follows the previous unit's, and its data starts at the next word
+/// boundary after the previous unit's data. Relocated code words are moved
+/// by their unit's base address, and every import is bound to the unit that
+/// exports a symbol of that name. Line and function tables are moved with
+/// the code, and functions are marked with the name of their unit. The
+/// result has no imports and starts at
+/// the exported `main`, or if there is none, at the first unit's entry
+/// point (an assembled unit can start anywhere).
+#[derive(Default)]
+pub struct Linker {
+    /// Units with the names used for them in diagnostics
+    units: Vec<(String, ObjectFile)>,
+}
+
+impl Linker {
+    /// Create a linker with no units
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a unit, named (usually after its file) for diagnostics
+    pub fn add(&mut self, name: &str, object: ObjectFile) {
+        self.units.push((name.to_string(), object));
+    }
+
+    /// Link the units
+    ///
+    /// Fails if two units export the same name, if an import has no
+    /// matching export, or if an import names a function where the export
+    /// is a global (or the other way around).
+    pub fn link(&self) -> Result<ObjectFile, CompilerError> {
+        // Base addresses of each unit's code and data
+        let mut bases = Vec::with_capacity(self.units.len());
+        let (mut code_len, mut data_len) = (0, 0);
+        for (_, unit) in &self.units {
+            bases.push((code_len, data_len));
+            code_len += unit.code.len();
+            data_len = (data_len + unit.data.len()).next_multiple_of(8);
+        }
+
+        // Exported symbols by name, with the index of their unit
+        let mut exports: HashMap<&str, (usize, &ObjectSymbol)> = HashMap::new();
+        for (i, (name, unit)) in self.units.iter().enumerate() {
+            for sym in &unit.symbols {
+                if let Some(&(first, _)) = exports.get(sym.name.as_str()) {
+                    return Err(CompilerError::link_error(
+                        &format!("Duplicate symbol '{}'", sym.name), &[&self.units[first].0, name]));
+                }
+                exports.insert(&sym.name, (i, sym));
+            }
+        }
+        let address = |unit: usize, sym: &ObjectSymbol| -> (i64, RelocationKind) {
+            let (code_base, data_base) = bases[unit];
+            match sym.kind {
+                SymbolKind::Function => (sym.value + code_base as i64, RelocationKind::Code),
+                SymbolKind::Global => (sym.value + data_base as i64, RelocationKind::Data),
+            }
+        };
+
+        let mut code = Vec::with_capacity(code_len);
+        let mut data = Vec::with_capacity(data_len);
+        let mut symbols = Vec::new();
+        let mut relocations = Vec::new();
+        let mut debug = DebugInfo::default();
+        for (i, (name, unit)) in self.units.iter().enumerate() {
+            // Bind this unit's imports
+            let mut imports = Vec::with_capacity(unit.imports.len());
+            for import in &unit.imports {
+                match exports.get(import.name.as_str()) {
+                    Some(&(j, sym)) if sym.kind == import.kind => imports.push(address(j, sym)),
+                    Some(&(j, sym)) => {
+                        let message = format!("'{}' is defined as a {} but used as a {}",
+                                              import.name, kind_name(sym.kind), kind_name(import.kind));
+                        return Err(CompilerError::link_error(&message, &[&self.units[j].0, name]));
+                    },
+                    None => {
+                        let users: Vec<&str> = self.units.iter()
+                            .filter(|(_, unit)| unit.imports.iter().any(|other| other.name == import.name))
+                            .map(|(name, _)| name.as_str())
+                            .collect();
+                        return Err(CompilerError::link_error(
+                            &format!("Undefined symbol '{}'", import.name), &users));
+                    },
+                }
+            }
+
+            let (code_base, data_base) = bases[i];
+            code.extend_from_slice(&unit.code);
+            data.resize(data_base, 0);
+            data.extend_from_slice(&unit.data);
+
+            for reloc in &unit.relocations {
+                let (delta, kind) = match reloc.kind {
+                    RelocationKind::Code => (code_base as i64, RelocationKind::Code),
+                    RelocationKind::Data => (data_base as i64, RelocationKind::Data),
+                    RelocationKind::Import(index) => *imports.get(index).ok_or_else(|| {
+                        CompilerError::link_error(&format!("Relocation refers to missing import {}", index), &[name])
+                    })?,
+                };
+                let offset = code_base + reloc.offset;
+                let word = code.get_mut(offset).ok_or_else(|| {
+                    CompilerError::link_error(&format!("Relocation outside the code at word {}", reloc.offset), &[name])
+                })?;
+                *word += delta;
+                relocations.push(Relocation { offset, kind });
+            }
+
+            let mut unit_debug = unit.debug.clone();
+            unit_debug.rebase(code_base, name);
+            for entry in unit_debug.lines {
+                debug.add_line(entry.pc, entry.location);
+            }
+            debug.functions.extend(unit_debug.functions);
+
+            symbols.extend(unit.symbols.iter().map(|sym| ObjectSymbol {
+                value: address(i, sym).0,
+                ..sym.clone()
+            }));
+        }
+
+        let entry = exports.get("main")
+            .filter(|(_, sym)| sym.kind == SymbolKind::Function)
+            .map(|&(unit, sym)| address(unit, sym).0 as usize)
+            .or_else(|| self.units.iter().zip(&bases)
+                .find_map(|((_, unit), &(code_base, _))| unit.entry.map(|entry| entry + code_base)));
+
+        Ok(ObjectFile {
+            code,
+            data,
+            entry,
+            symbols,
+            imports: Vec::new(),
+            relocations,
+            debug,
+        })
+    }
+}
+
+/// How a symbol kind is named in diagnostics
+fn kind_name(kind: SymbolKind) -> &'static str {
+    match kind {
+        SymbolKind::Function => "function",
+        SymbolKind::Global => "global",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::compile_with;
+    use crate::vm::VirtualMachine;
+
+    /// Compile one unit of a program
+    fn compile(source: &str) -> ObjectFile {
+        compile_with(source, |parser| parser.set_separate_compilation(true))
+    }
+
+    fn link(units: &[(&str, &str)]) -> Result<ObjectFile, CompilerError> {
+        let mut linker = Linker::new();
+        for (name, source) in units {
+            linker.add(name, compile(source));
+        }
+        linker.link()
+    }
+
+    #[test]
+    fn test_link_and_run() {
+        let main = r#"
+            extern int total;
+            extern char *label;
+            int add(int n);
+            int main() {
+                add(20); add(22);
+                printf("%s=%d\n", label, total);
+                return total;
+            }
+        "#;
+        let lib = r#"
+            char *label;
+            int total;
+            int add(int n) { if (!label) label = "total"; total = total + n; return total; }
+        "#;
+        let object = link(&[("main.c", main), ("lib.c", lib)]).unwrap();
+
+        assert!(object.imports.is_empty());
+        assert!(object.relocations.iter().all(|reloc| !matches!(reloc.kind, RelocationKind::Import(_))));
+        let add = object.symbols.iter().find(|sym| sym.name == "add").unwrap();
+        assert!(add.value as usize >= compile(main).code.len(), "lib.c is placed after main.c");
+
+        // The result is a closed program that survives a round trip
+        let object = ObjectFile::from_bytes(&object.to_bytes()).unwrap();
+        let mut vm = VirtualMachine::new(object.code, object.data, 1024, false);
+        assert_eq!(vm.run(object.entry.unwrap(), &[]).unwrap(), 42);
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let expect = |result: Result<ObjectFile, CompilerError>, expected: &str, units: &[&str]| {
+            match result {
+                Err(CompilerError::LinkError { message, units: found }) => {
+                    assert_eq!(message, expected);
+                    assert_eq!(found, units);
+                },
+                other => panic!("expected a link error, got {:?}", other.map(|_| ())),
+            }
+        };
+
+        expect(link(&[("a.c", "int f() { return 1; }"), ("b.c", "int f() { return 2; } int main() { return f(); }")]),
+               "Duplicate symbol 'f'", &["a.c", "b.c"]);
+        expect(link(&[("a.c", "int f(); int main() { return f(); }"), ("b.c", "int f(); int g() { return f(); }")]),
+               "Undefined symbol 'f'", &["a.c", "b.c"]);
+        expect(link(&[("a.c", "int f(); int main() { return f(); }"), ("b.c", "int f;")]),
+               "'f' is defined as a global but used as a function", &["b.c", "a.c"]);
+    }
+}
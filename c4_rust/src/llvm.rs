@@ -0,0 +1,962 @@
+use crate::debug_info::FunctionRange;
+use crate::error::CompilerError;
+use crate::instr::{self, Instr};
+use crate::object::{ObjectFile, RelocationKind, SymbolKind};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+
+/// The C library functions system calls become
+const DECLARATIONS: &str = "\
+declare i32 @open(i8*, i32, ...)
+declare i64 @read(i32, i8*, i64)
+declare i32 @close(i32)
+declare i32 @printf(i8*, ...)
+declare i8* @malloc(i64)
+declare void @free(i8*)
+declare i8* @memset(i8*, i32, i64)
+declare i32 @memcmp(i8*, i8*, i64)
+declare void @exit(i32) noreturn
+declare void @llvm.trap() noreturn nounwind
+";
+
+/// `@c4rt.printf(fmt, args, count)`: `printf` with the VM's formatting
+///
+/// The arguments are the `count` words at `args`, read as 0 once they run
+/// out. Each conversion is printed by the C library's `printf` on its own,
+/// given the `l` length it needs for 64-bit values and at most three
+/// arguments; an unknown conversion is printed as written, and literal
+/// text with `%.*s`.
+const PRINTF: &str = r#"
+@c4rt.percent = internal constant [2 x i8] c"%\00"
+@c4rt.text = internal constant [5 x i8] c"%.*s\00"
+declare i64 @strlen(i8*)
+declare i64 @strcspn(i8*, i8*)
+declare i32 @putchar(i32)
+
+define internal i64 @c4rt.arg(i64* %args, i64 %count, i64* %next) {
+entry:
+  %i = load i64, i64* %next
+  %more = icmp slt i64 %i, %count
+  br i1 %more, label %take, label %none
+take:
+  %p = getelementptr i64, i64* %args, i64 %i
+  %v = load i64, i64* %p
+  %i1 = add i64 %i, 1
+  store i64 %i1, i64* %next
+  ret i64 %v
+none:
+  ret i64 0
+}
+
+define internal void @c4rt.append(i8* %spec, i64* %n, i8 %c) {
+entry:
+  %i = load i64, i64* %n
+  %p = getelementptr i8, i8* %spec, i64 %i
+  store i8 %c, i8* %p
+  %i1 = add i64 %i, 1
+  store i64 %i1, i64* %n
+  ret void
+}
+
+define internal void @c4rt.copy(i8** %pos, i8* %spec, i64* %n) {
+entry:
+  %p = load i8*, i8** %pos
+  %c = load i8, i8* %p
+  call void @c4rt.append(i8* %spec, i64* %n, i8 %c)
+  %p1 = getelementptr i8, i8* %p, i64 1
+  store i8* %p1, i8** %pos
+  ret void
+}
+
+define internal void @c4rt.take(i64* %vals, i64* %k, i64 %v) {
+entry:
+  %i = load i64, i64* %k
+  %p = getelementptr i64, i64* %vals, i64 %i
+  store i64 %v, i64* %p
+  %i1 = add i64 %i, 1
+  store i64 %i1, i64* %k
+  ret void
+}
+
+define internal i64 @c4rt.printf(i8* %fmt, i64* %args, i64 %count) {
+entry:
+  %len = call i64 @strlen(i8* %fmt)
+  %size = add i64 %len, 3
+  %spec = alloca i8, i64 %size
+  %unknown = alloca [3 x i8]
+  %vals = alloca [3 x i64]
+  %pos = alloca i8*
+  %next = alloca i64
+  %total = alloca i64
+  %n = alloca i64
+  %k = alloca i64
+  %vals0 = getelementptr [3 x i64], [3 x i64]* %vals, i64 0, i64 0
+  %vals1 = getelementptr [3 x i64], [3 x i64]* %vals, i64 0, i64 1
+  %vals2 = getelementptr [3 x i64], [3 x i64]* %vals, i64 0, i64 2
+  %unknown0 = getelementptr [3 x i8], [3 x i8]* %unknown, i64 0, i64 0
+  %unknown1 = getelementptr [3 x i8], [3 x i8]* %unknown, i64 0, i64 1
+  %unknown2 = getelementptr [3 x i8], [3 x i8]* %unknown, i64 0, i64 2
+  store i8* %fmt, i8** %pos
+  store i64 0, i64* %next
+  store i64 0, i64* %total
+  br label %loop
+loop:
+  %p0 = load i8*, i8** %pos
+  %c0 = load i8, i8* %p0
+  switch i8 %c0, label %literal [ i8 0, label %done
+                                  i8 37, label %conversion ]
+literal:
+  %run = call i64 @strcspn(i8* %p0, i8* getelementptr ([2 x i8], [2 x i8]* @c4rt.percent, i64 0, i64 0))
+  %run32 = trunc i64 %run to i32
+  %printed = call i32 (i8*, ...) @printf(i8* getelementptr ([5 x i8], [5 x i8]* @c4rt.text, i64 0, i64 0), i32 %run32, i8* %p0)
+  %p1 = getelementptr i8, i8* %p0, i64 %run
+  store i8* %p1, i8** %pos
+  %t0 = load i64, i64* %total
+  %t1 = add i64 %t0, %run
+  store i64 %t1, i64* %total
+  br label %loop
+conversion:
+  store i64 0, i64* %n
+  store i64 0, i64* %k
+  store i64 0, i64* %vals0
+  store i64 0, i64* %vals1
+  store i64 0, i64* %vals2
+  call void @c4rt.copy(i8** %pos, i8* %spec, i64* %n)
+  br label %flags
+flags:
+  %p2 = load i8*, i8** %pos
+  %c2 = load i8, i8* %p2
+  switch i8 %c2, label %width [ i8 45, label %flag
+                                i8 48, label %flag
+                                i8 43, label %flag
+                                i8 32, label %flag ]
+flag:
+  call void @c4rt.copy(i8** %pos, i8* %spec, i64* %n)
+  br label %flags
+width:
+  %star = icmp eq i8 %c2, 42
+  br i1 %star, label %width.star, label %width.digits
+width.star:
+  call void @c4rt.copy(i8** %pos, i8* %spec, i64* %n)
+  %w = call i64 @c4rt.arg(i64* %args, i64 %count, i64* %next)
+  call void @c4rt.take(i64* %vals0, i64* %k, i64 %w)
+  br label %width.digits
+width.digits:
+  %p3 = load i8*, i8** %pos
+  %c3 = load i8, i8* %p3
+  %d3 = sub i8 %c3, 48
+  %digit3 = icmp ult i8 %d3, 10
+  br i1 %digit3, label %width.digit, label %precision
+width.digit:
+  call void @c4rt.copy(i8** %pos, i8* %spec, i64* %n)
+  br label %width.digits
+precision:
+  %dot = icmp eq i8 %c3, 46
+  br i1 %dot, label %precision.dot, label %length
+precision.dot:
+  call void @c4rt.copy(i8** %pos, i8* %spec, i64* %n)
+  %p4 = load i8*, i8** %pos
+  %c4 = load i8, i8* %p4
+  %pstar = icmp eq i8 %c4, 42
+  br i1 %pstar, label %precision.star, label %precision.digits
+precision.star:
+  call void @c4rt.copy(i8** %pos, i8* %spec, i64* %n)
+  %pv = call i64 @c4rt.arg(i64* %args, i64 %count, i64* %next)
+  %negative = icmp slt i64 %pv, 0
+  %prec = select i1 %negative, i64 0, i64 %pv
+  call void @c4rt.take(i64* %vals0, i64* %k, i64 %prec)
+  br label %precision.digits
+precision.digits:
+  %p5 = load i8*, i8** %pos
+  %c5 = load i8, i8* %p5
+  %d5 = sub i8 %c5, 48
+  %digit5 = icmp ult i8 %d5, 10
+  br i1 %digit5, label %precision.digit, label %length
+precision.digit:
+  call void @c4rt.copy(i8** %pos, i8* %spec, i64* %n)
+  br label %precision.digits
+length:
+  %p6 = load i8*, i8** %pos
+  %c6 = load i8, i8* %p6
+  %long = icmp eq i8 %c6, 108
+  br i1 %long, label %length.skip, label %convert
+length.skip:
+  %p7 = getelementptr i8, i8* %p6, i64 1
+  store i8* %p7, i8** %pos
+  br label %length
+convert:
+  switch i8 %c6, label %unknown.conv [ i8 0, label %trailing
+                                       i8 100, label %integer
+                                       i8 105, label %integer
+                                       i8 117, label %integer
+                                       i8 120, label %integer
+                                       i8 88, label %integer
+                                       i8 111, label %integer
+                                       i8 99, label %argument
+                                       i8 115, label %argument
+                                       i8 112, label %argument
+                                       i8 37, label %plain ]
+trailing:
+  %putc = call i32 @putchar(i32 37)
+  %t2 = load i64, i64* %total
+  %t3 = add i64 %t2, 1
+  store i64 %t3, i64* %total
+  br label %done
+integer:
+  call void @c4rt.append(i8* %spec, i64* %n, i8 108)
+  br label %argument
+argument:
+  call void @c4rt.copy(i8** %pos, i8* %spec, i64* %n)
+  %v = call i64 @c4rt.arg(i64* %args, i64 %count, i64* %next)
+  call void @c4rt.take(i64* %vals0, i64* %k, i64 %v)
+  br label %print
+plain:
+  call void @c4rt.copy(i8** %pos, i8* %spec, i64* %n)
+  br label %print
+unknown.conv:
+  store i8 37, i8* %unknown0
+  store i8 %c6, i8* %unknown1
+  store i8 0, i8* %unknown2
+  %p8 = getelementptr i8, i8* %p6, i64 1
+  store i8* %p8, i8** %pos
+  call void @c4rt.append(i8* %spec, i64* %n, i8 115)
+  %u = ptrtoint i8* %unknown0 to i64
+  call void @c4rt.take(i64* %vals0, i64* %k, i64 %u)
+  br label %print
+print:
+  call void @c4rt.append(i8* %spec, i64* %n, i8 0)
+  %a0 = load i64, i64* %vals0
+  %a1 = load i64, i64* %vals1
+  %a2 = load i64, i64* %vals2
+  %r = call i32 (i8*, ...) @printf(i8* %spec, i64 %a0, i64 %a1, i64 %a2)
+  %r64 = sext i32 %r to i64
+  %t4 = load i64, i64* %total
+  %t5 = add i64 %t4, %r64
+  store i64 %t5, i64* %total
+  br label %loop
+done:
+  %result = load i64, i64* %total
+  ret i64 %result
+}
+"#;
+
+/// Translate a linked program to textual LLVM IR
+///
+/// Each C4 function becomes an LLVM function taking and returning `i64`s,
+/// its arguments being the words its callers push. `AX`, the parameters,
+/// the locals (an `alloca` per variable, arrays included, using the debug
+/// information) and the expression stack, whose depth is known at every
+/// instruction, all live in `alloca`s for `mem2reg` to promote. The data
+/// segment is split into `@` globals at the addresses the code refers to,
+/// named after the program's globals where there is one; the rest are
+/// string literals. System calls call the C library functions of the same
+/// name, and a C `main` passes `argc` and `argv` to the program's `main`.
+///
+/// As with the x86-64 backend, `printf` goes through a formatter emitted
+/// with the program, so its conversions take 64-bit values as in the VM,
+/// and division by zero traps. Programs that call native
+/// functions, that still need linking, whose stack depth differs where
+/// control flow joins, or that jump or call into the middle of a function
+/// are rejected.
+pub fn emit(program: &ObjectFile) -> Result<String, CompilerError> {
+    if let Some(import) = program.imports.first() {
+        return Err(CompilerError::codegen_error(&format!("'{}' is not defined (link the program first)", import.name), None));
+    }
+    let entry = program.entry
+        .ok_or_else(|| CompilerError::codegen_error("main() not defined", None))?;
+    let code = instr::decode(&program.code)?;
+
+    let functions: BTreeMap<usize, &str> = program.symbols.iter()
+        .filter(|sym| sym.kind == SymbolKind::Function)
+        .map(|sym| (sym.value as usize, sym.name.as_str()))
+        .collect();
+    if !functions.contains_key(&entry) {
+        return Err(CompilerError::codegen_error("main() is not a function", Some(entry)));
+    }
+    let ranges: Vec<(usize, usize)> = functions.keys()
+        .zip(functions.keys().skip(1).copied().chain([code.len()]))
+        .map(|(&start, end)| (start, end))
+        .collect();
+    let arities = arities(&code, &ranges, &functions)?;
+    let data = Data::new(program);
+
+    let mut out = String::new();
+    writeln!(out, "; Generated by c4_rust from C4 bytecode").unwrap();
+    data.define(&mut out);
+    writeln!(out).unwrap();
+    out.push_str(DECLARATIONS);
+    if code.iter().any(|instr| matches!(instr, Instr::Prtf(_))) {
+        out.push_str(PRINTF);
+    }
+
+    for &(start, end) in &ranges {
+        let debug = program.debug.functions.iter().find(|func| func.start == start);
+        let function = Function {
+            code: &code,
+            start,
+            end,
+            name: functions[&start],
+            arities: &arities,
+            functions: &functions,
+            data: &data,
+            body: String::new(),
+            temps: 0,
+            depth: 0,
+            max_depth: 0,
+            slots: HashMap::new(),
+            traps: false,
+            printf_words: None,
+        };
+        function.emit(&mut out, debug)?;
+    }
+
+    // argc and argv are the last words main's frame gets, as in the VM
+    let arity = arities[&entry];
+    let pushed = ["%argc64", "%argv64"];
+    let args: Vec<String> = std::iter::repeat_n("0", arity.saturating_sub(2))
+        .chain(pushed[2 - arity.min(2)..].iter().copied())
+        .map(|arg| format!("i64 {}", arg))
+        .collect();
+    writeln!(out, "\ndefine i32 @main(i32 %argc, i8** %argv) {{").unwrap();
+    writeln!(out, "entry:").unwrap();
+    writeln!(out, "  %argc64 = sext i32 %argc to i64").unwrap();
+    writeln!(out, "  %argv64 = ptrtoint i8** %argv to i64").unwrap();
+    writeln!(out, "  %result = call i64 @c4.{}({})", functions[&entry], args.join(", ")).unwrap();
+    writeln!(out, "  %code = trunc i64 %result to i32").unwrap();
+    writeln!(out, "  ret i32 %code").unwrap();
+    writeln!(out, "}}").unwrap();
+
+    Ok(out)
+}
+
+/// The number of parameters of each function: as many as any caller
+/// passes, or its code reads
+fn arities(code: &[Instr], ranges: &[(usize, usize)], functions: &BTreeMap<usize, &str>) -> Result<HashMap<usize, usize>, CompilerError> {
+    let mut arities: HashMap<usize, usize> = functions.keys().map(|&start| (start, 0)).collect();
+    for &(start, end) in ranges {
+        for (pc, &instr) in code.iter().enumerate().take(end).skip(start) {
+            match instr {
+                Instr::Jsr(target) => {
+                    let Some(arity) = arities.get_mut(&target) else {
+                        return Err(CompilerError::codegen_error("call into the middle of a function", Some(pc)));
+                    };
+                    *arity = (*arity).max(passed(code, pc));
+                },
+                Instr::Tsr(target) if !arities.contains_key(&target) => {
+                    return Err(CompilerError::codegen_error("call into the middle of a function", Some(pc)));
+                },
+                Instr::Lea(offset) | Instr::Lli(offset) | Instr::Llc(offset) | Instr::Pshl(offset) if offset >= 2 => {
+                    let arity = arities.get_mut(&start).unwrap();
+                    *arity = (*arity).max(offset as usize - 1);
+                },
+                _ => {},
+            }
+        }
+    }
+    Ok(arities)
+}
+
+/// Arguments passed by the call at `pc`: what the `ADJ` after it removes
+fn passed(code: &[Instr], pc: usize) -> usize {
+    match code.get(pc + 2) {
+        Some(&Instr::Adj(n)) => n.max(0) as usize,
+        _ => 0,
+    }
+}
+
+/// The data segment as LLVM globals
+struct Data<'a> {
+    bytes: &'a [u8],
+    /// Start of each global, with its name
+    pieces: BTreeMap<usize, String>,
+    /// Operand words holding data addresses
+    relocations: BTreeSet<usize>,
+}
+
+impl<'a> Data<'a> {
+    /// Split the data where globals start and where the code points
+    fn new(program: &'a ObjectFile) -> Self {
+        let len = program.data.len();
+        let relocations: BTreeSet<usize> = program.relocations.iter()
+            .filter(|reloc| reloc.kind == RelocationKind::Data)
+            .map(|reloc| reloc.offset)
+            .collect();
+        let mut pieces: BTreeMap<usize, String> = relocations.iter()
+            .map(|&offset| program.code[offset] as usize)
+            .chain((len > 0).then_some(0))
+            .filter(|&offset| offset < len)
+            .map(|offset| (offset, format!("@.str.{}", offset)))
+            .collect();
+        for sym in program.symbols.iter().filter(|sym| sym.kind == SymbolKind::Global) {
+            if (sym.value as usize) < len {
+                pieces.insert(sym.value as usize, format!("@{}", sym.name));
+            }
+        }
+        Data { bytes: &program.data, pieces, relocations }
+    }
+
+    /// The global holding `offset` (or ending there), its start and its
+    /// size
+    fn piece(&self, offset: i64) -> (&str, usize, usize) {
+        let (&start, name) = self.pieces.range(..=offset.max(0) as usize).next_back()
+            .expect("data addresses need data");
+        let end = self.pieces.range(start + 1..).next().map_or(self.bytes.len(), |(&end, _)| end);
+        (name, start, end - start)
+    }
+
+    fn define(&self, out: &mut String) {
+        for (&start, name) in &self.pieces {
+            let (_, _, size) = self.piece(start as i64);
+            let bytes = &self.bytes[start..start + size];
+            let init = if bytes.iter().all(|&byte| byte == 0) {
+                "zeroinitializer".to_string()
+            } else {
+                let text: String = bytes.iter()
+                    .map(|&byte| match byte {
+                        b' '..=b'~' if byte != b'"' && byte != b'\\' => (byte as char).to_string(),
+                        _ => format!("\\{:02X}", byte),
+                    })
+                    .collect();
+                format!("c\"{}\"", text)
+            };
+            writeln!(out, "{} = internal global [{} x i8] {}, align 8", name, size, init).unwrap();
+        }
+    }
+}
+
+/// Translates one function
+struct Function<'a> {
+    code: &'a [Instr],
+    start: usize,
+    end: usize,
+    name: &'a str,
+    arities: &'a HashMap<usize, usize>,
+    functions: &'a BTreeMap<usize, &'a str>,
+    data: &'a Data<'a>,
+    /// Instructions after the entry block
+    body: String,
+    temps: usize,
+    /// Words on the expression stack
+    depth: usize,
+    max_depth: usize,
+    /// The `alloca` holding each frame word, its size in words, and the
+    /// word's index in it
+    slots: HashMap<i64, (String, usize, usize)>,
+    /// Whether division needs the trap block
+    traps: bool,
+    /// Size of `%printf.args`, where the arguments after a `printf`
+    /// format are stored
+    printf_words: Option<usize>,
+}
+
+impl Function<'_> {
+    fn emit(mut self, out: &mut String, debug: Option<&FunctionRange>) -> Result<(), CompilerError> {
+        let Instr::Ent(locals) = self.code[self.start] else {
+            return Err(CompilerError::codegen_error("function does not start with ENT", Some(self.start)));
+        };
+        let arity = self.arities[&self.start];
+        let allocas = self.frame(locals, arity, debug);
+        let leaders = self.blocks();
+        let depths = self.depths(&leaders)?;
+
+        for (&leader, &depth) in &depths {
+            self.depth = depth;
+            self.line(&format!("L{}:", leader));
+            let end = leaders.range(leader + 1..).next().copied().unwrap_or(self.end);
+            let mut terminated = false;
+            for pc in leader..end {
+                terminated = self.instruction(pc, self.code[pc])?;
+            }
+            if !terminated {
+                if end < self.end {
+                    self.op(&format!("br label %L{}", end));
+                } else {
+                    self.op("unreachable");
+                }
+            }
+        }
+        if self.traps {
+            self.line("div.zero:");
+            self.op("call void @llvm.trap()");
+            self.op("unreachable");
+        }
+
+        let params: Vec<String> = (0..arity).map(|j| format!("i64 %a{}", j)).collect();
+        writeln!(out, "\ndefine internal i64 @c4.{}({}) {{", self.name, params.join(", ")).unwrap();
+        writeln!(out, "entry:").unwrap();
+        writeln!(out, "  %ax = alloca i64").unwrap();
+        for d in 0..self.max_depth {
+            writeln!(out, "  %s{} = alloca i64", d).unwrap();
+        }
+        for (name, start, end) in &allocas {
+            writeln!(out, "  {} = alloca [{} x i64]", name, end - start).unwrap();
+        }
+        if let Some(words) = self.printf_words {
+            writeln!(out, "  %printf.args = alloca [{} x i64]", words).unwrap();
+            writeln!(out, "  %printf.words = getelementptr [{} x i64], [{} x i64]* %printf.args, i64 0, i64 0", words, words).unwrap();
+        }
+        // The last argument is nearest the frame, at bp + 2
+        for j in 0..arity {
+            let (name, words, index) = &self.slots[&(2 + (arity - 1 - j) as i64)];
+            writeln!(out, "  %p{} = getelementptr [{} x i64], [{} x i64]* {}, i64 0, i64 {}", j, words, words, name, index).unwrap();
+            writeln!(out, "  store i64 %a{}, i64* %p{}", j, j).unwrap();
+        }
+        writeln!(out, "  br label %L{}", self.start).unwrap();
+        out.push_str(&self.body);
+        writeln!(out, "}}").unwrap();
+        Ok(())
+    }
+
+    /// Give every frame word an `alloca`: one per variable the debug
+    /// information describes, merged where they overlap, and one per word
+    /// otherwise. Returns each `alloca` with the frame words it spans.
+    fn frame(&mut self, locals: i64, arity: usize, debug: Option<&FunctionRange>) -> Vec<(String, i64, i64)> {
+        let mut spans: Vec<(i64, i64, &str)> = debug.map_or(&[][..], |func| &func.locals).iter()
+            .filter(|var| var.offset < 0 || var.offset >= 2)
+            .map(|var| {
+                let bytes = var.array_len.map_or(8, |len| len * var.typ.size());
+                (var.offset, var.offset + bytes.div_ceil(8).max(1) as i64, var.name.as_str())
+            })
+            .collect();
+        spans.extend((-locals..0).chain(2..2 + arity as i64).map(|offset| (offset, offset + 1, "frame")));
+        spans.sort_by_key(|&(start, end, _)| (start, std::cmp::Reverse(end)));
+
+        let mut allocas: Vec<(String, i64, i64)> = Vec::new();
+        for (start, end, name) in spans {
+            match allocas.last_mut() {
+                Some(last) if start < last.2 => last.2 = last.2.max(end),
+                _ => allocas.push((format!("%{}.{}", name, allocas.len()), start, end)),
+            }
+        }
+        for (name, start, end) in &allocas {
+            for offset in *start..*end {
+                self.slots.insert(offset, (name.clone(), (end - start) as usize, (offset - start) as usize));
+            }
+        }
+        allocas
+    }
+
+    /// Where basic blocks may start: branch targets and after terminators
+    fn blocks(&self) -> BTreeSet<usize> {
+        let mut leaders = BTreeSet::from([self.start]);
+        for (pc, &instr) in self.code.iter().enumerate().take(self.end).skip(self.start) {
+            match instr {
+                Instr::Jmp(target) | Instr::Bz(target) | Instr::Bnz(target) => {
+                    leaders.insert(target);
+                    leaders.insert(pc + 2);
+                },
+                Instr::Lev | Instr::Exit => {
+                    leaders.insert(pc + 1);
+                },
+                Instr::Tsr(_) => {
+                    leaders.insert(pc + 2);
+                },
+                _ => {},
+            }
+        }
+        leaders.retain(|&pc| pc < self.end);
+        leaders
+    }
+
+    /// The stack depth at the start of each reachable block
+    fn depths(&self, leaders: &BTreeSet<usize>) -> Result<BTreeMap<usize, usize>, CompilerError> {
+        let mut depths = BTreeMap::from([(self.start, 0)]);
+        let mut work = vec![self.start];
+        while let Some(leader) = work.pop() {
+            let mut depth: usize = depths[&leader];
+            let end = leaders.range(leader + 1..).next().copied().unwrap_or(self.end);
+            let mut successors = Vec::new();
+            let mut falls_through = true;
+            for pc in leader..end {
+                let instr = self.code[pc];
+                let (pops, pushes) = instr.stack_effect();
+                depth = depth.checked_sub(pops)
+                    .ok_or_else(|| CompilerError::codegen_error("expression stack underflow", Some(pc)))? + pushes;
+                match instr {
+                    Instr::Jmp(target) => {
+                        successors.push((pc, target));
+                        falls_through = false;
+                    },
+                    Instr::Bz(target) | Instr::Bnz(target) => successors.push((pc, target)),
+                    Instr::Lev | Instr::Exit | Instr::Tsr(_) => falls_through = false,
+                    _ => {},
+                }
+            }
+            if falls_through && end < self.end {
+                successors.push((end - 1, end));
+            }
+            for (pc, target) in successors {
+                if !(self.start..self.end).contains(&target) {
+                    return Err(CompilerError::codegen_error("jump out of the function", Some(pc)));
+                }
+                match depths.insert(target, depth) {
+                    None => work.push(target),
+                    Some(old) if old != depth => {
+                        return Err(CompilerError::codegen_error("stack depth differs where control flow joins", Some(pc)));
+                    },
+                    Some(_) => {},
+                }
+            }
+        }
+        Ok(depths)
+    }
+
+    fn line(&mut self, text: &str) {
+        writeln!(self.body, "{}", text).unwrap();
+    }
+
+    fn op(&mut self, text: &str) {
+        writeln!(self.body, "  {}", text).unwrap();
+    }
+
+    /// Emit `expr` into a new temporary and return its name
+    fn temp(&mut self, expr: &str) -> String {
+        self.temps += 1;
+        let name = format!("%t{}", self.temps);
+        self.op(&format!("{} = {}", name, expr));
+        name
+    }
+
+    fn ax(&mut self) -> String {
+        self.temp("load i64, i64* %ax")
+    }
+
+    fn set_ax(&mut self, value: &str) {
+        self.op(&format!("store i64 {}, i64* %ax", value));
+    }
+
+    fn push(&mut self, value: &str) {
+        self.op(&format!("store i64 {}, i64* %s{}", value, self.depth));
+        self.depth += 1;
+        self.max_depth = self.max_depth.max(self.depth);
+    }
+
+    fn pop(&mut self) -> String {
+        self.depth -= 1;
+        self.temp(&format!("load i64, i64* %s{}", self.depth))
+    }
+
+    /// Word `i` of the top `n` on the stack, the deepest first
+    fn arg(&mut self, n: usize, i: usize) -> String {
+        self.temp(&format!("load i64, i64* %s{}", self.depth - n + i))
+    }
+
+    /// Pointer to the frame word at `offset` from `bp`
+    fn frame_word(&mut self, pc: usize, offset: i64) -> Result<String, CompilerError> {
+        let Some((name, words, index)) = self.slots.get(&offset).cloned() else {
+            return Err(CompilerError::codegen_error(&format!("frame word {} is not a parameter or local", offset), Some(pc)));
+        };
+        Ok(self.temp(&format!("getelementptr [{} x i64], [{} x i64]* {}, i64 0, i64 {}", words, words, name, index)))
+    }
+
+    /// The operand of the instruction at `pc`, which may be a data address
+    fn value(&mut self, pc: usize, value: i64) -> String {
+        if !self.data.relocations.contains(&(pc + 1)) {
+            return value.to_string();
+        }
+        let (name, start, size) = self.data.piece(value);
+        let expr = format!("getelementptr [{} x i8], [{} x i8]* {}, i64 0, i64 {}", size, size, name, value - start as i64);
+        let address = self.temp(&expr);
+        self.temp(&format!("ptrtoint i8* {} to i64", address))
+    }
+
+    fn pointer(&mut self, address: &str, typ: &str) -> String {
+        self.temp(&format!("inttoptr i64 {} to {}*", address, typ))
+    }
+
+    fn binary(&mut self, op: &str) {
+        let lhs = self.pop();
+        let rhs = self.ax();
+        let result = self.temp(&format!("{} i64 {}, {}", op, lhs, rhs));
+        self.set_ax(&result);
+    }
+
+    fn compare(&mut self, cond: &str) {
+        let lhs = self.pop();
+        let rhs = self.ax();
+        let flag = self.temp(&format!("icmp {} i64 {}, {}", cond, lhs, rhs));
+        let result = self.temp(&format!("zext i1 {} to i64", flag));
+        self.set_ax(&result);
+    }
+
+    fn shift(&mut self, op: &str) {
+        let lhs = self.pop();
+        let rhs = self.ax();
+        let count = self.temp(&format!("and i64 {}, 63", rhs));
+        let result = self.temp(&format!("{} i64 {}, {}", op, lhs, count));
+        self.set_ax(&result);
+    }
+
+    /// Divide, trapping on zero and negating for -1 as the VM does
+    fn divide(&mut self, pc: usize, modulo: bool) {
+        let lhs = self.pop();
+        let rhs = self.ax();
+        let zero = self.temp(&format!("icmp eq i64 {}, 0", rhs));
+        self.op(&format!("br i1 {}, label %div.zero, label %div.{}", zero, pc));
+        self.line(&format!("div.{}:", pc));
+        self.traps = true;
+        let minus_one = self.temp(&format!("icmp eq i64 {}, -1", rhs));
+        let divisor = self.temp(&format!("select i1 {}, i64 1, i64 {}", minus_one, rhs));
+        let (op, special) = if modulo { ("srem", "0".to_string()) } else { ("sdiv", self.temp(&format!("sub i64 0, {}", lhs))) };
+        let result = self.temp(&format!("{} i64 {}, {}", op, lhs, divisor));
+        let result = self.temp(&format!("select i1 {}, i64 {}, i64 {}", minus_one, special, result));
+        self.set_ax(&result);
+    }
+
+    /// Call a function of type `typ` whose result, of type `ret`, goes
+    /// in `AX`
+    fn call(&mut self, ret: &str, typ: &str, callee: &str, args: &[String]) {
+        let call = format!("call {} {}({})", typ, callee, args.join(", "));
+        let result = match ret {
+            "i32" => {
+                let result = self.temp(&call);
+                self.temp(&format!("sext i32 {} to i64", result))
+            },
+            "i8*" => {
+                let result = self.temp(&call);
+                self.temp(&format!("ptrtoint i8* {} to i64", result))
+            },
+            _ => self.temp(&call),
+        };
+        self.set_ax(&result);
+    }
+
+    /// The top words of the stack as arguments of the given types
+    fn c_args(&mut self, types: &[&str]) -> Vec<String> {
+        let n = types.len();
+        (0..n)
+            .map(|i| {
+                let word = self.arg(n, i);
+                match types[i] {
+                    "i64" => format!("i64 {}", word),
+                    "i32" => format!("i32 {}", self.temp(&format!("trunc i64 {} to i32", word))),
+                    _ => format!("i8* {}", self.pointer(&word, "i8")),
+                }
+            })
+            .collect()
+    }
+
+    /// Translate one instruction; returns whether it ended the block
+    fn instruction(&mut self, pc: usize, instr: Instr) -> Result<bool, CompilerError> {
+        match instr {
+            Instr::Lea(offset) => {
+                let word = self.frame_word(pc, offset)?;
+                let address = self.temp(&format!("ptrtoint i64* {} to i64", word));
+                self.set_ax(&address);
+            },
+            Instr::Imm(value) => {
+                let value = self.value(pc, value);
+                self.set_ax(&value);
+            },
+            Instr::Jmp(target) => {
+                self.op(&format!("br label %L{}", target));
+                return Ok(true);
+            },
+            Instr::Jsr(target) => {
+                let arity = self.arities[&target];
+                let passed = passed(self.code, pc);
+                let mut args: Vec<String> = vec!["i64 0".to_string(); arity - passed];
+                for i in 0..passed {
+                    let word = self.arg(passed, i);
+                    args.push(format!("i64 {}", word));
+                }
+                let callee = format!("@c4.{}", self.functions[&target]);
+                self.call("i64", "i64", &callee, &args);
+            },
+            Instr::Bz(target) | Instr::Bnz(target) => {
+                let ax = self.ax();
+                let zero = self.temp(&format!("icmp eq i64 {}, 0", ax));
+                let (if_zero, if_not) = if matches!(instr, Instr::Bz(_)) { (target, pc + 2) } else { (pc + 2, target) };
+                self.op(&format!("br i1 {}, label %L{}, label %L{}", zero, if_zero, if_not));
+                return Ok(true);
+            },
+            Instr::Ent(_) => {},
+            Instr::Adj(n) => self.depth -= n as usize,
+            Instr::Lev => {
+                let ax = self.ax();
+                self.op(&format!("ret i64 {}", ax));
+                return Ok(true);
+            },
+            Instr::Tsr(target) => {
+                // The arguments were stored over this function's parameters
+                let arity = self.arities[&target];
+                let mut args = Vec::new();
+                for j in 0..arity {
+                    let word = self.frame_word(pc, 2 + (arity - 1 - j) as i64)?;
+                    args.push(format!("i64 {}", self.temp(&format!("load i64, i64* {}", word))));
+                }
+                let result = self.temp(&format!("tail call i64 @c4.{}({})", self.functions[&target], args.join(", ")));
+                self.op(&format!("ret i64 {}", result));
+                return Ok(true);
+            },
+            Instr::Li | Instr::Lc => {
+                let ax = self.ax();
+                let result = if instr == Instr::Li {
+                    let pointer = self.pointer(&ax, "i64");
+                    self.temp(&format!("load i64, i64* {}, align 1", pointer))
+                } else {
+                    let pointer = self.pointer(&ax, "i8");
+                    let byte = self.temp(&format!("load i8, i8* {}", pointer));
+                    self.temp(&format!("sext i8 {} to i64", byte))
+                };
+                self.set_ax(&result);
+            },
+            Instr::Si => {
+                let address = self.pop();
+                let ax = self.ax();
+                let pointer = self.pointer(&address, "i64");
+                self.op(&format!("store i64 {}, i64* {}, align 1", ax, pointer));
+            },
+            Instr::Sc => {
+                let address = self.pop();
+                let ax = self.ax();
+                let pointer = self.pointer(&address, "i8");
+                let byte = self.temp(&format!("trunc i64 {} to i8", ax));
+                self.op(&format!("store i8 {}, i8* {}", byte, pointer));
+                let result = self.temp(&format!("sext i8 {} to i64", byte));
+                self.set_ax(&result);
+            },
+            Instr::Psh => {
+                let ax = self.ax();
+                self.push(&ax);
+            },
+            Instr::Or => self.binary("or"),
+            Instr::Xor => self.binary("xor"),
+            Instr::And => self.binary("and"),
+            Instr::Eq => self.compare("eq"),
+            Instr::Ne => self.compare("ne"),
+            Instr::Lt => self.compare("slt"),
+            Instr::Gt => self.compare("sgt"),
+            Instr::Le => self.compare("sle"),
+            Instr::Ge => self.compare("sge"),
+            Instr::Shl => self.shift("shl"),
+            Instr::Shr => self.shift("ashr"),
+            Instr::Add => self.binary("add"),
+            Instr::Sub => self.binary("sub"),
+            Instr::Mul => self.binary("mul"),
+            Instr::Div => self.divide(pc, false),
+            Instr::Mod => self.divide(pc, true),
+            Instr::Open => {
+                let mut args = self.c_args(&["i8*", "i32"]);
+                args.push("i32 420".to_string());
+                self.call("i32", "i32 (i8*, i32, ...)", "@open", &args);
+            },
+            Instr::Read => {
+                let args = self.c_args(&["i32", "i8*", "i64"]);
+                self.call("i64", "i64", "@read", &args);
+            },
+            Instr::Clos => {
+                let args = self.c_args(&["i32"]);
+                self.call("i32", "i32", "@close", &args);
+            },
+            Instr::Prtf(argc) => {
+                let words = argc.saturating_sub(1).max(1);
+                self.printf_words = Some(self.printf_words.map_or(words, |size| size.max(words)));
+                let format = self.arg(argc, 0);
+                let format = self.pointer(&format, "i8");
+                for i in 1..argc {
+                    let word = self.arg(argc, i);
+                    let slot = self.temp(&format!("getelementptr i64, i64* %printf.words, i64 {}", i - 1));
+                    self.op(&format!("store i64 {}, i64* {}", word, slot));
+                }
+                let args = [format!("i8* {}", format), "i64* %printf.words".to_string(), format!("i64 {}", argc - 1)];
+                self.call("i64", "i64", "@c4rt.printf", &args);
+            },
+            Instr::Malc => {
+                let args = self.c_args(&["i64"]);
+                self.call("i8*", "i8*", "@malloc", &args);
+            },
+            Instr::Free => {
+                let args = self.c_args(&["i8*"]);
+                self.op(&format!("call void @free({})", args.join(", ")));
+            },
+            Instr::Mset => {
+                let args = self.c_args(&["i8*", "i32", "i64"]);
+                self.call("i8*", "i8*", "@memset", &args);
+            },
+            Instr::Mcmp => {
+                let args = self.c_args(&["i8*", "i8*", "i64"]);
+                self.call("i32", "i32", "@memcmp", &args);
+            },
+            Instr::Exit => {
+                let ax = self.ax();
+                let code = self.temp(&format!("trunc i64 {} to i32", ax));
+                self.op(&format!("call void @exit(i32 {})", code));
+                self.op("unreachable");
+                return Ok(true);
+            },
+            Instr::Neg => {
+                let ax = self.ax();
+                let result = self.temp(&format!("sub i64 0, {}", ax));
+                self.set_ax(&result);
+            },
+            Instr::Lli(offset) | Instr::Pshl(offset) => {
+                let word = self.frame_word(pc, offset)?;
+                let value = self.temp(&format!("load i64, i64* {}", word));
+                self.set_ax(&value);
+                if matches!(instr, Instr::Pshl(_)) {
+                    self.push(&value);
+                }
+            },
+            Instr::Llc(offset) => {
+                let word = self.frame_word(pc, offset)?;
+                let pointer = self.temp(&format!("bitcast i64* {} to i8*", word));
+                let byte = self.temp(&format!("load i8, i8* {}", pointer));
+                let value = self.temp(&format!("sext i8 {} to i64", byte));
+                self.set_ax(&value);
+            },
+            Instr::Pshi(value) => {
+                let value = self.value(pc, value);
+                self.set_ax(&value);
+                self.push(&value);
+            },
+            Instr::Addi(value) | Instr::Subi(value) | Instr::Muli(value) => {
+                let value = self.value(pc, value);
+                let ax = self.ax();
+                let op = match instr {
+                    Instr::Addi(_) => "add",
+                    Instr::Subi(_) => "sub",
+                    _ => "mul",
+                };
+                let result = self.temp(&format!("{} i64 {}, {}", op, ax, value));
+                self.set_ax(&result);
+            },
+            Instr::Natv(_) => {
+                return Err(CompilerError::codegen_error("native functions cannot be called from compiled code", Some(pc)));
+            },
+            Instr::Operand => {},
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compile, compile_with};
+
+    #[test]
+    fn test_emit() {
+        let program = compile(r#"
+            int total;
+            int add(int a, int b) { int sum[2]; sum[0] = a + b; return sum[0]; }
+            int main() { total = add(2, 3); printf("%d\n", total); return 0; }
+        "#);
+        let ir = emit(&program).unwrap();
+        assert!(ir.contains("@total = internal global [8 x i8] zeroinitializer, align 8"), "{}", ir);
+        assert!(ir.contains("c\"%d\\0A\\00\""));
+        assert!(ir.contains("define internal i64 @c4.add(i64 %a0, i64 %a1) {"));
+        assert!(ir.contains("%sum.0 = alloca [2 x i64]"));
+        assert!(ir.contains("call i64 @c4.add(i64 "));
+        assert!(ir.contains("call i64 @c4rt.printf(i8* "), "{}", ir);
+        assert!(ir.contains("%printf.args = alloca [1 x i64]"));
+        assert!(ir.contains("declare i8* @malloc(i64)"));
+        assert!(ir.contains("call i64 @c4.main()"));
+    }
+
+    #[test]
+    fn test_emit_rejects_unlinked_programs() {
+        let object = compile_with("int f(); int main() { return f(); }", |parser| parser.set_separate_compilation(true));
+        assert!(matches!(emit(&object), Err(CompilerError::CodegenError { .. })));
+    }
+}
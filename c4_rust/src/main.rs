@@ -1,103 +1,455 @@
 use std::env;
+use std::io::{self, BufReader};
+use std::net::TcpListener;
 use std::fs;
+use std::path::Path;
 use std::process;
+use std::time::Duration;
+use c4_rust::asm;
+use c4_rust::c_source;
+use c4_rust::debugger::Debugger;
+use c4_rust::disasm::Disassembler;
+use c4_rust::elf;
+use c4_rust::gdbstub::GdbStub;
+use c4_rust::jit::Jit;
+use c4_rust::host::{Host, OsHost, SandboxHost};
+use c4_rust::link::Linker;
+use c4_rust::ir;
+use c4_rust::llvm;
+use c4_rust::object::ObjectFile;
 use c4_rust::parser::Parser;
-use c4_rust::vm::VirtualMachine;
+use c4_rust::regvm::RegisterVm;
+use c4_rust::types::InstructionSet;
+use c4_rust::vm::{VirtualMachine, VmLimits};
+use c4_rust::wasm;
+use c4_rust::x86_64;
+
+const USAGE: &str = "usage: c4_rust [debug] [-s] [-d] [--no-simplify] [--no-opt] [-O0|-O1] [--c4-opcodes] [--disasm] [--emit=ir|cfg] [--gdb port|stdio] [--max-cycles n] [--max-heap bytes] [--stack words] [--timeout ms] [--max-output bytes] [--sandbox dir] [--jit] [--vm=stack|reg] [--target=vm|x86_64-asm|elf64|wasm|wat|c|llvm] [-c] [-o output] file ... [-- args ...]";
+
+/// What a linked program is turned into
+#[derive(Clone, Copy, PartialEq)]
+enum Target {
+    /// Run it on the VM
+    Vm,
+    /// Translate it to x86-64 assembly
+    X86_64Asm,
+    /// Write it as a Linux executable
+    Elf64,
+    /// Write it as a WebAssembly module
+    Wasm,
+    /// Write it as a WebAssembly module in the text format
+    Wat,
+    /// Translate it to C source
+    C,
+    /// Translate it to textual LLVM IR
+    Llvm,
+}
+
+/// Settings for compiling source files
+struct Options {
+    src_flag: bool,
+    simplify: bool,
+    optimize: bool,
+    /// Whether to run the call and dataflow optimizations (`-O1`)
+    dataflow: bool,
+    instruction_set: InstructionSet,
+    /// Whether each file is one unit of a multi-file program
+    separate: bool,
+}
 
 fn main() {
     // Parse command line arguments
     let args: Vec<String> = env::args().collect();
-    
+
     // Check for command-line flags and input file
-    let mut i = 1;
+    let debug_mode = args.get(1).is_some_and(|arg| arg == "debug");
+    let mut i = if debug_mode { 2 } else { 1 };
     let mut src_flag = false;
     let mut debug_flag = false;
-    let mut input_file = None;
-    
+    let mut simplify = true;
+    let mut optimize = true;
+    let mut dataflow = false;
+    let mut instruction_set = InstructionSet::Extended;
+    let mut compile_only = false;
+    let mut disasm_flag = false;
+    let mut emit = None;
+    let mut output_file = None;
+    let mut gdb = None;
+    let mut limits = VmLimits::default();
+    let mut sandbox = None;
+    let mut target = Target::Vm;
+    let mut jit = false;
+    let mut registers = false;
+
+    // Flags may come anywhere among the input files when compiling (-c or
+    // --target) or when `--` ends the inputs. Otherwise the first file ends
+    // the flags, and it and the rest are passed to the program (argv[0] is
+    // the first file, as in C4.c)
+    let inputs_end = args.iter().skip(i).position(|arg| arg == "--").map(|end| i + end);
+    let mut input_files = Vec::new();
     while i < args.len() {
         if args[i] == "-s" {
             src_flag = true;
         } else if args[i] == "-d" {
             debug_flag = true;
+        } else if args[i] == "--no-simplify" {
+            simplify = false;
+        } else if args[i] == "--no-opt" || args[i] == "-O0" {
+            optimize = false;
+            dataflow = false;
+        } else if args[i] == "-O1" {
+            optimize = true;
+            dataflow = true;
+        } else if args[i] == "--c4-opcodes" {
+            instruction_set = InstructionSet::C4;
+        } else if args[i] == "--disasm" {
+            disasm_flag = true;
+        } else if let Some(form) = args[i].strip_prefix("--emit=") {
+            if form != "ir" && form != "cfg" {
+                eprintln!("unknown form '{}' for --emit (expected ir or cfg)", form);
+                process::exit(1);
+            }
+            emit = Some(form.to_string());
+        } else if args[i] == "--gdb" && i + 1 < args.len() {
+            gdb = Some(args[i + 1].clone());
+            i += 1;
+        } else if args[i] == "--max-cycles" && i + 1 < args.len() {
+            limits.max_cycles = Some(number(&args[i], &args[i + 1]));
+            i += 1;
+        } else if args[i] == "--max-heap" && i + 1 < args.len() {
+            limits.max_heap_bytes = Some(number(&args[i], &args[i + 1]) as usize);
+            i += 1;
+        } else if args[i] == "--stack" && i + 1 < args.len() {
+            limits.stack_size = number(&args[i], &args[i + 1]) as usize;
+            i += 1;
+        } else if args[i] == "--timeout" && i + 1 < args.len() {
+            limits.timeout = Some(Duration::from_millis(number(&args[i], &args[i + 1])));
+            i += 1;
+        } else if args[i] == "--max-output" && i + 1 < args.len() {
+            limits.max_output_bytes = Some(number(&args[i], &args[i + 1]) as usize);
+            i += 1;
+        } else if args[i] == "--sandbox" && i + 1 < args.len() {
+            sandbox = Some(args[i + 1].clone());
+            i += 1;
+        } else if args[i] == "--jit" {
+            jit = true;
+        } else if let Some(machine) = args[i].strip_prefix("--vm=") {
+            registers = match machine {
+                "stack" => false,
+                "reg" => true,
+                _ => {
+                    eprintln!("unknown machine '{}' for --vm (expected stack or reg)", machine);
+                    process::exit(1);
+                }
+            };
+        } else if let Some(name) = args[i].strip_prefix("--target=") {
+            target = match name {
+                "vm" => Target::Vm,
+                "x86_64-asm" => Target::X86_64Asm,
+                "elf64" => Target::Elf64,
+                "wasm" => Target::Wasm,
+                "wat" => Target::Wat,
+                "c" => Target::C,
+                "llvm" => Target::Llvm,
+                _ => {
+                    eprintln!("unknown target '{}' (expected vm, x86_64-asm, elf64, wasm, wat, c or llvm)", name);
+                    process::exit(1);
+                }
+            };
+        } else if args[i] == "-c" {
+            compile_only = true;
+        } else if args[i] == "-o" && i + 1 < args.len() {
+            output_file = Some(args[i + 1].clone());
+            i += 1;
+        } else if Some(i) == inputs_end {
+            i += 1;
+            break;
+        } else if compile_only || target != Target::Vm || inputs_end.is_some() {
+            input_files.push(args[i].clone());
         } else {
-            input_file = Some(args[i].clone());
             break;
         }
         i += 1;
     }
-    
+    let prog_args: Vec<String> = if compile_only {
+        Vec::new()
+    } else if input_files.is_empty() {
+        input_files.extend(args.get(i).cloned());
+        args[i..].to_vec()
+    } else {
+        input_files.iter().take(1).chain(&args[i..]).cloned().collect()
+    };
+
     // Check if we have an input file
-    if input_file.is_none() {
-        eprintln!("usage: c4_rust [-s] [-d] file ...");
+    if input_files.is_empty() {
+        eprintln!("{}", USAGE);
         process::exit(1);
     }
-    
-    // Read source file
-    let input_file = input_file.unwrap();
-    let source = match fs::read_to_string(&input_file) {
-        Ok(content) => content,
+    if output_file.is_some() && !compile_only && target == Target::Vm {
+        eprintln!("-o requires -c or --target");
+        eprintln!("{}", USAGE);
+        process::exit(1);
+    }
+    if output_file.is_some() && compile_only && input_files.len() > 1 {
+        eprintln!("-o cannot be used with more than one input file");
+        process::exit(1);
+    }
+
+    let options = Options {
+        src_flag,
+        simplify,
+        optimize,
+        dataflow,
+        instruction_set,
+        separate: compile_only || input_files.len() > 1,
+    };
+
+    // Compile or load every input
+    let mut units = Vec::new();
+    for input_file in &input_files {
+        let (object, is_object) = load(input_file, &options);
+
+        // With -c, write an object file instead of running
+        if compile_only {
+            if is_object {
+                eprintln!("{} is already compiled", input_file);
+                process::exit(1);
+            }
+            write_object(&object, input_file, output_file.clone());
+            continue;
+        }
+        units.push(object);
+    }
+    if compile_only {
+        process::exit(0);
+    }
+
+    // A single file is listed as it is, even if it still needs linking,
+    // with its source lines if it was compiled from C
+    if disasm_flag && units.len() == 1 {
+        let source = fs::read_to_string(&input_files[0]).unwrap_or_default();
+        let lines: Vec<(usize, usize)> = units[0].debug.lines.iter()
+            .map(|entry| (entry.pc, entry.location.line))
+            .collect();
+        print!("{}", Disassembler::from_object(&units[0]).with_source(&source, &lines).disassemble());
+        process::exit(0);
+    }
+
+    let mut linker = Linker::new();
+    for (input_file, object) in input_files.iter().zip(units) {
+        linker.add(input_file, object);
+    }
+    let program = match linker.link() {
+        Ok(program) => program,
         Err(err) => {
-            eprintln!("could not open({}): {}", input_file, err);
+            eprint!("{}", err);
             process::exit(1);
         }
     };
-    
-    // Print banner if -s flag is set
-    if src_flag {
-        println!("C4 Rust Compiler - Compiling {}", input_file);
+
+    // With --disasm, list the linked program instead of running it
+    if disasm_flag {
+        print!("{}", Disassembler::from_object(&program).disassemble());
+        process::exit(0);
     }
-    
-    // Create parser
-    let mut parser = match Parser::new(source, src_flag) {
-        mut parser => {
-            match parser.init() {
-                Ok(()) => parser,
-                Err(err) => {
-                    eprintln!("Parser initialization error: {}", err);
+
+    // With --emit, print the control flow graph instead of running it
+    if let Some(form) = emit {
+        let graph = ir::Program::from_object(&program)
+            .and_then(|graph| graph.verify().map(|()| graph));
+        match graph {
+            Ok(graph) if form == "cfg" => print!("{}", graph.to_dot()),
+            Ok(graph) => print!("{}", graph),
+            Err(err) => {
+                eprint!("{}", err);
+                process::exit(1);
+            }
+        }
+        process::exit(0);
+    }
+
+    // With --target=elf64, write an executable named after the first input
+    if target == Target::Elf64 {
+        let path = output_file.unwrap_or_else(|| {
+            Path::new(&input_files[0]).with_extension("").to_string_lossy().into_owned()
+        });
+        if let Err(err) = elf::emit(&program).map(|bytes| write_executable(&path, &bytes)) {
+            eprint!("{}", err);
+            process::exit(1);
+        }
+        process::exit(0);
+    }
+
+    // With --target=wasm, write a module named after the first input
+    if target == Target::Wasm {
+        let path = output_file.unwrap_or_else(|| {
+            Path::new(&input_files[0]).with_extension("wasm").to_string_lossy().into_owned()
+        });
+        let module = match wasm::Module::from_object(&program) {
+            Ok(module) => module,
+            Err(err) => {
+                eprint!("{}", err);
+                process::exit(1);
+            }
+        };
+        if let Err(err) = fs::write(&path, module.to_wasm()) {
+            eprintln!("could not write({}): {}", path, err);
+            process::exit(1);
+        }
+        process::exit(0);
+    }
+
+    // With --target, translate the program instead of running it, to the
+    // output file or stdout
+    if matches!(target, Target::X86_64Asm | Target::Wat | Target::C | Target::Llvm) {
+        let translated = match target {
+            Target::Wat => wasm::Module::from_object(&program).map(|module| module.to_wat()),
+            Target::C => c_source::emit(&program),
+            Target::Llvm => llvm::emit(&program),
+            _ => x86_64::emit(&program),
+        };
+        let assembly = match translated {
+            Ok(assembly) => assembly,
+            Err(err) => {
+                eprint!("{}", err);
+                process::exit(1);
+            }
+        };
+        match output_file {
+            Some(path) => {
+                if let Err(err) = fs::write(&path, assembly) {
+                    eprintln!("could not write({}): {}", path, err);
                     process::exit(1);
                 }
+            },
+            None => print!("{}", assembly),
+        }
+        process::exit(0);
+    }
+
+    // In debug mode, hand the program to the debugger, which reads its
+    // commands from stdin
+    if debug_mode {
+        let mut debugger = match Debugger::new(program, prog_args, limits) {
+            Ok(debugger) => debugger,
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        };
+        for input_file in &input_files {
+            if let Ok(source) = fs::read_to_string(input_file) {
+                debugger = debugger.with_source(input_file, &source);
             }
         }
-    };
-    
-    // Parse source code
-    if let Err(err) = parser.parse() {
-        eprintln!("Compilation error: {}", err);
-        process::exit(1);
+        if let Err(err) = debugger.run_script(BufReader::new(io::stdin()), &mut io::stdout(), true) {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+        process::exit(0);
     }
-    
-    // Get main function
-    let main_addr = match parser.get_main_function() {
-        Some(addr) => addr,
-        None => {
-            eprintln!("main() not defined");
+
+    // With --gdb, wait for a debugger on a local port or on stdin/stdout
+    if let Some(target) = gdb {
+        let mut stub = match GdbStub::new(program, &prog_args, limits) {
+            Ok(stub) => stub,
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        };
+        let served = if target == "stdio" {
+            stub.serve(io::stdin().lock(), io::stdout())
+        } else {
+            serve_tcp(&mut stub, &target)
+        };
+        if let Err(err) = served {
+            eprintln!("gdb connection failed: {}", err);
             process::exit(1);
         }
+        process::exit(stub.exit_code().unwrap_or(0) as i32);
+    }
+
+    // Get main function
+    let Some(entry) = program.entry else {
+        eprintln!("main() not defined");
+        process::exit(1);
     };
-    
+
     // If -s flag is set, just print the source and exit
     if src_flag {
         println!("Compilation successful!");
-        
+
         // Print code segment summary
-        println!("\nCode segment size: {} bytes", parser.get_code().len() * 8);
-        println!("Data segment size: {} bytes", parser.get_data().len());
-        println!("main() function found at offset: {}", (*main_addr).value as usize);
-        
+        println!("\nCode segment size: {} bytes", program.code.len() * 8);
+        println!("Data segment size: {} bytes", program.data.len());
+        println!("main() function found at offset: {}", entry);
+
         // Exit with success
         process::exit(0);
     }
-    
-    // Create VM
-    let code = parser.get_code().to_vec();
-    let data = parser.get_data().to_vec();
-    let mut vm = VirtualMachine::new(code, data, 256 * 1024, debug_flag);
-    
-    // Extract command-line arguments for the program
-    let prog_args: Vec<String> = args.iter().skip(i + 1).cloned().collect();
-    
+
+    // With --sandbox, the program can only open files under one directory
+    let sandboxed = sandbox.is_some();
+    let host: Box<dyn Host> = match sandbox {
+        Some(dir) => match SandboxHost::new(&dir) {
+            Ok(host) => Box::new(host),
+            Err(err) => {
+                eprintln!("could not use sandbox {}: {}", dir, err);
+                process::exit(1);
+            }
+        },
+        None => Box::new(OsHost::new()),
+    };
+
+    // With --jit, run the program as machine code, unless it needs
+    // something only the interpreter has. JIT code does not check memory
+    // accesses, so it could write anywhere in this process, the sandbox
+    // included
+    let compiled = if jit && sandboxed {
+        if debug_flag {
+            eprintln!("falling back to the interpreter: --sandbox needs checked memory accesses");
+        }
+        None
+    } else if jit {
+        Jit::compile(&program, limits)
+            .inspect_err(|err| if debug_flag { eprint!("falling back to the interpreter: {}", err) })
+            .ok()
+    } else {
+        None
+    };
+
     // Run the program
-    match vm.run(main_addr.value as usize, &prog_args) {
+    let result = match compiled {
+        Some(mut jit) => {
+            jit.set_host(host);
+            jit.run(&prog_args)
+        },
+        None => {
+            // With --vm=reg, run the program on the register machine,
+            // unless it cannot be translated
+            let machine = if registers {
+                RegisterVm::compile(&program, limits, debug_flag)
+                    .inspect_err(|err| if debug_flag { eprint!("falling back to the stack VM: {}", err) })
+                    .ok()
+            } else {
+                None
+            };
+            match machine {
+                Some(mut machine) => {
+                    machine.set_host(host);
+                    machine.run(&prog_args)
+                },
+                None => {
+                    let mut vm = VirtualMachine::with_limits(program.code, program.data, limits, debug_flag);
+                    vm.set_debug_info(program.debug);
+                    vm.set_host(host);
+                    vm.run(entry, &prog_args)
+                },
+            }
+        },
+    };
+    match result {
         Ok(exit_code) => {
             if debug_flag {
                 println!("Program exited with code: {}", exit_code);
@@ -109,4 +461,143 @@ fn main() {
             process::exit(1);
         }
     }
-}
\ No newline at end of file
+}
+
+/// Parse the number given to a flag, exiting with a message if it is not one
+fn number(flag: &str, value: &str) -> u64 {
+    match value.parse::<u64>() {
+        Ok(n) => n,
+        Err(_) => {
+            eprintln!("{} expects a number, not '{}'", flag, value);
+            process::exit(1);
+        }
+    }
+}
+
+/// Accept one debugger connection on a local port and serve it
+fn serve_tcp(stub: &mut GdbStub, port: &str) -> io::Result<()> {
+    let port = port.parse::<u16>()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid port '{}'", port)))?;
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    eprintln!("Listening for gdb on {}", listener.local_addr()?);
+    let (stream, _) = listener.accept()?;
+    stub.serve(BufReader::new(stream.try_clone()?), stream)
+}
+
+/// Read an input file, which is C source, assembly (`.s`) or a compiled
+/// object, exiting with a message on errors
+///
+/// Also returns whether the file was already an object file.
+fn load(input_file: &str, options: &Options) -> (ObjectFile, bool) {
+    let bytes = match fs::read(input_file) {
+        Ok(content) => content,
+        Err(err) => {
+            eprintln!("could not open({}): {}", input_file, err);
+            process::exit(1);
+        }
+    };
+
+    // Assembly files are recognised by their extension, objects by their magic number
+    let is_assembly = Path::new(input_file).extension().is_some_and(|ext| ext == "s");
+    let is_object = ObjectFile::is_object(&bytes);
+    let result = if is_object {
+        ObjectFile::from_bytes(&bytes)
+    } else if is_assembly {
+        asm::assemble(&String::from_utf8_lossy(&bytes))
+    } else {
+        let source = match String::from_utf8(bytes) {
+            Ok(source) => source,
+            Err(_) => {
+                eprintln!("{} is neither UTF-8 source nor a C4 object file", input_file);
+                process::exit(1);
+            }
+        };
+
+        // Print banner if -s flag is set
+        if options.src_flag {
+            println!("C4 Rust Compiler - Compiling {}", input_file);
+        }
+
+        // Functions remember their source file, even once written to an object
+        let parser = compile(source, options);
+        match ObjectFile::from_parser(&parser) {
+            Ok(mut object) => {
+                object.debug.rebase(0, input_file);
+                Ok(object)
+            },
+            Err(err) => {
+                eprintln!("Compilation error: {}", err);
+                process::exit(1);
+            }
+        }
+    };
+
+    match result {
+        Ok(object) => (object, is_object),
+        Err(err) => {
+            eprintln!("Could not load {}: {}", input_file, err);
+            process::exit(1);
+        }
+    }
+}
+
+/// Write an executable file, exiting with a message on errors
+fn write_executable(path: &str, bytes: &[u8]) {
+    let result = fs::write(path, bytes).and_then(|()| {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+        }
+        Ok(())
+    });
+    if let Err(err) = result {
+        eprintln!("could not write({}): {}", path, err);
+        process::exit(1);
+    }
+}
+
+/// Write an object file next to its input (or to `output_file`)
+fn write_object(object: &ObjectFile, input_file: &str, output_file: Option<String>) {
+    let output_file = output_file.unwrap_or_else(|| {
+        Path::new(input_file).with_extension("c4o").to_string_lossy().into_owned()
+    });
+    if let Err(err) = object.save(&output_file) {
+        eprintln!("could not write({}): {}", output_file, err);
+        process::exit(1);
+    }
+}
+
+/// Compile source code, exiting with a message on errors
+fn compile(source: String, options: &Options) -> Parser {
+    // Create parser
+    let mut parser = Parser::new(source, options.src_flag);
+    parser.set_simplify(options.simplify);
+    parser.set_instruction_set(options.instruction_set);
+    parser.set_separate_compilation(options.separate);
+    if let Err(err) = parser.init() {
+        eprintln!("Parser initialization error: {}", err);
+        process::exit(1);
+    }
+
+    // Parse source code
+    let result = parser.parse();
+    for warning in parser.get_warnings() {
+        eprint!("{}", warning);
+    }
+    if let Err(err) = result {
+        eprintln!("Compilation error: {}", err);
+        process::exit(1);
+    }
+    if options.optimize {
+        parser.optimize();
+    }
+    if options.dataflow {
+        parser.optimize_calls();
+        parser.optimize_dataflow();
+        parser.optimize();
+    }
+    parser.fuse();
+
+    parser
+}
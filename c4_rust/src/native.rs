@@ -0,0 +1,81 @@
+use crate::vm::VirtualMachine;
+use std::fmt;
+use std::rc::Rc;
+
+/// Signature of a native function: the VM (for reading and writing its
+/// memory) and the arguments in call order, returning the call's value
+/// or an error message
+pub type NativeFn = dyn Fn(&mut VirtualMachine, &[i64]) -> Result<i64, String>;
+
+/// A Rust function callable from C4 code
+#[derive(Clone)]
+pub struct Native {
+    pub name: String,
+    /// Number of arguments every call must pass
+    pub arity: usize,
+    pub func: Rc<NativeFn>,
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Native({}/{})", self.name, self.arity)
+    }
+}
+
+/// Native functions, numbered in registration order
+///
+/// The parser makes each name callable like a system function, compiling
+/// calls to `NATV index`, and the VM dispatches `NATV` through the same
+/// table. Compiled code therefore only runs against a table registered in
+/// the same order.
+#[derive(Debug, Clone, Default)]
+pub struct Natives {
+    natives: Vec<Native>,
+}
+
+impl Natives {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a native function, returning its index
+    ///
+    /// Registering a name again replaces the earlier function but keeps its
+    /// index.
+    pub fn register(
+        &mut self,
+        name: &str,
+        arity: usize,
+        func: impl Fn(&mut VirtualMachine, &[i64]) -> Result<i64, String> + 'static,
+    ) -> usize {
+        let native = Native { name: name.to_string(), arity, func: Rc::new(func) };
+        match self.natives.iter().position(|native| native.name == name) {
+            Some(index) => {
+                self.natives[index] = native;
+                index
+            },
+            None => {
+                self.natives.push(native);
+                self.natives.len() - 1
+            },
+        }
+    }
+
+    /// The native function with an index
+    pub fn get(&self, index: usize) -> Option<&Native> {
+        self.natives.get(index)
+    }
+
+    /// All native functions, in index order
+    pub fn iter(&self) -> impl Iterator<Item = &Native> {
+        self.natives.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.natives.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.natives.is_empty()
+    }
+}
@@ -0,0 +1,607 @@
+use crate::debug_info::{DebugInfo, FunctionRange, LineEntry, LocalVariable};
+use crate::error::{CompilerError, SourceLocation};
+use crate::instr::{self, Instr};
+use crate::parser::Parser;
+use crate::types::{TokenType, Type};
+use std::fs;
+use std::path::Path;
+
+/// Magic number at the start of every object file
+pub const MAGIC: [u8; 4] = *b"C4O\0";
+
+/// Current object file format version
+///
+/// Readers reject any other version rather than guess at its layout.
+pub const VERSION: u32 = 4;
+
+/// What a symbol in an object file names
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    /// A function; the value is its code address
+    Function = 0,
+    /// A global variable; the value is its data address
+    Global = 1,
+}
+
+/// An exported symbol
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectSymbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub typ: Type,
+    pub value: i64,
+}
+
+/// A symbol the object uses but another unit defines
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectImport {
+    pub name: String,
+    pub kind: SymbolKind,
+}
+
+/// What a relocated code word refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocationKind {
+    /// A code address (jump, branch or call target)
+    Code,
+    /// A data segment address (string literal or global variable)
+    Data,
+    /// The address of an imported symbol, by index into
+    /// [`ObjectFile::imports`], plus the word's current value
+    Import(usize),
+}
+
+impl RelocationKind {
+    /// The tag stored in object files
+    fn tag(&self) -> u8 {
+        match self {
+            RelocationKind::Code => 0,
+            RelocationKind::Data => 1,
+            RelocationKind::Import(_) => 2,
+        }
+    }
+}
+
+/// A code word whose value depends on where a segment is placed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Relocation {
+    /// Index of the code word
+    pub offset: usize,
+    pub kind: RelocationKind,
+}
+
+/// A compiled program in the `.c4o` container format
+///
+/// All values are little-endian:
+///
+/// ```text
+/// magic     4 bytes  "C4O\0"
+/// version   u32
+/// entry     i64      code address of main, or -1
+/// code      u64 count, then count i64 words
+/// data      u64 count, then count bytes
+/// symbols   u32 count, then per symbol:
+///           u8 kind, u8 type, i64 value, u32 name length, name bytes
+/// imports   u32 count, then per import: u8 kind, u32 name length, name bytes
+/// relocs    u32 count, then per relocation: u8 kind, u64 code word index,
+///           and for imports a u32 import index
+/// lines     u32 count, then per entry: u64 code address, u32 line, u32 column
+/// functions u32 count, then per function: u32 name length, name bytes,
+///           u64 start, u64 end, u32 file name length, file name bytes
+///           (empty if unknown), u32 local count, then per local:
+///           u32 name length, name bytes, u8 type, i64 bp offset,
+///           u64 array length (0 if not an array), u64 start, u64 end
+/// ```
+///
+/// An object with imports is one unit of a program and has to be linked
+/// (see [`crate::link`]) before it can run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectFile {
+    pub code: Vec<i64>,
+    pub data: Vec<u8>,
+    pub entry: Option<usize>,
+    pub symbols: Vec<ObjectSymbol>,
+    pub imports: Vec<ObjectImport>,
+    pub relocations: Vec<Relocation>,
+    /// Source lines and functions of the code, empty if unknown
+    pub debug: DebugInfo,
+}
+
+impl ObjectFile {
+    /// Package the output of a parser
+    ///
+    /// Functions and globals are exported, and `extern` symbols the unit
+    /// uses but does not define are imported. Code relocations are found by
+    /// decoding the code; data and symbol relocations come from the parser.
+    pub fn from_parser(parser: &Parser) -> Result<Self, CompilerError> {
+        let code = parser.get_code().to_vec();
+        let program = instr::decode(&code)?;
+
+        // Imports in order of first use
+        let mut imports: Vec<ObjectImport> = Vec::new();
+        let mut relocations = Vec::new();
+        for (offset, name) in parser.get_symbol_relocations() {
+            let index = match imports.iter().position(|import| import.name == *name) {
+                Some(index) => index,
+                None => {
+                    let is_function = parser.get_symbol_table().get(name)
+                        .is_some_and(|sym| sym.class == TokenType::Fun);
+                    let kind = if is_function { SymbolKind::Function } else { SymbolKind::Global };
+                    imports.push(ObjectImport { name: name.clone(), kind });
+                    imports.len() - 1
+                },
+            };
+            relocations.push(Relocation { offset: *offset, kind: RelocationKind::Import(index) });
+        }
+
+        relocations.extend(program.iter().enumerate()
+            .filter(|(_, instr)| matches!(instr, Instr::Jmp(_) | Instr::Jsr(_) | Instr::Bz(_) | Instr::Bnz(_) | Instr::Tsr(_)))
+            .map(|(pc, _)| Relocation { offset: pc + 1, kind: RelocationKind::Code })
+            .filter(|reloc| !parser.get_symbol_relocations().iter().any(|(offset, _)| *offset == reloc.offset)));
+        relocations.extend(parser.get_data_relocations().iter()
+            .map(|&offset| Relocation { offset, kind: RelocationKind::Data }));
+        relocations.sort_by_key(|reloc| reloc.offset);
+
+        let symbols = parser.get_symbol_table().iter()
+            .filter(|sym| !sym.external)
+            .filter_map(|sym| {
+                let kind = match sym.class {
+                    TokenType::Fun => SymbolKind::Function,
+                    TokenType::Glo => SymbolKind::Global,
+                    _ => return None,
+                };
+                Some(ObjectSymbol { name: sym.name.clone(), kind, typ: sym.typ, value: sym.value })
+            })
+            .collect();
+
+        Ok(ObjectFile {
+            code,
+            data: parser.get_data().to_vec(),
+            entry: parser.get_main_function()
+                .filter(|sym| sym.class == TokenType::Fun)
+                .map(|sym| sym.value as usize),
+            symbols,
+            imports,
+            relocations,
+            debug: parser.get_debug_info().clone(),
+        })
+    }
+
+    /// Whether a file starts with the object file magic number
+    pub fn is_object(bytes: &[u8]) -> bool {
+        bytes.starts_with(&MAGIC)
+    }
+
+    /// Serialize to the binary format
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(32 + self.code.len() * 8 + self.data.len());
+        out.extend_from_slice(&MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.extend_from_slice(&self.entry.map_or(-1, |entry| entry as i64).to_le_bytes());
+
+        out.extend_from_slice(&(self.code.len() as u64).to_le_bytes());
+        for word in &self.code {
+            out.extend_from_slice(&word.to_le_bytes());
+        }
+
+        out.extend_from_slice(&(self.data.len() as u64).to_le_bytes());
+        out.extend_from_slice(&self.data);
+
+        out.extend_from_slice(&(self.symbols.len() as u32).to_le_bytes());
+        for sym in &self.symbols {
+            out.push(sym.kind as u8);
+            out.push(sym.typ as u8);
+            out.extend_from_slice(&sym.value.to_le_bytes());
+            out.extend_from_slice(&(sym.name.len() as u32).to_le_bytes());
+            out.extend_from_slice(sym.name.as_bytes());
+        }
+
+        out.extend_from_slice(&(self.imports.len() as u32).to_le_bytes());
+        for import in &self.imports {
+            out.push(import.kind as u8);
+            out.extend_from_slice(&(import.name.len() as u32).to_le_bytes());
+            out.extend_from_slice(import.name.as_bytes());
+        }
+
+        out.extend_from_slice(&(self.relocations.len() as u32).to_le_bytes());
+        for reloc in &self.relocations {
+            out.push(reloc.kind.tag());
+            out.extend_from_slice(&(reloc.offset as u64).to_le_bytes());
+            if let RelocationKind::Import(index) = reloc.kind {
+                out.extend_from_slice(&(index as u32).to_le_bytes());
+            }
+        }
+
+        out.extend_from_slice(&(self.debug.lines.len() as u32).to_le_bytes());
+        for entry in &self.debug.lines {
+            out.extend_from_slice(&(entry.pc as u64).to_le_bytes());
+            out.extend_from_slice(&(entry.location.line as u32).to_le_bytes());
+            out.extend_from_slice(&(entry.location.column as u32).to_le_bytes());
+        }
+
+        out.extend_from_slice(&(self.debug.functions.len() as u32).to_le_bytes());
+        for func in &self.debug.functions {
+            out.extend_from_slice(&(func.name.len() as u32).to_le_bytes());
+            out.extend_from_slice(func.name.as_bytes());
+            out.extend_from_slice(&(func.start as u64).to_le_bytes());
+            out.extend_from_slice(&(func.end as u64).to_le_bytes());
+            let file = func.file.as_deref().unwrap_or("");
+            out.extend_from_slice(&(file.len() as u32).to_le_bytes());
+            out.extend_from_slice(file.as_bytes());
+
+            out.extend_from_slice(&(func.locals.len() as u32).to_le_bytes());
+            for var in &func.locals {
+                out.extend_from_slice(&(var.name.len() as u32).to_le_bytes());
+                out.extend_from_slice(var.name.as_bytes());
+                out.push(var.typ as u8);
+                out.extend_from_slice(&var.offset.to_le_bytes());
+                out.extend_from_slice(&(var.array_len.unwrap_or(0) as u64).to_le_bytes());
+                out.extend_from_slice(&(var.start as u64).to_le_bytes());
+                out.extend_from_slice(&(var.end as u64).to_le_bytes());
+            }
+        }
+
+        out
+    }
+
+    /// Parse and validate the binary format
+    ///
+    /// Besides the container structure, the code must decode (see
+    /// [`instr::decode`]) and the entry point, symbols and relocations must
+    /// all refer to places that exist.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CompilerError> {
+        let mut reader = Reader { bytes, pos: 0 };
+
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(CompilerError::object_error("Not a C4 object file (bad magic number)", Some(0)));
+        }
+        let version = reader.u32()?;
+        if version != VERSION {
+            return Err(CompilerError::object_error(
+                &format!("Unsupported object file version {} (expected {})", version, VERSION), Some(4)));
+        }
+
+        let entry = match reader.i64()? {
+            -1 => None,
+            entry => Some(usize::try_from(entry)
+                .map_err(|_| reader.error(&format!("Invalid entry point {}", entry)))?),
+        };
+
+        let count = reader.count_u64(8)?;
+        let code = (0..count).map(|_| reader.i64()).collect::<Result<Vec<_>, _>>()?;
+
+        let count = reader.count_u64(1)?;
+        let data = reader.take(count)?.to_vec();
+
+        let count = reader.count_u32(14)?;
+        let mut symbols = Vec::with_capacity(count);
+        for _ in 0..count {
+            let kind = reader.symbol_kind()?;
+            let typ = reader.typ()?;
+            let value = reader.i64()?;
+            let name = reader.name()?;
+            symbols.push(ObjectSymbol { name, kind, typ, value });
+        }
+
+        let count = reader.count_u32(5)?;
+        let mut imports = Vec::with_capacity(count);
+        for _ in 0..count {
+            let kind = reader.symbol_kind()?;
+            let name = reader.name()?;
+            imports.push(ObjectImport { name, kind });
+        }
+
+        let count = reader.count_u32(9)?;
+        let mut relocations = Vec::with_capacity(count);
+        for _ in 0..count {
+            let tag = reader.u8()?;
+            let offset = reader.u64()? as usize;
+            let kind = match tag {
+                0 => RelocationKind::Code,
+                1 => RelocationKind::Data,
+                2 => RelocationKind::Import(reader.u32()? as usize),
+                other => return Err(reader.error(&format!("Unknown relocation kind {}", other))),
+            };
+            relocations.push(Relocation { offset, kind });
+        }
+
+        let mut debug = DebugInfo::default();
+        let count = reader.count_u32(16)?;
+        for _ in 0..count {
+            let pc = reader.u64()? as usize;
+            let line = reader.u32()? as usize;
+            let column = reader.u32()? as usize;
+            debug.lines.push(LineEntry { pc, location: SourceLocation::new(line, column) });
+        }
+
+        let count = reader.count_u32(28)?;
+        for _ in 0..count {
+            let name = reader.name()?;
+            let start = reader.u64()? as usize;
+            let end = reader.u64()? as usize;
+            let file = Some(reader.name()?).filter(|file| !file.is_empty());
+
+            let count = reader.count_u32(37)?;
+            let mut locals = Vec::with_capacity(count);
+            for _ in 0..count {
+                let name = reader.name()?;
+                let typ = reader.typ()?;
+                let offset = reader.i64()?;
+                let array_len = Some(reader.u64()? as usize).filter(|&len| len > 0);
+                let start = reader.u64()? as usize;
+                let end = reader.u64()? as usize;
+                locals.push(LocalVariable { name, typ, offset, array_len, start, end });
+            }
+            debug.functions.push(FunctionRange { name, start, end, file, locals });
+        }
+
+        if reader.pos != bytes.len() {
+            return Err(reader.error("Trailing bytes after object file"));
+        }
+
+        let object = ObjectFile { code, data, entry, symbols, imports, relocations, debug };
+        object.validate()?;
+        Ok(object)
+    }
+
+    /// Write an object file to disk
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), CompilerError> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    /// Read and validate an object file from disk
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, CompilerError> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    /// Check that everything the header points at exists
+    fn validate(&self) -> Result<(), CompilerError> {
+        let error = |message: String| CompilerError::object_error(&message, None);
+
+        let program = instr::decode(&self.code).map_err(|err| match err {
+            CompilerError::VMError { message, .. } => error(format!("Invalid code: {}", message)),
+            other => other,
+        })?;
+        let is_instruction = |addr: i64| {
+            usize::try_from(addr).ok()
+                .and_then(|addr| program.get(addr))
+                .is_some_and(|instr| *instr != Instr::Operand)
+        };
+
+        if let Some(entry) = self.entry {
+            if !is_instruction(entry as i64) {
+                return Err(error(format!("Entry point {} is not an instruction", entry)));
+            }
+        }
+
+        for sym in &self.symbols {
+            let valid = match sym.kind {
+                SymbolKind::Function => is_instruction(sym.value),
+                SymbolKind::Global => sym.value >= 0 && sym.value as usize <= self.data.len(),
+            };
+            if !valid {
+                return Err(error(format!("Symbol '{}' has invalid address {}", sym.name, sym.value)));
+            }
+        }
+
+        for reloc in &self.relocations {
+            // Only operand words are relocated
+            let instr = reloc.offset.checked_sub(1)
+                .filter(|_| program.get(reloc.offset) == Some(&Instr::Operand))
+                .and_then(|pc| program.get(pc));
+            let valid = match (reloc.kind, instr) {
+                (RelocationKind::Code, Some(instr)) => {
+                    matches!(instr, Instr::Jmp(_) | Instr::Jsr(_) | Instr::Bz(_) | Instr::Bnz(_) | Instr::Tsr(_))
+                },
+                (RelocationKind::Data, Some(instr)) => {
+                    let value = self.code[reloc.offset];
+                    instr.operand().is_some() && value >= 0 && value as usize <= self.data.len()
+                },
+                (RelocationKind::Import(index), Some(instr)) => {
+                    // Calls to imported functions, address operands for globals
+                    let is_jump = instr.opcode().is_some_and(|op| op.is_jump());
+                    match self.imports.get(index) {
+                        Some(import) if import.kind == SymbolKind::Function => is_jump,
+                        Some(_) => !is_jump && instr.operand().is_some(),
+                        None => false,
+                    }
+                },
+                (_, None) => false,
+            };
+            if !valid {
+                return Err(error(format!("Invalid {:?} relocation at code word {}", reloc.kind, reloc.offset)));
+            }
+        }
+
+        if !self.debug.lines.windows(2).all(|pair| pair[0].pc < pair[1].pc) ||
+           self.debug.lines.last().is_some_and(|entry| entry.pc > self.code.len()) {
+            return Err(error("Line table is out of order or outside the code".to_string()));
+        }
+        for func in &self.debug.functions {
+            if func.start > func.end || func.end > self.code.len() {
+                return Err(error(format!("Function '{}' has invalid range {}..{}", func.name, func.start, func.end)));
+            }
+            if let Some(var) = func.locals.iter().find(|var| var.start > var.end || var.end > func.end) {
+                return Err(error(format!("Local '{}' of '{}' has invalid range {}..{}",
+                                         var.name, func.name, var.start, var.end)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Cursor over the bytes of an object file
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Build an error at the current position
+    fn error(&self, message: &str) -> CompilerError {
+        CompilerError::object_error(message, Some(self.pos))
+    }
+
+    /// Take the next `len` bytes
+    fn take(&mut self, len: usize) -> Result<&'a [u8], CompilerError> {
+        match self.bytes.get(self.pos..self.pos.saturating_add(len)) {
+            Some(slice) => {
+                self.pos += len;
+                Ok(slice)
+            },
+            None => Err(self.error("Unexpected end of object file")),
+        }
+    }
+
+    fn u8(&mut self) -> Result<u8, CompilerError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, CompilerError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, CompilerError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn i64(&mut self) -> Result<i64, CompilerError> {
+        Ok(i64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn symbol_kind(&mut self) -> Result<SymbolKind, CompilerError> {
+        match self.u8()? {
+            0 => Ok(SymbolKind::Function),
+            1 => Ok(SymbolKind::Global),
+            other => Err(self.error(&format!("Unknown symbol kind {}", other))),
+        }
+    }
+
+    fn typ(&mut self) -> Result<Type, CompilerError> {
+        let typ = self.u8()?;
+        Type::from_i64(typ as i64).ok_or_else(|| self.error(&format!("Unknown symbol type {}", typ)))
+    }
+
+    /// Read a length-prefixed UTF-8 symbol name
+    fn name(&mut self) -> Result<String, CompilerError> {
+        let len = self.count_u32(1)?;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| self.error("Symbol name is not valid UTF-8"))
+    }
+
+    /// Read a 64-bit element count
+    fn count_u64(&mut self, min_size: usize) -> Result<usize, CompilerError> {
+        let count = self.u64()?;
+        self.check_count(count, min_size)
+    }
+
+    /// Read a 32-bit element count
+    fn count_u32(&mut self, min_size: usize) -> Result<usize, CompilerError> {
+        let count = self.u32()? as u64;
+        self.check_count(count, min_size)
+    }
+
+    /// Check an element count against the bytes left, so a corrupt count
+    /// cannot trigger a huge allocation
+    fn check_count(&self, count: u64, min_size: usize) -> Result<usize, CompilerError> {
+        let remaining = (self.bytes.len() - self.pos) as u64;
+        if count.saturating_mul(min_size as u64) > remaining {
+            return Err(self.error(&format!("Count {} exceeds the remaining {} bytes", count, remaining)));
+        }
+        Ok(count as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compile, compile_with};
+
+    #[test]
+    fn test_round_trip() {
+        let object = compile(r#"
+            int counter;
+            int bump(int n) { counter = counter + n; return counter; }
+            int main() { bump(2); printf("%d\n", bump(3)); return 0; }
+        "#);
+
+        assert!(object.entry.is_some());
+        assert!(object.symbols.iter().any(|sym| sym.name == "counter" && sym.kind == SymbolKind::Global));
+        assert!(object.symbols.iter().any(|sym| sym.name == "bump" && sym.kind == SymbolKind::Function));
+        assert!(object.relocations.iter().any(|reloc| reloc.kind == RelocationKind::Data));
+        assert!(object.relocations.iter().any(|reloc| reloc.kind == RelocationKind::Code));
+
+        let bytes = object.to_bytes();
+        assert!(ObjectFile::is_object(&bytes));
+        assert_eq!(ObjectFile::from_bytes(&bytes).unwrap(), object);
+    }
+
+    #[test]
+    fn test_imports_round_trip() {
+        let object = compile_with("extern int g; int f(int n); int main() { return f(g); }", |parser| parser.set_separate_compilation(true));
+
+        let names: Vec<_> = object.imports.iter().map(|import| (import.name.as_str(), import.kind)).collect();
+        assert_eq!(names, [("g", SymbolKind::Global), ("f", SymbolKind::Function)]);
+        assert!(object.symbols.iter().all(|sym| sym.name == "main"), "imports are not exported");
+        assert_eq!(ObjectFile::from_bytes(&object.to_bytes()).unwrap(), object);
+
+        // An import used the wrong way round is rejected
+        let mut swapped = object.clone();
+        swapped.imports[0].kind = SymbolKind::Function;
+        assert!(ObjectFile::from_bytes(&swapped.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_rejects_corruption() {
+        let bytes = compile("int main() { return 7; }").to_bytes();
+
+        // Truncated at every possible length
+        for len in 0..bytes.len() {
+            assert!(ObjectFile::from_bytes(&bytes[..len]).is_err(), "truncated to {} bytes", len);
+        }
+
+        let mut bad_version = bytes.clone();
+        bad_version[4] = 99;
+        assert!(ObjectFile::from_bytes(&bad_version).is_err());
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(ObjectFile::from_bytes(&trailing).is_err());
+
+        // An opcode word replaced with garbage
+        let mut bad_code = bytes;
+        bad_code[28] = 0xff;
+        assert!(ObjectFile::from_bytes(&bad_code).is_err());
+    }
+
+    #[test]
+    fn test_data_relocations_are_complete() {
+        // Globals and strings reached through folded and simplified code
+        let source = r#"
+            int g; char *s;
+            int main() {
+                g = 40; s = "xyz" + 1;
+                return 0 + g + (1 && g == 40) + *s - 'y' + (0 || s[1] == 'z');
+            }
+        "#;
+        let run = |object: &ObjectFile| {
+            let mut vm = crate::vm::VirtualMachine::new(object.code.clone(), object.data.clone(), 1024, false);
+            vm.run(object.entry.unwrap(), &[]).unwrap()
+        };
+        let object = compile(source);
+
+        // Move the data segment up by 64 bytes, patching only relocated words
+        let mut moved = object.clone();
+        moved.data = [vec![0xAA; 64], object.data.clone()].concat();
+        for reloc in &object.relocations {
+            if reloc.kind == RelocationKind::Data {
+                moved.code[reloc.offset] += 64;
+            }
+        }
+
+        assert_eq!(run(&object), 42);
+        assert_eq!(run(&moved), 42);
+    }
+}
@@ -1,11 +1,19 @@
-use crate::error::CompilerError;
+use crate::dataflow;
+use crate::debug_info::{DebugInfo, FunctionRange, LocalVariable};
+use crate::error::{CompilerError, CompilerWarning, SourceLocation};
+use crate::inline;
+use crate::ir;
 use crate::lexer::{Lexer, Token};
+use crate::native::Natives;
 use crate::symbol::{Symbol, SymbolTable};
-use crate::types::{Opcode, TokenType, Type};
-use std::collections::HashMap;
+use crate::types::{InstructionSet, Opcode, TokenType, Type};
+
+/// A pass over the code segment, given entry points and data relocations,
+/// that returns a map from old to new code addresses
+type CodeRewrite = fn(&mut Vec<i64>, &[usize], &mut Vec<usize>) -> Vec<usize>;
 
 /// Parser for C4 compiler
-/// 
+///
 /// The parser transforms tokens from the lexer into bytecode
 /// and manages the symbol table.
 pub struct Parser {
@@ -24,7 +32,7 @@ pub struct Parser {
     /// Symbol table
     symbol_table: SymbolTable,
 
-    /// Current parsing type
+    /// Type of the most recently parsed expression
     current_type: Type,
 
     /// Current identifier name
@@ -33,11 +41,46 @@ pub struct Parser {
     /// Current token value
     current_value: i64,
 
-    /// Local variable offset
+    /// Number of local variable words currently in use
     local_offset: i64,
 
-    /// Print source flag
-    print_source: bool,
+    /// Largest number of local variable words used by the current function
+    frame_size: i64,
+
+    /// Position of the last emitted load (LI/LC), used to find lvalues
+    last_load: Option<usize>,
+
+    /// Whether identities such as `x + 0` and `x * 1` are simplified away
+    simplify: bool,
+
+    /// Which opcodes code generation may use
+    instruction_set: InstructionSet,
+
+    /// Positions of code words that hold data segment addresses
+    data_relocations: Vec<usize>,
+
+    /// Positions of code words that hold the address of an `extern`
+    /// symbol, with the symbol's name
+    symbol_relocations: Vec<(usize, String)>,
+
+    /// Whether this is one unit of a multi-file program, so `extern`
+    /// symbols may stay undefined and `main` may be missing
+    separate_compilation: bool,
+
+    /// Source positions and function ranges of the generated code
+    debug_info: DebugInfo,
+
+    /// Parameters and locals of the current function, for the debug info
+    locals: Vec<LocalVariable>,
+
+    /// Index into `locals` where each open scope's variables start
+    scope_starts: Vec<usize>,
+
+    /// Warnings collected while parsing
+    warnings: Vec<CompilerWarning>,
+
+    /// Native functions callable from the source
+    natives: Natives,
 }
 
 impl Parser {
@@ -57,10 +100,57 @@ impl Parser {
             current_id_name: None,
             current_value: 0,
             local_offset: 0,
-            print_source,
+            frame_size: 0,
+            last_load: None,
+            simplify: true,
+            instruction_set: InstructionSet::default(),
+            data_relocations: Vec::new(),
+            symbol_relocations: Vec::new(),
+            separate_compilation: false,
+            debug_info: DebugInfo::default(),
+            locals: Vec::new(),
+            scope_starts: Vec::new(),
+            warnings: Vec::new(),
+            natives: Natives::new(),
         }
     }
 
+    /// Enable or disable algebraic identity simplification
+    ///
+    /// Constant folding always happens; turning simplification off keeps
+    /// operations like `x * 1` in the generated code, which can make
+    /// bytecode easier to follow when debugging.
+    pub fn set_simplify(&mut self, enabled: bool) {
+        self.simplify = enabled;
+    }
+
+    /// Select the opcodes code generation may use
+    ///
+    /// With [`InstructionSet::C4`] the generated code only contains opcodes
+    /// of the reference implementation, so it can be loaded by anything
+    /// that understands C4's bytecode layout.
+    pub fn set_instruction_set(&mut self, instruction_set: InstructionSet) {
+        self.instruction_set = instruction_set;
+    }
+
+    /// Compile a unit that will be linked with others
+    ///
+    /// References to `extern` functions and globals that are not defined in
+    /// this unit are left for the linker (see [`Parser::get_symbol_relocations`])
+    /// instead of being reported, and the unit does not need a `main`.
+    pub fn set_separate_compilation(&mut self, enabled: bool) {
+        self.separate_compilation = enabled;
+    }
+
+    /// Make native functions callable from the source
+    ///
+    /// Calls compile to `NATV`, so the program must run on a VM given the
+    /// same natives (see [`VirtualMachine::set_natives`](crate::vm::VirtualMachine::set_natives)).
+    /// Must be called before [`Parser::init`].
+    pub fn set_natives(&mut self, natives: &Natives) {
+        self.natives = natives.clone();
+    }
+
     /// Initialize the parser
     pub fn init(&mut self) -> Result<(), CompilerError> {
         // Initialize system functions
@@ -85,6 +175,11 @@ impl Parser {
         self.symbol_table.add("memcmp", TokenType::Sys, Type::INT, Opcode::MCMP as i64);
         self.symbol_table.add("exit", TokenType::Sys, Type::INT, Opcode::EXIT as i64);
         self.symbol_table.add("void", TokenType::Void, Type::INT, 0);
+
+        // Native functions are represented by their index
+        for (index, native) in self.natives.iter().enumerate() {
+            self.symbol_table.add(&native.name, TokenType::Native, Type::INT, index as i64);
+        }
     }
 
     /// Get the next token from the lexer
@@ -112,6 +207,80 @@ impl Parser {
         pos
     }
 
+    /// Emit `IMM addr` for an address in the data segment
+    ///
+    /// The operand is recorded as a relocation so object files can say
+    /// which code words depend on where the data segment ends up.
+    fn emit_data_address(&mut self, addr: usize) {
+        self.emit(Opcode::IMM as i64);
+        let pos = self.emit(addr as i64);
+        self.data_relocations.push(pos);
+    }
+
+    /// Emit a placeholder operand for the address of an `extern` symbol
+    ///
+    /// The word is filled in at the end of parsing if the symbol turns out
+    /// to be defined in this unit, and by the linker otherwise.
+    fn emit_symbol_address(&mut self, name: &str) {
+        let pos = self.emit(0);
+        self.symbol_relocations.push((pos, name.to_string()));
+    }
+
+    /// Record that the code emitted next comes from the current token
+    fn mark_line(&mut self) {
+        self.debug_info.add_line(self.code.len(), self.lexer.token_location());
+    }
+
+    /// Discard generated code from `len` onwards
+    fn truncate_code(&mut self, len: usize) {
+        self.debug_info.remove_code(len, self.code.len());
+        self.code.truncate(len);
+        self.data_relocations.retain(|&pos| pos < len);
+        self.symbol_relocations.retain(|(pos, _)| *pos < len);
+    }
+
+    /// Remove a range of generated code, moving relocations after it down
+    fn drain_code(&mut self, start: usize, end: usize) {
+        self.code.drain(start..end);
+        self.debug_info.remove_code(start, end);
+        self.data_relocations.retain(|&pos| pos < start || pos >= end);
+        self.symbol_relocations.retain(|(pos, _)| *pos < start || *pos >= end);
+        let positions = self.data_relocations.iter_mut()
+            .chain(self.symbol_relocations.iter_mut().map(|(pos, _)| pos));
+        for pos in positions {
+            if *pos >= end {
+                *pos -= end - start;
+            }
+        }
+    }
+
+    /// Emit a load of the given type from the address in AX
+    fn emit_load(&mut self, typ: Type) {
+        let op = if typ == Type::CHAR { Opcode::LC } else { Opcode::LI };
+        self.last_load = Some(self.emit(op as i64));
+    }
+
+    /// Emit a store of the given type to the address on top of the stack
+    fn emit_store(&mut self, typ: Type) {
+        let op = if typ == Type::CHAR { Opcode::SC } else { Opcode::SI };
+        self.emit(op as i64);
+    }
+
+    /// Check whether the last emitted instruction is a load (an lvalue)
+    fn ends_with_load(&self) -> bool {
+        self.last_load.is_some() && self.last_load == self.code.len().checked_sub(1)
+    }
+
+    /// Build a parser error at the current source location
+    fn error(&self, message: &str, suggestion: Option<&str>) -> CompilerError {
+        CompilerError::ParserError {
+            message: message.to_string(),
+            location: Some(crate::error::SourceLocation::new(self.lexer.line(), self.lexer.column())),
+            source_line: Some(self.lexer.get_current_line()),
+            suggestion: suggestion.map(|s| s.to_string()),
+        }
+    }
+
     /// Check if current token matches the expected token, then advance
     fn match_token(&mut self, expected: TokenType) -> Result<(), CompilerError> {
         if self.current_token.token_type == expected {
@@ -121,7 +290,7 @@ impl Parser {
             let message = format!("Expected {:?}, got {:?}", expected, self.current_token.token_type);
             let location = self.lexer.line();
             let source_line = self.lexer.get_current_line();
-            
+
             let suggestion = match expected {
                 TokenType::Semicolon => Some("Add a semicolon at the end of the statement".to_string()),
                 TokenType::LBrace => Some("Add an opening brace '{'".to_string()),
@@ -130,7 +299,7 @@ impl Parser {
                 TokenType::RParen => Some("Add a closing parenthesis ')'".to_string()),
                 _ => None,
             };
-            
+
             Err(CompilerError::ParserError {
                 message,
                 location: Some(crate::error::SourceLocation::new(location, self.lexer.column())),
@@ -144,9 +313,10 @@ impl Parser {
     pub fn parse(&mut self) -> Result<(), CompilerError> {
         // Parse global declarations until end of file
         self.parse_declarations()?;
+        self.resolve_symbol_relocations()?;
 
         // Look for main function
-        if self.get_main_function().is_none() {
+        if self.get_main_function().is_none() && !self.separate_compilation {
             return Err(CompilerError::ParserError {
                 message: "main() not defined".to_string(),
                 location: None,
@@ -161,21 +331,28 @@ impl Parser {
     /// Parse global declarations
     fn parse_declarations(&mut self) -> Result<(), CompilerError> {
         while self.current_token.token_type != TokenType::Eof {
+            // `extern` declares symbols defined in another unit
+            let external = self.current_token.token_type == TokenType::Extern;
+            if external {
+                self.next_token()?;
+            }
+
             // Parse type
             let base_type = self.parse_type()?;
-            
+            let mut is_function = false;
+
             // Continue parsing declarations until we hit a semicolon or closing brace
-            while self.current_token.token_type != TokenType::Semicolon && 
+            while self.current_token.token_type != TokenType::Semicolon &&
                   self.current_token.token_type != TokenType::RBrace {
-                
+
                 let mut ty = base_type;
-                
+
                 // Handle pointer types with multiple '*'
                 while self.current_token.token_type == TokenType::Mul {
                     ty = ty.to_ptr();
                     self.next_token()?;
                 }
-                
+
                 // Parse identifier
                 if self.current_token.token_type != TokenType::Id {
                     return Err(CompilerError::ParserError {
@@ -185,7 +362,7 @@ impl Parser {
                         suggestion: None,
                     });
                 }
-                
+
                 let id_name = self.current_token.name.as_ref()
                     .ok_or_else(|| CompilerError::ParserError {
                         message: "Missing identifier name".to_string(),
@@ -194,17 +371,17 @@ impl Parser {
                         suggestion: None,
                     })?
                     .clone();
-                
+
                 self.next_token()?;
-                
+
                 // Handle array declarations
                 let mut is_array = false;
                 let mut array_size = 0;
-                
+
                 if self.current_token.token_type == TokenType::Brak {
                     is_array = true;
                     self.next_token()?;
-                    
+
                     // Parse array size
                     if self.current_token.token_type == TokenType::Num {
                         array_size = self.current_value;
@@ -217,210 +394,52 @@ impl Parser {
                             suggestion: None,
                         });
                     }
-                    
+
                     // Close bracket
                     self.match_token(TokenType::RBracket)?;
-                    
+
                     // Adjust type for array (in C4, arrays are just pointers)
                     ty = ty.to_ptr();
                 }
-                
+
                 // Check for function declaration
                 if self.current_token.token_type == TokenType::LParen {
-                    // Function declaration
                     self.next_token()?; // Skip '('
-                    
-                    // Create new function symbol
-                    let fn_addr = self.code.len();
-                    self.symbol_table.add(&id_name, TokenType::Fun, ty, fn_addr as i64);
-                    
-                    // Enter function scope
-                    self.symbol_table.enter_scope();
-                    
-                    // Reset local offset for parameters
-                    self.local_offset = 8; // Skip return address and base pointer
-                    
-                    // Parse parameters
-                    if self.current_token.token_type != TokenType::RParen {
-                        // First parameter
-                        let param_type = self.parse_type()?;
-                        
-                        // Handle pointer types in parameters
-                        let mut ptr_type = param_type;
-                        while self.current_token.token_type == TokenType::Mul {
-                            ptr_type = ptr_type.to_ptr();
-                            self.next_token()?;
-                        }
-                        
-                        if self.current_token.token_type != TokenType::Id {
-                            return Err(CompilerError::ParserError {
-                                message: "Expected parameter name".to_string(),
-                                location: Some(crate::error::SourceLocation::new(self.lexer.line(), self.lexer.column())),
-                                source_line: Some(self.lexer.get_current_line()),
-                                suggestion: None,
-                            });
-                        }
-                        
-                        let param_name = self.current_token.name.as_ref().unwrap().clone();
-                        self.next_token()?;
-                        
-                        // Add parameter to symbol table (in reversed order due to stack layout)
-                        self.symbol_table.add(&param_name, TokenType::Loc, ptr_type, self.local_offset);
-                        self.local_offset += 8; // Each parameter takes 8 bytes
-                        
-                        // More parameters
-                        while self.current_token.token_type == TokenType::Comma {
-                            self.next_token()?; // Skip ','
-                            
-                            let param_type = self.parse_type()?;
-                            
-                            // Handle pointer types in parameters
-                            let mut ptr_type = param_type;
-                            while self.current_token.token_type == TokenType::Mul {
-                                ptr_type = ptr_type.to_ptr();
-                                self.next_token()?;
-                            }
-                            
-                            if self.current_token.token_type != TokenType::Id {
-                                return Err(CompilerError::ParserError {
-                                    message: "Expected parameter name".to_string(),
-                                    location: Some(crate::error::SourceLocation::new(self.lexer.line(), self.lexer.column())),
-                                    source_line: Some(self.lexer.get_current_line()),
-                                    suggestion: None,
-                                });
-                            }
-                            
-                            let param_name = self.current_token.name.as_ref().unwrap().clone();
-                            self.next_token()?;
-                            
-                            // Add parameter to symbol table
-                            self.symbol_table.add(&param_name, TokenType::Loc, ptr_type, self.local_offset);
-                            self.local_offset += 8; // Each parameter takes 8 bytes
-                        }
-                    }
-                    
-                    self.match_token(TokenType::RParen)?;
-                    
-                    // Parse function body
-                    self.match_token(TokenType::LBrace)?;
-                    
-                    // Setup stack frame
-                    self.emit(Opcode::ENT as i64);
-                    self.emit(0); // Placeholder for local variable space
-                    
-                    // Reset local offset for local variables (negative offsets from base pointer)
-                    self.local_offset = -8;
-                    
-                    // Parse local variable declarations at the beginning of function
-                    while self.current_token.token_type == TokenType::Int || 
-                          self.current_token.token_type == TokenType::Char {
-                        
-                        let local_type = self.parse_type()?;
-                        
-                        // Parse all variables of this type
-                        while self.current_token.token_type != TokenType::Semicolon {
-                            // Handle pointer types
-                            let mut ptr_type = local_type;
-                            while self.current_token.token_type == TokenType::Mul {
-                                ptr_type = ptr_type.to_ptr();
-                                self.next_token()?;
-                            }
-                            
-                            if self.current_token.token_type != TokenType::Id {
-                                return Err(CompilerError::ParserError {
-                                    message: "Expected local variable name".to_string(),
-                                    location: Some(crate::error::SourceLocation::new(self.lexer.line(), self.lexer.column())),
-                                    source_line: Some(self.lexer.get_current_line()),
-                                    suggestion: None,
-                                });
-                            }
-                            
-                            let var_name = self.current_token.name.as_ref().unwrap().clone();
-                            self.next_token()?;
-                            
-                            // Handle array declarations
-                            if self.current_token.token_type == TokenType::Brak {
-                                self.next_token()?;
-                                
-                                // Get array size
-                                if self.current_token.token_type == TokenType::Num {
-                                    let size = self.current_value;
-                                    self.next_token()?;
-                                    
-                                    // Make space for array
-                                    self.local_offset -= 8 * size;
-                                    
-                                    // Register as pointer type
-                                    self.symbol_table.add(&var_name, TokenType::Loc, ptr_type.to_ptr(), self.local_offset);
-                                } else {
-                                    return Err(CompilerError::ParserError {
-                                        message: "Expected array size".to_string(),
-                                        location: Some(crate::error::SourceLocation::new(self.lexer.line(), self.lexer.column())),
-                                        source_line: Some(self.lexer.get_current_line()),
-                                        suggestion: None,
-                                    });
-                                }
-                                
-                                self.match_token(TokenType::RBracket)?;
-                            } else {
-                                // Regular variable
-                                self.symbol_table.add(&var_name, TokenType::Loc, ptr_type, self.local_offset);
-                                self.local_offset -= 8; // Each variable takes 8 bytes
-                            }
-                            
-                            // Check for comma for multiple declarations
-                            if self.current_token.token_type == TokenType::Comma {
-                                self.next_token()?;
-                            } else {
-                                break;
-                            }
-                        }
-                        
-                        self.match_token(TokenType::Semicolon)?;
-                    }
-                    
-                    // Update ENT instruction with local variable count
-                    self.code[fn_addr + 1] = (-self.local_offset / 8) as i64;
-                    
-                    // Parse statements
-                    while self.current_token.token_type != TokenType::RBrace {
-                        self.parse_statement()?;
+
+                    // A function definition is not followed by a semicolon;
+                    // a prototype is
+                    is_function = self.parse_function(&id_name, ty)?;
+                    break;
+                } else if external {
+                    self.declare_external(&id_name, TokenType::Glo, ty)?;
+                    if is_array {
+                        self.symbol_table.get_mut(&id_name).unwrap().array_len = Some(array_size as usize);
                     }
-                    
-                    // Add implicit return if none exists
-                    // (In C, reaching the end of a function without a return is undefined,
-                    // but in C4 we'll just return 0)
-                    if self.code.last() != Some(&(Opcode::LEV as i64)) {
-                        self.emit(Opcode::IMM as i64);
-                        self.emit(0);
-                        self.emit(Opcode::LEV as i64);
+                    if self.current_token.token_type == TokenType::Assign {
+                        return Err(self.error("An extern declaration cannot have an initializer", None));
                     }
-                    
-                    self.match_token(TokenType::RBrace)?;
-                    
-                    // Exit function scope
-                    self.symbol_table.exit_scope();
                 } else {
                     // Global variable declaration
                     if is_array {
                         // Allocate array space
                         let var_addr = self.data.len();
-                        self.data.resize(var_addr + (array_size as usize) * ty.size(), 0);
-                        
+                        self.data.resize(var_addr + (array_size as usize) * ty.elem_size(), 0);
+
                         // Register as pointer type
-                        self.symbol_table.add(&id_name, TokenType::Glo, ty, var_addr as i64);
+                        self.define_global(&id_name, TokenType::Glo, ty, var_addr as i64)?;
+                        self.symbol_table.get_mut(&id_name).unwrap().array_len = Some(array_size as usize);
                     } else {
                         // Regular variable
                         let var_addr = self.data.len();
                         self.data.resize(var_addr + ty.size(), 0);
-                        
-                        self.symbol_table.add(&id_name, TokenType::Glo, ty, var_addr as i64);
+
+                        self.define_global(&id_name, TokenType::Glo, ty, var_addr as i64)?;
                     }
-                    
+
                     // Check for initialization
                     if self.current_token.token_type == TokenType::Assign {
                         self.next_token()?; // Skip '='
-                        
+
                         // For now, we only support numeric initializers at global scope
                         if self.current_token.token_type != TokenType::Num {
                             return Err(CompilerError::ParserError {
@@ -430,15 +449,15 @@ impl Parser {
                                 suggestion: None,
                             });
                         }
-                        
+
                         // Store the initializer value
                         let value = self.current_value;
                         self.next_token()?;
-                        
+
                         // Update the data segment (crude, but works for POD types)
                         let var = self.symbol_table.get(&id_name).unwrap();
                         let addr = var.value as usize;
-                        
+
                         match ty {
                             Type::CHAR => {
                                 if addr < self.data.len() {
@@ -448,16 +467,13 @@ impl Parser {
                             _ => {
                                 // For INT and PTR, store as 64-bit value
                                 if addr + 8 <= self.data.len() {
-                                    let bytes = value.to_le_bytes();
-                                    for i in 0..8 {
-                                        self.data[addr + i] = bytes[i];
-                                    }
+                                    self.data[addr..addr + 8].copy_from_slice(&value.to_le_bytes());
                                 }
                             }
                         }
                     }
                 }
-                
+
                 // Check for comma for multiple declarations
                 if self.current_token.token_type == TokenType::Comma {
                     self.next_token()?;
@@ -465,11 +481,14 @@ impl Parser {
                     break;
                 }
             }
-            
+
             // Skip semicolon
+            if is_function {
+                continue;
+            }
             if self.current_token.token_type == TokenType::Semicolon {
                 self.next_token()?;
-            } else if self.current_token.token_type != TokenType::RBrace && 
+            } else if self.current_token.token_type != TokenType::RBrace &&
                       self.current_token.token_type != TokenType::Eof {
                 return Err(CompilerError::ParserError {
                     message: "Expected semicolon after declaration".to_string(),
@@ -483,6 +502,390 @@ impl Parser {
         Ok(())
     }
 
+    /// Parse a function definition or prototype after its opening parenthesis
+    ///
+    /// Parameters are addressed above the saved base pointer and return
+    /// address, the first parameter furthest away (as in C4.c). Locals live
+    /// below the base pointer; the `ENT` operand is patched once the whole
+    /// body has been seen so it covers every block's declarations.
+    ///
+    /// A prototype declares a function that is defined later or in another
+    /// unit. Returns whether a body was parsed.
+    fn parse_function(&mut self, name: &str, ty: Type) -> Result<bool, CompilerError> {
+        // Parse parameters
+        let mut params = Vec::new();
+        if self.current_token.token_type == TokenType::Void {
+            self.next_token()?; // `f(void)` has no parameters
+        }
+        while self.current_token.token_type != TokenType::RParen {
+            let mut param_type = self.parse_type()?;
+
+            // Handle pointer types in parameters
+            while self.current_token.token_type == TokenType::Mul {
+                param_type = param_type.to_ptr();
+                self.next_token()?;
+            }
+
+            if self.current_token.token_type != TokenType::Id {
+                return Err(self.error("Expected parameter name", None));
+            }
+
+            params.push((self.current_token.name.clone().unwrap(), param_type));
+            self.next_token()?;
+
+            if self.current_token.token_type == TokenType::Comma {
+                self.next_token()?; // Skip ','
+            } else {
+                break;
+            }
+        }
+
+        self.match_token(TokenType::RParen)?;
+
+        // A prototype ends here
+        if self.current_token.token_type == TokenType::Semicolon {
+            self.declare_external(name, TokenType::Fun, ty)?;
+            return Ok(false);
+        }
+
+        // Create new function symbol
+        let fn_addr = self.code.len();
+        self.define_global(name, TokenType::Fun, ty, fn_addr as i64)?;
+
+        // Enter function scope
+        self.enter_scope();
+
+        // Add parameters to symbol table (in reversed order due to stack layout)
+        let param_count = params.len() as i64;
+        for (i, (param_name, param_type)) in params.iter().enumerate() {
+            self.add_local(param_name, *param_type, param_count + 1 - i as i64, None);
+        }
+
+        // Parse function body
+        self.mark_line();
+        self.match_token(TokenType::LBrace)?;
+
+        // Setup stack frame
+        self.emit(Opcode::ENT as i64);
+        self.emit(0); // Placeholder for local variable space
+
+        self.local_offset = 0;
+        self.frame_size = 0;
+
+        // Parse declarations and statements
+        while self.current_token.token_type != TokenType::RBrace {
+            if self.current_token.token_type == TokenType::Eof {
+                return Err(self.error("Unexpected end of file in function body", Some("Add a closing brace '}'")));
+            }
+            self.parse_block_item()?;
+        }
+
+        // Update ENT instruction with local variable count
+        self.code[fn_addr + 1] = self.frame_size;
+
+        // Add implicit return
+        // (In C, reaching the end of a function without a return is undefined,
+        // but in C4 we'll just return 0)
+        self.mark_line();
+        self.emit(Opcode::IMM as i64);
+        self.emit(0);
+        self.emit(Opcode::LEV as i64);
+
+        self.match_token(TokenType::RBrace)?;
+
+        // Exit function scope
+        self.exit_scope();
+        self.debug_info.functions.push(FunctionRange {
+            name: name.to_string(),
+            start: fn_addr,
+            end: self.code.len(),
+            file: None,
+            locals: std::mem::take(&mut self.locals),
+        });
+
+        Ok(true)
+    }
+
+    /// Declare a function or global that is defined later or elsewhere
+    fn declare_external(&mut self, name: &str, class: TokenType, typ: Type) -> Result<(), CompilerError> {
+        match self.symbol_table.get(name) {
+            // Already declared or defined in this unit
+            Some(sym) if sym.class == class => Ok(()),
+            Some(sym) if matches!(sym.class, TokenType::Fun | TokenType::Glo | TokenType::Sys | TokenType::Native | TokenType::Num) => {
+                Err(self.error(&format!("'{}' redeclared as a different kind of symbol", name), None))
+            },
+            _ => {
+                let index = self.symbol_table.add(name, class, typ, 0);
+                self.symbol_table.get_by_index_mut(index).unwrap().external = true;
+                Ok(())
+            },
+        }
+    }
+
+    /// Define a function or global, completing any earlier declaration
+    fn define_global(&mut self, name: &str, class: TokenType, typ: Type, value: i64) -> Result<(), CompilerError> {
+        match self.symbol_table.get(name) {
+            Some(sym) if sym.external && sym.class != class => {
+                Err(self.error(&format!("'{}' redeclared as a different kind of symbol", name), None))
+            },
+            Some(sym) if sym.external => {
+                let sym = self.symbol_table.get_mut(name).unwrap();
+                sym.external = false;
+                sym.typ = typ;
+                sym.value = value;
+                Ok(())
+            },
+            _ => {
+                self.symbol_table.add(name, class, typ, value);
+                Ok(())
+            },
+        }
+    }
+
+    /// Fill in references to `extern` symbols that this unit defines
+    ///
+    /// Whatever is still undefined is left for the linker, or reported when
+    /// compiling a single file.
+    fn resolve_symbol_relocations(&mut self) -> Result<(), CompilerError> {
+        let mut unresolved = Vec::new();
+        for (pos, name) in std::mem::take(&mut self.symbol_relocations) {
+            match self.symbol_table.get(&name) {
+                Some(sym) if !sym.external => {
+                    self.code[pos] = sym.value;
+                    if sym.class == TokenType::Glo {
+                        self.data_relocations.push(pos);
+                    }
+                },
+                Some(sym) if !self.separate_compilation => {
+                    let kind = if sym.class == TokenType::Fun { "function" } else { "variable" };
+                    return Err(CompilerError::ParserError {
+                        message: format!("Undefined {}: {}", kind, name),
+                        location: None,
+                        source_line: None,
+                        suggestion: Some("Define it here, or compile it with the file that defines it".to_string()),
+                    });
+                },
+                _ => unresolved.push((pos, name)),
+            }
+        }
+        self.data_relocations.sort_unstable();
+        self.symbol_relocations = unresolved;
+        Ok(())
+    }
+
+    /// Open a block scope
+    fn enter_scope(&mut self) {
+        self.symbol_table.enter_scope();
+        self.scope_starts.push(self.locals.len());
+    }
+
+    /// Close a block scope, ending the range of its variables here
+    fn exit_scope(&mut self) {
+        self.symbol_table.exit_scope();
+        let start = self.scope_starts.pop().unwrap_or(0);
+        let end = self.code.len();
+        for var in &mut self.locals[start..] {
+            var.end = var.end.min(end);
+        }
+    }
+
+    /// Add a parameter or local variable to the current scope
+    fn add_local(&mut self, name: &str, typ: Type, offset: i64, array_len: Option<usize>) {
+        let index = self.symbol_table.add(name, TokenType::Loc, typ, offset);
+        if let Some(sym) = self.symbol_table.get_by_index_mut(index) {
+            sym.array_len = array_len;
+        }
+        self.locals.push(LocalVariable {
+            name: name.to_string(),
+            typ,
+            offset,
+            array_len,
+            start: self.code.len(),
+            end: usize::MAX,
+        });
+    }
+
+    /// Parse one item of a block: a local declaration or a statement
+    fn parse_block_item(&mut self) -> Result<(), CompilerError> {
+        match self.current_token.token_type {
+            TokenType::Int | TokenType::Char => self.parse_local_declaration(),
+            _ => self.parse_statement(),
+        }
+    }
+
+    /// Reserve `words` stack words for a local and return its base offset
+    fn allocate_local(&mut self, words: i64) -> i64 {
+        self.local_offset += words;
+        self.frame_size = self.frame_size.max(self.local_offset);
+        -self.local_offset
+    }
+
+    /// Parse a local declaration such as `int x = 5, *p, a[3] = {1, 2, 3};`
+    fn parse_local_declaration(&mut self) -> Result<(), CompilerError> {
+        self.mark_line();
+        let local_type = self.parse_type()?;
+
+        loop {
+            // Handle pointer types
+            let mut ty = local_type;
+            while self.current_token.token_type == TokenType::Mul {
+                ty = ty.to_ptr();
+                self.next_token()?;
+            }
+
+            if self.current_token.token_type != TokenType::Id {
+                return Err(self.error("Expected local variable name", None));
+            }
+
+            let var_name = self.current_token.name.clone().unwrap();
+            self.next_token()?;
+
+            if self.current_token.token_type == TokenType::Brak {
+                self.parse_local_array(&var_name, ty)?;
+            } else {
+                // Regular variable, visible from its own initializer on
+                let offset = self.allocate_local(1);
+                self.add_local(&var_name, ty, offset, None);
+
+                if self.current_token.token_type == TokenType::Assign {
+                    self.next_token()?; // Skip '='
+                    self.emit(Opcode::LEA as i64);
+                    self.emit(offset);
+                    self.emit(Opcode::PSH as i64);
+                    self.parse_assignment_expression()?;
+                    self.emit_store(ty);
+                }
+            }
+
+            // Check for comma for multiple declarations
+            if self.current_token.token_type == TokenType::Comma {
+                self.next_token()?;
+            } else {
+                break;
+            }
+        }
+
+        self.match_token(TokenType::Semicolon)
+    }
+
+    /// Parse a local array declarator after its name
+    ///
+    /// The size may be omitted when an initializer is present. Elements not
+    /// covered by a brace list are zeroed, as in C. Because the array's
+    /// storage is only allocated once its size is known, the `LEA` operands
+    /// of the initializer code are patched afterwards.
+    fn parse_local_array(&mut self, name: &str, elem_type: Type) -> Result<(), CompilerError> {
+        self.next_token()?; // Skip '['
+
+        let mut size = None;
+        if self.current_token.token_type == TokenType::Num {
+            if self.current_value <= 0 {
+                return Err(self.error("Array size must be positive", None));
+            }
+            size = Some(self.current_value as usize);
+            self.next_token()?;
+        }
+        self.match_token(TokenType::RBracket)?;
+
+        let elem_size = elem_type.size();
+        // (position of LEA operand, byte offset of the element)
+        let mut fixups: Vec<(usize, usize)> = Vec::new();
+        let mut count = 0;
+
+        if self.current_token.token_type == TokenType::Assign {
+            self.next_token()?; // Skip '='
+
+            if elem_type == Type::CHAR && self.is_string_token() {
+                // char s[] = "text";
+                let mut bytes = self.current_token.name.clone().unwrap().into_bytes();
+                self.next_token()?;
+                bytes.push(0);
+                for (i, byte) in bytes.iter().enumerate() {
+                    fixups.push((self.emit_element_address(i * elem_size), i * elem_size));
+                    self.emit(Opcode::PSH as i64);
+                    self.emit(Opcode::IMM as i64);
+                    self.emit(*byte as i64);
+                    self.emit_store(elem_type);
+                }
+                count = bytes.len();
+            } else {
+                self.match_token(TokenType::LBrace)?;
+                while self.current_token.token_type != TokenType::RBrace {
+                    fixups.push((self.emit_element_address(count * elem_size), count * elem_size));
+                    self.emit(Opcode::PSH as i64);
+                    self.parse_assignment_expression()?;
+                    self.emit_store(elem_type);
+                    count += 1;
+
+                    if self.current_token.token_type == TokenType::Comma {
+                        self.next_token()?;
+                    } else {
+                        break;
+                    }
+                }
+                self.match_token(TokenType::RBrace)?;
+            }
+
+            if let Some(n) = size {
+                if count > n {
+                    return Err(self.error("Too many initializers for array", None));
+                }
+            }
+        }
+
+        let len = match size.or(if count > 0 { Some(count) } else { None }) {
+            Some(len) => len,
+            None => return Err(self.error("Expected array size", Some("Give the array a size or an initializer"))),
+        };
+
+        // Zero the elements the initializer did not cover
+        if count > 0 && count < len {
+            let start = count * elem_size;
+            fixups.push((self.emit_element_address(start), start));
+            self.emit(Opcode::PSH as i64);
+            self.emit(Opcode::IMM as i64);
+            self.emit(0);
+            self.emit(Opcode::PSH as i64);
+            self.emit(Opcode::IMM as i64);
+            self.emit(((len - count) * elem_size) as i64);
+            self.emit(Opcode::PSH as i64);
+            self.emit(Opcode::MSET as i64);
+            self.emit(Opcode::ADJ as i64);
+            self.emit(3);
+        }
+
+        let words = (len * elem_size).div_ceil(8) as i64;
+        let base = self.allocate_local(words);
+        for (pos, byte_offset) in fixups {
+            self.code[pos] = base + (byte_offset / 8) as i64;
+        }
+
+        self.add_local(name, elem_type.to_ptr(), base, Some(len));
+
+        Ok(())
+    }
+
+    /// Emit code leaving the address of a local array element in AX
+    ///
+    /// Returns the position of the `LEA` operand, which still needs the
+    /// array's base offset added.
+    fn emit_element_address(&mut self, byte_offset: usize) -> usize {
+        self.emit(Opcode::LEA as i64);
+        let pos = self.emit(0);
+        if !byte_offset.is_multiple_of(8) {
+            self.emit(Opcode::PSH as i64);
+            self.emit(Opcode::IMM as i64);
+            self.emit((byte_offset % 8) as i64);
+            self.emit(Opcode::ADD as i64);
+        }
+        pos
+    }
+
+    /// Check whether the current token is a string literal
+    fn is_string_token(&self) -> bool {
+        self.current_token.token_type == TokenType::Num && self.current_token.name.is_some()
+    }
+
     /// Parse a type (int, char, etc.)
     fn parse_type(&mut self) -> Result<Type, CompilerError> {
         // Default to int
@@ -584,6 +987,7 @@ impl Parser {
 
     /// Parse a statement
     fn parse_statement(&mut self) -> Result<(), CompilerError> {
+        self.mark_line();
         match self.current_token.token_type {
             TokenType::If => {
                 self.next_token()?; // Skip 'if'
@@ -601,17 +1005,17 @@ impl Parser {
                 // Check for 'else'
                 let else_jump_addr = if self.current_token.token_type == TokenType::Else {
                     self.next_token()?; // Skip 'else'
-                    
+
                     // Jump over else block
                     let jmp_addr = self.emit(Opcode::JMP as i64);
                     self.emit(0); // Placeholder for jump address
-                    
+
                     // Update the 'if' branch target to jump to the 'else' part
                     self.code[jz_addr + 1] = self.code.len() as i64;
-                    
+
                     // Parse 'else' body
                     self.parse_statement()?;
-                    
+
                     // Return the address of the jump after the 'if' block
                     Some(jmp_addr)
                 } else {
@@ -627,10 +1031,10 @@ impl Parser {
             },
             TokenType::While => {
                 self.next_token()?; // Skip 'while'
-                
+
                 // Save the start address for loop condition
                 let loop_start = self.code.len();
-                
+
                 self.match_token(TokenType::LParen)?;
                 self.parse_expression()?;
                 self.match_token(TokenType::RParen)?;
@@ -651,7 +1055,7 @@ impl Parser {
             },
             TokenType::Return => {
                 self.next_token()?; // Skip 'return'
-                
+
                 // Parse return value (if any)
                 if self.current_token.token_type != TokenType::Semicolon {
                     self.parse_expression()?;
@@ -660,20 +1064,28 @@ impl Parser {
                     self.emit(Opcode::IMM as i64);
                     self.emit(0);
                 }
-                
+
                 // Return from function
                 self.emit(Opcode::LEV as i64);
                 self.match_token(TokenType::Semicolon)?;
             },
             TokenType::LBrace => {
                 self.next_token()?; // Skip '{'
-                
-                // Parse all statements in the block
-                while self.current_token.token_type != TokenType::RBrace && 
+
+                // Declarations in the block go out of scope at its end, and
+                // their stack slots can be reused by later blocks
+                self.enter_scope();
+                let saved_offset = self.local_offset;
+
+                // Parse all declarations and statements in the block
+                while self.current_token.token_type != TokenType::RBrace &&
                       self.current_token.token_type != TokenType::Eof {
-                    self.parse_statement()?;
+                    self.parse_block_item()?;
                 }
-                
+
+                self.local_offset = saved_offset;
+                self.exit_scope();
+
                 self.match_token(TokenType::RBrace)?;
             },
             TokenType::Semicolon => {
@@ -681,16 +1093,9 @@ impl Parser {
                 self.next_token()?;
             },
             _ => {
-                // Expression statement
+                // Expression statement (the result in AX is simply discarded)
                 self.parse_expression()?;
                 self.match_token(TokenType::Semicolon)?;
-                
-                // Discard the expression result
-                // (in C, expression statement results are discarded)
-                // In some cases we need to adjust the stack
-                if self.code.last() == Some(&(Opcode::PSH as i64)) {
-                    self.code.pop(); // Remove the PSH instruction
-                }
             }
         }
 
@@ -703,357 +1108,710 @@ impl Parser {
     }
 
     /// Parse an assignment expression
+    ///
+    /// The left-hand side is parsed as an ordinary expression; if an `=`
+    /// follows, its trailing load is turned into a push of the address
+    /// (the same trick C4.c uses).
     fn parse_assignment_expression(&mut self) -> Result<(), CompilerError> {
-        // First, check if this is an assignment
-        if self.current_token.token_type == TokenType::Id {
-            let var_name = self.current_token.name.as_ref().unwrap().clone();
-            
-            // Get variable info first
-            let var = self.symbol_table.get(&var_name).cloned();
-            
-            // Look ahead to see if the next token is '='
-            let is_assignment = match self.peek_next_token()? {
-                TokenType::Assign => true,
-                _ => false,
-            };
-            
-            if is_assignment {
-                // This is an assignment expression
-                self.next_token()?; // Skip identifier
-                self.next_token()?; // Skip '='
-                
-                if let Some(var) = var {
-                    // Load variable address
-                    match var.class {
-                        TokenType::Loc => {
-                            self.emit(Opcode::LEA as i64);
-                            self.emit(var.value);
-                        },
-                        TokenType::Glo => {
-                            self.emit(Opcode::IMM as i64);
-                            self.emit(var.value);
-                        },
-                        _ => {
-                            return Err(CompilerError::ParserError {
-                                message: format!("Cannot assign to {}", var_name),
-                                location: Some(crate::error::SourceLocation::new(self.lexer.line(), self.lexer.column())),
-                                source_line: Some(self.lexer.get_current_line()),
-                                suggestion: None,
-                            });
-                        }
-                    }
-                    
-                    // Push address to stack
-                    self.emit(Opcode::PSH as i64);
-                    
-                    // Parse the right-hand side
-                    self.parse_expression()?;
-                    
-                    // Store the result
-                    if var.typ == Type::CHAR {
-                        self.emit(Opcode::SC as i64);
-                    } else {
-                        self.emit(Opcode::SI as i64);
-                    }
-                    
-                    return Ok(());
-                }
+        self.parse_conditional_expression()?;
+
+        if self.current_token.token_type == TokenType::Assign {
+            let ty = self.current_type;
+            if !self.ends_with_load() {
+                return Err(self.error("Bad lvalue in assignment", Some("Only variables, dereferenced pointers and array elements can be assigned")));
             }
+            self.code.pop();
+            self.last_load = None;
+            self.emit(Opcode::PSH as i64);
+
+            self.next_token()?; // Skip '='
+            self.parse_assignment_expression()?;
+
+            self.emit_store(ty);
+            self.current_type = ty;
         }
-        
-        // Not an assignment, parse as conditional expression
-        self.parse_logical_or_expression()
+
+        Ok(())
+    }
+
+    /// Parse a conditional (`?:`) expression
+    fn parse_conditional_expression(&mut self) -> Result<(), CompilerError> {
+        self.parse_logical_or_expression()?;
+
+        if self.current_token.token_type == TokenType::Cond {
+            self.next_token()?;
+
+            let bz_addr = self.emit(Opcode::BZ as i64);
+            self.emit(0); // Placeholder for the false branch
+            self.parse_assignment_expression()?;
+            self.match_token(TokenType::Colon)?;
+
+            let jmp_addr = self.emit(Opcode::JMP as i64);
+            self.emit(0); // Placeholder for the end
+            self.code[bz_addr + 1] = self.code.len() as i64;
+
+            self.parse_conditional_expression()?;
+            self.code[jmp_addr + 1] = self.code.len() as i64;
+        }
+
+        Ok(())
     }
 
     /// Parse a logical OR expression
     fn parse_logical_or_expression(&mut self) -> Result<(), CompilerError> {
+        let start = self.code.len();
         self.parse_logical_and_expression()?;
-        
+
         while self.current_token.token_type == TokenType::Lor {
-            self.emit(Opcode::PSH as i64);
+            let left_type = self.current_type;
             self.next_token()?;
-            
+
             // Mark location for jump if true
             let jnz_addr = self.emit(Opcode::BNZ as i64);
             self.emit(0); // Placeholder for jump address
-            
+
             // Evaluate right side of OR
             self.parse_logical_and_expression()?;
-            
+
             // Update jump address to current location
             self.code[jnz_addr + 1] = self.code.len() as i64;
+            self.fold_short_circuit(start, jnz_addr, left_type, true);
+            self.current_type = Type::INT;
         }
-        
+
         Ok(())
     }
-    
+
     /// Parse a logical AND expression
     fn parse_logical_and_expression(&mut self) -> Result<(), CompilerError> {
+        let start = self.code.len();
         self.parse_bitwise_or_expression()?;
-        
+
         while self.current_token.token_type == TokenType::Lan {
-            self.emit(Opcode::PSH as i64);
+            let left_type = self.current_type;
             self.next_token()?;
-            
+
             // Mark location for jump if false
             let jz_addr = self.emit(Opcode::BZ as i64);
             self.emit(0); // Placeholder for jump address
-            
+
             // Evaluate right side of AND
             self.parse_bitwise_or_expression()?;
-            
+
             // Update jump address to current location
             self.code[jz_addr + 1] = self.code.len() as i64;
+            self.fold_short_circuit(start, jz_addr, left_type, false);
+            self.current_type = Type::INT;
         }
-        
+
         Ok(())
     }
-    
+
     /// Parse a bitwise OR expression
     fn parse_bitwise_or_expression(&mut self) -> Result<(), CompilerError> {
+        let start = self.code.len();
         self.parse_bitwise_xor_expression()?;
-        
+
         while self.current_token.token_type == TokenType::Or {
+            let left_type = self.current_type;
             self.emit(Opcode::PSH as i64);
             self.next_token()?;
+            let rhs_start = self.code.len();
             self.parse_bitwise_xor_expression()?;
-            self.emit(Opcode::OR as i64);
+            self.emit_binary(Opcode::OR, start, rhs_start, left_type, self.current_type);
+            self.current_type = Type::INT;
         }
-        
+
         Ok(())
     }
-    
+
     /// Parse a bitwise XOR expression
     fn parse_bitwise_xor_expression(&mut self) -> Result<(), CompilerError> {
+        let start = self.code.len();
         self.parse_bitwise_and_expression()?;
-        
+
         while self.current_token.token_type == TokenType::Xor {
+            let left_type = self.current_type;
             self.emit(Opcode::PSH as i64);
             self.next_token()?;
+            let rhs_start = self.code.len();
             self.parse_bitwise_and_expression()?;
-            self.emit(Opcode::XOR as i64);
+            self.emit_binary(Opcode::XOR, start, rhs_start, left_type, self.current_type);
+            self.current_type = Type::INT;
         }
-        
+
         Ok(())
     }
-    
+
     /// Parse a bitwise AND expression
     fn parse_bitwise_and_expression(&mut self) -> Result<(), CompilerError> {
+        let start = self.code.len();
         self.parse_equality_expression()?;
-        
+
         while self.current_token.token_type == TokenType::And {
+            let left_type = self.current_type;
             self.emit(Opcode::PSH as i64);
             self.next_token()?;
+            let rhs_start = self.code.len();
             self.parse_equality_expression()?;
-            self.emit(Opcode::AND as i64);
+            self.emit_binary(Opcode::AND, start, rhs_start, left_type, self.current_type);
+            self.current_type = Type::INT;
         }
-        
+
         Ok(())
     }
-    
+
     /// Parse an equality expression
     fn parse_equality_expression(&mut self) -> Result<(), CompilerError> {
+        let start = self.code.len();
         self.parse_relational_expression()?;
-        
-        while self.current_token.token_type == TokenType::Eq || 
+
+        while self.current_token.token_type == TokenType::Eq ||
               self.current_token.token_type == TokenType::Ne {
-            let op = self.current_token.token_type;
+            let op = match self.current_token.token_type {
+                TokenType::Eq => Opcode::EQ,
+                _ => Opcode::NE,
+            };
+            let left_type = self.current_type;
             self.emit(Opcode::PSH as i64);
             self.next_token()?;
+            let rhs_start = self.code.len();
             self.parse_relational_expression()?;
-            
-            match op {
-                TokenType::Eq => { self.emit(Opcode::EQ as i64); },
-                TokenType::Ne => { self.emit(Opcode::NE as i64); },
-                _ => unreachable!(),
-            }
+            self.emit_binary(op, start, rhs_start, left_type, self.current_type);
+            self.current_type = Type::INT;
         }
-        
+
         Ok(())
     }
-    
+
     /// Parse a relational expression
     fn parse_relational_expression(&mut self) -> Result<(), CompilerError> {
+        let start = self.code.len();
         self.parse_shift_expression()?;
-        
-        while self.current_token.token_type == TokenType::Lt || 
+
+        while self.current_token.token_type == TokenType::Lt ||
               self.current_token.token_type == TokenType::Gt ||
               self.current_token.token_type == TokenType::Le ||
               self.current_token.token_type == TokenType::Ge {
-            let op = self.current_token.token_type;
+            let op = match self.current_token.token_type {
+                TokenType::Lt => Opcode::LT,
+                TokenType::Gt => Opcode::GT,
+                TokenType::Le => Opcode::LE,
+                _ => Opcode::GE,
+            };
+            let left_type = self.current_type;
             self.emit(Opcode::PSH as i64);
             self.next_token()?;
+            let rhs_start = self.code.len();
             self.parse_shift_expression()?;
-            
-            match op {
-                TokenType::Lt => { self.emit(Opcode::LT as i64); },
-                TokenType::Gt => { self.emit(Opcode::GT as i64); },
-                TokenType::Le => { self.emit(Opcode::LE as i64); },
-                TokenType::Ge => { self.emit(Opcode::GE as i64); },
-                _ => unreachable!(),
-            }
+            self.emit_binary(op, start, rhs_start, left_type, self.current_type);
+            self.current_type = Type::INT;
         }
-        
+
         Ok(())
     }
-    
+
     /// Parse a shift expression
     fn parse_shift_expression(&mut self) -> Result<(), CompilerError> {
+        let start = self.code.len();
         self.parse_additive_expression()?;
-        
-        while self.current_token.token_type == TokenType::Shl || 
+
+        while self.current_token.token_type == TokenType::Shl ||
               self.current_token.token_type == TokenType::Shr {
-            let op = self.current_token.token_type;
+            let op = match self.current_token.token_type {
+                TokenType::Shl => Opcode::SHL,
+                _ => Opcode::SHR,
+            };
+            let left_type = self.current_type;
             self.emit(Opcode::PSH as i64);
             self.next_token()?;
+            let rhs_start = self.code.len();
             self.parse_additive_expression()?;
-            
-            match op {
-                TokenType::Shl => { self.emit(Opcode::SHL as i64); },
-                TokenType::Shr => { self.emit(Opcode::SHR as i64); },
-                _ => unreachable!(),
-            }
+            self.emit_binary(op, start, rhs_start, left_type, self.current_type);
+            self.current_type = Type::INT;
         }
-        
+
         Ok(())
     }
-    
+
     /// Parse an additive expression
+    ///
+    /// Integers added to or subtracted from pointers are scaled by the
+    /// pointed-to element size; the difference of two pointers is divided
+    /// by it.
     fn parse_additive_expression(&mut self) -> Result<(), CompilerError> {
+        let start = self.code.len();
         self.parse_multiplicative_expression()?;
-        
-        while self.current_token.token_type == TokenType::Add || 
+
+        while self.current_token.token_type == TokenType::Add ||
               self.current_token.token_type == TokenType::Sub {
-            let op = self.current_token.token_type;
+            let op = match self.current_token.token_type {
+                TokenType::Add => Opcode::ADD,
+                _ => Opcode::SUB,
+            };
+            let left_type = self.current_type;
             self.emit(Opcode::PSH as i64);
             self.next_token()?;
+            let rhs_start = self.code.len();
             self.parse_multiplicative_expression()?;
-            
-            match op {
-                TokenType::Add => { self.emit(Opcode::ADD as i64); },
-                TokenType::Sub => { self.emit(Opcode::SUB as i64); },
-                _ => unreachable!(),
+            let right_type = self.current_type;
+
+            let scale = if left_type.is_ptr() { left_type.elem_size() as i64 } else { 1 };
+
+            if op == Opcode::SUB && left_type.is_ptr() && left_type == right_type {
+                self.emit_binary(Opcode::SUB, start, rhs_start, left_type, right_type);
+                if scale > 1 {
+                    self.emit(Opcode::PSH as i64);
+                    let scale_start = self.emit(Opcode::IMM as i64);
+                    self.emit(scale);
+                    self.emit_binary(Opcode::DIV, start, scale_start, Type::INT, Type::INT);
+                }
+                self.current_type = Type::INT;
+                continue;
+            }
+
+            if scale > 1 {
+                self.emit(Opcode::PSH as i64);
+                let scale_start = self.emit(Opcode::IMM as i64);
+                self.emit(scale);
+                self.emit_binary(Opcode::MUL, rhs_start, scale_start, right_type, Type::INT);
             }
+
+            self.emit_binary(op, start, rhs_start, left_type, right_type);
+            self.current_type = left_type;
         }
-        
+
         Ok(())
     }
-    
+
     /// Parse a multiplicative expression
     fn parse_multiplicative_expression(&mut self) -> Result<(), CompilerError> {
+        let start = self.code.len();
         self.parse_unary_expression()?;
-        
-        while self.current_token.token_type == TokenType::Mul || 
+
+        while self.current_token.token_type == TokenType::Mul ||
               self.current_token.token_type == TokenType::Div ||
               self.current_token.token_type == TokenType::Mod {
-            let op = self.current_token.token_type;
+            let op = match self.current_token.token_type {
+                TokenType::Mul => Opcode::MUL,
+                TokenType::Div => Opcode::DIV,
+                _ => Opcode::MOD,
+            };
+            let left_type = self.current_type;
+            let location = self.lexer.token_location();
             self.emit(Opcode::PSH as i64);
             self.next_token()?;
+            let rhs_start = self.code.len();
             self.parse_unary_expression()?;
-            
-            match op {
-                TokenType::Mul => { self.emit(Opcode::MUL as i64); },
-                TokenType::Div => { self.emit(Opcode::DIV as i64); },
-                TokenType::Mod => { self.emit(Opcode::MOD as i64); },
-                _ => unreachable!(),
+            if op != Opcode::MUL && self.constant(rhs_start, self.code.len(), self.current_type) == Some(0) {
+                self.warn("Division by zero", location);
             }
+            self.emit_binary(op, start, rhs_start, left_type, self.current_type);
+            self.current_type = Type::INT;
         }
-        
+
         Ok(())
     }
 
+    /// Value of the code in `start..end` if it is a single integer `IMM`
+    ///
+    /// Pointer-typed immediates (string literals and array addresses) are
+    /// never treated as constants.
+    fn constant(&self, start: usize, end: usize, typ: Type) -> Option<i64> {
+        if !typ.is_ptr() && end == start + 2 && self.code[start] == Opcode::IMM as i64 {
+            Some(self.code[start + 1])
+        } else {
+            None
+        }
+    }
+
+    /// Check that a stretch of code contains no jumps or branches, so it can
+    /// be moved without invalidating any target
+    fn is_straight_line(code: &[i64]) -> bool {
+        let mut i = 0;
+        while i < code.len() {
+            match Opcode::from_i64(code[i]) {
+                Some(Opcode::JMP | Opcode::BZ | Opcode::BNZ) | None => return false,
+                Some(op) => i += if op.has_operand() { 2 } else { 1 },
+            }
+        }
+        true
+    }
+
+    /// Finish a binary operation emitted as `<lhs> PSH <rhs>`
+    ///
+    /// `start` is where the left operand begins and `rhs_start` where the
+    /// right one does. Constant operands are folded and, unless disabled,
+    /// identities such as `x + 0` and `x * 1` are removed; otherwise the
+    /// operator itself is emitted.
+    fn emit_binary(&mut self, op: Opcode, start: usize, rhs_start: usize, lhs_type: Type, rhs_type: Type) {
+        let lhs = if self.code[rhs_start - 1] == Opcode::PSH as i64 {
+            self.constant(start, rhs_start - 1, lhs_type)
+        } else {
+            None
+        };
+        let rhs = self.constant(rhs_start, self.code.len(), rhs_type);
+
+        if let (Some(a), Some(b)) = (lhs, rhs) {
+            if let Some(value) = op.fold(a, b) {
+                self.truncate_code(start);
+                self.emit(Opcode::IMM as i64);
+                self.emit(value);
+                self.last_load = None;
+                return;
+            }
+        }
+
+        if self.simplify {
+            let identity = |k: i64| match op {
+                Opcode::ADD | Opcode::OR | Opcode::XOR => k == 0,
+                Opcode::MUL => k == 1,
+                Opcode::AND => k == -1,
+                _ => false,
+            };
+
+            // x + 0, x - 0, x * 1, x / 1, x << 0, ...
+            let right_identity = |k: i64| identity(k) || match op {
+                Opcode::SUB | Opcode::SHL | Opcode::SHR => k == 0,
+                Opcode::DIV => k == 1,
+                _ => false,
+            };
+            if rhs.is_some_and(right_identity) {
+                self.truncate_code(rhs_start - 1);
+                self.last_load = None;
+                return;
+            }
+
+            // 0 + x, 1 * x, ...
+            if lhs.is_some_and(identity) && Self::is_straight_line(&self.code[rhs_start..]) {
+                self.drain_code(start, rhs_start);
+                self.last_load = None;
+                return;
+            }
+        }
+
+        self.emit(op as i64);
+    }
+
+    /// Fold `&&`/`||` whose left operand is constant
+    ///
+    /// The code is `<lhs> BZ/BNZ end <rhs>`, with the branch at `branch`.
+    fn fold_short_circuit(&mut self, start: usize, branch: usize, lhs_type: Type, is_or: bool) {
+        let Some(value) = self.constant(start, branch, lhs_type) else {
+            return;
+        };
+
+        if (value != 0) == is_or {
+            // The right side never runs
+            self.truncate_code(start);
+            self.emit(Opcode::IMM as i64);
+            self.emit(value);
+            self.last_load = None;
+        } else if self.simplify && Self::is_straight_line(&self.code[branch + 2..]) {
+            // The result is the right side
+            self.drain_code(start, branch + 2);
+            self.last_load = None;
+        }
+    }
+
+    /// Record a warning about the token at `location`
+    fn warn(&mut self, message: &str, location: SourceLocation) {
+        self.warnings.push(CompilerWarning {
+            message: message.to_string(),
+            location: Some(location),
+            source_line: Some(self.lexer.source_line(location.line)),
+        });
+    }
+
     /// Parse a unary expression
     fn parse_unary_expression(&mut self) -> Result<(), CompilerError> {
         match self.current_token.token_type {
             TokenType::Add => {
                 self.next_token()?;
-                self.parse_primary_expression()
+                self.parse_unary_expression()?;
+                self.current_type = Type::INT;
+                Ok(())
             },
             TokenType::Sub => {
                 self.next_token()?;
-                self.parse_primary_expression()?;
-                self.emit(Opcode::NEG as i64);
+                let start = self.code.len();
+                self.parse_unary_expression()?;
+                if let Some(value) = self.constant(start, self.code.len(), self.current_type) {
+                    self.truncate_code(start);
+                    self.emit(Opcode::IMM as i64);
+                    self.emit(value.wrapping_neg());
+                } else if self.instruction_set == InstructionSet::C4 {
+                    // C4 has no negate instruction
+                    self.emit(Opcode::PSH as i64);
+                    self.emit(Opcode::IMM as i64);
+                    self.emit(-1);
+                    self.emit(Opcode::MUL as i64);
+                } else {
+                    self.emit(Opcode::NEG as i64);
+                }
+                self.last_load = None;
+                self.current_type = Type::INT;
                 Ok(())
             },
             TokenType::Tilde => {
+                // The lexer reports both '!' and '~' as Tilde
+                let is_not = self.current_token.value == Some('!' as i64);
+                self.next_token()?;
+                let start = self.code.len();
+                self.parse_unary_expression()?;
+                let operand_type = self.current_type;
+                self.emit(Opcode::PSH as i64);
+                let rhs_start = self.emit(Opcode::IMM as i64);
+                if is_not {
+                    self.emit(0);
+                    self.emit_binary(Opcode::EQ, start, rhs_start, operand_type, Type::INT);
+                } else {
+                    self.emit(-1);
+                    self.emit_binary(Opcode::XOR, start, rhs_start, operand_type, Type::INT);
+                }
+                self.current_type = Type::INT;
+                Ok(())
+            },
+            TokenType::Mul => {
+                self.next_token()?;
+                self.parse_unary_expression()?;
+                if !self.current_type.is_ptr() {
+                    return Err(self.error("Bad dereference", Some("Only pointers can be dereferenced")));
+                }
+                self.current_type = self.current_type.deref();
+                self.emit_load(self.current_type);
+                Ok(())
+            },
+            TokenType::And => {
+                self.next_token()?;
+                self.parse_unary_expression()?;
+                if !self.ends_with_load() {
+                    return Err(self.error("Bad address-of", None));
+                }
+                self.code.pop();
+                self.last_load = None;
+                self.current_type = self.current_type.to_ptr();
+                Ok(())
+            },
+            TokenType::Inc | TokenType::Dec => {
+                let op = self.current_token.token_type;
                 self.next_token()?;
-                self.parse_primary_expression()?;
-                self.emit(Opcode::XOR as i64);
-                self.emit(-1);
+                self.parse_unary_expression()?;
+                let ty = self.current_type;
+                self.emit_increment(op, ty, "Bad lvalue in pre-increment")?;
                 Ok(())
             },
-            _ => self.parse_primary_expression()
+            TokenType::Sizeof => {
+                self.next_token()?;
+                self.match_token(TokenType::LParen)?;
+                let mut ty = match self.current_token.token_type {
+                    TokenType::Int => Type::INT,
+                    TokenType::Char => Type::CHAR,
+                    _ => return Err(self.error("Expected type in sizeof", Some("Use sizeof(int), sizeof(char) or a pointer type"))),
+                };
+                self.next_token()?;
+                while self.current_token.token_type == TokenType::Mul {
+                    ty = ty.to_ptr();
+                    self.next_token()?;
+                }
+                self.match_token(TokenType::RParen)?;
+                self.emit(Opcode::IMM as i64);
+                self.emit(ty.size() as i64);
+                self.current_type = Type::INT;
+                Ok(())
+            },
+            _ => self.parse_postfix_expression()
+        }
+    }
+
+    /// Emit the read-modify-write sequence for `++`/`--` on the lvalue whose
+    /// load was just emitted, leaving the new value in AX
+    fn emit_increment(&mut self, op: TokenType, ty: Type, message: &str) -> Result<(), CompilerError> {
+        if !self.ends_with_load() {
+            return Err(self.error(message, None));
+        }
+        // Keep the address on the stack and reload the value
+        let load = self.code.pop().unwrap();
+        self.emit(Opcode::PSH as i64);
+        self.emit(load);
+
+        self.emit(Opcode::PSH as i64);
+        self.emit(Opcode::IMM as i64);
+        self.emit(Self::increment_step(ty));
+        self.emit(if op == TokenType::Inc { Opcode::ADD } else { Opcode::SUB } as i64);
+        self.emit_store(ty);
+        self.last_load = None;
+        Ok(())
+    }
+
+    /// Amount `++`/`--` adds to a value of the given type
+    fn increment_step(ty: Type) -> i64 {
+        if ty.is_ptr() { ty.elem_size() as i64 } else { 1 }
+    }
+
+    /// Parse a postfix expression: indexing and post-increment/decrement
+    fn parse_postfix_expression(&mut self) -> Result<(), CompilerError> {
+        let start = self.code.len();
+        self.parse_primary_expression()?;
+
+        loop {
+            match self.current_token.token_type {
+                TokenType::Brak => {
+                    let base_type = self.current_type;
+                    self.next_token()?;
+                    self.emit(Opcode::PSH as i64);
+                    let index_start = self.code.len();
+                    self.parse_expression()?;
+                    let index_type = self.current_type;
+                    self.match_token(TokenType::RBracket)?;
+
+                    if !base_type.is_ptr() {
+                        return Err(self.error("Pointer type expected", Some("Only pointers and arrays can be indexed")));
+                    }
+                    if base_type.elem_size() > 1 {
+                        self.emit(Opcode::PSH as i64);
+                        let scale_start = self.emit(Opcode::IMM as i64);
+                        self.emit(base_type.elem_size() as i64);
+                        self.emit_binary(Opcode::MUL, index_start, scale_start, index_type, Type::INT);
+                    }
+                    self.emit_binary(Opcode::ADD, start, index_start, base_type, index_type);
+                    self.current_type = base_type.deref();
+                    self.emit_load(self.current_type);
+                },
+                TokenType::Inc | TokenType::Dec => {
+                    let op = self.current_token.token_type;
+                    let ty = self.current_type;
+                    self.emit_increment(op, ty, "Bad lvalue in post-increment")?;
+
+                    // Undo the step on the value left in AX
+                    self.emit(Opcode::PSH as i64);
+                    self.emit(Opcode::IMM as i64);
+                    self.emit(Self::increment_step(ty));
+                    self.emit(if op == TokenType::Inc { Opcode::SUB } else { Opcode::ADD } as i64);
+                    self.next_token()?;
+                },
+                _ => break,
+            }
         }
+
+        Ok(())
     }
 
     /// Parse a primary expression
     fn parse_primary_expression(&mut self) -> Result<(), CompilerError> {
         match self.current_token.token_type {
+            TokenType::Num if self.is_string_token() => {
+                // String literal: place it in the data segment, merging
+                // adjacent literals as C does
+                let addr = self.data.len();
+                while self.is_string_token() {
+                    let text = self.current_token.name.clone().unwrap();
+                    self.data.extend_from_slice(text.as_bytes());
+                    self.next_token()?;
+                }
+                self.data.push(0);
+
+                self.emit_data_address(addr);
+                self.current_type = Type::CHAR.to_ptr();
+                Ok(())
+            },
             TokenType::Num => {
                 self.emit(Opcode::IMM as i64);
                 self.emit(self.current_value);
                 self.next_token()?;
+                self.current_type = Type::INT;
                 Ok(())
             },
             TokenType::Id => {
                 let id_name = self.current_token.name.as_ref().unwrap().clone();
                 self.next_token()?;
-                
+
                 if self.current_token.token_type == TokenType::LParen {
                     // Function call
                     self.next_token()?;
-                    
-                    // Parse arguments
+
+                    // Parse arguments, pushing each in order
                     let mut arg_count = 0;
-                    if self.current_token.token_type != TokenType::RParen {
-                        loop {
-                            self.parse_expression()?;
-                            arg_count += 1;
-                            
-                            if self.current_token.token_type == TokenType::RParen {
-                                break;
-                            }
-                            
-                            self.match_token(TokenType::Comma)?;
+                    while self.current_token.token_type != TokenType::RParen {
+                        self.parse_assignment_expression()?;
+                        self.emit(Opcode::PSH as i64);
+                        arg_count += 1;
+
+                        if self.current_token.token_type == TokenType::RParen {
+                            break;
                         }
+
+                        self.match_token(TokenType::Comma)?;
                     }
-                    
+
                     self.match_token(TokenType::RParen)?;
-                    
+
                     // Call function
-                    if let Some(sym) = self.symbol_table.get(&id_name).cloned() {
-                        self.emit(Opcode::JSR as i64);
-                        self.emit(sym.value);
+                    match self.symbol_table.get(&id_name).cloned() {
+                        Some(sym) if sym.class == TokenType::Sys => {
+                            self.emit(sym.value);
+                            self.current_type = sym.typ;
+                        },
+                        Some(sym) if sym.class == TokenType::Native => {
+                            if self.instruction_set == InstructionSet::C4 {
+                                return Err(self.error(&format!("Native function '{}' cannot be called in C4 bytecode", id_name), None));
+                            }
+                            let arity = self.natives.get(sym.value as usize).map_or(0, |native| native.arity);
+                            if arg_count != arity {
+                                return Err(self.error(
+                                    &format!("'{}' takes {} argument(s) but {} were given", id_name, arity, arg_count),
+                                    None,
+                                ));
+                            }
+                            self.emit(Opcode::NATV as i64);
+                            self.emit(sym.value);
+                            self.current_type = sym.typ;
+                        },
+                        Some(sym) if sym.class == TokenType::Fun => {
+                            self.emit(Opcode::JSR as i64);
+                            if sym.external {
+                                self.emit_symbol_address(&id_name);
+                            } else {
+                                self.emit(sym.value);
+                            }
+                            self.current_type = sym.typ;
+                        },
+                        _ => {
+                            return Err(CompilerError::ParserError {
+                                message: format!("Undefined function: {}", id_name),
+                                location: Some(crate::error::SourceLocation::new(self.lexer.line(), self.lexer.column())),
+                                source_line: Some(self.lexer.get_current_line()),
+                                suggestion: None,
+                            });
+                        }
+                    }
+
+                    // Pop the arguments
+                    if arg_count > 0 {
+                        self.emit(Opcode::ADJ as i64);
                         self.emit(arg_count as i64);
-                    } else {
-                        return Err(CompilerError::ParserError {
-                            message: format!("Undefined function: {}", id_name),
-                            location: Some(crate::error::SourceLocation::new(self.lexer.line(), self.lexer.column())),
-                            source_line: Some(self.lexer.get_current_line()),
-                            suggestion: None,
-                        });
                     }
                 } else {
                     // Variable
                     if let Some(sym) = self.symbol_table.get(&id_name).cloned() {
                         match sym.class {
+                            TokenType::Num => {
+                                // Enum constant
+                                self.emit(Opcode::IMM as i64);
+                                self.emit(sym.value);
+                                self.current_type = Type::INT;
+                                return Ok(());
+                            },
                             TokenType::Loc => {
                                 self.emit(Opcode::LEA as i64);
                                 self.emit(sym.value);
-                                if sym.typ == Type::CHAR {
-                                    self.emit(Opcode::LC as i64);
-                                } else {
-                                    self.emit(Opcode::LI as i64);
-                                }
                             },
-                            TokenType::Glo => {
+                            TokenType::Glo if sym.external => {
                                 self.emit(Opcode::IMM as i64);
-                                self.emit(sym.value);
-                                if sym.typ == Type::CHAR {
-                                    self.emit(Opcode::LC as i64);
-                                } else {
-                                    self.emit(Opcode::LI as i64);
-                                }
+                                self.emit_symbol_address(&id_name);
+                            },
+                            TokenType::Glo => {
+                                self.emit_data_address(sym.value as usize);
                             },
                             _ => {
                                 return Err(CompilerError::ParserError {
@@ -1064,6 +1822,12 @@ impl Parser {
                                 });
                             }
                         }
+
+                        // Arrays evaluate to their address; everything else is loaded
+                        self.current_type = sym.typ;
+                        if sym.array_len.is_none() {
+                            self.emit_load(sym.typ);
+                        }
                     } else {
                         return Err(CompilerError::ParserError {
                             message: format!("Undefined variable: {}", id_name),
@@ -1077,8 +1841,22 @@ impl Parser {
             },
             TokenType::LParen => {
                 self.next_token()?;
-                self.parse_expression()?;
-                self.match_token(TokenType::RParen)?;
+
+                if matches!(self.current_token.token_type, TokenType::Int | TokenType::Char | TokenType::Void) {
+                    // Cast (`void *` is treated as `char *`, as in C4.c)
+                    let mut ty = if self.current_token.token_type == TokenType::Int { Type::INT } else { Type::CHAR };
+                    self.next_token()?;
+                    while self.current_token.token_type == TokenType::Mul {
+                        ty = ty.to_ptr();
+                        self.next_token()?;
+                    }
+                    self.match_token(TokenType::RParen)?;
+                    self.parse_unary_expression()?;
+                    self.current_type = ty;
+                } else {
+                    self.parse_expression()?;
+                    self.match_token(TokenType::RParen)?;
+                }
                 Ok(())
             },
             _ => {
@@ -1092,17 +1870,126 @@ impl Parser {
         }
     }
 
-    /// Peek at the next token without consuming it
-    fn peek_next_token(&mut self) -> Result<TokenType, CompilerError> {
-        let current = self.current_token.clone();
-        let next = self.lexer.next_token()?;
-        self.current_token = current;
-        Ok(next.token_type)
+    /// Run the peephole optimizer over the generated code
+    ///
+    /// Function addresses in the symbol table are updated to match.
+    pub fn optimize(&mut self) {
+        self.rewrite_code(crate::peephole::optimize);
+    }
+
+    /// Replace common instruction sequences with superinstructions
+    ///
+    /// Does nothing when the instruction set is [`InstructionSet::C4`].
+    /// Run this after [`Parser::optimize`], since the optimizer only knows
+    /// the plain C4 sequences.
+    pub fn fuse(&mut self) {
+        if self.instruction_set == InstructionSet::Extended {
+            self.rewrite_code(crate::peephole::fuse);
+        }
+    }
+
+    /// Inline small functions and turn tail calls into jumps, with the
+    /// passes of [`crate::inline`]
+    ///
+    /// `TSR` is only used for the extended instruction set; with plain C4
+    /// opcodes only self-recursive tail calls are turned into jumps. Run
+    /// it after [`Parser::optimize`] and before the dataflow optimizations,
+    /// which clean up the argument copies it leaves behind. Does nothing if
+    /// the code cannot be split into functions.
+    pub fn optimize_calls(&mut self) {
+        let Ok(mut program) = ir::Program::from_parser(self) else { return };
+        let mut changed = inline::inline_calls(&mut program);
+        changed |= inline::tail_calls(&mut program, self.instruction_set == InstructionSet::Extended);
+        if changed && program.verify().is_ok() {
+            self.install(&program);
+        }
+    }
+
+    /// Run the dataflow optimizations of [`crate::dataflow`] over the
+    /// generated code
+    ///
+    /// Works on the control flow graph of each function, so run it after
+    /// [`Parser::optimize`] and before [`Parser::fuse`], and run the
+    /// peephole optimizer again afterwards to clean up what it exposes.
+    /// Does nothing if the code cannot be split into functions.
+    pub fn optimize_dataflow(&mut self) {
+        let Ok(mut program) = ir::Program::from_parser(self) else { return };
+        let frames = dataflow::optimize(&mut program);
+        if program.verify().is_err() {
+            return;
+        }
+        self.install(&program);
+
+        // Locals that were packed together move, and removed ones go
+        for func in &mut self.debug_info.functions {
+            let Some(frame) = frames.get(&func.name) else { continue };
+            func.locals.retain_mut(|var| {
+                if var.offset > 0 {
+                    return true;
+                }
+                frame.get(&var.offset).map(|&offset| var.offset = offset).is_some()
+            });
+        }
+    }
+
+    /// Replace the code with a lowered IR program, taking relocations
+    /// from the lowering, since the IR may have duplicated code, and
+    /// following function addresses and the debug info through its map
+    fn install(&mut self, program: &ir::Program) {
+        let lowered = program.to_code();
+        self.code = lowered.code;
+        self.debug_info.remap(&lowered.map);
+        self.data_relocations.clear();
+        self.symbol_relocations.clear();
+        for (pos, reloc) in lowered.relocations {
+            match reloc {
+                ir::Reloc::Data => self.data_relocations.push(pos),
+                ir::Reloc::Symbol(name) => self.symbol_relocations.push((pos, name)),
+            }
+        }
+        for sym in self.symbol_table.iter_mut() {
+            if sym.class == TokenType::Fun && !sym.external {
+                sym.value = lowered.map[sym.value as usize] as i64;
+            }
+        }
+    }
+
+    /// Apply a code rewrite that returns an old-to-new address map, and
+    /// update function addresses in the symbol table and the debug info to
+    /// match
+    fn rewrite_code(&mut self, rewrite: CodeRewrite) {
+        let entries: Vec<usize> = self.symbol_table.iter()
+            .filter(|sym| sym.class == TokenType::Fun && !sym.external)
+            .map(|sym| sym.value as usize)
+            .collect();
+
+        // Symbol references are relocated operands too, so the rewrite
+        // leaves them alone; both kinds are then followed through the map
+        let mut relocations: Vec<usize> = self.data_relocations.iter().copied()
+            .chain(self.symbol_relocations.iter().map(|(pos, _)| *pos))
+            .collect();
+        relocations.sort_unstable();
+        let map = rewrite(&mut self.code, &entries, &mut relocations);
+        self.debug_info.remap(&map);
+
+        // An operand at `pos` belongs to the instruction at `pos - 1`, which
+        // was removed if it now has the same address as the one after it
+        let moved = |pos: usize| (map[pos + 1] > map[pos - 1]).then(|| map[pos - 1] + 1);
+        self.data_relocations = self.data_relocations.iter().filter_map(|&pos| moved(pos)).collect();
+        self.symbol_relocations = std::mem::take(&mut self.symbol_relocations).into_iter()
+            .filter_map(|(pos, name)| moved(pos).map(|pos| (pos, name)))
+            .collect();
+
+        for sym in self.symbol_table.iter_mut() {
+            if sym.class == TokenType::Fun && !sym.external {
+                sym.value = map[sym.value as usize] as i64;
+            }
+        }
     }
 
     /// Get the main function symbol if it exists
     pub fn get_main_function(&self) -> Option<&Symbol> {
-        self.symbol_table.get("main")
+        self.symbol_table.get("main").filter(|sym| !sym.external)
     }
 
     /// Get the code segment
@@ -1114,4 +2001,31 @@ impl Parser {
     pub fn get_data(&self) -> &[u8] {
         &self.data
     }
-}
\ No newline at end of file
+
+    /// Get the positions of code words holding data segment addresses
+    pub fn get_data_relocations(&self) -> &[usize] {
+        &self.data_relocations
+    }
+
+    /// Get the positions of code words holding the address of an `extern`
+    /// symbol this unit does not define, with the symbol's name
+    pub fn get_symbol_relocations(&self) -> &[(usize, String)] {
+        &self.symbol_relocations
+    }
+
+    /// Get the tables mapping code addresses back to source lines and
+    /// functions
+    pub fn get_debug_info(&self) -> &DebugInfo {
+        &self.debug_info
+    }
+
+    /// Get the symbol table
+    pub fn get_symbol_table(&self) -> &SymbolTable {
+        &self.symbol_table
+    }
+
+    /// Get the warnings produced while parsing
+    pub fn get_warnings(&self) -> &[CompilerWarning] {
+        &self.warnings
+    }
+}
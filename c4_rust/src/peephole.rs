@@ -0,0 +1,491 @@
+use crate::types::Opcode;
+
+/// A decoded instruction in the peephole window
+#[derive(Debug, Clone, Copy)]
+struct Instr {
+    /// The operation
+    op: Opcode,
+    /// Operand; for jumps and branches this is an instruction index
+    arg: i64,
+    /// Whether control can arrive here other than by falling through
+    label: bool,
+    /// Whether the instruction has been removed
+    dead: bool,
+    /// Number of words the instruction had in the original code
+    width: usize,
+    /// Whether the operand is a data address that needs relocating
+    reloc: bool,
+}
+
+/// Peephole optimizer for the code segment
+///
+/// Works on the finished code vector: constant operations are folded,
+/// jump chains are threaded, and code that can never run is dropped.
+/// Because removing instructions moves code around, every jump target is
+/// rewritten and callers get a map from old to new addresses for anything
+/// else that points into the code (function symbols, the `main` entry).
+/// Operands that hold data addresses are tracked so they stay relocatable:
+/// they are never folded into other constants.
+pub struct Peephole {
+    instrs: Vec<Instr>,
+    /// Instruction indices entered from outside the code
+    entries: Vec<usize>,
+}
+
+impl Peephole {
+    /// Decode a code segment
+    ///
+    /// `entries` are addresses reached from outside the code, such as
+    /// function starts, and `relocations` the positions of operand words
+    /// holding data addresses. Returns `None` if the code cannot be decoded.
+    fn decode(code: &[i64], entries: &[usize], relocations: &[usize]) -> Option<Self> {
+        let mut instrs = Vec::new();
+        let mut index_of = vec![usize::MAX; code.len() + 1];
+        let mut pc = 0;
+        while pc < code.len() {
+            let op = Opcode::from_i64(code[pc])?;
+            index_of[pc] = instrs.len();
+            let width = if op.has_operand() { 2 } else { 1 };
+            let arg = if op.has_operand() { *code.get(pc + 1)? } else { 0 };
+            instrs.push(Instr { op, arg, label: false, dead: false, width, reloc: false });
+            pc += width;
+        }
+        index_of[code.len()] = instrs.len();
+
+        let lookup = |addr: i64| -> Option<usize> {
+            let index = *index_of.get(usize::try_from(addr).ok()?)?;
+            (index != usize::MAX).then_some(index)
+        };
+
+        // Convert targets to instruction indices
+        for instr in instrs.iter_mut() {
+            if Self::is_jump(instr.op) {
+                instr.arg = lookup(instr.arg)? as i64;
+            }
+        }
+        let entries = entries.iter()
+            .map(|&entry| lookup(entry as i64))
+            .collect::<Option<Vec<_>>>()?;
+        for &operand in relocations {
+            let index = lookup(operand.checked_sub(1)? as i64)?;
+            instrs[index].reloc = true;
+        }
+
+        Some(Peephole { instrs, entries })
+    }
+
+    /// Recompute which instructions are entered other than by falling through
+    fn relabel(&mut self) {
+        for instr in self.instrs.iter_mut() {
+            instr.label = false;
+        }
+        let mut targets = self.entries.clone();
+        targets.extend(self.instrs.iter()
+            .filter(|instr| !instr.dead && Self::is_jump(instr.op))
+            .map(|instr| instr.arg as usize));
+        for target in targets {
+            if let Some(instr) = self.instrs.get_mut(target) {
+                instr.label = true;
+            }
+        }
+    }
+
+    /// Whether the operand of an opcode is a code address
+    fn is_jump(op: Opcode) -> bool {
+        matches!(op, Opcode::JMP | Opcode::JSR | Opcode::BZ | Opcode::BNZ | Opcode::TSR)
+    }
+
+    /// Index of the first live instruction at or after `i`
+    fn next_live(&self, mut i: usize) -> usize {
+        while i < self.instrs.len() && self.instrs[i].dead {
+            i += 1;
+        }
+        i
+    }
+
+    /// Indices of the next `n` live instructions after `i`, if none of them
+    /// is a label (so the window is only ever entered at `i`)
+    fn window(&self, i: usize, n: usize) -> Option<Vec<usize>> {
+        let mut indices = Vec::with_capacity(n);
+        let mut j = i;
+        for _ in 0..n {
+            j = self.next_live(j + 1);
+            if j >= self.instrs.len() || self.instrs[j].label {
+                return None;
+            }
+            indices.push(j);
+        }
+        Some(indices)
+    }
+
+    /// Run one round of every rule, returning whether anything changed
+    fn pass(&mut self) -> bool {
+        self.relabel();
+        let mut changed = false;
+
+        for i in 0..self.instrs.len() {
+            if self.instrs[i].dead {
+                continue;
+            }
+            let instr = self.instrs[i];
+
+            match instr.op {
+                // Thread jumps to jumps, and turn jumps to a return into the return
+                Opcode::JMP | Opcode::BZ | Opcode::BNZ => {
+                    let mut target = instr.arg as usize;
+                    let mut hops = 0;
+                    loop {
+                        let next = self.next_live(target);
+                        if next >= self.instrs.len() || hops > self.instrs.len() {
+                            break;
+                        }
+                        match self.instrs[next] {
+                            Instr { op: Opcode::JMP, arg, .. } if arg as usize != target => {
+                                target = arg as usize;
+                                hops += 1;
+                            },
+                            _ => {
+                                target = next;
+                                break;
+                            },
+                        }
+                    }
+                    if target as i64 != instr.arg {
+                        self.instrs[i].arg = target as i64;
+                        self.instrs[target].label = true;
+                        changed = true;
+                    }
+
+                    if instr.op == Opcode::JMP {
+                        if self.next_live(i + 1) == target {
+                            // Jump to the next instruction
+                            self.instrs[i].dead = true;
+                            changed = true;
+                        } else if target < self.instrs.len() && self.instrs[target].op == Opcode::LEV {
+                            self.instrs[i] = Instr { op: Opcode::LEV, arg: 0, ..self.instrs[i] };
+                            changed = true;
+                        }
+                    }
+                },
+
+                // IMM a; PSH; IMM b; <op>  =>  IMM (a op b)
+                Opcode::IMM => {
+                    if let Some(w) = self.window(i, 3) {
+                        let (psh, imm, op) = (self.instrs[w[0]], self.instrs[w[1]], self.instrs[w[2]]);
+                        if psh.op == Opcode::PSH && imm.op == Opcode::IMM && !instr.reloc && !imm.reloc {
+                            if let Some(value) = op.op.fold(instr.arg, imm.arg) {
+                                self.instrs[i].arg = value;
+                                for j in w {
+                                    self.instrs[j].dead = true;
+                                }
+                                changed = true;
+                                continue;
+                            }
+                        }
+                    }
+                    changed |= self.drop_dead_value(i);
+                },
+
+                // A local whose value is overwritten before being used
+                Opcode::LEA => {
+                    changed |= self.drop_dead_value(i);
+                },
+
+                // PSH; IMM 0; ADD  =>  (nothing), and similar identities
+                Opcode::PSH => {
+                    if let Some(w) = self.window(i, 2) {
+                        let (imm, op) = (self.instrs[w[0]], self.instrs[w[1]]);
+                        let identity = imm.op == Opcode::IMM && !imm.reloc && match op.op {
+                            Opcode::ADD | Opcode::SUB | Opcode::OR | Opcode::XOR |
+                            Opcode::SHL | Opcode::SHR => imm.arg == 0,
+                            Opcode::MUL | Opcode::DIV => imm.arg == 1,
+                            Opcode::AND => imm.arg == -1,
+                            _ => false,
+                        };
+                        if identity {
+                            self.instrs[i].dead = true;
+                            for j in w {
+                                self.instrs[j].dead = true;
+                            }
+                            changed = true;
+                        }
+                    }
+                },
+
+                // ADJ 0 does nothing; consecutive adjustments combine
+                Opcode::ADJ => {
+                    if instr.arg == 0 {
+                        self.instrs[i].dead = true;
+                        changed = true;
+                    } else if let Some(w) = self.window(i, 1) {
+                        if self.instrs[w[0]].op == Opcode::ADJ {
+                            self.instrs[i].arg += self.instrs[w[0]].arg;
+                            self.instrs[w[0]].dead = true;
+                            changed = true;
+                        }
+                    }
+                },
+
+                _ => {}
+            }
+
+            // Nothing after an unconditional transfer runs until the next label
+            let instr = self.instrs[i];
+            if !instr.dead && matches!(instr.op, Opcode::JMP | Opcode::LEV | Opcode::TSR) {
+                let mut j = i + 1;
+                while j < self.instrs.len() && !self.instrs[j].label {
+                    if !self.instrs[j].dead {
+                        self.instrs[j].dead = true;
+                        changed = true;
+                    }
+                    j += 1;
+                }
+            }
+        }
+
+        changed
+    }
+
+    /// Replace common instruction sequences with superinstructions
+    ///
+    /// Arithmetic with an immediate is fused first so that `x + 1` becomes
+    /// `LLI x; ADDI 1` rather than `PSHL x; IMM 1; ADD`.
+    fn fuse(&mut self) {
+        self.relabel();
+
+        for i in 0..self.instrs.len() {
+            if self.instrs[i].dead || self.instrs[i].op != Opcode::PSH {
+                continue;
+            }
+            // PSH; IMM k; ADD  =>  ADDI k
+            if let Some(w) = self.window(i, 2) {
+                let (imm, op) = (self.instrs[w[0]], self.instrs[w[1]]);
+                let fused = match op.op {
+                    Opcode::ADD => Some(Opcode::ADDI),
+                    Opcode::SUB => Some(Opcode::SUBI),
+                    Opcode::MUL => Some(Opcode::MULI),
+                    _ => None,
+                };
+                if let (Opcode::IMM, Some(fused), false) = (imm.op, fused, imm.reloc) {
+                    self.instrs[i] = Instr { op: fused, arg: imm.arg, ..self.instrs[i] };
+                    for j in w {
+                        self.instrs[j].dead = true;
+                    }
+                }
+            }
+        }
+
+        for i in 0..self.instrs.len() {
+            if self.instrs[i].dead || self.instrs[i].op != Opcode::LEA {
+                continue;
+            }
+            // LEA n; LI; PSH  =>  PSHL n
+            if let Some(w) = self.window(i, 2) {
+                if self.instrs[w[0]].op == Opcode::LI && self.instrs[w[1]].op == Opcode::PSH {
+                    self.instrs[i].op = Opcode::PSHL;
+                    for j in w {
+                        self.instrs[j].dead = true;
+                    }
+                    continue;
+                }
+            }
+            // LEA n; LI  =>  LLI n
+            if let Some(w) = self.window(i, 1) {
+                let fused = match self.instrs[w[0]].op {
+                    Opcode::LI => Some(Opcode::LLI),
+                    Opcode::LC => Some(Opcode::LLC),
+                    _ => None,
+                };
+                if let Some(fused) = fused {
+                    self.instrs[i].op = fused;
+                    self.instrs[w[0]].dead = true;
+                }
+            }
+        }
+
+        for i in 0..self.instrs.len() {
+            if self.instrs[i].dead || self.instrs[i].op != Opcode::IMM {
+                continue;
+            }
+            // IMM k; PSH  =>  PSHI k
+            if let Some(w) = self.window(i, 1) {
+                if self.instrs[w[0]].op == Opcode::PSH {
+                    self.instrs[i].op = Opcode::PSHI;
+                    self.instrs[w[0]].dead = true;
+                }
+            }
+        }
+    }
+
+    /// Remove a value computation at `i` (`IMM`/`LEA`, optionally followed
+    /// by a load) whose result is replaced by the next instruction before
+    /// anything reads it
+    fn drop_dead_value(&mut self, i: usize) -> bool {
+        let mut next = self.next_live(i + 1);
+        let mut span = vec![i];
+        if next < self.instrs.len() && matches!(self.instrs[next].op, Opcode::LI | Opcode::LC) {
+            span.push(next);
+            next = self.next_live(next + 1);
+        }
+        if next < self.instrs.len() && matches!(self.instrs[next].op, Opcode::IMM | Opcode::LEA) {
+            for j in span {
+                self.instrs[j].dead = true;
+            }
+            return true;
+        }
+        false
+    }
+
+    /// Encode the surviving instructions
+    ///
+    /// Returns the new code, a map from every old address to its new one,
+    /// and the new relocation positions. Addresses of removed instructions
+    /// map to the next survivor.
+    fn encode(&self, old_len: usize) -> (Vec<i64>, Vec<usize>, Vec<usize>) {
+        // New address of each instruction index (removed ones fall through)
+        let mut new_addr = vec![0; self.instrs.len() + 1];
+        let mut addr = 0;
+        for (i, instr) in self.instrs.iter().enumerate() {
+            new_addr[i] = addr;
+            if !instr.dead {
+                addr += if instr.op.has_operand() { 2 } else { 1 };
+            }
+        }
+        new_addr[self.instrs.len()] = addr;
+
+        let mut code = Vec::with_capacity(addr);
+        let mut map = Vec::with_capacity(old_len + 1);
+        let mut relocations = Vec::new();
+        for (i, instr) in self.instrs.iter().enumerate() {
+            map.extend(std::iter::repeat_n(new_addr[i], instr.width));
+            if instr.dead {
+                continue;
+            }
+            if instr.reloc {
+                relocations.push(code.len() + 1);
+            }
+            code.push(instr.op as i64);
+            if Self::is_jump(instr.op) {
+                code.push(new_addr[instr.arg as usize] as i64);
+            } else if instr.op.has_operand() {
+                code.push(instr.arg);
+            }
+        }
+        map.push(addr);
+
+        (code, map, relocations)
+    }
+}
+
+/// Optimize a code segment in place
+///
+/// `entries` lists addresses entered from outside the code (function
+/// starts) and `relocations` the operand words holding data addresses,
+/// which are updated to their new positions. Returns a map from each old
+/// address to its new address, with one extra entry for the end of the
+/// code. Code that cannot be decoded is left untouched.
+pub fn optimize(code: &mut Vec<i64>, entries: &[usize], relocations: &mut Vec<usize>) -> Vec<usize> {
+    let Some(mut peephole) = Peephole::decode(code, entries, relocations) else {
+        return (0..=code.len()).collect();
+    };
+
+    // Rules enable each other, so iterate to a fixed point
+    while peephole.pass() {}
+
+    let (new_code, map, new_relocations) = peephole.encode(code.len());
+    *code = new_code;
+    *relocations = new_relocations;
+    map
+}
+
+/// Rewrite a code segment in place to use superinstructions
+///
+/// Takes the same arguments and returns the same kind of address map as
+/// [`optimize`]. Nothing is fused across a jump target, so every sequence
+/// that is replaced was only ever run from its first instruction.
+pub fn fuse(code: &mut Vec<i64>, entries: &[usize], relocations: &mut Vec<usize>) -> Vec<usize> {
+    let Some(mut peephole) = Peephole::decode(code, entries, relocations) else {
+        return (0..=code.len()).collect();
+    };
+
+    peephole.fuse();
+
+    let (new_code, map, new_relocations) = peephole.encode(code.len());
+    *code = new_code;
+    *relocations = new_relocations;
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fold_and_thread() {
+        let mut code = vec![
+            Opcode::IMM as i64, 2, Opcode::PSH as i64, Opcode::IMM as i64, 3, Opcode::ADD as i64,
+            Opcode::BZ as i64, 10,
+            Opcode::JMP as i64, 10,
+            Opcode::JMP as i64, 12,
+            Opcode::LEV as i64,
+        ];
+        let map = optimize(&mut code, &[0], &mut Vec::new());
+
+        assert_eq!(code, vec![
+            Opcode::IMM as i64, 5,
+            Opcode::BZ as i64, 5,
+            Opcode::LEV as i64,
+            Opcode::LEV as i64,
+        ]);
+        assert_eq!(map[0], 0);
+        assert_eq!(map[12], 5);
+    }
+
+    #[test]
+    fn test_labels_block_folding() {
+        // The PSH is a branch target, so the IMM/PSH/IMM/ADD window is not fused
+        let mut code = vec![
+            Opcode::IMM as i64, 2, Opcode::PSH as i64, Opcode::IMM as i64, 3, Opcode::ADD as i64,
+            Opcode::BZ as i64, 2,
+            Opcode::LEV as i64,
+        ];
+        let original = code.clone();
+        optimize(&mut code, &[0], &mut Vec::new());
+        assert_eq!(code, original);
+    }
+
+    #[test]
+    fn test_fuse_superinstructions() {
+        // x + 1 where x is the local at bp-1, passed as an argument
+        let mut code = vec![
+            Opcode::LEA as i64, -1, Opcode::LI as i64, Opcode::PSH as i64,
+            Opcode::IMM as i64, 1, Opcode::ADD as i64, Opcode::PSH as i64,
+            Opcode::IMM as i64, 7, Opcode::PSH as i64,
+            Opcode::LEA as i64, -1, Opcode::LI as i64, Opcode::PSH as i64,
+            Opcode::LEV as i64,
+        ];
+        let map = fuse(&mut code, &[0], &mut Vec::new());
+
+        assert_eq!(code, vec![
+            Opcode::LLI as i64, -1, Opcode::ADDI as i64, 1, Opcode::PSH as i64,
+            Opcode::PSHI as i64, 7, Opcode::PSHL as i64, -1,
+            Opcode::LEV as i64,
+        ]);
+        assert_eq!(map[15], 9);
+    }
+
+    #[test]
+    fn test_relocated_operands_stay_put() {
+        // "abc" + 1: the string address must not be folded away
+        let mut code = vec![
+            Opcode::IMM as i64, 16, Opcode::PSH as i64, Opcode::IMM as i64, 1, Opcode::ADD as i64,
+            Opcode::LEV as i64,
+        ];
+        let mut relocations = vec![1];
+        optimize(&mut code, &[0], &mut relocations);
+        fuse(&mut code, &[0], &mut relocations);
+
+        assert_eq!(code, vec![Opcode::IMM as i64, 16, Opcode::ADDI as i64, 1, Opcode::LEV as i64]);
+        assert_eq!(relocations, vec![1]);
+    }
+}
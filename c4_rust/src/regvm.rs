@@ -0,0 +1,1110 @@
+use crate::dataflow::clobbers_ax;
+use crate::error::{CompilerError, ResourceLimit};
+use crate::host::Host;
+use crate::instr::Instr;
+use crate::ir::{self, BlockId, Terminator};
+use crate::native::Natives;
+use crate::object::ObjectFile;
+use crate::vm::{VirtualMachine, VmLimits, STACK_BASE};
+use std::collections::HashMap;
+use std::fmt;
+
+/// A register: a word of the current frame, as an offset in words from
+/// the base pointer
+///
+/// Locals and temporaries are below the base pointer and the arguments
+/// from offset 2 up, as with `LEA`, so every register has a stack address
+/// and pointers to locals keep working.
+pub type Reg = i32;
+
+/// An operation on two values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+impl Op {
+    /// The operation of a stack instruction that pops its left operand
+    fn from_instr(instr: Instr) -> Option<Op> {
+        Some(match instr {
+            Instr::Add => Op::Add, Instr::Sub => Op::Sub, Instr::Mul => Op::Mul,
+            Instr::Div => Op::Div, Instr::Mod => Op::Mod, Instr::And => Op::And,
+            Instr::Or => Op::Or, Instr::Xor => Op::Xor, Instr::Shl => Op::Shl,
+            Instr::Shr => Op::Shr, Instr::Eq => Op::Eq, Instr::Ne => Op::Ne,
+            Instr::Lt => Op::Lt, Instr::Gt => Op::Gt, Instr::Le => Op::Le,
+            Instr::Ge => Op::Ge,
+            _ => return None,
+        })
+    }
+
+    /// The operation giving the same result with its operands swapped
+    fn swapped(self) -> Option<Op> {
+        match self {
+            Op::Add | Op::Mul | Op::And | Op::Or | Op::Xor | Op::Eq | Op::Ne => Some(self),
+            Op::Lt => Some(Op::Gt),
+            Op::Gt => Some(Op::Lt),
+            Op::Le => Some(Op::Ge),
+            Op::Ge => Some(Op::Le),
+            Op::Sub | Op::Div | Op::Mod | Op::Shl | Op::Shr => None,
+        }
+    }
+
+    /// The comparison that holds exactly when this one does not
+    fn negated(self) -> Option<Op> {
+        match self {
+            Op::Eq => Some(Op::Ne),
+            Op::Ne => Some(Op::Eq),
+            Op::Lt => Some(Op::Ge),
+            Op::Ge => Some(Op::Lt),
+            Op::Gt => Some(Op::Le),
+            Op::Le => Some(Op::Gt),
+            _ => None,
+        }
+    }
+
+    /// Apply a comparison
+    #[inline(always)]
+    fn compare(self, a: i64, b: i64) -> bool {
+        match self {
+            Op::Eq => a == b,
+            Op::Ne => a != b,
+            Op::Lt => a < b,
+            Op::Gt => a > b,
+            Op::Le => a <= b,
+            _ => a >= b,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Op::Add => "add", Op::Sub => "sub", Op::Mul => "mul", Op::Div => "div",
+            Op::Mod => "mod", Op::And => "and", Op::Or => "or", Op::Xor => "xor",
+            Op::Shl => "shl", Op::Shr => "shr", Op::Eq => "eq", Op::Ne => "ne",
+            Op::Lt => "lt", Op::Gt => "gt", Op::Le => "le", Op::Ge => "ge",
+        }
+    }
+}
+
+/// A three-address instruction of the register machine
+///
+/// Jump and call targets are indices into the register code; return
+/// addresses stay code addresses of the stack program, so frames look
+/// exactly as the stack VM would leave them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegInstr {
+    /// `dst = src`
+    Mov(Reg, Reg),
+    /// `dst = value`
+    Imm(Reg, i64),
+    /// `dst =` the address of a frame word
+    Addr(Reg, Reg),
+    /// `dst = a op b`
+    Bin(Op, Reg, Reg, Reg),
+    /// `dst = a op value`
+    BinI(Op, Reg, Reg, i64),
+    /// `dst = *(int *)addr`
+    Load(Reg, Reg),
+    /// `dst = *(char *)addr`
+    LoadC(Reg, Reg),
+    /// `*(int *)addr = src`
+    Store(Reg, Reg),
+    /// `*(char *)addr = src`
+    StoreC(Reg, Reg),
+    Jmp(usize),
+    /// Jump if the register is zero
+    Jz(Reg, usize),
+    /// Jump if the register is not zero
+    Jnz(Reg, usize),
+    /// Jump if the comparison of two registers holds
+    Br(Op, Reg, Reg, usize),
+    /// Jump if the comparison of a register with a value holds
+    BrI(Op, Reg, i64, usize),
+    /// Save the caller's frame and make room for this many words of
+    /// locals and temporaries
+    Enter(usize),
+    /// Call a function whose arguments end just above `sp`, keeping the
+    /// stack program's return address; `LEV` puts the result in `result`
+    Call { target: usize, sp: Reg, result: Reg, ret: usize },
+    /// Leave the frame and jump to a function, which returns to the caller
+    TailCall(usize),
+    /// Return the value of a register
+    Ret(Reg),
+    /// A system or native call whose arguments end just above `sp`
+    Sys { instr: Instr, sp: Reg, result: Reg },
+    /// End the program with the value of a register as its exit status
+    Exit(Reg),
+}
+
+/// A register, written as its frame offset
+struct Name(Reg);
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "fp{:+}", self.0)
+    }
+}
+
+impl fmt::Display for RegInstr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            RegInstr::Mov(dst, src) => write!(f, "mov {}, {}", Name(dst), Name(src)),
+            RegInstr::Imm(dst, value) => write!(f, "imm {}, {}", Name(dst), value),
+            RegInstr::Addr(dst, word) => write!(f, "lea {}, {}", Name(dst), Name(word)),
+            RegInstr::Bin(op, dst, a, b) => write!(f, "{} {}, {}, {}", op.name(), Name(dst), Name(a), Name(b)),
+            RegInstr::BinI(op, dst, a, value) => write!(f, "{} {}, {}, {}", op.name(), Name(dst), Name(a), value),
+            RegInstr::Load(dst, addr) => write!(f, "load {}, [{}]", Name(dst), Name(addr)),
+            RegInstr::LoadC(dst, addr) => write!(f, "loadc {}, [{}]", Name(dst), Name(addr)),
+            RegInstr::Store(addr, src) => write!(f, "store [{}], {}", Name(addr), Name(src)),
+            RegInstr::StoreC(addr, src) => write!(f, "storec [{}], {}", Name(addr), Name(src)),
+            RegInstr::Jmp(target) => write!(f, "jmp {}", target),
+            RegInstr::Jz(reg, target) => write!(f, "jz {}, {}", Name(reg), target),
+            RegInstr::Jnz(reg, target) => write!(f, "jnz {}, {}", Name(reg), target),
+            RegInstr::Br(op, a, b, target) => write!(f, "b{} {}, {}, {}", op.name(), Name(a), Name(b), target),
+            RegInstr::BrI(op, a, value, target) => write!(f, "b{} {}, {}, {}", op.name(), Name(a), value, target),
+            RegInstr::Enter(words) => write!(f, "enter {}", words),
+            RegInstr::Call { target, sp, result, .. } => write!(f, "call {}, {}, {}", target, Name(sp), Name(result)),
+            RegInstr::TailCall(target) => write!(f, "tail {}", target),
+            RegInstr::Ret(reg) => write!(f, "ret {}", Name(reg)),
+            RegInstr::Sys { instr, sp, result } => write!(f, "sys {}, {}, {}", instr, Name(sp), Name(result)),
+            RegInstr::Exit(reg) => write!(f, "exit {}", Name(reg)),
+        }
+    }
+}
+
+/// A program translated for the register machine
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisterCode {
+    pub instrs: Vec<RegInstr>,
+    /// Code address of the stack instruction each one came from
+    pub origins: Vec<usize>,
+    /// For each return address of the stack program, the instruction to
+    /// continue at and the register the result goes in
+    returns: Vec<Option<(usize, Reg)>>,
+}
+
+/// Translate a linked program to register code
+///
+/// The program is lifted to the IR, and each function translated a block
+/// at a time. The expression stack's depth is known at every instruction,
+/// so the word a `PSH` would write becomes a register of its own, and
+/// values that are only read (constants, addresses of locals, the locals
+/// themselves) are not copied at all until something could change them.
+/// `ax` is tracked the same way: `LLI x; PSH; IMM 1; ADD` and the `SI`
+/// that stores it become one `add`, and a comparison feeding a branch
+/// becomes a compare-and-branch. Between blocks, the stack is in its
+/// registers and `ax` in a register of its own, where a later block reads
+/// it.
+///
+/// Calls keep the stack VM's frame layout: arguments are in the words
+/// `PSH` would have written, return addresses are the stack program's,
+/// and system calls read their arguments from the stack as before.
+/// Programs that still need linking are rejected.
+pub fn translate(program: &ObjectFile) -> Result<RegisterCode, CompilerError> {
+    if let Some(import) = program.imports.first() {
+        return Err(CompilerError::codegen_error(&format!("'{}' is not defined (link the program first)", import.name), None));
+    }
+    let entry = program.entry
+        .ok_or_else(|| CompilerError::codegen_error("main() not defined", None))?;
+    let graph = ir::Program::from_object(program)?;
+    graph.verify()?;
+
+    // The trampoline main returns to, as `PSH; EXIT` does on the stack VM:
+    // `start` leaves argc and argv above the return address
+    let trampoline = program.code.len();
+    let mut translator = Translator::new(trampoline);
+    translator.origin = trampoline;
+    translator.call(entry, -2, -3, trampoline);
+    translator.origin = trampoline + 1;
+    translator.emit(RegInstr::Exit(-3));
+
+    let mut starts = HashMap::new();
+    for func in &graph.functions {
+        starts.insert(func.start, translator.instrs.len());
+        translator.function(func)?;
+    }
+    for (at, start) in std::mem::take(&mut translator.calls) {
+        let Some(&index) = starts.get(&start) else {
+            return Err(CompilerError::codegen_error("call to something that is not a function", translator.origins.get(at).copied()));
+        };
+        match &mut translator.instrs[at] {
+            RegInstr::Call { target, .. } | RegInstr::TailCall(target) => *target = index,
+            _ => unreachable!("only calls are resolved"),
+        }
+    }
+
+    Ok(RegisterCode {
+        instrs: translator.instrs,
+        origins: translator.origins,
+        returns: translator.returns,
+    })
+}
+
+/// A value the translation knows without emitting code for it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Val {
+    Reg(Reg),
+    Imm(i64),
+    /// The address of a frame word
+    Addr(Reg),
+}
+
+/// What `ax` holds: a value, or an operation not emitted yet because the
+/// instruction that uses it decides where the result goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Ax {
+    Val(Val),
+    Bin(Op, Val, Val),
+    Load(Val),
+    LoadC(Val),
+    /// The value truncated to a char, as `SC` leaves it
+    Char(Val),
+}
+
+/// Translates functions, keeping the stack and `ax` of the current one
+struct Translator {
+    instrs: Vec<RegInstr>,
+    origins: Vec<usize>,
+    returns: Vec<Option<(usize, Reg)>>,
+    /// Calls and tail calls, with the function they go to
+    calls: Vec<(usize, usize)>,
+    /// Code address of the stack instruction being translated
+    origin: usize,
+    /// Words of locals
+    frame: Reg,
+    /// Where `ax` is kept between blocks
+    acc: Reg,
+    /// A register for values needed for one instruction
+    scratch: Reg,
+    stack: Vec<Val>,
+    ax: Ax,
+    /// Code address of the instruction that left an operation in `ax`
+    ax_origin: usize,
+}
+
+impl Translator {
+    fn new(code_len: usize) -> Self {
+        Translator {
+            instrs: Vec::new(),
+            origins: Vec::new(),
+            returns: vec![None; code_len + 2],
+            calls: Vec::new(),
+            origin: 0,
+            frame: 0,
+            acc: 0,
+            scratch: 0,
+            stack: Vec::new(),
+            ax: Ax::Val(Val::Imm(0)),
+            ax_origin: 0,
+        }
+    }
+
+    fn emit(&mut self, instr: RegInstr) {
+        self.instrs.push(instr);
+        self.origins.push(self.origin);
+    }
+
+    fn error(&self, message: &str) -> CompilerError {
+        CompilerError::codegen_error(message, Some(self.origin))
+    }
+
+    fn function(&mut self, func: &ir::Function) -> Result<(), CompilerError> {
+        let depths = func.stack_depths()?;
+        let live = ax_live_in(func);
+
+        // Below the locals: one register per stack word, one more for the
+        // return address of a call made with the stack full, then `ax` and
+        // the scratch register
+        self.frame = func.frame as Reg;
+        self.acc = -(self.frame + max_depth(func, &depths) as Reg + 2);
+        self.scratch = self.acc - 1;
+        self.origin = func.start;
+        self.emit(RegInstr::Enter(-self.scratch as usize));
+
+        let order: Vec<BlockId> = (0..func.blocks.len()).filter(|&id| depths[id].is_some()).collect();
+        let mut starts = vec![0; func.blocks.len()];
+        let mut jumps = Vec::new();
+        for (i, &id) in order.iter().enumerate() {
+            starts[id] = self.instrs.len();
+            self.stack = (0..depths[id].unwrap_or(0)).map(|d| Val::Reg(self.temp(d))).collect();
+            self.ax = Ax::Val(Val::Reg(self.acc));
+
+            let block = &func.blocks[id];
+            for (at, inst) in block.insts.iter().enumerate() {
+                self.origin = inst.origin.unwrap_or(func.start);
+                self.instruction(&block.insts, at)?;
+            }
+            if let Some(origin) = block.terminator_origin {
+                self.origin = origin;
+            }
+            let live_out = block.terminator.successors().iter().any(|&succ| live[succ]);
+            self.terminator(block.terminator, order.get(i + 1).copied(), live_out, &mut jumps);
+        }
+
+        for (at, block) in jumps {
+            match &mut self.instrs[at] {
+                RegInstr::Jmp(target) | RegInstr::Jz(_, target) | RegInstr::Jnz(_, target)
+                | RegInstr::Br(_, _, _, target) | RegInstr::BrI(_, _, _, target) => *target = starts[block],
+                _ => unreachable!("only jumps are resolved"),
+            }
+        }
+        Ok(())
+    }
+
+    /// The register for stack word `depth`, where `PSH` would put it
+    fn temp(&self, depth: usize) -> Reg {
+        -(self.frame + 1 + depth as Reg)
+    }
+
+    /// Whether a register is a local or an argument, which code other
+    /// than the translation's own may write
+    fn is_variable(&self, reg: Reg) -> bool {
+        reg >= 2 || (-self.frame..0).contains(&reg)
+    }
+
+    fn instruction(&mut self, insts: &[ir::Inst], at: usize) -> Result<(), CompilerError> {
+        let instr = insts[at].instr;
+        let before = self.ax;
+        match instr {
+            Instr::Lea(offset) => self.ax = Ax::Val(Val::Addr(offset as Reg)),
+            Instr::Imm(value) => self.ax = Ax::Val(Val::Imm(value)),
+            Instr::Li => {
+                self.ax = match self.settle() {
+                    Val::Addr(word) => Ax::Val(Val::Reg(word)),
+                    addr => Ax::Load(addr),
+                };
+            },
+            Instr::Lc => self.ax = Ax::LoadC(self.settle()),
+            Instr::Lli(offset) => self.ax = Ax::Val(Val::Reg(offset as Reg)),
+            Instr::Llc(offset) => self.ax = Ax::LoadC(Val::Addr(offset as Reg)),
+            Instr::Psh => self.push(),
+            Instr::Pshl(offset) => {
+                self.ax = Ax::Val(Val::Reg(offset as Reg));
+                self.push();
+            },
+            Instr::Pshi(value) => {
+                self.ax = Ax::Val(Val::Imm(value));
+                self.push();
+            },
+            Instr::Si | Instr::Sc => self.store(instr == Instr::Sc)?,
+            Instr::Addi(value) => self.ax = Ax::Bin(Op::Add, self.settle(), Val::Imm(value)),
+            Instr::Subi(value) => self.ax = Ax::Bin(Op::Sub, self.settle(), Val::Imm(value)),
+            Instr::Muli(value) => self.ax = Ax::Bin(Op::Mul, self.settle(), Val::Imm(value)),
+            Instr::Neg => self.ax = Ax::Bin(Op::Mul, self.settle(), Val::Imm(-1)),
+            Instr::Adj(words) => {
+                let depth = self.stack.len().saturating_sub(words.max(0) as usize);
+                self.stack.truncate(depth);
+            },
+            Instr::Jsr(_) | Instr::Natv(_) | Instr::Open | Instr::Read | Instr::Clos | Instr::Prtf(_)
+            | Instr::Malc | Instr::Free | Instr::Mset | Instr::Mcmp => self.calling(insts, at)?,
+            _ => {
+                let Some(op) = Op::from_instr(instr) else {
+                    return Err(self.error(&format!("{} cannot be translated", instr)));
+                };
+                let b = self.settle();
+                let a = self.pop()?;
+                self.ax = Ax::Bin(op, a, b);
+            },
+        }
+        if self.ax != before && !matches!(self.ax, Ax::Val(_)) {
+            self.ax_origin = self.origin;
+        }
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<Val, CompilerError> {
+        self.stack.pop().ok_or_else(|| self.error("expression stack underflow"))
+    }
+
+    /// Push `ax`: constants, addresses and variables are remembered, and
+    /// anything else is put in the stack word's register
+    fn push(&mut self) {
+        let entry = match self.ax {
+            Ax::Val(val @ (Val::Imm(_) | Val::Addr(_))) => val,
+            Ax::Val(Val::Reg(reg)) if self.is_variable(reg) => Val::Reg(reg),
+            ax => {
+                let temp = self.temp(self.stack.len());
+                self.place(ax, temp);
+                self.ax = Ax::Val(Val::Reg(temp));
+                Val::Reg(temp)
+            },
+        };
+        self.stack.push(entry);
+    }
+
+    /// `SI` and `SC`: an int stored to a local is computed straight into
+    /// its register
+    fn store(&mut self, char: bool) -> Result<(), CompilerError> {
+        match self.pop()? {
+            Val::Addr(word) if !char => {
+                self.before_write(Some(word));
+                self.place(self.ax, word);
+                self.ax = Ax::Val(Val::Reg(word));
+            },
+            addr => {
+                let value = self.settle_reg();
+                let addr_reg = self.register(addr, self.scratch);
+                self.before_write(match addr {
+                    Val::Addr(word) => Some(word),
+                    _ => None,
+                });
+                if char {
+                    self.emit(RegInstr::StoreC(addr_reg, value));
+                    self.ax = Ax::Char(Val::Reg(value));
+                } else {
+                    self.emit(RegInstr::Store(addr_reg, value));
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// A call or system call, with the `ADJ` after it giving the number
+    /// of arguments
+    fn calling(&mut self, insts: &[ir::Inst], at: usize) -> Result<(), CompilerError> {
+        let depth = self.stack.len();
+        let (args, next) = match insts.get(at + 1).map(|inst| inst.instr) {
+            Some(Instr::Adj(words)) => ((words.max(0) as usize).min(depth), at + 2),
+            _ => (depth, at + 1),
+        };
+
+        // The arguments have to be in memory, and variables below them may
+        // be changed through pointers
+        for d in 0..depth {
+            if d >= depth - args || matches!(self.stack[d], Val::Reg(reg) if self.is_variable(reg)) {
+                self.materialize(d);
+            }
+        }
+
+        // Put the result where the next instruction wants it
+        let rest = depth - args;
+        let result = match insts.get(next).map(|inst| inst.instr) {
+            Some(Instr::Psh) => self.temp(rest),
+            Some(Instr::Si) => match rest.checked_sub(1).map(|top| self.stack[top]) {
+                Some(Val::Addr(word)) => word,
+                _ => self.acc,
+            },
+            _ => self.acc,
+        };
+
+        let sp = -(self.frame + depth as Reg);
+        match insts[at].instr {
+            Instr::Jsr(target) => {
+                let origin = insts[at].origin.ok_or_else(|| self.error("call without a code address"))?;
+                self.call(target, sp, result, origin + 2);
+            },
+            instr => self.emit(RegInstr::Sys { instr, sp, result }),
+        }
+        self.ax = Ax::Val(Val::Reg(result));
+        Ok(())
+    }
+
+    /// Emit a call returning to the stack program's address `ret`
+    fn call(&mut self, target: usize, sp: Reg, result: Reg, ret: usize) {
+        self.calls.push((self.instrs.len(), target));
+        self.emit(RegInstr::Call { target, sp, result, ret });
+        self.returns[ret] = Some((self.instrs.len(), result));
+    }
+
+    /// Copy stack word `depth` into its register
+    fn materialize(&mut self, depth: usize) {
+        let temp = self.temp(depth);
+        if self.stack[depth] != Val::Reg(temp) {
+            self.copy(self.stack[depth], temp);
+            self.stack[depth] = Val::Reg(temp);
+        }
+    }
+
+    /// Before a variable is written (or, with `None`, any memory), copy
+    /// the stack words that still read it
+    fn before_write(&mut self, word: Option<Reg>) {
+        for depth in 0..self.stack.len() {
+            if let Val::Reg(reg) = self.stack[depth] {
+                if self.is_variable(reg) && word.is_none_or(|word| word == reg) {
+                    self.materialize(depth);
+                }
+            }
+        }
+    }
+
+    /// Emit what is pending in `ax`, so that it is a value
+    fn settle(&mut self) -> Val {
+        match self.ax {
+            Ax::Val(val) => val,
+            ax => {
+                self.place(ax, self.acc);
+                self.ax = Ax::Val(Val::Reg(self.acc));
+                Val::Reg(self.acc)
+            },
+        }
+    }
+
+    /// The register holding `ax`, putting it in the `ax` register if it
+    /// has none
+    fn settle_reg(&mut self) -> Reg {
+        match self.ax {
+            Ax::Val(Val::Reg(reg)) => reg,
+            ax => {
+                self.place(ax, self.acc);
+                self.ax = Ax::Val(Val::Reg(self.acc));
+                self.acc
+            },
+        }
+    }
+
+    /// Emit `ax` into `dst`, as coming from the instruction that computed
+    /// it
+    fn place(&mut self, ax: Ax, dst: Reg) {
+        let origin = match ax {
+            Ax::Val(_) => self.origin,
+            _ => std::mem::replace(&mut self.origin, self.ax_origin),
+        };
+        match ax {
+            Ax::Val(val) => self.copy(val, dst),
+            Ax::Bin(op, a, Val::Imm(value)) => {
+                let a = self.register(a, dst);
+                self.emit(RegInstr::BinI(op, dst, a, value));
+            },
+            Ax::Bin(op, Val::Imm(value), b) if op.swapped().is_some() => {
+                let b = self.register(b, dst);
+                self.emit(RegInstr::BinI(op.swapped().unwrap(), dst, b, value));
+            },
+            Ax::Bin(op, a, b) => {
+                let (a, b) = self.operands(a, b, dst);
+                self.emit(RegInstr::Bin(op, dst, a, b));
+            },
+            Ax::Load(addr) => {
+                let addr = self.register(addr, dst);
+                self.emit(RegInstr::Load(dst, addr));
+            },
+            Ax::LoadC(addr) => {
+                let addr = self.register(addr, dst);
+                self.emit(RegInstr::LoadC(dst, addr));
+            },
+            Ax::Char(val) => {
+                let reg = self.register(val, dst);
+                self.emit(RegInstr::BinI(Op::Shl, dst, reg, 56));
+                self.emit(RegInstr::BinI(Op::Shr, dst, dst, 56));
+            },
+        }
+        self.origin = origin;
+    }
+
+    /// Emit a value into `dst`
+    fn copy(&mut self, val: Val, dst: Reg) {
+        match val {
+            Val::Reg(reg) if reg == dst => {},
+            Val::Reg(reg) => self.emit(RegInstr::Mov(dst, reg)),
+            Val::Imm(value) => self.emit(RegInstr::Imm(dst, value)),
+            Val::Addr(word) => self.emit(RegInstr::Addr(dst, word)),
+        }
+    }
+
+    /// The register holding a value, using `spare` if it is in none
+    fn register(&mut self, val: Val, spare: Reg) -> Reg {
+        match val {
+            Val::Reg(reg) => reg,
+            _ => {
+                self.copy(val, spare);
+                spare
+            },
+        }
+    }
+
+    /// Registers for both operands of an operation writing `dst`, which
+    /// may be used for the first, and the scratch register for the other
+    fn operands(&mut self, a: Val, b: Val, dst: Reg) -> (Reg, Reg) {
+        let b = self.register(b, self.scratch);
+        let a = self.register(a, if b == dst { self.scratch } else { dst });
+        (a, b)
+    }
+
+    /// End a block, leaving the stack in its registers and, if a later
+    /// block reads it, `ax` in its own
+    fn terminator(&mut self, terminator: Terminator, next: Option<BlockId>, live_out: bool, jumps: &mut Vec<(usize, BlockId)>) {
+        for depth in 0..self.stack.len() {
+            self.materialize(depth);
+        }
+        if live_out && self.ax != Ax::Val(Val::Reg(self.acc)) {
+            self.place(self.ax, self.acc);
+            self.ax = Ax::Val(Val::Reg(self.acc));
+        }
+
+        let mut jump = |translator: &mut Self, instr: RegInstr, block: BlockId| {
+            jumps.push((translator.instrs.len(), block));
+            translator.emit(instr);
+        };
+        match terminator {
+            Terminator::Jump(block) => {
+                if next != Some(block) {
+                    jump(self, RegInstr::Jmp(0), block);
+                }
+            },
+            Terminator::Branch { zero, nonzero } if zero == nonzero => {
+                if next != Some(zero) {
+                    jump(self, RegInstr::Jmp(0), zero);
+                }
+            },
+            Terminator::Branch { zero, nonzero } => {
+                if next == Some(nonzero) {
+                    let instr = self.branch(false);
+                    jump(self, instr, zero);
+                } else {
+                    let instr = self.branch(true);
+                    jump(self, instr, nonzero);
+                    if next != Some(zero) {
+                        jump(self, RegInstr::Jmp(0), zero);
+                    }
+                }
+            },
+            Terminator::Return => {
+                let reg = self.settle_reg();
+                self.emit(RegInstr::Ret(reg));
+            },
+            Terminator::Exit => {
+                let reg = self.settle_reg();
+                self.emit(RegInstr::Exit(reg));
+            },
+            Terminator::TailCall(start) => {
+                self.calls.push((self.instrs.len(), start));
+                self.emit(RegInstr::TailCall(start));
+            },
+        }
+    }
+
+    /// A jump taken when `ax` is non-zero (or zero), comparing directly
+    /// if `ax` is a comparison no later block needs
+    fn branch(&mut self, nonzero: bool) -> RegInstr {
+        match self.ax {
+            Ax::Bin(op, a, b) if op.negated().is_some() => {
+                let op = if nonzero { op } else { op.negated().unwrap() };
+                match (a, b) {
+                    (_, Val::Imm(value)) => RegInstr::BrI(op, self.register(a, self.scratch), value, 0),
+                    (Val::Imm(value), _) => RegInstr::BrI(op.swapped().unwrap(), self.register(b, self.scratch), value, 0),
+                    _ => {
+                        let (a, b) = self.operands(a, b, self.acc);
+                        RegInstr::Br(op, a, b, 0)
+                    },
+                }
+            },
+            _ => {
+                let reg = self.settle_reg();
+                if nonzero { RegInstr::Jnz(reg, 0) } else { RegInstr::Jz(reg, 0) }
+            },
+        }
+    }
+}
+
+/// Whether each block reads the `ax` it is entered with
+fn ax_live_in(func: &ir::Function) -> Vec<bool> {
+    // Some(true) if a block reads ax before replacing it, Some(false) if
+    // it replaces it first, None if it leaves it to its successor
+    let uses: Vec<Option<bool>> = func.blocks.iter()
+        .map(|block| {
+            block.insts.iter()
+                .find_map(|inst| match inst.instr {
+                    Instr::Adj(_) => None,
+                    instr => Some(!clobbers_ax(instr)),
+                })
+                .or(match block.terminator {
+                    Terminator::Jump(_) => None,
+                    Terminator::TailCall(_) => Some(false),
+                    Terminator::Branch { .. } | Terminator::Return | Terminator::Exit => Some(true),
+                })
+        })
+        .collect();
+    let mut live: Vec<bool> = uses.iter().map(|&used| used == Some(true)).collect();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (id, block) in func.blocks.iter().enumerate() {
+            if uses[id].is_none() && !live[id] && block.terminator.successors().iter().any(|&succ| live[succ]) {
+                live[id] = true;
+                changed = true;
+            }
+        }
+    }
+    live
+}
+
+/// The deepest the expression stack gets in a function
+fn max_depth(func: &ir::Function, depths: &[Option<usize>]) -> usize {
+    let mut max = 0;
+    for (block, depth) in func.blocks.iter().zip(depths) {
+        let Some(mut depth) = *depth else { continue };
+        max = max.max(depth);
+        for inst in &block.insts {
+            let (pops, pushes) = inst.instr.stack_effect();
+            depth = depth.saturating_sub(pops) + pushes;
+            max = max.max(depth);
+        }
+    }
+    max
+}
+
+/// A register machine running translated programs
+///
+/// Each frame's registers are words of the VM's stack, so memory, system
+/// calls, native functions, limits and runtime errors are the stack VM's
+/// own; errors point at the stack instruction a register instruction came
+/// from. The cycle limit counts register instructions, and is checked at
+/// the same places as on the stack VM: taken jumps and branches, calls
+/// and returns.
+pub struct RegisterVm {
+    vm: VirtualMachine,
+    code: RegisterCode,
+    entry: usize,
+    pc: usize,
+    /// Words of stack memory
+    stack_size: usize,
+    debug: bool,
+}
+
+impl RegisterVm {
+    /// Translate a linked program to run under `limits`
+    pub fn compile(program: &ObjectFile, limits: VmLimits, debug: bool) -> Result<RegisterVm, CompilerError> {
+        let code = translate(program)?;
+        let mut vm = VirtualMachine::with_limits(program.code.clone(), program.data.clone(), limits, debug);
+        vm.set_debug_info(program.debug.clone());
+        let entry = program.entry.unwrap_or(0);
+        Ok(RegisterVm { vm, code, entry, pc: 0, stack_size: limits.stack_size, debug })
+    }
+
+    /// Route the program's system calls to `host`
+    pub fn set_host(&mut self, host: Box<dyn Host>) {
+        self.vm.set_host(host);
+    }
+
+    /// Provide the native functions that `NATV` calls
+    pub fn set_natives(&mut self, natives: Natives) {
+        self.vm.set_natives(natives);
+    }
+
+    /// The translated program
+    pub fn code(&self) -> &RegisterCode {
+        &self.code
+    }
+
+    /// Number of register instructions executed so far
+    pub fn cycle(&self) -> i64 {
+        self.vm.cycle()
+    }
+
+    /// Run the program, returning its exit code
+    pub fn run(&mut self, args: &[String]) -> Result<i64, CompilerError> {
+        self.vm.start(self.entry, args)?;
+        self.pc = 0;
+        self.dispatch()
+    }
+
+    fn dispatch(&mut self) -> Result<i64, CompilerError> {
+        loop {
+            self.vm.tick();
+            let Some(&instr) = self.code.instrs.get(self.pc) else {
+                return Err(self.error(format!("Program counter out of bounds: {}", self.pc)));
+            };
+            if self.debug {
+                println!("{:4}> {}", self.vm.cycle(), instr);
+            }
+
+            match instr {
+                RegInstr::Mov(dst, src) => {
+                    let value = self.get(src)?;
+                    self.set(dst, value)?;
+                },
+                RegInstr::Imm(dst, value) => self.set(dst, value)?,
+                RegInstr::Addr(dst, word) => {
+                    let addr = self.address(word);
+                    self.set(dst, addr)?;
+                },
+                RegInstr::Bin(op, dst, a, b) => {
+                    let (a, b) = (self.get(a)?, self.get(b)?);
+                    let value = self.binary(op, a, b)?;
+                    self.set(dst, value)?;
+                },
+                RegInstr::BinI(op, dst, a, b) => {
+                    let a = self.get(a)?;
+                    let value = self.binary(op, a, b)?;
+                    self.set(dst, value)?;
+                },
+                RegInstr::Load(dst, addr) => {
+                    let addr = self.get(addr)?;
+                    let value = self.memory(|vm| vm.load_int(addr))?;
+                    self.set(dst, value)?;
+                },
+                RegInstr::LoadC(dst, addr) => {
+                    let addr = self.get(addr)?;
+                    let value = self.memory(|vm| vm.load_char(addr))?;
+                    self.set(dst, value)?;
+                },
+                RegInstr::Store(addr, src) => {
+                    let (addr, value) = (self.get(addr)?, self.get(src)?);
+                    self.memory(|vm| vm.store_int(addr, value))?;
+                },
+                RegInstr::StoreC(addr, src) => {
+                    let (addr, value) = (self.get(addr)?, self.get(src)?);
+                    self.memory(|vm| vm.store_char(addr, value))?;
+                },
+                RegInstr::Jmp(target) => {
+                    self.jump(target)?;
+                    continue;
+                },
+                RegInstr::Jz(reg, target) => {
+                    if self.get(reg)? == 0 {
+                        self.jump(target)?;
+                        continue;
+                    }
+                },
+                RegInstr::Jnz(reg, target) => {
+                    if self.get(reg)? != 0 {
+                        self.jump(target)?;
+                        continue;
+                    }
+                },
+                RegInstr::Br(op, a, b, target) => {
+                    let (a, b) = (self.get(a)?, self.get(b)?);
+                    if op.compare(a, b) {
+                        self.jump(target)?;
+                        continue;
+                    }
+                },
+                RegInstr::BrI(op, a, b, target) => {
+                    let a = self.get(a)?;
+                    if op.compare(a, b) {
+                        self.jump(target)?;
+                        continue;
+                    }
+                },
+                RegInstr::Enter(words) => {
+                    // sp is at the return address, and the caller's bp
+                    // goes below it
+                    let (sp, caller) = self.vm.frame();
+                    if sp <= words {
+                        return Err(self.stack_overflow());
+                    }
+                    self.vm.set_frame(sp - 1 - words, sp - 1);
+                    self.set(0, caller as i64)?;
+                },
+                RegInstr::Call { target, sp, ret, .. } => {
+                    self.set(sp - 1, ret as i64)?;
+                    let bp = self.vm.frame().1;
+                    self.vm.set_frame(bp.wrapping_add_signed(sp as isize - 1), bp);
+                    self.jump(target)?;
+                    continue;
+                },
+                RegInstr::TailCall(target) => {
+                    let caller = self.saved_bp()?;
+                    self.vm.set_frame(self.vm.frame().1 + 1, caller);
+                    self.jump(target)?;
+                    continue;
+                },
+                RegInstr::Ret(reg) => {
+                    // A program that overwrites its return address can
+                    // loop through returns alone, so they check the limits
+                    let value = self.get(reg)?;
+                    let ret = self.get(1)?;
+                    let caller = self.saved_bp()?;
+                    let Some((next, result)) = self.code.returns.get(ret as usize).copied().flatten() else {
+                        return Err(self.error(format!("Program counter out of bounds: {}", ret)));
+                    };
+                    self.jump(next)?;
+                    self.vm.set_frame(self.vm.frame().1 + 2, caller);
+                    self.set(result, value)?;
+                    continue;
+                },
+                RegInstr::Sys { instr, sp, result } => {
+                    let bp = self.vm.frame().1;
+                    self.vm.set_frame(bp.wrapping_add_signed(sp as isize), bp);
+                    self.sync_pc();
+                    let value = self.vm.system_call(instr)?;
+                    self.set(result, value)?;
+                },
+                RegInstr::Exit(reg) => {
+                    let value = self.get(reg)?;
+                    return Ok(self.vm.exit(value));
+                },
+            }
+            self.pc += 1;
+        }
+    }
+
+    /// Read a register
+    #[inline(always)]
+    fn get(&mut self, reg: Reg) -> Result<i64, CompilerError> {
+        match self.vm.frame_word(reg as isize) {
+            Some(value) => Ok(value),
+            None => Err(self.out_of_bounds(reg)),
+        }
+    }
+
+    /// Write a register
+    #[inline(always)]
+    fn set(&mut self, reg: Reg, value: i64) -> Result<(), CompilerError> {
+        match self.vm.frame_word_mut(reg as isize) {
+            Some(slot) => {
+                *slot = value;
+                Ok(())
+            },
+            None => Err(self.out_of_bounds(reg)),
+        }
+    }
+
+    /// The caller's bp, saved by `Enter`
+    fn saved_bp(&mut self) -> Result<usize, CompilerError> {
+        let bp = self.get(0)?;
+        if bp < 0 || bp as usize > self.stack_size {
+            return Err(self.error("Stack underflow".to_string()));
+        }
+        Ok(bp as usize)
+    }
+
+    #[inline(always)]
+    fn binary(&mut self, op: Op, a: i64, b: i64) -> Result<i64, CompilerError> {
+        Ok(match op {
+            Op::Add => a.wrapping_add(b),
+            Op::Sub => a.wrapping_sub(b),
+            Op::Mul => a.wrapping_mul(b),
+            Op::Div if b == 0 => return Err(self.error("Division by zero".to_string())),
+            Op::Div => a.wrapping_div(b),
+            Op::Mod if b == 0 => return Err(self.error("Division by zero in modulo".to_string())),
+            Op::Mod => a.wrapping_rem(b),
+            Op::And => a & b,
+            Op::Or => a | b,
+            Op::Xor => a ^ b,
+            Op::Shl => a.wrapping_shl(b as u32),
+            Op::Shr => a.wrapping_shr(b as u32),
+            _ => op.compare(a, b) as i64,
+        })
+    }
+
+    /// Continue at `target`, checking the limits when they are due
+    #[inline(always)]
+    fn jump(&mut self, target: usize) -> Result<(), CompilerError> {
+        let (origins, pc) = (&self.code.origins, self.pc);
+        self.vm.poll_limits(|| origins.get(pc).copied().unwrap_or(0))?;
+        self.pc = target;
+        Ok(())
+    }
+
+    /// Access memory, naming the instruction being executed if that fails
+    ///
+    /// A failed access is repeated once the stack VM points at the
+    /// instruction, to build its error, so that accesses that succeed do
+    /// not have to update it.
+    #[inline(always)]
+    fn memory<T>(&mut self, access: impl Fn(&mut VirtualMachine) -> Result<T, CompilerError>) -> Result<T, CompilerError> {
+        match access(&mut self.vm) {
+            Ok(value) => Ok(value),
+            Err(_) => {
+                self.sync_pc();
+                access(&mut self.vm)
+            },
+        }
+    }
+
+    /// Memory address of a register
+    fn address(&self, reg: Reg) -> i64 {
+        STACK_BASE + (self.vm.frame().1 as i64 + reg as i64) * 8
+    }
+
+    /// Point the stack VM at the instruction being executed, which errors
+    /// name
+    fn sync_pc(&mut self) {
+        self.vm.set_pc(self.code.origins.get(self.pc).copied().unwrap_or(0));
+    }
+
+    #[cold]
+    fn error(&mut self, message: String) -> CompilerError {
+        self.sync_pc();
+        self.vm.error(message)
+    }
+
+    #[cold]
+    fn stack_overflow(&mut self) -> CompilerError {
+        self.sync_pc();
+        self.vm.limit_error(ResourceLimit::Stack(self.stack_size))
+    }
+
+    #[cold]
+    fn out_of_bounds(&mut self, reg: Reg) -> CompilerError {
+        let addr = self.address(reg);
+        self.error(format!("Memory access out of bounds: {}", addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::MemoryHost;
+    use crate::test_support::compile;
+
+
+    /// Run on both machines, returning the output, exit code and
+    /// instruction count of each
+    fn run_both(source: &str) -> [(String, i64, i64); 2] {
+        let program = compile(source);
+        let host = MemoryHost::new();
+        let mut vm = VirtualMachine::new(program.code.clone(), program.data.clone(), 64 * 1024, false);
+        vm.set_host(Box::new(host.clone()));
+        let code = vm.run(program.entry.unwrap(), &[]).unwrap();
+        let stack = (String::from_utf8(host.stdout()).unwrap(), code, vm.cycle());
+
+        let host = MemoryHost::new();
+        let mut machine = RegisterVm::compile(&program, VmLimits { stack_size: 64 * 1024, ..VmLimits::default() }, false).unwrap();
+        machine.set_host(Box::new(host.clone()));
+        let code = machine.run(&[]).unwrap();
+        [stack, (String::from_utf8(host.stdout()).unwrap(), code, machine.cycle())]
+    }
+
+    #[test]
+    fn test_loops_need_fewer_instructions() {
+        let [stack, registers] = run_both(
+            "int main() { int i, total; i = 0; total = 0;
+                          while (i < 1000) { total = total + i * 3; i++; }
+                          printf(\"%d\\n\", total); return total & 127; }");
+        assert_eq!((&stack.0, stack.1), (&registers.0, registers.1));
+        assert_eq!(registers.0, "1498500\n");
+        assert!(registers.2 * 2 < stack.2, "{} vs {} instructions", registers.2, stack.2);
+    }
+
+    #[test]
+    fn test_values_flow_through_blocks_and_calls() {
+        let [stack, registers] = run_both(
+            "int a[4];
+             int f(int x, int y) { return x * 10 + y; }
+             int main() {
+                 int i, *p; char c;
+                 i = 0; p = a;
+                 while (i < 4) { *p = f(i, i + 1) + (i && i - 1 || i == 3); p++; i++; }
+                 c = 300; i = c = 65;
+                 printf(\"%d %d %d %d %d %d\\n\", a[0], a[1], a[2], a[3], c, i > 2 ? f(c, -i) : 0);
+                 return (i = f(1, 2)) == 12;
+             }");
+        assert_eq!(stack, (registers.0.clone(), registers.1, stack.2));
+        assert_eq!(registers.0, "1 12 24 36 65 585\n");
+        assert_eq!(registers.1, 1);
+    }
+
+    #[test]
+    fn test_comparisons_become_branches() {
+        let program = compile("int main() { int i; i = 0; while (i < 10) i++; return i; }");
+        let code = translate(&program).unwrap();
+        assert!(code.instrs.iter().any(|instr| matches!(instr, RegInstr::BrI(Op::Ge, _, 10, _))),
+                "{:?}", code.instrs);
+        assert!(!code.instrs.iter().any(|instr| matches!(instr, RegInstr::Jz(..) | RegInstr::Jnz(..))));
+    }
+}
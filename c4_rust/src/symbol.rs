@@ -12,6 +12,10 @@ pub struct Symbol {
     pub typ: Type,
     /// Value or address
     pub value: i64,
+    /// Number of elements if the symbol names an array
+    pub array_len: Option<usize>,
+    /// Whether the symbol is declared here but defined in another unit
+    pub external: bool,
     
     // Fields for saving local symbol state when entering a new scope
     pub h_class: Option<TokenType>,
@@ -27,6 +31,8 @@ impl Symbol {
             class,
             typ,
             value,
+            array_len: None,
+            external: false,
             h_class: None,
             h_type: None,
             h_value: None,
@@ -67,6 +73,14 @@ pub struct SymbolTable {
     name_map: HashMap<String, usize>,
     /// Current scope level (0 = global)
     scope_level: usize,
+    /// Names declared in each open scope, with the index they shadowed
+    scopes: Vec<Vec<(String, Option<usize>)>>,
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SymbolTable {
@@ -76,11 +90,15 @@ impl SymbolTable {
             symbols: Vec::new(),
             name_map: HashMap::new(),
             scope_level: 0, // Start at global scope
+            scopes: Vec::new(),
         }
     }
     
     /// Add a symbol to the symbol table
     ///
+    /// Symbols added inside a scope shadow any outer symbol with the same
+    /// name until that scope is exited.
+    ///
     /// # Arguments
     ///
     /// * `name` - Symbol name
@@ -96,8 +114,11 @@ impl SymbolTable {
         let symbol = Symbol::new(name, class, typ, value);
         let index = self.symbols.len();
         
-        // Add to the lookup map
-        self.name_map.insert(name.to_string(), index);
+        // Add to the lookup map, remembering what we shadow
+        let shadowed = self.name_map.insert(name.to_string(), index);
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.push((name.to_string(), shadowed));
+        }
         
         // Add to the table
         self.symbols.push(symbol);
@@ -128,11 +149,7 @@ impl SymbolTable {
     ///
     /// Mutable reference to the symbol if found, or None
     pub fn get_mut(&mut self, name: &str) -> Option<&mut Symbol> {
-        if let Some(&index) = self.name_map.get(name) {
-            Some(&mut self.symbols[index])
-        } else {
-            None
-        }
+        self.name_map.get(name).map(|&index| &mut self.symbols[index])
     }
     
     /// Get a symbol by index
@@ -177,13 +194,26 @@ impl SymbolTable {
     /// Enter a new scope level
     pub fn enter_scope(&mut self) {
         self.scope_level += 1;
+        self.scopes.push(Vec::new());
     }
     
     /// Exit the current scope level
+    ///
+    /// Names declared in the scope become invisible again and any symbols
+    /// they shadowed are restored. The symbols themselves stay in the table
+    /// so indices handed out earlier remain valid.
     pub fn exit_scope(&mut self) {
         if self.scope_level > 0 {
             self.scope_level -= 1;
         }
+        if let Some(scope) = self.scopes.pop() {
+            for (name, shadowed) in scope.into_iter().rev() {
+                match shadowed {
+                    Some(index) => { self.name_map.insert(name, index); },
+                    None => { self.name_map.remove(&name); },
+                }
+            }
+        }
     }
     
     /// Get the current scope level
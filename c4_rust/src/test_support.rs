@@ -0,0 +1,20 @@
+//! Helpers shared by the unit tests
+
+use crate::object::ObjectFile;
+use crate::parser::Parser;
+
+/// Compile `source` for a unit test, optimized and fused as the CLI does
+pub(crate) fn compile(source: &str) -> ObjectFile {
+    compile_with(source, |_| {})
+}
+
+/// [`compile`], after `configure` has set up the parser
+pub(crate) fn compile_with(source: &str, configure: impl FnOnce(&mut Parser)) -> ObjectFile {
+    let mut parser = Parser::new(source.to_string(), false);
+    configure(&mut parser);
+    parser.init().unwrap();
+    parser.parse().unwrap();
+    parser.optimize();
+    parser.fuse();
+    ObjectFile::from_parser(&parser).unwrap()
+}
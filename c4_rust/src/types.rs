@@ -14,11 +14,13 @@ pub enum TokenType {
     Sizeof,
     While,
     Void,   // Added to match C4.c
+    Extern, // Declarations defined in another unit
     
     // Variable/function classes
     Num,
     Fun,
     Sys,
+    Native, // Native function registered by the embedder
     Glo,
     Loc,
     Id,
@@ -129,7 +131,6 @@ pub enum Opcode {
     MUL,    // Multiply
     DIV,    // Divide
     MOD,    // Modulo
-    NEG,    // Negate
     
     // System calls
     OPEN,   // Open file
@@ -141,9 +142,101 @@ pub enum Opcode {
     MSET,   // Memset
     MCMP,   // Memcmp
     EXIT,   // Exit
+    
+    // Extensions beyond the C4 instruction set
+    NEG,    // Negate
+    
+    // Superinstructions fusing common C4 sequences
+    LLI,    // Load int local (LEA n; LI)
+    LLC,    // Load char local (LEA n; LC)
+    PSHL,   // Push int local (LEA n; LI; PSH)
+    PSHI,   // Push immediate (IMM k; PSH)
+    ADDI,   // Add immediate (PSH; IMM k; ADD)
+    SUBI,   // Subtract immediate (PSH; IMM k; SUB)
+    MULI,   // Multiply by immediate (PSH; IMM k; MUL)
+    
+    // Host extensions
+    NATV,   // Call a registered native function
+    TSR,    // Tail call: leave the frame (LEV) and jump to a subroutine
+}
+
+/// Which opcodes code generation may use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InstructionSet {
+    /// Only the opcodes of the reference C4 implementation, numbered as
+    /// in C4.c
+    C4,
+    /// C4's opcodes plus `NEG`, the superinstructions and native calls
+    #[default]
+    Extended,
 }
 
 impl Opcode {
+    /// All opcodes, indexed by their numeric value
+    pub const ALL: [Opcode; 49] = [
+        Opcode::LEA, Opcode::IMM, Opcode::JMP, Opcode::JSR, Opcode::BZ,
+        Opcode::BNZ, Opcode::ENT, Opcode::ADJ, Opcode::LEV, Opcode::LI,
+        Opcode::LC, Opcode::SI, Opcode::SC, Opcode::PSH, Opcode::OR,
+        Opcode::XOR, Opcode::AND, Opcode::EQ, Opcode::NE, Opcode::LT,
+        Opcode::GT, Opcode::LE, Opcode::GE, Opcode::SHL, Opcode::SHR,
+        Opcode::ADD, Opcode::SUB, Opcode::MUL, Opcode::DIV, Opcode::MOD,
+        Opcode::OPEN, Opcode::READ, Opcode::CLOS, Opcode::PRTF, Opcode::MALC,
+        Opcode::FREE, Opcode::MSET, Opcode::MCMP, Opcode::EXIT, Opcode::NEG,
+        Opcode::LLI, Opcode::LLC, Opcode::PSHL, Opcode::PSHI, Opcode::ADDI,
+        Opcode::SUBI, Opcode::MULI, Opcode::NATV, Opcode::TSR,
+    ];
+
+    /// Convert a code word back into an opcode
+    pub fn from_i64(value: i64) -> Option<Opcode> {
+        usize::try_from(value).ok().and_then(|i| Self::ALL.get(i).copied())
+    }
+
+    /// Whether the opcode is followed by an operand word
+    pub fn has_operand(&self) -> bool {
+        matches!(self,
+            Opcode::LEA | Opcode::IMM | Opcode::JMP | Opcode::JSR |
+            Opcode::BZ | Opcode::BNZ | Opcode::ENT | Opcode::ADJ |
+            Opcode::LLI | Opcode::LLC | Opcode::PSHL | Opcode::PSHI |
+            Opcode::ADDI | Opcode::SUBI | Opcode::MULI | Opcode::NATV |
+            Opcode::TSR)
+    }
+
+    /// Whether the operand is a code address (a jump, branch or call target)
+    pub fn is_jump(&self) -> bool {
+        matches!(self, Opcode::JMP | Opcode::JSR | Opcode::BZ | Opcode::BNZ | Opcode::TSR)
+    }
+
+    /// Whether the opcode belongs to the reference C4 instruction set
+    pub fn is_c4(&self) -> bool {
+        (*self as i64) <= Opcode::EXIT as i64
+    }
+
+    /// Evaluate a binary operator on constant operands, as the VM would
+    ///
+    /// Returns `None` for opcodes that are not binary operators and for
+    /// division or modulo by zero.
+    pub fn fold(&self, lhs: i64, rhs: i64) -> Option<i64> {
+        Some(match self {
+            Opcode::OR => lhs | rhs,
+            Opcode::XOR => lhs ^ rhs,
+            Opcode::AND => lhs & rhs,
+            Opcode::EQ => (lhs == rhs) as i64,
+            Opcode::NE => (lhs != rhs) as i64,
+            Opcode::LT => (lhs < rhs) as i64,
+            Opcode::GT => (lhs > rhs) as i64,
+            Opcode::LE => (lhs <= rhs) as i64,
+            Opcode::GE => (lhs >= rhs) as i64,
+            Opcode::SHL => lhs.wrapping_shl(rhs as u32),
+            Opcode::SHR => lhs.wrapping_shr(rhs as u32),
+            Opcode::ADD => lhs.wrapping_add(rhs),
+            Opcode::SUB => lhs.wrapping_sub(rhs),
+            Opcode::MUL => lhs.wrapping_mul(rhs),
+            Opcode::DIV if rhs != 0 => lhs.wrapping_div(rhs),
+            Opcode::MOD if rhs != 0 => lhs.wrapping_rem(rhs),
+            _ => return None,
+        })
+    }
+
     /// Convert opcode to string representation for debugging
     pub fn to_string(&self) -> &'static str {
         match self {
@@ -160,44 +253,79 @@ impl Opcode {
             Opcode::NEG => "NEG", Opcode::OPEN => "OPEN", Opcode::READ => "READ", 
             Opcode::CLOS => "CLOS", Opcode::PRTF => "PRTF", Opcode::MALC => "MALC", 
             Opcode::FREE => "FREE", Opcode::MSET => "MSET", Opcode::MCMP => "MCMP", 
-            Opcode::EXIT => "EXIT",
+            Opcode::EXIT => "EXIT", Opcode::LLI => "LLI", Opcode::LLC => "LLC",
+            Opcode::PSHL => "PSHL", Opcode::PSHI => "PSHI", Opcode::ADDI => "ADDI",
+            Opcode::SUBI => "SUBI", Opcode::MULI => "MULI", Opcode::NATV => "NATV",
+            Opcode::TSR => "TSR",
         }
     }
 }
 
 /// Type system
 /// 
-/// The C4 compiler handles char, int, and pointer types
+/// The C4 compiler handles char, int, and pointer types. Pointer types keep
+/// track of both their level of indirection and whether they ultimately
+/// point at `char` or `int`, so that dereferencing and pointer arithmetic
+/// know the element type.
 #[derive(Debug, Clone, PartialEq, Copy)]
 pub enum Type {
     CHAR = 0,   // Character type (8-bit)
     INT = 1,    // Integer type (64-bit)
-    PTR = 2,    // Pointer type (starts at 2 and increments for each level of indirection)
+    PTR = 2,    // Pointer to int
+    PTR2 = 3,   // Pointer to pointer to int
+    PTR3 = 4,   // Three levels of indirection to int
+    CPTR = 5,   // Pointer to char
+    CPTR2 = 6,  // Pointer to pointer to char
+    CPTR3 = 7,  // Three levels of indirection to char
 }
 
 impl Type {
+    /// All types, indexed by their numeric value
+    pub const ALL: [Type; 8] = [
+        Type::CHAR, Type::INT, Type::PTR, Type::PTR2,
+        Type::PTR3, Type::CPTR, Type::CPTR2, Type::CPTR3,
+    ];
+
+    /// Convert a numeric value back to a type
+    pub fn from_i64(value: i64) -> Option<Type> {
+        usize::try_from(value).ok().and_then(|i| Type::ALL.get(i).copied())
+    }
+
     /// Create a pointer to this type
+    ///
+    /// Indirection saturates at three levels, which is deeper than anything
+    /// the C4 sources need.
     pub fn to_ptr(self) -> Self {
         match self {
-            Type::CHAR => Type::PTR,
+            Type::CHAR => Type::CPTR,
             Type::INT => Type::PTR,
-            Type::PTR => {
-                // Create a pointer to pointer (PTR + 1)
-                // This mimics C4's behavior where pointer types are represented by integers
-                let ptr_level = self as u8 + 1;
-                unsafe { std::mem::transmute::<u8, Type>(ptr_level) }
-            }
+            Type::PTR => Type::PTR2,
+            Type::PTR2 | Type::PTR3 => Type::PTR3,
+            Type::CPTR => Type::CPTR2,
+            Type::CPTR2 | Type::CPTR3 => Type::CPTR3,
         }
     }
     
-    /// Check if this is a pointer type
-    pub fn is_ptr(self) -> bool {
+    /// Get the type obtained by dereferencing this pointer type
+    ///
+    /// Non-pointer types dereference to `INT`, mirroring C4's permissive
+    /// treatment of integers used as addresses.
+    pub fn deref(self) -> Self {
         match self {
-            Type::PTR => true,
-            _ => (self as i32) > Type::PTR as i32,
+            Type::CHAR | Type::INT | Type::PTR => Type::INT,
+            Type::PTR2 => Type::PTR,
+            Type::PTR3 => Type::PTR2,
+            Type::CPTR => Type::CHAR,
+            Type::CPTR2 => Type::CPTR,
+            Type::CPTR3 => Type::CPTR2,
         }
     }
     
+    /// Check if this is a pointer type
+    pub fn is_ptr(self) -> bool {
+        (self as i32) >= Type::PTR as i32
+    }
+    
     /// Get the size of this type in bytes
     pub fn size(self) -> usize {
         match self {
@@ -205,6 +333,13 @@ impl Type {
             _ => std::mem::size_of::<i64>(), // Use i64 for INT and PTR
         }
     }
+    
+    /// Get the size of the element this pointer type points at
+    ///
+    /// Used to scale integers in pointer arithmetic and indexing.
+    pub fn elem_size(self) -> usize {
+        self.deref().size()
+    }
 }
 
 #[cfg(test)]
@@ -232,6 +367,32 @@ mod tests {
         assert!(ptr_to_ptr_t as i32 > ptr_t as i32);
     }
     
+    #[test]
+    fn test_opcode_fold() {
+        assert_eq!(Opcode::from_i64(Opcode::MUL as i64), Some(Opcode::MUL));
+        assert_eq!(Opcode::from_i64(-1), None);
+        assert_eq!(Opcode::EXIT as i64, 38, "C4 opcodes keep their C4.c numbering");
+        assert!(Opcode::EXIT.is_c4() && !Opcode::NEG.is_c4());
+        for (i, op) in Opcode::ALL.iter().enumerate() {
+            assert_eq!(*op as usize, i);
+        }
+        assert_eq!(Opcode::MUL.fold(6, 7), Some(42));
+        assert_eq!(Opcode::LT.fold(1, 2), Some(1));
+        assert_eq!(Opcode::DIV.fold(1, 0), None);
+        assert_eq!(Opcode::LI.fold(1, 2), None);
+    }
+    
+    #[test]
+    fn test_type_deref() {
+        assert_eq!(Type::CHAR.to_ptr(), Type::CPTR);
+        assert_eq!(Type::CPTR.deref(), Type::CHAR);
+        assert_eq!(Type::CPTR.to_ptr().deref(), Type::CPTR);
+        assert_eq!(Type::PTR2.deref(), Type::PTR);
+        assert_eq!(Type::CPTR.elem_size(), 1);
+        assert_eq!(Type::PTR.elem_size(), 8);
+        assert_eq!(Type::CPTR2.elem_size(), 8);
+    }
+    
     #[test]
     fn test_type_size() {
         assert_eq!(Type::CHAR.size(), 1);
@@ -1,10 +1,84 @@
-use crate::error::CompilerError;
+use crate::debug_info::DebugInfo;
+use crate::error::{CompilerError, ResourceLimit, StackFrame};
+use crate::host::{Host, OsHost, STDOUT};
+use crate::instr::{self, Instr};
+use crate::native::Natives;
 use crate::types::Opcode;
-use std::io::{self, Read, Write};
-use std::process::exit;
+use std::time::{Duration, Instant};
+
+/// Address of the first stack word
+///
+/// Data and stack share one address space so that pointers to locals and
+/// pointers into the data segment can be used interchangeably. Data lives
+/// at byte addresses `0..data.len()`; stack word `i` lives at
+/// `STACK_BASE + i * 8`.
+pub const STACK_BASE: i64 = 1 << 32;
+
+/// Largest size the data segment may grow to (64 MiB)
+pub(crate) const DATA_LIMIT: usize = 64 << 20;
+
+/// Most frames a runtime error's backtrace lists
+const MAX_BACKTRACE: usize = 16;
+
+/// Stack size in words when no other is given
+pub const DEFAULT_STACK_SIZE: usize = 256 * 1024;
+
+/// Instructions executed between checks of the clock
+const CLOCK_INTERVAL: i64 = 1 << 14;
+
+/// Resources a program may use
+///
+/// Running into a limit stops the program with
+/// [`CompilerError::LimitExceeded`], naming the limit. Without a heap
+/// limit, `malloc` still fails (returns 0) past the 64 MiB data segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmLimits {
+    /// Most instructions to execute. Like the timeout, this is checked at
+    /// jumps, branches and calls, so a program may finish the straight run
+    /// of code it is in first
+    pub max_cycles: Option<u64>,
+    /// Most bytes the data segment may grow by while running, through
+    /// `malloc` or writes past its end
+    pub max_heap_bytes: Option<usize>,
+    /// Size of the stack in words, which bounds the depth of calls
+    pub stack_size: usize,
+    /// Longest the program may run, from the start
+    pub timeout: Option<Duration>,
+    /// Most bytes `printf` may write
+    pub max_output_bytes: Option<usize>,
+}
+
+impl Default for VmLimits {
+    fn default() -> Self {
+        VmLimits {
+            max_cycles: None,
+            max_heap_bytes: None,
+            stack_size: DEFAULT_STACK_SIZE,
+            timeout: None,
+            max_output_bytes: None,
+        }
+    }
+}
+
+/// Register values, with `sp` and `bp` as stack addresses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    pub pc: usize,
+    pub sp: i64,
+    pub bp: i64,
+    pub ax: i64,
+}
+
+/// An active call: where it is executing and its frame's base pointer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub pc: usize,
+    /// Stack address of the frame, where locals are counted from
+    pub bp: i64,
+}
 
 /// Virtual Machine for executing compiled C4 code
-/// 
+///
 /// This VM executes the bytecode produced by the C4 compiler.
 /// It has a simple register-based architecture with a stack.
 pub struct VirtualMachine {
@@ -13,15 +87,28 @@ pub struct VirtualMachine {
     sp: usize,     // stack pointer
     bp: usize,     // base pointer
     ax: i64,       // accumulator
-    
+
     // Memory areas
     code: Vec<i64>,    // code segment
+    program: Vec<Instr>, // decoded code segment, indexed by code address
     stack: Vec<i64>,   // stack segment
     data: Vec<u8>,     // data segment
-    
+
     // Debugging
     debug: bool,
     cycle: i64,
+    debug_info: DebugInfo, // source positions for runtime errors
+
+    // Files, standard streams and exit, for the system calls
+    host: Box<dyn Host>,
+    natives: Natives, // functions called by NATV
+
+    // Resource limits
+    limits: VmLimits,
+    next_check: i64,           // cycle at which to check the limits again
+    deadline: Option<Instant>, // when the program runs out of time
+    heap_start: usize,         // data segment size when the program started
+    output_bytes: usize,       // bytes written by printf so far
 }
 
 impl VirtualMachine {
@@ -31,27 +118,63 @@ impl VirtualMachine {
     ///
     /// * `code` - The bytecode to execute
     /// * `data` - Initial data segment
-    /// * `stack_size` - Size of the stack
+    /// * `stack_size` - Size of the stack in words
     /// * `debug` - Whether to print debug information
     pub fn new(code: Vec<i64>, data: Vec<u8>, stack_size: usize, debug: bool) -> Self {
-        let stack = vec![0; stack_size];
-        
+        Self::with_limits(code, data, VmLimits { stack_size, ..VmLimits::default() }, debug)
+    }
+
+    /// Create a virtual machine whose programs are held to `limits`
+    pub fn with_limits(code: Vec<i64>, data: Vec<u8>, limits: VmLimits, debug: bool) -> Self {
+        let stack = vec![0; limits.stack_size];
+
         // Initialize stack pointer at the end of stack (like C4.c)
-        let sp = stack_size;
-        
+        let sp = limits.stack_size;
+
         VirtualMachine {
             pc: 0,
             sp,
             bp: sp,
             ax: 0,
             code,
+            program: Vec::new(),
             stack,
             data,
             debug,
             cycle: 0,
+            debug_info: DebugInfo::default(),
+            host: Box::new(OsHost::new()),
+            natives: Natives::new(),
+            limits,
+            next_check: i64::MAX,
+            deadline: None,
+            heap_start: 0,
+            output_bytes: 0,
         }
     }
-    
+
+    /// Use line and function tables to locate runtime errors
+    ///
+    /// With them, errors name the failing function and source line, and
+    /// each frame of the backtrace is described the same way.
+    pub fn set_debug_info(&mut self, debug_info: DebugInfo) {
+        self.debug_info = debug_info;
+    }
+
+    /// Route the program's system calls to `host` instead of the real
+    /// operating system
+    pub fn set_host(&mut self, host: Box<dyn Host>) {
+        self.host = host;
+    }
+
+    /// Provide the native functions that `NATV` calls
+    ///
+    /// They must be registered in the same order as when the program was
+    /// compiled.
+    pub fn set_natives(&mut self, natives: Natives) {
+        self.natives = natives;
+    }
+
     /// Run the VM starting at the specified entry point
     ///
     /// # Arguments
@@ -63,881 +186,1012 @@ impl VirtualMachine {
     ///
     /// The exit code from the program
     pub fn run(&mut self, entry_point: usize, args: &[String]) -> Result<i64, CompilerError> {
+        self.start(entry_point, args)?;
+        Ok(self.dispatch::<false>()?.unwrap_or(self.ax))
+    }
+
+    /// Prepare to run from `entry_point` without executing anything
+    ///
+    /// Validates the code and sets up `main`'s arguments and return
+    /// address; [`VirtualMachine::step`] then executes the program one
+    /// instruction at a time.
+    pub fn start(&mut self, entry_point: usize, args: &[String]) -> Result<(), CompilerError> {
         // Setup stack for main() - matching C4.c's setup
         self.pc = entry_point;
-        
-        // Setup for EXIT when main returns
-        self.sp -= 1;
-        self.stack[self.sp] = Opcode::EXIT as i64;
-        
-        // Save stack pointer location for args setup
-        let t = self.sp;
-        
-        // Push argc (number of arguments)
-        self.sp -= 1;
-        self.stack[self.sp] = args.len() as i64;
-        
-        // Push argv pointer (simplified - in real C4, this would be more involved)
-        self.sp -= 1;
-        self.stack[self.sp] = 0; // Not fully implementing argv handling for simplicity
-        
-        // Push address for EXIT location
-        self.sp -= 1;
-        self.stack[self.sp] = t as i64;
-        
-        // Main execution loop
+
+        // When main returns it lands on `PSH; EXIT`, which exits with its result
+        let trampoline = self.code.len();
+        self.code.push(Opcode::PSH as i64);
+        self.code.push(Opcode::EXIT as i64);
+
+        // Validate and decode everything up front
+        self.program = instr::decode(&self.code)?;
+        if !matches!(self.program.get(entry_point), Some(instr) if instr.opcode().is_some()) {
+            return Err(self.error(format!("Entry point is not an instruction: {}", entry_point)));
+        }
+
+        // Copy argv strings and the pointer array into the data segment
+        let mut argv = Vec::with_capacity(args.len() + 1);
+        for arg in args {
+            argv.push(self.data.len() as i64);
+            self.data.extend_from_slice(arg.as_bytes());
+            self.data.push(0);
+        }
+        argv.push(0);
+        let argv_addr = self.align_data();
+        for ptr in argv {
+            self.data.extend_from_slice(&ptr.to_le_bytes());
+        }
+
+        self.push(args.len() as i64)?;      // argc
+        self.push(argv_addr as i64)?;       // argv
+        self.push(trampoline as i64)?;      // return address
+
+        // Limits count from here
+        self.heap_start = self.data.len();
+        self.deadline = self.limits.timeout.map(|timeout| Instant::now() + timeout);
+        self.next_check = self.next_check();
+
+        Ok(())
+    }
+
+    /// Execute one instruction
+    ///
+    /// Returns the exit code once the program has exited.
+    pub fn step(&mut self) -> Result<Option<i64>, CompilerError> {
+        self.dispatch::<true>()
+    }
+
+    /// The dispatch loop, which runs until the program exits or, if
+    /// `SINGLE_STEP` is set, for one instruction
+    ///
+    /// Stepping gets its own copy so that running to completion keeps a
+    /// tight loop.
+    fn dispatch<const SINGLE_STEP: bool>(&mut self) -> Result<Option<i64>, CompilerError> {
         loop {
             self.cycle += 1;
-            
-            // Check if PC is out of bounds
-            if self.pc >= self.code.len() {
-                return Err(CompilerError::VMError {
-                    message: format!("Program counter out of bounds: {}", self.pc),
-                    instruction: None,
-                    cycle: Some(self.cycle),
-                });
-            }
-            
+
             // Fetch instruction
-            let op = self.code[self.pc];
-            
+            let Some(&instr) = self.program.get(self.pc) else {
+                return Err(self.error(format!("Program counter out of bounds: {}", self.pc)));
+            };
+
             // Debug output
             if self.debug {
-                self.print_debug_info(op);
+                self.print_debug_info(instr);
             }
-            
+
             // Execute instruction
-            match op as usize {
-                i if i == Opcode::LEA as usize => {
+            match instr {
+                Instr::Lea(offset) => {
                     // Load effective address
-                    if self.pc + 1 >= self.code.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Unexpected end of code".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    self.ax = (self.bp as i64) + self.code[self.pc + 1];
+                    self.ax = Self::stack_address(self.bp as i64 + offset);
                     self.pc += 2;
                 },
-                i if i == Opcode::IMM as usize => {
+                Instr::Imm(value) => {
                     // Load immediate value
-                    if self.pc + 1 >= self.code.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Unexpected end of code".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    self.ax = self.code[self.pc + 1];
+                    self.ax = value;
                     self.pc += 2;
                 },
-                i if i == Opcode::JMP as usize => {
-                    // Jump
-                    if self.pc + 1 >= self.code.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Unexpected end of code".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    let target = self.code[self.pc + 1] as usize;
-                    if target >= self.code.len() {
-                        return Err(CompilerError::VMError {
-                            message: format!("Jump target out of bounds: {}", target),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
+                Instr::Jmp(target) => {
+                    // Jump. Every loop and recursion passes through a jump,
+                    // branch, call or return, so the cycle and time limits
+                    // are only checked on those
+                    if self.cycle > self.next_check {
+                        self.check_limits()?;
                     }
                     self.pc = target;
                 },
-                i if i == Opcode::JSR as usize => {
+                Instr::Jsr(target) => {
                     // Jump to subroutine
-                    if self.pc + 1 >= self.code.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Unexpected end of code".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    self.sp -= 1;
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack overflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    self.stack[self.sp] = (self.pc + 2) as i64;
-                    
-                    let target = self.code[self.pc + 1] as usize;
-                    if target >= self.code.len() {
-                        return Err(CompilerError::VMError {
-                            message: format!("Jump target out of bounds: {}", target),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
+                    if self.cycle > self.next_check {
+                        self.check_limits()?;
                     }
+                    self.push((self.pc + 2) as i64)?;
                     self.pc = target;
                 },
-                i if i == Opcode::BZ as usize => {
+                Instr::Bz(target) => {
                     // Branch if zero
-                    if self.pc + 1 >= self.code.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Unexpected end of code".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
                     if self.ax == 0 {
-                        let target = self.code[self.pc + 1] as usize;
-                        if target >= self.code.len() {
-                            return Err(CompilerError::VMError {
-                                message: format!("Branch target out of bounds: {}", target),
-                                instruction: None,
-                                cycle: Some(self.cycle),
-                            });
+                        if self.cycle > self.next_check {
+                            self.check_limits()?;
                         }
                         self.pc = target;
                     } else {
                         self.pc += 2;
                     }
                 },
-                i if i == Opcode::BNZ as usize => {
+                Instr::Bnz(target) => {
                     // Branch if not zero
-                    if self.pc + 1 >= self.code.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Unexpected end of code".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
                     if self.ax != 0 {
-                        let target = self.code[self.pc + 1] as usize;
-                        if target >= self.code.len() {
-                            return Err(CompilerError::VMError {
-                                message: format!("Branch target out of bounds: {}", target),
-                                instruction: None,
-                                cycle: Some(self.cycle),
-                            });
+                        if self.cycle > self.next_check {
+                            self.check_limits()?;
                         }
                         self.pc = target;
                     } else {
                         self.pc += 2;
                     }
                 },
-                i if i == Opcode::ENT as usize => {
+                Instr::Ent(locals) => {
                     // Enter subroutine
-                    if self.pc + 1 >= self.code.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Unexpected end of code".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    self.sp -= 1;
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack overflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    self.stack[self.sp] = self.bp as i64;
+                    self.push(self.bp as i64)?;
                     self.bp = self.sp;
-                    self.sp -= self.code[self.pc + 1] as usize;
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack overflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
+                    if locals < 0 || locals as usize > self.sp {
+                        return Err(self.limit_error(ResourceLimit::Stack(self.stack.len())));
                     }
+                    self.sp -= locals as usize;
                     self.pc += 2;
                 },
-                i if i == Opcode::ADJ as usize => {
+                Instr::Adj(words) => {
                     // Adjust stack
-                    if self.pc + 1 >= self.code.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Unexpected end of code".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
+                    let sp = self.sp as i64 + words;
+                    if sp < 0 {
+                        return Err(self.limit_error(ResourceLimit::Stack(self.stack.len())));
                     }
-                    self.sp += self.code[self.pc + 1] as usize;
-                    if self.sp > self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
+                    if sp as usize > self.stack.len() {
+                        return Err(self.error("Stack underflow".to_string()));
                     }
+                    self.sp = sp as usize;
                     self.pc += 2;
                 },
-                i if i == Opcode::LEV as usize => {
-                    // Leave subroutine
-                    self.sp = self.bp;
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack pointer out of bounds".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
+                Instr::Lev => {
+                    // Leave subroutine. A program that overwrites its saved
+                    // return address can loop through returns alone
+                    if self.cycle > self.next_check {
+                        self.check_limits()?;
                     }
-                    self.bp = self.stack[self.sp] as usize;
-                    self.sp += 1;
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack pointer out of bounds".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
+                    self.sp = self.bp;
+                    self.bp = self.pop()? as usize;
+                    self.pc = self.pop()? as usize;
+                },
+                Instr::Tsr(target) => {
+                    // Tail call: leave the frame, but keep the return
+                    // address for the function jumped to
+                    if self.cycle > self.next_check {
+                        self.check_limits()?;
                     }
-                    self.pc = self.stack[self.sp] as usize;
-                    self.sp += 1;
+                    self.sp = self.bp;
+                    self.bp = self.pop()? as usize;
+                    self.pc = target;
                 },
-                i if i == Opcode::LI as usize => {
+                Instr::Li => {
                     // Load int
-                    if self.ax as usize >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: format!("Memory access out of bounds: {}", self.ax),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    self.ax = self.stack[self.ax as usize];
+                    self.ax = self.load_int(self.ax)?;
                     self.pc += 1;
                 },
-                i if i == Opcode::LC as usize => {
+                Instr::Lc => {
                     // Load char
-                    if self.ax as usize >= self.data.len() {
-                        return Err(CompilerError::VMError {
-                            message: format!("Memory access out of bounds: {}", self.ax),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    self.ax = self.data[self.ax as usize] as i64;
+                    self.ax = self.read_byte(self.ax)? as i8 as i64;
                     self.pc += 1;
                 },
-                i if i == Opcode::SI as usize => {
+                Instr::Si => {
                     // Store int
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    let addr = self.stack[self.sp] as usize;
-                    self.sp += 1;
-                    if addr >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: format!("Memory access out of bounds: {}", addr),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    self.stack[addr] = self.ax;
+                    let addr = self.pop()?;
+                    self.store_int(addr, self.ax)?;
                     self.pc += 1;
                 },
-                i if i == Opcode::SC as usize => {
+                Instr::Sc => {
                     // Store char
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    let addr = self.stack[self.sp] as usize;
-                    self.sp += 1;
-                    if addr >= self.data.len() {
-                        // Grow data segment if necessary
-                        if addr < 1_000_000 { // Reasonable limit to prevent OOM
-                            self.data.resize(addr + 1, 0);
-                        } else {
-                            return Err(CompilerError::VMError {
-                                message: format!("Memory access out of bounds: {}", addr),
-                                instruction: None,
-                                cycle: Some(self.cycle),
-                            });
-                        }
-                    }
-                    self.data[addr] = self.ax as u8;
+                    let addr = self.pop()?;
+                    self.ax = self.ax as u8 as i8 as i64;
+                    self.write_byte(addr, self.ax as u8)?;
                     self.pc += 1;
                 },
-                i if i == Opcode::PSH as usize => {
+                Instr::Psh => {
                     // Push value onto stack
-                    self.sp -= 1;
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack overflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    self.stack[self.sp] = self.ax;
+                    self.push(self.ax)?;
                     self.pc += 1;
                 },
-                i if i == Opcode::OR as usize => {
+                Instr::Or => {
                     // Bitwise OR
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    self.ax = self.stack[self.sp] | self.ax;
-                    self.sp += 1;
+                    self.ax |= self.pop()?;
                     self.pc += 1;
                 },
-                i if i == Opcode::XOR as usize => {
+                Instr::Xor => {
                     // Bitwise XOR
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    self.ax = self.stack[self.sp] ^ self.ax;
-                    self.sp += 1;
+                    self.ax ^= self.pop()?;
                     self.pc += 1;
                 },
-                i if i == Opcode::AND as usize => {
+                Instr::And => {
                     // Bitwise AND
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    self.ax = self.stack[self.sp] & self.ax;
-                    self.sp += 1;
+                    self.ax &= self.pop()?;
                     self.pc += 1;
                 },
-                i if i == Opcode::EQ as usize => {
+                Instr::Eq => {
                     // Equal
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    self.ax = (self.stack[self.sp] == self.ax) as i64;
-                    self.sp += 1;
+                    self.ax = (self.pop()? == self.ax) as i64;
                     self.pc += 1;
                 },
-                i if i == Opcode::NE as usize => {
+                Instr::Ne => {
                     // Not equal
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    self.ax = (self.stack[self.sp] != self.ax) as i64;
-                    self.sp += 1;
+                    self.ax = (self.pop()? != self.ax) as i64;
                     self.pc += 1;
                 },
-                i if i == Opcode::LT as usize => {
+                Instr::Lt => {
                     // Less than
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    self.ax = (self.stack[self.sp] < self.ax) as i64;
-                    self.sp += 1;
+                    self.ax = (self.pop()? < self.ax) as i64;
                     self.pc += 1;
                 },
-                i if i == Opcode::GT as usize => {
+                Instr::Gt => {
                     // Greater than
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    self.ax = (self.stack[self.sp] > self.ax) as i64;
-                    self.sp += 1;
+                    self.ax = (self.pop()? > self.ax) as i64;
                     self.pc += 1;
                 },
-                i if i == Opcode::LE as usize => {
+                Instr::Le => {
                     // Less than or equal
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    self.ax = (self.stack[self.sp] <= self.ax) as i64;
-                    self.sp += 1;
+                    self.ax = (self.pop()? <= self.ax) as i64;
                     self.pc += 1;
                 },
-                i if i == Opcode::GE as usize => {
+                Instr::Ge => {
                     // Greater than or equal
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    self.ax = (self.stack[self.sp] >= self.ax) as i64;
-                    self.sp += 1;
+                    self.ax = (self.pop()? >= self.ax) as i64;
                     self.pc += 1;
                 },
-                i if i == Opcode::SHL as usize => {
+                Instr::Shl => {
                     // Shift left
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    self.ax = self.stack[self.sp] << self.ax;
-                    self.sp += 1;
+                    self.ax = self.pop()?.wrapping_shl(self.ax as u32);
                     self.pc += 1;
                 },
-                i if i == Opcode::SHR as usize => {
+                Instr::Shr => {
                     // Shift right
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    self.ax = self.stack[self.sp] >> self.ax;
-                    self.sp += 1;
+                    self.ax = self.pop()?.wrapping_shr(self.ax as u32);
                     self.pc += 1;
                 },
-                i if i == Opcode::ADD as usize => {
+                Instr::Add => {
                     // Add
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    self.ax = self.stack[self.sp] + self.ax;
-                    self.sp += 1;
+                    self.ax = self.pop()?.wrapping_add(self.ax);
                     self.pc += 1;
                 },
-                i if i == Opcode::SUB as usize => {
+                Instr::Sub => {
                     // Subtract
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    self.ax = self.stack[self.sp] - self.ax;
-                    self.sp += 1;
+                    self.ax = self.pop()?.wrapping_sub(self.ax);
                     self.pc += 1;
                 },
-                i if i == Opcode::MUL as usize => {
+                Instr::Mul => {
                     // Multiply
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    self.ax = self.stack[self.sp] * self.ax;
-                    self.sp += 1;
+                    self.ax = self.pop()?.wrapping_mul(self.ax);
                     self.pc += 1;
                 },
-                i if i == Opcode::DIV as usize => {
+                Instr::Div => {
                     // Divide
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
+                    let lhs = self.pop()?;
                     if self.ax == 0 {
-                        return Err(CompilerError::VMError {
-                            message: "Division by zero".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
+                        return Err(self.error("Division by zero".to_string()));
                     }
-                    self.ax = self.stack[self.sp] / self.ax;
-                    self.sp += 1;
+                    self.ax = lhs.wrapping_div(self.ax);
                     self.pc += 1;
                 },
-                i if i == Opcode::MOD as usize => {
+                Instr::Mod => {
                     // Modulo
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
+                    let lhs = self.pop()?;
                     if self.ax == 0 {
-                        return Err(CompilerError::VMError {
-                            message: "Division by zero in modulo".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
+                        return Err(self.error("Division by zero in modulo".to_string()));
                     }
-                    self.ax = self.stack[self.sp] % self.ax;
-                    self.sp += 1;
+                    self.ax = lhs.wrapping_rem(self.ax);
                     self.pc += 1;
                 },
-                i if i == Opcode::OPEN as usize => {
-                    // Open file - simplified for cross-platform compatibility
-                    if self.sp + 1 >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    let path_ptr = self.stack[self.sp + 1] as usize;
-                    let mode = self.stack[self.sp] as i32;
-                    
-                    // Read null-terminated string from data segment
-                    let mut path = Vec::new();
-                    let mut ptr = path_ptr;
-                    while ptr < self.data.len() && self.data[ptr] != 0 {
-                        path.push(self.data[ptr]);
-                        ptr += 1;
-                    }
-                    
-                    let path_str = match std::str::from_utf8(&path) {
-                        Ok(s) => s,
-                        Err(_) => return Err(CompilerError::VMError {
-                            message: "Invalid path string".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        }),
-                    };
-                    
-                    // Simple file open implementation
-                    match std::fs::OpenOptions::new()
-                        .read(mode & 0o1 != 0)
-                        .write(mode & 0o2 != 0)
-                        .open(path_str) {
-                        Ok(_) => self.ax = 3, // Simplified: always return fd 3 (real C4 would track file handles)
-                        Err(_) => self.ax = -1,
-                    }
-                    
-                    self.sp += 2;
+                // System calls leave their arguments on the stack; the ADJ
+                // that follows the call removes them (as in C4.c)
+                Instr::Open | Instr::Read | Instr::Clos | Instr::Prtf(_) |
+                Instr::Malc | Instr::Free | Instr::Mset | Instr::Mcmp => {
+                    self.syscall(instr)?;
                     self.pc += 1;
                 },
-                i if i == Opcode::READ as usize => {
-                    // Read from file - simplified
-                    if self.sp + 2 >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    
-                    let fd = self.stack[self.sp + 2] as i32;
-                    let buf_ptr = self.stack[self.sp + 1] as usize;
-                    let count = self.stack[self.sp] as usize;
-                    
-                    // Ensure data segment is large enough
-                    if buf_ptr + count > self.data.len() {
-                        self.data.resize(buf_ptr + count, 0);
-                    }
-                    
-                    // Simplified read implementation (just read from stdin)
-                    if fd == 0 {
-                        let mut input = io::stdin();
-                        let bytes_read = match input.read(&mut self.data[buf_ptr..buf_ptr + count]) {
-                            Ok(n) => n as i64,
-                            Err(_) => -1,
-                        };
-                        self.ax = bytes_read;
-                    } else {
-                        self.ax = -1; // Simplified: not implementing file reads
-                    }
-                    
-                    self.sp += 3;
-                    self.pc += 1;
+                Instr::Natv(index) => {
+                    // Arguments stay on the stack for the following ADJ, as
+                    // with system calls
+                    self.call_native(index)?;
+                    self.pc += 2;
                 },
-                i if i == Opcode::CLOS as usize => {
-                    // Close file - simplified
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    
-                    // Just return success
-                    self.ax = 0;
-                    self.sp += 1;
+                Instr::Exit => {
+                    // Exit with the value in AX (the pushed argument of exit(),
+                    // or main's result via the return trampoline)
+                    return Ok(Some(self.exit(self.ax)));
+                },
+                Instr::Neg => {
+                    // Negate
+                    self.ax = self.ax.wrapping_neg();
                     self.pc += 1;
                 },
-                i if i == Opcode::PRTF as usize => {
-                    // Printf - simplified implementation
-                    if self.pc + 1 >= self.code.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Unexpected end of code".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    
-                    let arg_count = self.code[self.pc + 1] as usize;
-                    if self.sp + arg_count > self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    
-                    // The format string is the first argument
-                    let fmt_ptr = self.stack[self.sp] as usize;
-                    
-                    // Read format string from memory
-                    let mut fmt = Vec::new();
-                    let mut ptr = fmt_ptr;
-                    while ptr < self.data.len() && self.data[ptr] != 0 {
-                        fmt.push(self.data[ptr]);
-                        ptr += 1;
-                    }
-                    
-                    let fmt_str = match std::str::from_utf8(&fmt) {
-                        Ok(s) => s,
-                        Err(_) => return Err(CompilerError::VMError {
-                            message: "Invalid format string".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        }),
-                    };
-                    
-                    // Very simplified printf implementation - just print the format string
-                    print!("{}", fmt_str);
-                    io::stdout().flush().unwrap();
-                    
-                    self.ax = fmt_str.len() as i64;
-                    self.sp += arg_count;
+                Instr::Lli(offset) => {
+                    // Load int local
+                    self.ax = self.local(offset)?;
                     self.pc += 2;
                 },
-                i if i == Opcode::MALC as usize => {
-                    // Malloc - simplified implementation
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    
-                    let size = self.stack[self.sp] as usize;
-                    
-                    // Simplified: allocate from the end of the data segment
-                    let addr = self.data.len();
-                    self.data.resize(addr + size, 0);
-                    
-                    self.ax = addr as i64;
-                    self.sp += 1;
-                    self.pc += 1;
+                Instr::Llc(offset) => {
+                    // Load char local
+                    self.ax = self.read_byte(Self::stack_address(self.bp as i64 + offset))? as i8 as i64;
+                    self.pc += 2;
                 },
-                i if i == Opcode::FREE as usize => {
-                    // Free - no-op in this simplified implementation
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    
-                    self.sp += 1;
-                    self.pc += 1;
+                Instr::Pshl(offset) => {
+                    // Push int local
+                    self.ax = self.local(offset)?;
+                    self.push(self.ax)?;
+                    self.pc += 2;
                 },
-                i if i == Opcode::MSET as usize => {
-                    // Memset
-                    if self.sp + 2 >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    
-                    let dst_ptr = self.stack[self.sp + 2] as usize;
-                    let value = self.stack[self.sp + 1] as u8;
-                    let count = self.stack[self.sp] as usize;
-                    
-                    // Ensure data segment is large enough
-                    if dst_ptr + count > self.data.len() {
-                        self.data.resize(dst_ptr + count, 0);
-                    }
-                    
-                    // Set memory
-                    for i in 0..count {
-                        self.data[dst_ptr + i] = value;
-                    }
-                    
-                    self.ax = dst_ptr as i64;
-                    self.sp += 3;
-                    self.pc += 1;
+                Instr::Pshi(value) => {
+                    // Push immediate
+                    self.ax = value;
+                    self.push(self.ax)?;
+                    self.pc += 2;
                 },
-                i if i == Opcode::MCMP as usize => {
-                    // Memcmp
-                    if self.sp + 2 >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    
-                    let s1_ptr = self.stack[self.sp + 2] as usize;
-                    let s2_ptr = self.stack[self.sp + 1] as usize;
-                    let count = self.stack[self.sp] as usize;
-                    
-                    // Check bounds
-                    if s1_ptr + count > self.data.len() || s2_ptr + count > self.data.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Memory access out of bounds".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    
-                    // Compare memory
-                    for i in 0..count {
-                        let a = self.data[s1_ptr + i];
-                        let b = self.data[s2_ptr + i];
-                        if a != b {
-                            self.ax = (a as i64) - (b as i64);
-                            self.sp += 3;
-                            self.pc += 1;
-                            return Ok(0); // Return 0 to continue execution
-                        }
-                    }
-                    
-                    self.ax = 0; // Equal
-                    self.sp += 3;
-                    self.pc += 1;
+                Instr::Addi(value) => {
+                    // Add immediate
+                    self.ax = self.ax.wrapping_add(value);
+                    self.pc += 2;
                 },
-                i if i == Opcode::EXIT as usize => {
-                    // Exit
-                    if self.sp >= self.stack.len() {
-                        return Err(CompilerError::VMError {
-                            message: "Stack underflow".to_string(),
-                            instruction: None,
-                            cycle: Some(self.cycle),
-                        });
-                    }
-                    
-                    if self.debug {
-                        println!("exit({}) cycle = {}", self.stack[self.sp], self.cycle);
-                    }
-                    
-                    return Ok(self.stack[self.sp]);
+                Instr::Subi(value) => {
+                    // Subtract immediate
+                    self.ax = self.ax.wrapping_sub(value);
+                    self.pc += 2;
+                },
+                Instr::Muli(value) => {
+                    // Multiply by immediate
+                    self.ax = self.ax.wrapping_mul(value);
+                    self.pc += 2;
                 },
-                _ => {
-                    return Err(CompilerError::VMError {
-                        message: format!("Unknown opcode: {}", op),
-                        instruction: None,
-                        cycle: Some(self.cycle),
-                    });
+                Instr::Operand => {
+                    // Only reachable through a corrupted return address
+                    return Err(self.error(format!("Program counter is not at an instruction: {}", self.pc)));
+                },
+            }
+
+            if SINGLE_STEP {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Execute a system call
+    ///
+    /// Kept out of the dispatch loop so the common instructions stay compact.
+    #[inline(never)]
+    fn syscall(&mut self, instr: Instr) -> Result<(), CompilerError> {
+        match instr {
+            Instr::Open => {
+                // Open file through the host, returning a descriptor or -1
+                let path = self.read_cstring(self.arg(1)?)?;
+                let flags = self.arg(0)?;
+
+                let path_str = match std::str::from_utf8(&path) {
+                    Ok(s) => s,
+                    Err(_) => return Err(self.error("Invalid path string".to_string())),
+                };
+                self.ax = self.host.open(path_str, flags).unwrap_or(-1);
+            },
+            Instr::Read => {
+                // Read from a descriptor, returning the byte count or -1
+                let fd = self.arg(2)?;
+                let buf = self.arg(1)?;
+                let count = (self.arg(0)?.max(0) as usize).min(DATA_LIMIT);
+
+                let mut bytes = vec![0; count];
+                match self.host.read(fd, &mut bytes) {
+                    Ok(n) => {
+                        self.write_memory(buf, &bytes[..n])?;
+                        self.ax = n as i64;
+                    },
+                    Err(_) => self.ax = -1,
+                }
+            },
+            Instr::Clos => {
+                // Close a descriptor, returning 0 or -1
+                let fd = self.arg(0)?;
+                self.ax = if self.host.close(fd).is_ok() { 0 } else { -1 };
+            },
+            Instr::Prtf(arg_count) => {
+                // Printf - the argument count comes from the following ADJ
+                if self.sp + arg_count > self.stack.len() {
+                    return Err(self.error("Stack underflow".to_string()));
+                }
+
+                // The format string is the first (deepest) argument
+                let top = self.sp + arg_count;
+                let fmt = self.read_cstring(self.stack[top - 1])?;
+                let args: Vec<i64> = (2..=arg_count).map(|k| self.stack[top - k]).collect();
+
+                let out = format(&fmt, &args, |addr| self.read_cstring(addr))?;
+                self.output_bytes += out.len();
+                if let Some(max) = self.limits.max_output_bytes.filter(|&max| self.output_bytes > max) {
+                    return Err(self.limit_error(ResourceLimit::Output(max)));
+                }
+                self.host.write(STDOUT, &out).map_err(CompilerError::IOError)?;
+
+                self.ax = out.len() as i64;
+            },
+            Instr::Malc => {
+                // Malloc - simplified implementation
+                let size = self.arg(0)?;
+
+                // Simplified: allocate from the end of the data segment
+                let addr = self.align_data();
+                if size < 0 || addr + size as usize > DATA_LIMIT {
+                    self.ax = 0;
+                } else {
+                    self.check_heap(addr + size as usize)?;
+                    self.data.resize(addr + size as usize, 0);
+                    self.ax = addr as i64;
+                }
+            },
+            Instr::Free => {
+                // Free - no-op in this simplified implementation
+                self.arg(0)?;
+            },
+            Instr::Mset => {
+                // Memset
+                let dst = self.arg(2)?;
+                let value = self.arg(1)? as u8;
+                let count = self.arg(0)?;
+
+                for i in 0..count {
+                    self.write_byte(dst + i, value)?;
                 }
+
+                self.ax = dst;
+            },
+            Instr::Mcmp => {
+                // Memcmp
+                let s1 = self.arg(2)?;
+                let s2 = self.arg(1)?;
+                let count = self.arg(0)?;
+
+                self.ax = 0; // Equal
+                for i in 0..count {
+                    let a = self.read_byte(s1 + i)?;
+                    let b = self.read_byte(s2 + i)?;
+                    if a != b {
+                        self.ax = (a as i64) - (b as i64);
+                        break;
+                    }
+                }
+            },
+            _ => unreachable!("{} is not a system call", instr),
+        }
+        Ok(())
+    }
+
+    /// Call a native function with its arguments from the stack
+    #[inline(never)]
+    fn call_native(&mut self, index: usize) -> Result<(), CompilerError> {
+        let Some(native) = self.natives.get(index).cloned() else {
+            return Err(self.error(format!("Unknown native function {}", index)));
+        };
+        let args = (0..native.arity).rev()
+            .map(|n| self.arg(n))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.ax = (native.func)(self, &args)
+            .map_err(|message| self.error(format!("{}: {}", native.name, message)))?;
+        Ok(())
+    }
+
+    /// Build a runtime error for the current cycle
+    #[cold]
+    pub(crate) fn error(&self, message: String) -> CompilerError {
+        CompilerError::VMError {
+            message,
+            instruction: self.program.get(self.pc)
+                .filter(|instr| instr.opcode().is_some())
+                .map(|instr| instr.to_string()),
+            cycle: Some(self.cycle),
+            backtrace: self.backtrace(),
+        }
+    }
+
+    /// Error for running into a resource limit
+    pub(crate) fn limit_error(&self, limit: ResourceLimit) -> CompilerError {
+        CompilerError::LimitExceeded {
+            limit,
+            instruction: self.program.get(self.pc)
+                .filter(|instr| instr.opcode().is_some())
+                .map(|instr| instr.to_string()),
+            cycle: Some(self.cycle),
+            backtrace: self.backtrace(),
+        }
+    }
+
+    /// Check the cycle and time limits, and decide when to check next
+    ///
+    /// Called on taken jumps, branches, calls and returns once `cycle`
+    /// passes `next_check`, so a program is stopped within one straight run
+    /// of code after reaching a limit, and checking costs nothing elsewhere.
+    #[cold]
+    #[inline(never)]
+    fn check_limits(&mut self) -> Result<(), CompilerError> {
+        if let Some(max) = self.limits.max_cycles.filter(|&max| self.cycle as u64 > max) {
+            return Err(self.limit_error(ResourceLimit::Cycles(max)));
+        }
+        if let (Some(deadline), Some(timeout)) = (self.deadline, self.limits.timeout) {
+            if Instant::now() >= deadline {
+                return Err(self.limit_error(ResourceLimit::Time(timeout)));
             }
         }
+        self.next_check = self.next_check();
+        Ok(())
     }
-    
-    /// Print debugging information for the current instruction
-    fn print_debug_info(&self, op: i64) {
-        let opcode_str = match op as usize {
-            i if i == Opcode::LEA as usize => "LEA",
-            i if i == Opcode::IMM as usize => "IMM",
-            i if i == Opcode::JMP as usize => "JMP",
-            i if i == Opcode::JSR as usize => "JSR",
-            i if i == Opcode::BZ as usize => "BZ",
-            i if i == Opcode::BNZ as usize => "BNZ",
-            i if i == Opcode::ENT as usize => "ENT",
-            i if i == Opcode::ADJ as usize => "ADJ",
-            i if i == Opcode::LEV as usize => "LEV",
-            i if i == Opcode::LI as usize => "LI",
-            i if i == Opcode::LC as usize => "LC",
-            i if i == Opcode::SI as usize => "SI",
-            i if i == Opcode::SC as usize => "SC",
-            i if i == Opcode::PSH as usize => "PSH",
-            i if i == Opcode::OR as usize => "OR",
-            i if i == Opcode::XOR as usize => "XOR",
-            i if i == Opcode::AND as usize => "AND",
-            i if i == Opcode::EQ as usize => "EQ",
-            i if i == Opcode::NE as usize => "NE",
-            i if i == Opcode::LT as usize => "LT",
-            i if i == Opcode::GT as usize => "GT",
-            i if i == Opcode::LE as usize => "LE",
-            i if i == Opcode::GE as usize => "GE",
-            i if i == Opcode::SHL as usize => "SHL",
-            i if i == Opcode::SHR as usize => "SHR",
-            i if i == Opcode::ADD as usize => "ADD",
-            i if i == Opcode::SUB as usize => "SUB",
-            i if i == Opcode::MUL as usize => "MUL",
-            i if i == Opcode::DIV as usize => "DIV",
-            i if i == Opcode::MOD as usize => "MOD",
-            i if i == Opcode::OPEN as usize => "OPEN",
-            i if i == Opcode::READ as usize => "READ",
-            i if i == Opcode::CLOS as usize => "CLOS",
-            i if i == Opcode::PRTF as usize => "PRTF",
-            i if i == Opcode::MALC as usize => "MALC",
-            i if i == Opcode::FREE as usize => "FREE",
-            i if i == Opcode::MSET as usize => "MSET",
-            i if i == Opcode::MCMP as usize => "MCMP",
-            i if i == Opcode::EXIT as usize => "EXIT",
-            _ => "???",
+
+    /// The last cycle that can run before the limits must be checked
+    fn next_check(&self) -> i64 {
+        let cycles = self.limits.max_cycles.map_or(i64::MAX, |max| max.min(i64::MAX as u64) as i64);
+        match self.deadline {
+            Some(_) => cycles.min(self.cycle.saturating_add(CLOCK_INTERVAL)),
+            None => cycles,
+        }
+    }
+
+    /// Check that growing the data segment to `len` bytes stays within the
+    /// heap limit
+    fn check_heap(&self, len: usize) -> Result<(), CompilerError> {
+        match self.limits.max_heap_bytes {
+            Some(max) if len.saturating_sub(self.heap_start) > max => {
+                Err(self.limit_error(ResourceLimit::Heap(max)))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Describe the call stack, innermost frame first, using the line and
+    /// function tables
+    ///
+    /// Lists at most a fixed number of frames.
+    pub fn backtrace(&self) -> Vec<StackFrame> {
+        self.frames().iter()
+            .take(MAX_BACKTRACE)
+            .map(|frame| self.debug_info.frame(frame.pc))
+            .collect()
+    }
+
+    /// The active calls, innermost first
+    ///
+    /// Each frame saved by `ENT` holds the caller's `bp` with the return
+    /// address above it, so the chain of saved `bp`s leads through every
+    /// active call; the call instruction sits just before its return
+    /// address. The walk stops at main's return to the trampoline, or at
+    /// anything that does not look like a frame.
+    pub fn frames(&self) -> Vec<Frame> {
+        if self.program.is_empty() {
+            return Vec::new();
+        }
+        let mut frames = vec![Frame { pc: self.pc, bp: Self::stack_address(self.bp as i64) }];
+        let trampoline = self.code.len() - 2;
+        let is_call = |ret: i64| {
+            ret >= 2 && (ret as usize) < trampoline &&
+                matches!(self.program[ret as usize - 2], Instr::Jsr(_))
         };
-        
-        print!("{:4}> {:8}", self.cycle, opcode_str);
-        
-        // Print operand for instructions that have one
-        if op as usize == Opcode::IMM as usize || 
-           op as usize == Opcode::LEA as usize || 
-           op as usize == Opcode::JMP as usize || 
-           op as usize == Opcode::JSR as usize || 
-           op as usize == Opcode::BZ as usize || 
-           op as usize == Opcode::BNZ as usize || 
-           op as usize == Opcode::ENT as usize || 
-           op as usize == Opcode::ADJ as usize {
-            if self.pc + 1 < self.code.len() {
-                println!(" {}", self.code[self.pc + 1]);
-            } else {
-                println!(" ???");
+
+        // An ENT that has not set up its frame yet leaves the return address
+        // on top of the stack, and the caller's frame in bp
+        let mut bp = self.bp;
+        if matches!(self.program.get(self.pc), Some(Instr::Ent(_))) && self.bp != self.sp {
+            if let Some(&ret) = self.stack.get(self.sp).filter(|&&ret| is_call(ret)) {
+                frames.push(Frame { pc: ret as usize - 2, bp: Self::stack_address(bp as i64) });
+            }
+        }
+
+        while bp + 1 < self.stack.len() {
+            let (saved_bp, ret) = (self.stack[bp], self.stack[bp + 1]);
+            if !is_call(ret) || saved_bp <= bp as i64 {
+                break;
+            }
+
+            // Frames are strictly further up the stack
+            bp = saved_bp as usize;
+            frames.push(Frame { pc: ret as usize - 2, bp: Self::stack_address(bp as i64) });
+        }
+        frames
+    }
+
+    /// Current register values
+    pub fn registers(&self) -> Registers {
+        Registers {
+            pc: self.pc,
+            sp: Self::stack_address(self.sp as i64),
+            bp: Self::stack_address(self.bp as i64),
+            ax: self.ax,
+        }
+    }
+
+    /// Number of instructions executed so far
+    pub fn cycle(&self) -> i64 {
+        self.cycle
+    }
+
+    /// The decoded instruction at `pc`, once the program has started
+    pub fn instruction(&self, pc: usize) -> Option<Instr> {
+        self.program.get(pc).copied().filter(|instr| instr.opcode().is_some())
+    }
+
+    /// The line and function tables in use
+    pub fn debug_info(&self) -> &DebugInfo {
+        &self.debug_info
+    }
+
+    /// Read `len` bytes of data or stack memory
+    pub fn read_memory(&self, addr: i64, len: usize) -> Result<Vec<u8>, CompilerError> {
+        (0..len as i64).map(|i| self.read_byte(addr + i)).collect()
+    }
+
+    /// Write bytes to data or stack memory
+    pub fn write_memory(&mut self, addr: i64, bytes: &[u8]) -> Result<(), CompilerError> {
+        for (i, &byte) in bytes.iter().enumerate() {
+            self.write_byte(addr + i as i64, byte)?;
+        }
+        Ok(())
+    }
+
+    /// Read a 64-bit integer from data or stack memory
+    pub fn read_int(&self, addr: i64) -> Result<i64, CompilerError> {
+        self.load_int(addr)
+    }
+
+    /// Read a NUL-terminated string from data or stack memory
+    ///
+    /// Invalid UTF-8 is replaced, as for printed output.
+    pub fn read_string(&self, addr: i64) -> Result<String, CompilerError> {
+        Ok(String::from_utf8_lossy(&self.read_cstring(addr)?).into_owned())
+    }
+
+    // The register machine runs its own code on this machine's memory, and
+    // drives it through the methods below
+
+    /// The stack and base pointers, as indices into the stack
+    pub(crate) fn frame(&self) -> (usize, usize) {
+        (self.sp, self.bp)
+    }
+
+    /// Move the stack and base pointers
+    pub(crate) fn set_frame(&mut self, sp: usize, bp: usize) {
+        self.sp = sp;
+        self.bp = bp;
+    }
+
+    /// The stack word `offset` words from the base pointer
+    #[inline(always)]
+    pub(crate) fn frame_word(&self, offset: isize) -> Option<i64> {
+        self.stack.get(self.bp.wrapping_add_signed(offset)).copied()
+    }
+
+    #[inline(always)]
+    pub(crate) fn frame_word_mut(&mut self, offset: isize) -> Option<&mut i64> {
+        self.stack.get_mut(self.bp.wrapping_add_signed(offset))
+    }
+
+    /// Point at the instruction being executed, which errors and
+    /// backtraces name
+    pub(crate) fn set_pc(&mut self, pc: usize) {
+        self.pc = pc;
+    }
+
+    /// Count an instruction executed elsewhere
+    #[inline(always)]
+    pub(crate) fn tick(&mut self) {
+        self.cycle += 1;
+    }
+
+    /// Check the cycle and time limits if they are due, as taken jumps do,
+    /// naming the instruction at `pc` if they have run out
+    #[inline(always)]
+    pub(crate) fn poll_limits(&mut self, pc: impl FnOnce() -> usize) -> Result<(), CompilerError> {
+        if self.cycle > self.next_check {
+            self.pc = pc();
+            self.check_limits()?;
+        }
+        Ok(())
+    }
+
+    /// Make a system call or call a native function with its arguments on
+    /// top of the stack, returning its result
+    pub(crate) fn system_call(&mut self, instr: Instr) -> Result<i64, CompilerError> {
+        match instr {
+            Instr::Natv(index) => self.call_native(index)?,
+            _ => self.syscall(instr)?,
+        }
+        Ok(self.ax)
+    }
+
+    /// Exit with `code`, as `EXIT` does
+    pub(crate) fn exit(&mut self, code: i64) -> i64 {
+        self.ax = code;
+        if self.debug {
+            println!("exit({}) cycle = {}", code, self.cycle);
+        }
+        self.host.exit(code);
+        code
+    }
+
+    /// Load a char, sign-extended as `LC` does
+    pub(crate) fn load_char(&self, addr: i64) -> Result<i64, CompilerError> {
+        Ok(self.read_byte(addr)? as i8 as i64)
+    }
+
+    /// Store the low byte of `value`, as `SC` does
+    pub(crate) fn store_char(&mut self, addr: i64, value: i64) -> Result<(), CompilerError> {
+        self.write_byte(addr, value as u8)
+    }
+
+    /// Push a value onto the stack
+    fn push(&mut self, value: i64) -> Result<(), CompilerError> {
+        if self.sp == 0 {
+            return Err(self.limit_error(ResourceLimit::Stack(self.stack.len())));
+        }
+        self.sp -= 1;
+        self.stack[self.sp] = value;
+        Ok(())
+    }
+
+    /// Pop a value from the stack
+    fn pop(&mut self) -> Result<i64, CompilerError> {
+        if self.sp >= self.stack.len() {
+            return Err(self.error("Stack underflow".to_string()));
+        }
+        let value = self.stack[self.sp];
+        self.sp += 1;
+        Ok(value)
+    }
+
+    /// Read the n-th system call argument, counting from the last one pushed
+    fn arg(&self, n: usize) -> Result<i64, CompilerError> {
+        match self.stack.get(self.sp + n) {
+            Some(&value) => Ok(value),
+            None => Err(self.error("Stack underflow".to_string())),
+        }
+    }
+
+    /// Read the int local at `offset` words from the base pointer
+    fn local(&self, offset: i64) -> Result<i64, CompilerError> {
+        match self.stack.get((self.bp as i64 + offset) as usize) {
+            Some(&value) => Ok(value),
+            None => Err(self.error(format!("Memory access out of bounds: {}",
+                                           Self::stack_address(self.bp as i64 + offset)))),
+        }
+    }
+
+    /// Address of a stack word
+    fn stack_address(word: i64) -> i64 {
+        STACK_BASE + word * 8
+    }
+
+    /// Pad the data segment to a word boundary and return its length
+    fn align_data(&mut self) -> usize {
+        let aligned = self.data.len().next_multiple_of(8);
+        self.data.resize(aligned, 0);
+        aligned
+    }
+
+    /// Read a byte from data or stack memory
+    fn read_byte(&self, addr: i64) -> Result<u8, CompilerError> {
+        if addr >= STACK_BASE {
+            let offset = (addr - STACK_BASE) as usize;
+            if let Some(word) = self.stack.get(offset / 8) {
+                return Ok(word.to_le_bytes()[offset % 8]);
+            }
+        } else if addr >= 0 && (addr as usize) < self.data.len() {
+            return Ok(self.data[addr as usize]);
+        }
+        Err(self.error(format!("Memory access out of bounds: {}", addr)))
+    }
+
+    /// Write a byte to data or stack memory
+    ///
+    /// Writes just past the end of the data segment grow it, up to a limit.
+    fn write_byte(&mut self, addr: i64, value: u8) -> Result<(), CompilerError> {
+        if addr >= STACK_BASE {
+            let offset = (addr - STACK_BASE) as usize;
+            if let Some(word) = self.stack.get_mut(offset / 8) {
+                let mut bytes = word.to_le_bytes();
+                bytes[offset % 8] = value;
+                *word = i64::from_le_bytes(bytes);
+                return Ok(());
+            }
+        } else if addr >= 0 && (addr as usize) < DATA_LIMIT {
+            let addr = addr as usize;
+            if addr >= self.data.len() {
+                self.check_heap(addr + 1)?;
+                self.data.resize(addr + 1, 0);
+            }
+            self.data[addr] = value;
+            return Ok(());
+        }
+        Err(self.error(format!("Memory access out of bounds: {}", addr)))
+    }
+
+    /// Load a 64-bit integer
+    pub(crate) fn load_int(&self, addr: i64) -> Result<i64, CompilerError> {
+        if addr >= STACK_BASE && (addr - STACK_BASE) % 8 == 0 {
+            if let Some(&value) = self.stack.get(((addr - STACK_BASE) / 8) as usize) {
+                return Ok(value);
+            }
+        } else if let Some(bytes) = self.data_word(addr) {
+            return Ok(i64::from_le_bytes(bytes.try_into().unwrap()));
+        }
+        let mut bytes = [0; 8];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.read_byte(addr + i as i64)?;
+        }
+        Ok(i64::from_le_bytes(bytes))
+    }
+
+    /// Store a 64-bit integer
+    pub(crate) fn store_int(&mut self, addr: i64, value: i64) -> Result<(), CompilerError> {
+        if addr >= STACK_BASE && (addr - STACK_BASE) % 8 == 0 {
+            if let Some(slot) = self.stack.get_mut(((addr - STACK_BASE) / 8) as usize) {
+                *slot = value;
+                return Ok(());
+            }
+        } else if self.data_word(addr).is_some() {
+            let addr = addr as usize;
+            self.data[addr..addr + 8].copy_from_slice(&value.to_le_bytes());
+            return Ok(());
+        }
+        for (i, byte) in value.to_le_bytes().iter().enumerate() {
+            self.write_byte(addr + i as i64, *byte)?;
+        }
+        Ok(())
+    }
+
+    /// The eight bytes of the data segment at `addr`, if they are all in it
+    #[inline(always)]
+    fn data_word(&self, addr: i64) -> Option<&[u8]> {
+        let addr = usize::try_from(addr).ok()?;
+        self.data.get(addr..addr.checked_add(8)?)
+    }
+
+    /// Read a NUL-terminated string
+    fn read_cstring(&self, addr: i64) -> Result<Vec<u8>, CompilerError> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = self.read_byte(addr + bytes.len() as i64)?;
+            if byte == 0 {
+                return Ok(bytes);
+            }
+            bytes.push(byte);
+        }
+    }
+
+    /// Print debugging information for the current instruction
+    #[cold]
+    fn print_debug_info(&self, instr: Instr) {
+        println!("{:4}> {}", self.cycle, instr);
+    }
+}
+
+/// Format a printf-style string
+///
+/// Supports the `-`, `0`, `+` and space flags, width and precision
+/// (including `*`), the `l` length modifier and the `d i u x X o c s p %`
+/// conversions. `%s` reads its string through `read_string`.
+pub(crate) fn format(
+    fmt: &[u8],
+    args: &[i64],
+    mut read_string: impl FnMut(i64) -> Result<Vec<u8>, CompilerError>,
+) -> Result<Vec<u8>, CompilerError> {
+    let mut out = Vec::new();
+    let mut args = args.iter().copied();
+    let mut next_arg = || args.next().unwrap_or(0);
+    let mut i = 0;
+
+    while i < fmt.len() {
+        if fmt[i] != b'%' {
+            out.push(fmt[i]);
+            i += 1;
+            continue;
+        }
+        i += 1;
+
+        // Flags
+        let (mut left, mut zero, mut plus, mut space) = (false, false, false, false);
+        while i < fmt.len() {
+            match fmt[i] {
+                b'-' => left = true,
+                b'0' => zero = true,
+                b'+' => plus = true,
+                b' ' => space = true,
+                _ => break,
+            }
+            i += 1;
+        }
+
+        // Width
+        let mut width = 0;
+        if i < fmt.len() && fmt[i] == b'*' {
+            let w = next_arg();
+            left |= w < 0;
+            width = w.unsigned_abs() as usize;
+            i += 1;
+        }
+        while i < fmt.len() && fmt[i].is_ascii_digit() {
+            width = width * 10 + (fmt[i] - b'0') as usize;
+            i += 1;
+        }
+
+        // Precision
+        let mut precision = None;
+        if i < fmt.len() && fmt[i] == b'.' {
+            i += 1;
+            let mut p = 0;
+            if i < fmt.len() && fmt[i] == b'*' {
+                p = next_arg().max(0) as usize;
+                i += 1;
+            }
+            while i < fmt.len() && fmt[i].is_ascii_digit() {
+                p = p * 10 + (fmt[i] - b'0') as usize;
+                i += 1;
             }
+            precision = Some(p);
+        }
+
+        // Length modifiers are accepted but every argument is 64-bit
+        while i < fmt.len() && fmt[i] == b'l' {
+            i += 1;
+        }
+
+        let Some(&conv) = fmt.get(i) else {
+            out.push(b'%');
+            break;
+        };
+        i += 1;
+
+        let (sign, body): (&[u8], Vec<u8>) = match conv {
+            b'd' | b'i' => {
+                let v = next_arg();
+                let sign: &[u8] = if v < 0 { b"-" } else if plus { b"+" } else if space { b" " } else { b"" };
+                (sign, pad_digits(v.unsigned_abs().to_string(), precision))
+            },
+            b'u' => (b"", pad_digits((next_arg() as u64).to_string(), precision)),
+            b'x' => (b"", pad_digits(format!("{:x}", next_arg()), precision)),
+            b'X' => (b"", pad_digits(format!("{:X}", next_arg()), precision)),
+            b'o' => (b"", pad_digits(format!("{:o}", next_arg()), precision)),
+            b'p' => (b"0x", format!("{:x}", next_arg()).into_bytes()),
+            b'c' => (b"", vec![next_arg() as u8]),
+            b's' => {
+                let mut s = read_string(next_arg())?;
+                if let Some(p) = precision {
+                    s.truncate(p);
+                }
+                (b"", s)
+            },
+            b'%' => (b"", b"%".to_vec()),
+            other => (b"", vec![b'%', other]),
+        };
+
+        let len = sign.len() + body.len();
+        let pad = width.saturating_sub(len);
+        let numeric = matches!(conv, b'd' | b'i' | b'u' | b'x' | b'X' | b'o');
+        if left {
+            out.extend_from_slice(sign);
+            out.extend_from_slice(&body);
+            out.resize(out.len() + pad, b' ');
+        } else if zero && numeric && precision.is_none() {
+            out.extend_from_slice(sign);
+            out.resize(out.len() + pad, b'0');
+            out.extend_from_slice(&body);
         } else {
-            println!();
+            out.resize(out.len() + pad, b' ');
+            out.extend_from_slice(sign);
+            out.extend_from_slice(&body);
         }
     }
+
+    Ok(out)
+}
+
+/// Left-pad digits with zeros to satisfy a printf precision
+fn pad_digits(digits: String, precision: Option<usize>) -> Vec<u8> {
+    match precision {
+        Some(0) if digits == "0" => Vec::new(),
+        Some(p) if p > digits.len() => {
+            let mut padded = vec![b'0'; p - digits.len()];
+            padded.extend_from_slice(digits.as_bytes());
+            padded
+        },
+        _ => digits.into_bytes(),
+    }
 }
 
 // Unit tests
 #[cfg(test)]
 mod tests {
     use super::*;
-    
+
     #[test]
     fn test_vm_basic() {
         // Basic program: return 42
@@ -945,13 +1199,13 @@ mod tests {
             Opcode::IMM as i64, 42,
             Opcode::EXIT as i64,
         ];
-        
+
         let mut vm = VirtualMachine::new(code, Vec::new(), 1024, false);
         let result = vm.run(0, &[]).unwrap();
-        
+
         assert_eq!(result, 42);
     }
-    
+
     #[test]
     fn test_vm_arithmetic() {
         // Test arithmetic operations
@@ -979,13 +1233,13 @@ mod tests {
             // Exit with 9
             Opcode::EXIT as i64,
         ];
-        
+
         let mut vm = VirtualMachine::new(code, Vec::new(), 1024, false);
         let result = vm.run(0, &[]).unwrap();
-        
+
         assert_eq!(result, 9);
     }
-    
+
     #[test]
     fn test_vm_conditional_branch() {
         // Test conditional branching
@@ -999,7 +1253,7 @@ mod tests {
             // Greater than: 10 > 5 = 1
             Opcode::GT as i64,
             // Branch if zero (not taken)
-            Opcode::BZ as i64, 12,
+            Opcode::BZ as i64, 11,
             // Load 42 (this branch is taken)
             Opcode::IMM as i64, 42,
             // Exit with 42
@@ -1009,20 +1263,20 @@ mod tests {
             // Exit with 24 (not reached)
             Opcode::EXIT as i64,
         ];
-        
+
         let mut vm = VirtualMachine::new(code, Vec::new(), 1024, false);
         let result = vm.run(0, &[]).unwrap();
-        
+
         assert_eq!(result, 42);
     }
-    
+
     #[test]
     fn test_vm_function_call() {
         // Test function calls
         let code = vec![
             // Jump to main
-            Opcode::JMP as i64, 10,
-            
+            Opcode::JMP as i64, 12,
+
             // Function: double(x) -> x * 2
             // Set up stack frame
             Opcode::ENT as i64, 0,
@@ -1038,7 +1292,7 @@ mod tests {
             Opcode::MUL as i64,
             // Return
             Opcode::LEV as i64,
-            
+
             // Main function
             // Load 21
             Opcode::IMM as i64, 21,
@@ -1051,10 +1305,38 @@ mod tests {
             // Exit with result (42)
             Opcode::EXIT as i64,
         ];
-        
+
         let mut vm = VirtualMachine::new(code, Vec::new(), 1024, false);
-        let result = vm.run(10, &[]).unwrap();
-        
+        let result = vm.run(12, &[]).unwrap();
+
         assert_eq!(result, 42);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_vm_stack_memory() {
+        // Store through a stack address and read it back as bytes
+        let code = vec![
+            Opcode::ENT as i64, 1,
+            Opcode::LEA as i64, -1,
+            Opcode::PSH as i64,
+            Opcode::IMM as i64, 0x0102,
+            Opcode::SI as i64,
+            Opcode::LEA as i64, -1,
+            Opcode::PSH as i64,
+            Opcode::IMM as i64, 1,
+            Opcode::ADD as i64,
+            Opcode::LC as i64,
+            Opcode::LEV as i64,
+        ];
+
+        let mut vm = VirtualMachine::new(code, Vec::new(), 1024, false);
+        assert_eq!(vm.run(0, &[]).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_vm_format() {
+        let vm = VirtualMachine::new(Vec::new(), b"hi\0".to_vec(), 16, false);
+        let out = format(b"[%5d|%-4x|%05d|%c|%s|%.1s|%%]", &[42, 255, -7, 'z' as i64, 0, 0], |addr| vm.read_cstring(addr)).unwrap();
+        assert_eq!(out, b"[   42|ff  |-0007|z|hi|h|%]");
+    }
+}
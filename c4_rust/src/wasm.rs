@@ -0,0 +1,1014 @@
+use crate::error::CompilerError;
+use crate::instr::{self, Instr};
+use crate::object::{ObjectFile, RelocationKind, SymbolKind};
+use crate::vm::DEFAULT_STACK_SIZE;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+
+/// Module the system calls are imported from
+const IMPORT_MODULE: &str = "c4";
+
+/// The imported system calls: name, parameter count and whether they
+/// return a value. `printf` takes the address of its last argument and the
+/// argument count.
+const IMPORTS: [(&str, usize, bool); 9] = [
+    ("open", 2, true),
+    ("read", 3, true),
+    ("close", 1, true),
+    ("printf", 2, true),
+    ("malloc", 1, true),
+    ("free", 1, true),
+    ("memset", 3, true),
+    ("memcmp", 3, true),
+    ("exit", 1, false),
+];
+
+/// Bytes of stack, which fills memory below the data
+const STACK_BYTES: u64 = DEFAULT_STACK_SIZE as u64 * 8;
+
+/// Size of a WebAssembly page
+const PAGE_SIZE: u64 = 0x10000;
+
+/// Globals: the stack and base pointers, then the heap's start
+const SP: u32 = 0;
+const BP: u32 = 1;
+const HEAP_BASE: u32 = 2;
+
+/// The one local of every function, holding `AX`
+const AX: u32 = 0;
+
+/// A WebAssembly instruction, nested where control flow is
+#[derive(Debug, Clone, PartialEq)]
+enum Op {
+    Block(Vec<Op>),
+    Loop(Vec<Op>),
+    If(Vec<Op>, Vec<Op>),
+    Br(u32),
+    Return,
+    Unreachable,
+    Call(u32),
+    LocalGet(u32),
+    LocalSet(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    Const(i64),
+    /// An instruction without immediates: its opcode and name
+    Plain(u8, &'static str),
+    /// A memory access: its opcode, name and natural alignment (log2)
+    Memory(u8, &'static str, u32),
+}
+
+const I64_EQZ: Op = Op::Plain(0x50, "i64.eqz");
+const I64_EQ: Op = Op::Plain(0x51, "i64.eq");
+const I64_ADD: Op = Op::Plain(0x7c, "i64.add");
+const I64_SUB: Op = Op::Plain(0x7d, "i64.sub");
+const I64_MUL: Op = Op::Plain(0x7e, "i64.mul");
+const I32_WRAP: Op = Op::Plain(0xa7, "i32.wrap_i64");
+const I64_EXTEND: Op = Op::Plain(0xad, "i64.extend_i32_u");
+const I64_LOAD: Op = Op::Memory(0x29, "i64.load", 3);
+const I64_LOAD8: Op = Op::Memory(0x30, "i64.load8_s", 0);
+const I64_STORE: Op = Op::Memory(0x37, "i64.store", 3);
+
+/// A function type: parameter count (all `i64`) and whether it returns an
+/// `i64`
+type FuncType = (usize, bool);
+
+struct Function {
+    name: String,
+    typ: u32,
+    /// Number of `i64` locals beyond the parameters
+    locals: u32,
+    body: Vec<Op>,
+}
+
+/// A C4 program lowered to a WebAssembly module
+///
+/// There is one linear memory. The stack fills its bottom, growing down
+/// from where the data segment starts, so overflowing it traps; the data
+/// follows, then the heap, from the exported `heap_base` global on. `AX`
+/// is a local of each function and the stack and base pointers are
+/// globals, so each C4 function becomes a WebAssembly function with no
+/// parameters that returns `AX`, and its branches are turned back into
+/// blocks, loops and ifs.
+///
+/// System calls are functions imported from the `c4` module, taking `i64`
+/// arguments in call order: `open`, `read`, `close`, `malloc`, `free`,
+/// `memset`, `memcmp` and `exit` take their arguments as values, while
+/// `printf(args, argc)` gets the address of its last argument and the
+/// argument count, with the format string deepest. The host provides
+/// `malloc`, growing the exported `memory` as it needs to, and `exit`
+/// should not return. The exported `start(argc, argv)` runs `main` and
+/// returns its result.
+///
+/// Division by zero traps, and programs that call native functions or
+/// still need linking are rejected.
+pub struct Module {
+    types: Vec<FuncType>,
+    functions: Vec<Function>,
+    data: Vec<u8>,
+    heap_base: u64,
+}
+
+impl Module {
+    /// Lower a linked program
+    pub fn from_object(program: &ObjectFile) -> Result<Module, CompilerError> {
+        if let Some(import) = program.imports.first() {
+            return Err(CompilerError::codegen_error(&format!("'{}' is not defined (link the program first)", import.name), None));
+        }
+        let entry = program.entry
+            .ok_or_else(|| CompilerError::codegen_error("main() not defined", None))?;
+        let code = instr::decode(&program.code)?;
+
+        // Functions are the symbols' code ranges; system calls come first
+        let mut starts: Vec<(usize, &str)> = program.symbols.iter()
+            .filter(|sym| sym.kind == SymbolKind::Function)
+            .map(|sym| (sym.value as usize, sym.name.as_str()))
+            .collect();
+        starts.sort();
+        let indices: HashMap<usize, u32> = starts.iter().enumerate()
+            .map(|(i, &(start, _))| (start, (IMPORTS.len() + i) as u32))
+            .collect();
+        let data_relocations: BTreeSet<usize> = program.relocations.iter()
+            .filter(|reloc| reloc.kind == RelocationKind::Data)
+            .map(|reloc| reloc.offset)
+            .collect();
+
+        let mut module = Module {
+            types: Vec::new(),
+            functions: Vec::new(),
+            data: program.data.clone(),
+            heap_base: (STACK_BYTES + program.data.len() as u64).next_multiple_of(8),
+        };
+        for &(name, params, returns) in &IMPORTS {
+            let typ = module.type_index((params, returns));
+            module.functions.push(Function { name: name.to_string(), typ, locals: 0, body: Vec::new() });
+        }
+        for (i, &(start, name)) in starts.iter().enumerate() {
+            let end = starts.get(i + 1).map_or(code.len(), |&(next, _)| next);
+            let lowering = Lowering { code: &code, data_relocations: &data_relocations, functions: &indices };
+            let body = lowering.function(start, end)?;
+            let typ = module.type_index((0, true));
+            module.functions.push(Function { name: name.to_string(), typ, locals: 1, body });
+        }
+
+        // start(argc, argv) pushes main's arguments and a return address
+        let mut body = Vec::new();
+        for param in 0..2 {
+            push(&mut body, vec![Op::LocalGet(param)]);
+        }
+        body.extend([Op::GlobalGet(SP), Op::Const(8), I64_SUB, Op::GlobalSet(SP)]);
+        body.push(Op::Call(indices[&entry]));
+        let typ = module.type_index((2, true));
+        module.functions.push(Function { name: "start".to_string(), typ, locals: 0, body });
+
+        Ok(module)
+    }
+
+    fn type_index(&mut self, typ: FuncType) -> u32 {
+        match self.types.iter().position(|&t| t == typ) {
+            Some(index) => index as u32,
+            None => {
+                self.types.push(typ);
+                (self.types.len() - 1) as u32
+            },
+        }
+    }
+
+    /// Pages of memory the module starts with: the stack and the data
+    fn pages(&self) -> u64 {
+        self.heap_base.div_ceil(PAGE_SIZE)
+    }
+
+    /// Index of the exported `start` function
+    fn start_index(&self) -> u32 {
+        (self.functions.len() - 1) as u32
+    }
+
+    /// Encode the module in the binary format
+    pub fn to_wasm(&self) -> Vec<u8> {
+        let mut out = b"\0asm".to_vec();
+        out.extend_from_slice(&1u32.to_le_bytes());
+
+        // Types
+        let mut types = Vec::new();
+        uleb(&mut types, self.types.len() as u64);
+        for &(params, returns) in &self.types {
+            types.push(0x60);
+            uleb(&mut types, params as u64);
+            types.resize(types.len() + params, 0x7e);
+            uleb(&mut types, returns as u64);
+            if returns {
+                types.push(0x7e);
+            }
+        }
+        section(&mut out, 1, &types);
+
+        // Imports
+        let mut imports = Vec::new();
+        uleb(&mut imports, IMPORTS.len() as u64);
+        for function in &self.functions[..IMPORTS.len()] {
+            name(&mut imports, IMPORT_MODULE);
+            name(&mut imports, &function.name);
+            imports.push(0x00);
+            uleb(&mut imports, function.typ as u64);
+        }
+        section(&mut out, 2, &imports);
+
+        // Function types
+        let defined = &self.functions[IMPORTS.len()..];
+        let mut functions = Vec::new();
+        uleb(&mut functions, defined.len() as u64);
+        for function in defined {
+            uleb(&mut functions, function.typ as u64);
+        }
+        section(&mut out, 3, &functions);
+
+        // Memory, with no maximum
+        let mut memory = vec![1, 0x00];
+        uleb(&mut memory, self.pages());
+        section(&mut out, 5, &memory);
+
+        // Globals: sp and bp at the top of the stack, then heap_base
+        let mut globals = vec![3];
+        for (mutable, value) in [(1, STACK_BYTES), (1, STACK_BYTES), (0, self.heap_base)] {
+            globals.extend_from_slice(&[0x7e, mutable, 0x42]);
+            sleb(&mut globals, value as i64);
+            globals.push(0x0b);
+        }
+        section(&mut out, 6, &globals);
+
+        // Exports
+        let mut exports = vec![3];
+        for (export, kind, index) in [("memory", 0x02, 0), ("start", 0x00, self.start_index()), ("heap_base", 0x03, HEAP_BASE)] {
+            name(&mut exports, export);
+            exports.push(kind);
+            uleb(&mut exports, index as u64);
+        }
+        section(&mut out, 7, &exports);
+
+        // Code
+        let mut code = Vec::new();
+        uleb(&mut code, defined.len() as u64);
+        for function in defined {
+            let mut body = Vec::new();
+            if function.locals > 0 {
+                body.extend_from_slice(&[1]);
+                uleb(&mut body, function.locals as u64);
+                body.push(0x7e);
+            } else {
+                body.push(0);
+            }
+            encode(&mut body, &function.body);
+            body.push(0x0b);
+            uleb(&mut code, body.len() as u64);
+            code.extend_from_slice(&body);
+        }
+        section(&mut out, 10, &code);
+
+        // Data, at the end of the stack
+        let mut data = vec![1, 0x00, 0x41];
+        sleb(&mut data, STACK_BYTES as i64);
+        data.push(0x0b);
+        uleb(&mut data, self.data.len() as u64);
+        data.extend_from_slice(&self.data);
+        section(&mut out, 11, &data);
+
+        // Function names, for debuggers and stack traces
+        let mut names = Vec::new();
+        name(&mut names, "name");
+        let mut function_names = Vec::new();
+        uleb(&mut function_names, self.functions.len() as u64);
+        for (index, function) in self.functions.iter().enumerate() {
+            uleb(&mut function_names, index as u64);
+            name(&mut function_names, &function.name);
+        }
+        names.push(1);
+        uleb(&mut names, function_names.len() as u64);
+        names.extend_from_slice(&function_names);
+        section(&mut out, 0, &names);
+
+        out
+    }
+
+    /// Write the module in the text format
+    pub fn to_wat(&self) -> String {
+        let mut out = String::from("(module\n");
+        for (index, &(params, returns)) in self.types.iter().enumerate() {
+            writeln!(out, "  (type (;{};) (func{}))", index, signature(params, returns)).unwrap();
+        }
+        for (index, &(name, params, returns)) in IMPORTS.iter().enumerate() {
+            writeln!(
+                out,
+                "  (import \"{}\" \"{}\" (func ${} (type {}){}))",
+                IMPORT_MODULE, name, self.function_id(index), self.functions[index].typ, signature(params, returns),
+            ).unwrap();
+        }
+        writeln!(out, "  (memory (export \"memory\") {})", self.pages()).unwrap();
+        writeln!(out, "  (global $sp (mut i64) (i64.const {}))", STACK_BYTES).unwrap();
+        writeln!(out, "  (global $bp (mut i64) (i64.const {}))", STACK_BYTES).unwrap();
+        writeln!(out, "  (global $heap_base (export \"heap_base\") i64 (i64.const {}))", self.heap_base).unwrap();
+
+        for (index, function) in self.functions.iter().enumerate().skip(IMPORTS.len()) {
+            let (params, returns) = self.types[function.typ as usize];
+            writeln!(out, "  (func ${} (type {}){}", self.function_id(index), function.typ, signature(params, returns)).unwrap();
+            if function.locals > 0 {
+                writeln!(out, "    (local $ax i64)").unwrap();
+            }
+            self.write_ops(&mut out, &function.body, 2);
+            writeln!(out, "  )").unwrap();
+        }
+        writeln!(out, "  (export \"start\" (func $c4:start))").unwrap();
+
+        write!(out, "  (data (i32.const {}) \"", STACK_BYTES).unwrap();
+        for &byte in &self.data {
+            match byte {
+                b'"' | b'\\' => write!(out, "\\{}", byte as char).unwrap(),
+                0x20..=0x7e => out.push(byte as char),
+                _ => write!(out, "\\{:02x}", byte).unwrap(),
+            }
+        }
+        out.push_str("\")\n)\n");
+        out
+    }
+
+    /// The text format's name for a function; imports and `start` are
+    /// marked so they cannot clash with C4 names
+    fn function_id(&self, index: usize) -> String {
+        let name = &self.functions[index].name;
+        if index < IMPORTS.len() || index as u32 == self.start_index() {
+            format!("c4:{}", name)
+        } else {
+            name.clone()
+        }
+    }
+
+    fn write_ops(&self, out: &mut String, ops: &[Op], depth: usize) {
+        let indent = "  ".repeat(depth);
+        for op in ops {
+            match op {
+                Op::Block(body) | Op::Loop(body) => {
+                    let keyword = if matches!(op, Op::Block(_)) { "block" } else { "loop" };
+                    writeln!(out, "{}{}", indent, keyword).unwrap();
+                    self.write_ops(out, body, depth + 1);
+                    writeln!(out, "{}end", indent).unwrap();
+                },
+                Op::If(then, otherwise) => {
+                    writeln!(out, "{}if", indent).unwrap();
+                    self.write_ops(out, then, depth + 1);
+                    writeln!(out, "{}else", indent).unwrap();
+                    self.write_ops(out, otherwise, depth + 1);
+                    writeln!(out, "{}end", indent).unwrap();
+                },
+                Op::Br(label) => writeln!(out, "{}br {}", indent, label).unwrap(),
+                Op::Return => writeln!(out, "{}return", indent).unwrap(),
+                Op::Unreachable => writeln!(out, "{}unreachable", indent).unwrap(),
+                Op::Call(index) => writeln!(out, "{}call ${}", indent, self.function_id(*index as usize)).unwrap(),
+                Op::LocalGet(index) => writeln!(out, "{}local.get {}", indent, local_id(*index)).unwrap(),
+                Op::LocalSet(index) => writeln!(out, "{}local.set {}", indent, local_id(*index)).unwrap(),
+                Op::GlobalGet(index) => writeln!(out, "{}global.get {}", indent, global_id(*index)).unwrap(),
+                Op::GlobalSet(index) => writeln!(out, "{}global.set {}", indent, global_id(*index)).unwrap(),
+                Op::Const(value) => writeln!(out, "{}i64.const {}", indent, value).unwrap(),
+                Op::Plain(_, name) | Op::Memory(_, name, _) => writeln!(out, "{}{}", indent, name).unwrap(),
+            }
+        }
+    }
+}
+
+/// Parameters and result of a function in the text format
+fn signature(params: usize, returns: bool) -> String {
+    let mut out = String::new();
+    if params > 0 {
+        out.push_str(" (param");
+        out.push_str(&" i64".repeat(params));
+        out.push(')');
+    }
+    if returns {
+        out.push_str(" (result i64)");
+    }
+    out
+}
+
+/// `$ax` in C4 functions; `start`'s parameters by index
+fn local_id(index: u32) -> String {
+    match index {
+        AX => "$ax".to_string(),
+        _ => index.to_string(),
+    }
+}
+
+fn global_id(index: u32) -> &'static str {
+    match index {
+        SP => "$sp",
+        BP => "$bp",
+        _ => "$heap_base",
+    }
+}
+
+/// Push a value, computed by `value`, onto the C4 stack
+fn push(ops: &mut Vec<Op>, value: Vec<Op>) {
+    ops.extend([Op::GlobalGet(SP), Op::Const(8), I64_SUB, Op::GlobalSet(SP)]);
+    ops.extend([Op::GlobalGet(SP), I32_WRAP]);
+    ops.extend(value);
+    ops.push(I64_STORE);
+}
+
+/// Pop the top of the C4 stack onto the WebAssembly stack
+fn pop(ops: &mut Vec<Op>) {
+    ops.extend([Op::GlobalGet(SP), I32_WRAP, I64_LOAD]);
+    ops.extend([Op::GlobalGet(SP), Op::Const(8), I64_ADD, Op::GlobalSet(SP)]);
+}
+
+/// A basic block's instructions, `start..end`, and where it goes next
+struct BasicBlock {
+    start: usize,
+    end: usize,
+    exit: Exit,
+}
+
+#[derive(Clone, Copy)]
+enum Exit {
+    /// Jump or fall through to a block
+    Goto(usize),
+    /// `BZ` or `BNZ`: the blocks for a zero and a nonzero `AX`
+    Branch { zero: usize, nonzero: usize },
+    /// `LEV`, `TSR` or `EXIT`
+    Stop,
+}
+
+impl Exit {
+    fn successors(self) -> Vec<usize> {
+        match self {
+            Exit::Goto(to) => vec![to],
+            Exit::Branch { zero, nonzero } => vec![zero, nonzero],
+            Exit::Stop => Vec::new(),
+        }
+    }
+}
+
+/// What a branch can target from inside the structured code
+#[derive(Clone, Copy, PartialEq)]
+enum Enclosing {
+    If,
+    /// A loop, whose label goes back to this block
+    LoopHeadedBy(usize),
+    /// A block, whose label goes on to this block
+    BlockFollowedBy(usize),
+}
+
+/// Turns one unit's bytecode into WebAssembly functions
+struct Lowering<'a> {
+    code: &'a [Instr],
+    data_relocations: &'a BTreeSet<usize>,
+    /// Function indices by code address
+    functions: &'a HashMap<usize, u32>,
+}
+
+impl Lowering<'_> {
+    /// Lower the function at `start..end`
+    fn function(&self, start: usize, end: usize) -> Result<Vec<Op>, CompilerError> {
+        let blocks = self.basic_blocks(start, end)?;
+        let code = blocks.iter()
+            .map(|block| self.straight_line(block))
+            .collect::<Result<Vec<_>, _>>()?;
+        let structure = Structure::new(&blocks, start)?;
+        let mut body = structure.tree(0, &code, &mut Vec::new());
+        body.push(Op::Unreachable);
+        Ok(body)
+    }
+
+    /// Split a function into basic blocks, the first being its entry
+    fn basic_blocks(&self, start: usize, end: usize) -> Result<Vec<BasicBlock>, CompilerError> {
+        let instructions = || (start..end).filter(|&pc| self.code[pc] != Instr::Operand);
+        let mut leaders = BTreeSet::from([start]);
+        for pc in instructions() {
+            match self.code[pc] {
+                Instr::Jmp(target) | Instr::Bz(target) | Instr::Bnz(target) => {
+                    if !(start..end).contains(&target) || self.code[target] == Instr::Operand {
+                        return Err(CompilerError::codegen_error("branch leaves its function", Some(pc)));
+                    }
+                    leaders.insert(target);
+                    leaders.insert(pc + 2);
+                },
+                Instr::Lev | Instr::Exit => {
+                    leaders.insert(pc + 1);
+                },
+                Instr::Tsr(_) => {
+                    leaders.insert(pc + 2);
+                },
+                _ => {},
+            }
+        }
+        let leaders: Vec<usize> = leaders.into_iter().filter(|&pc| pc < end).collect();
+        let index = |pc: usize| leaders.binary_search(&pc).unwrap();
+
+        let mut blocks = Vec::new();
+        for (i, &leader) in leaders.iter().enumerate() {
+            let block_end = leaders.get(i + 1).copied().unwrap_or(end);
+            let last = (leader..block_end).rev().find(|&pc| self.code[pc] != Instr::Operand).unwrap_or(leader);
+            let next = || {
+                (i + 1 < leaders.len()).then_some(i + 1)
+                    .ok_or_else(|| CompilerError::codegen_error("code runs off the end of its function", Some(last)))
+            };
+            let exit = match self.code[last] {
+                Instr::Jmp(target) => Exit::Goto(index(target)),
+                Instr::Bz(target) => Exit::Branch { zero: index(target), nonzero: next()? },
+                Instr::Bnz(target) => Exit::Branch { zero: next()?, nonzero: index(target) },
+                Instr::Lev | Instr::Exit | Instr::Tsr(_) => Exit::Stop,
+                _ => Exit::Goto(next()?),
+            };
+            blocks.push(BasicBlock { start: leader, end: block_end, exit });
+        }
+        Ok(blocks)
+    }
+
+    /// The code of a block, up to any branch it ends with
+    fn straight_line(&self, block: &BasicBlock) -> Result<Vec<Op>, CompilerError> {
+        let mut ops = Vec::new();
+        for pc in block.start..block.end {
+            let instr = self.code[pc];
+            if !matches!(instr, Instr::Jmp(_) | Instr::Bz(_) | Instr::Bnz(_)) {
+                self.instruction(&mut ops, pc, instr)?;
+            }
+        }
+        Ok(ops)
+    }
+
+    /// The value of an operand, moved into the data segment's place in
+    /// memory if it is a data address
+    fn operand(&self, pc: usize, value: i64) -> Op {
+        if self.data_relocations.contains(&(pc + 1)) {
+            Op::Const(value + STACK_BYTES as i64)
+        } else {
+            Op::Const(value)
+        }
+    }
+
+    fn instruction(&self, ops: &mut Vec<Op>, pc: usize, instr: Instr) -> Result<(), CompilerError> {
+        let set_ax = |ops: &mut Vec<Op>, value: Vec<Op>| {
+            ops.extend(value);
+            ops.push(Op::LocalSet(AX));
+        };
+        let local = |offset: i64, load: Op| {
+            vec![Op::GlobalGet(BP), Op::Const(offset * 8), I64_ADD, I32_WRAP, load]
+        };
+        let binary = |ops: &mut Vec<Op>, op: &[Op]| {
+            pop(ops);
+            ops.push(Op::LocalGet(AX));
+            ops.extend_from_slice(op);
+            ops.push(Op::LocalSet(AX));
+        };
+        let compare = |ops: &mut Vec<Op>, code: u8, name: &'static str| {
+            binary(ops, &[Op::Plain(code, name), I64_EXTEND]);
+        };
+        // A system call's arguments, in call order
+        let args = |ops: &mut Vec<Op>, count: i64| {
+            for n in (0..count).rev() {
+                ops.extend([Op::GlobalGet(SP), Op::Const(n * 8), I64_ADD, I32_WRAP, I64_LOAD]);
+            }
+        };
+        let system_call = |ops: &mut Vec<Op>, name: &str| {
+            let index = IMPORTS.iter().position(|&(import, _, _)| import == name).unwrap();
+            args(ops, IMPORTS[index].1 as i64);
+            ops.extend([Op::Call(index as u32), Op::LocalSet(AX)]);
+        };
+
+        match instr {
+            Instr::Lea(offset) => set_ax(ops, vec![Op::GlobalGet(BP), Op::Const(offset * 8), I64_ADD]),
+            Instr::Imm(value) => set_ax(ops, vec![self.operand(pc, value)]),
+            Instr::Jsr(target) => {
+                let &index = self.functions.get(&target)
+                    .ok_or_else(|| CompilerError::codegen_error("call to an address that is not a function", Some(pc)))?;
+                // Leave room for the return address, as the VM does
+                ops.extend([Op::GlobalGet(SP), Op::Const(8), I64_SUB, Op::GlobalSet(SP)]);
+                ops.extend([Op::Call(index), Op::LocalSet(AX)]);
+            },
+            Instr::Ent(locals) => {
+                push(ops, vec![Op::GlobalGet(BP)]);
+                ops.extend([Op::GlobalGet(SP), Op::GlobalSet(BP)]);
+                ops.extend([Op::GlobalGet(SP), Op::Const(locals * 8), I64_SUB, Op::GlobalSet(SP)]);
+            },
+            Instr::Adj(n) => ops.extend([Op::GlobalGet(SP), Op::Const(n * 8), I64_ADD, Op::GlobalSet(SP)]),
+            Instr::Lev => {
+                // Restore the caller's bp and pop the return address
+                ops.extend([Op::GlobalGet(BP), Op::GlobalSet(SP)]);
+                pop(ops);
+                ops.push(Op::GlobalSet(BP));
+                ops.extend([Op::GlobalGet(SP), Op::Const(8), I64_ADD, Op::GlobalSet(SP)]);
+                ops.extend([Op::LocalGet(AX), Op::Return]);
+            },
+            Instr::Tsr(target) => {
+                let &index = self.functions.get(&target)
+                    .ok_or_else(|| CompilerError::codegen_error("call to an address that is not a function", Some(pc)))?;
+                // Restore the caller's bp, and leave its return address for
+                // the callee's LEV to pop
+                ops.extend([Op::GlobalGet(BP), Op::GlobalSet(SP)]);
+                pop(ops);
+                ops.push(Op::GlobalSet(BP));
+                ops.extend([Op::Call(index), Op::Return]);
+            },
+            Instr::Li => set_ax(ops, vec![Op::LocalGet(AX), I32_WRAP, I64_LOAD]),
+            Instr::Lc => set_ax(ops, vec![Op::LocalGet(AX), I32_WRAP, I64_LOAD8]),
+            Instr::Si => {
+                pop(ops);
+                ops.extend([I32_WRAP, Op::LocalGet(AX), I64_STORE]);
+            },
+            Instr::Sc => {
+                pop(ops);
+                ops.extend([I32_WRAP, Op::LocalGet(AX), Op::Memory(0x3c, "i64.store8", 0)]);
+                set_ax(ops, vec![Op::LocalGet(AX), Op::Plain(0xc2, "i64.extend8_s")]);
+            },
+            Instr::Psh => push(ops, vec![Op::LocalGet(AX)]),
+            Instr::Or => binary(ops, &[Op::Plain(0x84, "i64.or")]),
+            Instr::Xor => binary(ops, &[Op::Plain(0x85, "i64.xor")]),
+            Instr::And => binary(ops, &[Op::Plain(0x83, "i64.and")]),
+            Instr::Eq => compare(ops, 0x51, "i64.eq"),
+            Instr::Ne => compare(ops, 0x52, "i64.ne"),
+            Instr::Lt => compare(ops, 0x53, "i64.lt_s"),
+            Instr::Gt => compare(ops, 0x55, "i64.gt_s"),
+            Instr::Le => compare(ops, 0x57, "i64.le_s"),
+            Instr::Ge => compare(ops, 0x59, "i64.ge_s"),
+            Instr::Shl => binary(ops, &[Op::Plain(0x86, "i64.shl")]),
+            Instr::Shr => binary(ops, &[Op::Plain(0x87, "i64.shr_s")]),
+            Instr::Add => binary(ops, &[I64_ADD]),
+            Instr::Sub => binary(ops, &[I64_SUB]),
+            Instr::Mul => binary(ops, &[I64_MUL]),
+            Instr::Div => {
+                // Dividing by -1 is negating, which wraps where i64.div_s traps
+                let mut negate = vec![Op::Const(0)];
+                pop(&mut negate);
+                negate.extend([I64_SUB, Op::LocalSet(AX)]);
+                let mut divide = Vec::new();
+                binary(&mut divide, &[Op::Plain(0x7f, "i64.div_s")]);
+                ops.extend([Op::LocalGet(AX), Op::Const(-1), I64_EQ, Op::If(negate, divide)]);
+            },
+            Instr::Mod => binary(ops, &[Op::Plain(0x81, "i64.rem_s")]),
+            Instr::Open => system_call(ops, "open"),
+            Instr::Read => system_call(ops, "read"),
+            Instr::Clos => system_call(ops, "close"),
+            Instr::Prtf(argc) => {
+                let index = IMPORTS.iter().position(|&(import, _, _)| import == "printf").unwrap();
+                ops.extend([Op::GlobalGet(SP), Op::Const(argc as i64), Op::Call(index as u32), Op::LocalSet(AX)]);
+            },
+            Instr::Malc => system_call(ops, "malloc"),
+            Instr::Free => system_call(ops, "free"),
+            Instr::Mset => system_call(ops, "memset"),
+            Instr::Mcmp => system_call(ops, "memcmp"),
+            Instr::Exit => {
+                let index = IMPORTS.iter().position(|&(import, _, _)| import == "exit").unwrap();
+                ops.extend([Op::LocalGet(AX), Op::Call(index as u32), Op::Unreachable]);
+            },
+            Instr::Neg => set_ax(ops, vec![Op::Const(0), Op::LocalGet(AX), I64_SUB]),
+            Instr::Lli(offset) => set_ax(ops, local(offset, I64_LOAD)),
+            Instr::Llc(offset) => set_ax(ops, local(offset, I64_LOAD8)),
+            Instr::Pshl(offset) => {
+                set_ax(ops, local(offset, I64_LOAD));
+                push(ops, vec![Op::LocalGet(AX)]);
+            },
+            Instr::Pshi(value) => {
+                set_ax(ops, vec![self.operand(pc, value)]);
+                push(ops, vec![Op::LocalGet(AX)]);
+            },
+            Instr::Addi(value) => set_ax(ops, vec![Op::LocalGet(AX), self.operand(pc, value), I64_ADD]),
+            Instr::Subi(value) => set_ax(ops, vec![Op::LocalGet(AX), self.operand(pc, value), I64_SUB]),
+            Instr::Muli(value) => set_ax(ops, vec![Op::LocalGet(AX), self.operand(pc, value), I64_MUL]),
+            Instr::Natv(_) => {
+                return Err(CompilerError::codegen_error("native functions cannot be called from compiled code", Some(pc)));
+            },
+            Instr::Jmp(_) | Instr::Bz(_) | Instr::Bnz(_) | Instr::Operand => {},
+        }
+        Ok(())
+    }
+}
+
+/// A function's control flow, arranged for structured code
+///
+/// Follows Ramsey's "Beyond Relooper": blocks are placed in reverse
+/// postorder, each block's dominator-tree children that several blocks
+/// branch to are laid out after it inside nested `block`s, loop headers
+/// get a `loop`, and other branches either leave a block, go back to a
+/// loop or are placed inline. Control flow must be reducible, which code
+/// from C4's structured statements always is.
+struct Structure<'a> {
+    blocks: &'a [BasicBlock],
+    /// Reverse postorder number of each block; unreachable ones have none
+    rpo: Vec<Option<usize>>,
+    /// Children of each block in the dominator tree
+    children: Vec<Vec<usize>>,
+    /// Blocks with more than one forward edge into them
+    merges: Vec<bool>,
+    /// Blocks with a back edge into them
+    loop_headers: Vec<bool>,
+}
+
+impl<'a> Structure<'a> {
+    fn new(blocks: &'a [BasicBlock], start: usize) -> Result<Self, CompilerError> {
+        let n = blocks.len();
+
+        // Reverse postorder from the entry
+        let mut postorder = Vec::new();
+        let mut visited = vec![false; n];
+        let mut stack = vec![(0, 0)];
+        visited[0] = true;
+        while let Some(&mut (block, ref mut next)) = stack.last_mut() {
+            let successors = blocks[block].exit.successors();
+            if let Some(&successor) = successors.get(*next) {
+                *next += 1;
+                if !visited[successor] {
+                    visited[successor] = true;
+                    stack.push((successor, 0));
+                }
+            } else {
+                postorder.push(block);
+                stack.pop();
+            }
+        }
+        let order: Vec<usize> = postorder.into_iter().rev().collect();
+        let mut rpo = vec![None; n];
+        for (number, &block) in order.iter().enumerate() {
+            rpo[block] = Some(number);
+        }
+
+        let mut predecessors = vec![Vec::new(); n];
+        for &block in &order {
+            for successor in blocks[block].exit.successors() {
+                predecessors[successor].push(block);
+            }
+        }
+
+        // Dominators, by Cooper, Harvey and Kennedy's iteration
+        let mut idom: Vec<Option<usize>> = vec![None; n];
+        idom[0] = Some(0);
+        let intersect = |idom: &[Option<usize>], mut a: usize, mut b: usize| {
+            while a != b {
+                while rpo[a] > rpo[b] {
+                    a = idom[a].unwrap();
+                }
+                while rpo[b] > rpo[a] {
+                    b = idom[b].unwrap();
+                }
+            }
+            a
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &block in &order[1..] {
+                let mut new = None;
+                for &pred in &predecessors[block] {
+                    if idom[pred].is_some() {
+                        new = Some(new.map_or(pred, |new| intersect(&idom, pred, new)));
+                    }
+                }
+                if new.is_some() && idom[block] != new {
+                    idom[block] = new;
+                    changed = true;
+                }
+            }
+        }
+        let dominates = |a: usize, mut b: usize| loop {
+            if a == b {
+                return true;
+            }
+            match idom[b] {
+                Some(parent) if parent != b => b = parent,
+                _ => return false,
+            }
+        };
+
+        let mut children = vec![Vec::new(); n];
+        for &block in &order[1..] {
+            children[idom[block].unwrap()].push(block);
+        }
+        let mut merges = vec![false; n];
+        let mut loop_headers = vec![false; n];
+        for &block in &order {
+            let forward = predecessors[block].iter().filter(|&&pred| rpo[pred] < rpo[block]).count();
+            merges[block] = forward > 1;
+            for &pred in predecessors[block].iter().filter(|&&pred| rpo[pred] >= rpo[block]) {
+                if !dominates(block, pred) {
+                    return Err(CompilerError::codegen_error("irreducible control flow", Some(start)));
+                }
+                loop_headers[block] = true;
+            }
+        }
+
+        Ok(Structure { blocks, rpo, children, merges, loop_headers })
+    }
+
+    /// Code for a block and everything it dominates
+    fn tree(&self, block: usize, code: &[Vec<Op>], enclosing: &mut Vec<Enclosing>) -> Vec<Op> {
+        let mut merge_children: Vec<usize> = self.children[block].iter()
+            .copied()
+            .filter(|&child| self.merges[child])
+            .collect();
+        merge_children.sort_by_key(|&child| std::cmp::Reverse(self.rpo[child]));
+
+        if self.loop_headers[block] {
+            enclosing.push(Enclosing::LoopHeadedBy(block));
+            let body = self.within(block, &merge_children, code, enclosing);
+            enclosing.pop();
+            vec![Op::Loop(body)]
+        } else {
+            self.within(block, &merge_children, code, enclosing)
+        }
+    }
+
+    /// Code for a block, followed by the merge children it dominates,
+    /// latest first in `merges`
+    fn within(&self, block: usize, merges: &[usize], code: &[Vec<Op>], enclosing: &mut Vec<Enclosing>) -> Vec<Op> {
+        if let Some((&follower, rest)) = merges.split_first() {
+            enclosing.push(Enclosing::BlockFollowedBy(follower));
+            let inner = self.within(block, rest, code, enclosing);
+            enclosing.pop();
+            let mut ops = vec![Op::Block(inner)];
+            ops.extend(self.tree(follower, code, enclosing));
+            return ops;
+        }
+
+        let mut ops = code[block].clone();
+        match self.blocks[block].exit {
+            Exit::Goto(to) => ops.extend(self.branch(block, to, code, enclosing)),
+            Exit::Branch { zero, nonzero } => {
+                enclosing.push(Enclosing::If);
+                let then = self.branch(block, zero, code, enclosing);
+                let otherwise = self.branch(block, nonzero, code, enclosing);
+                enclosing.pop();
+                ops.extend([Op::LocalGet(AX), I64_EQZ, Op::If(then, otherwise)]);
+            },
+            Exit::Stop => {},
+        }
+        ops
+    }
+
+    /// Code that goes from one block to another
+    fn branch(&self, from: usize, to: usize, code: &[Vec<Op>], enclosing: &mut Vec<Enclosing>) -> Vec<Op> {
+        let label = if self.rpo[to] <= self.rpo[from] {
+            Enclosing::LoopHeadedBy(to)
+        } else if self.merges[to] {
+            Enclosing::BlockFollowedBy(to)
+        } else {
+            return self.tree(to, code, enclosing);
+        };
+        let depth = enclosing.iter().rev().position(|&e| e == label)
+            .expect("branch target is not in scope");
+        vec![Op::Br(depth as u32)]
+    }
+}
+
+/// Encode structured instructions in the binary format
+fn encode(out: &mut Vec<u8>, ops: &[Op]) {
+    for op in ops {
+        match op {
+            Op::Block(body) | Op::Loop(body) => {
+                out.extend_from_slice(&[if matches!(op, Op::Block(_)) { 0x02 } else { 0x03 }, 0x40]);
+                encode(out, body);
+                out.push(0x0b);
+            },
+            Op::If(then, otherwise) => {
+                out.extend_from_slice(&[0x04, 0x40]);
+                encode(out, then);
+                out.push(0x05);
+                encode(out, otherwise);
+                out.push(0x0b);
+            },
+            Op::Br(label) => {
+                out.push(0x0c);
+                uleb(out, *label as u64);
+            },
+            Op::Return => out.push(0x0f),
+            Op::Unreachable => out.push(0x00),
+            Op::Call(index) => {
+                out.push(0x10);
+                uleb(out, *index as u64);
+            },
+            Op::LocalGet(index) | Op::LocalSet(index) | Op::GlobalGet(index) | Op::GlobalSet(index) => {
+                out.push(match op {
+                    Op::LocalGet(_) => 0x20,
+                    Op::LocalSet(_) => 0x21,
+                    Op::GlobalGet(_) => 0x23,
+                    _ => 0x24,
+                });
+                uleb(out, *index as u64);
+            },
+            Op::Const(value) => {
+                out.push(0x42);
+                sleb(out, *value);
+            },
+            Op::Plain(code, _) => out.push(*code),
+            Op::Memory(code, _, align) => {
+                out.push(*code);
+                uleb(out, *align as u64);
+                uleb(out, 0);
+            },
+        }
+    }
+}
+
+fn uleb(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn name(out: &mut Vec<u8>, name: &str) {
+    uleb(out, name.len() as u64);
+    out.extend_from_slice(name.as_bytes());
+}
+
+fn section(out: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    out.push(id);
+    uleb(out, contents.len() as u64);
+    out.extend_from_slice(contents);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm;
+    use crate::parser::Parser;
+
+    #[test]
+    fn test_leb128() {
+        let mut out = Vec::new();
+        uleb(&mut out, 624485);
+        sleb(&mut out, -123456);
+        sleb(&mut out, 64);
+        assert_eq!(out, [0xe5, 0x8e, 0x26, 0xc0, 0xbb, 0x78, 0xc0, 0x00]);
+    }
+
+    /// All ops in a body, nested ones included
+    fn flatten(ops: &[Op]) -> Vec<&Op> {
+        ops.iter()
+            .flat_map(|op| {
+                let nested = match op {
+                    Op::Block(body) | Op::Loop(body) => flatten(body),
+                    Op::If(then, otherwise) => flatten(then).into_iter().chain(flatten(otherwise)).collect(),
+                    _ => Vec::new(),
+                };
+                std::iter::once(op).chain(nested)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_structured_control_flow() {
+        let mut parser = Parser::new(r#"
+            int main() {
+                int i; int n;
+                i = 0; n = 0;
+                while (i < 10) {
+                    if (i & 1) n = n + i; else n = n - 1;
+                    i++;
+                }
+                return n;
+            }
+        "#.to_string(), false);
+        parser.init().unwrap();
+        parser.parse().unwrap();
+        let module = Module::from_object(&ObjectFile::from_parser(&parser).unwrap()).unwrap();
+
+        let main = &module.functions[IMPORTS.len()];
+        assert_eq!(main.name, "main");
+        let ops = flatten(&main.body);
+        assert_eq!(ops.iter().filter(|op| matches!(op, Op::Loop(_))).count(), 1);
+        assert_eq!(ops.iter().filter(|op| matches!(op, Op::If(..))).count(), 2);
+        assert!(ops.contains(&&Op::Return));
+    }
+
+    #[test]
+    fn test_irreducible_control_flow() {
+        // Two blocks jumping to each other, each entered from the start
+        let object = asm::assemble(r#"
+            .entry main
+            .text
+            main:
+                ENT   0
+                IMM   0
+                BZ    .second
+            .first:
+                JMP   .second
+            .second:
+                JMP   .first
+        "#).unwrap();
+        let err = Module::from_object(&object).err().unwrap();
+        assert!(err.to_string().contains("irreducible control flow"), "{}", err);
+    }
+}
@@ -0,0 +1,543 @@
+use crate::error::CompilerError;
+use crate::instr::{self, Instr};
+use crate::object::{ObjectFile, RelocationKind, SymbolKind};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+/// Registers for the first integer arguments of a System V call
+const ARGUMENT_REGISTERS: [&str; 6] = ["%rdi", "%rsi", "%rdx", "%rcx", "%r8", "%r9"];
+
+/// Label of the data segment
+const DATA_LABEL: &str = "c4_data";
+
+/// `c4rt_printf(fmt, args, count)`: `printf` with the VM's formatting
+///
+/// The arguments are 64-bit words going down from `args`, and read as 0
+/// once `count` run out. Each conversion is printed by libc's `printf` on
+/// its own, given the `l` length it needs for 64-bit values and at most
+/// three arguments; an unknown conversion is printed as written, and
+/// literal text with `%.*s`.
+const PRINTF: &str = "
+c4rt_printf:
+	pushq %rbp
+	movq %rsp, %rbp
+	pushq %rbx
+	pushq %r12
+	pushq %r13
+	pushq %r14
+	pushq %r15
+	subq $40, %rsp
+	movq %rdi, %rbx
+	movq %rsi, %r12
+	movq %rdx, %r13
+	xorl %r14d, %r14d
+	call strlen@PLT
+	addq $18, %rax
+	andq $-16, %rax
+	subq %rax, %rsp
+	movq %rsp, %r15
+.Lpf_loop:
+	movzbl (%rbx), %eax
+	testl %eax, %eax
+	jz .Lpf_done
+	cmpl $37, %eax
+	je .Lpf_conversion
+	movq %rbx, %rdi
+	leaq .Lpf_percent(%rip), %rsi
+	call strcspn@PLT
+	addq %rax, %r14
+	leaq .Lpf_text(%rip), %rdi
+	movl %eax, %esi
+	movq %rbx, %rdx
+	addq %rax, %rbx
+	xorl %eax, %eax
+	call printf@PLT
+	jmp .Lpf_loop
+.Lpf_conversion:
+	movq %r15, %r8
+	leaq -80(%rbp), %r9
+	movq $0, -80(%rbp)
+	movq $0, -72(%rbp)
+	movq $0, -64(%rbp)
+	movb $37, (%r8)
+	incq %r8
+	incq %rbx
+.Lpf_flags:
+	movzbl (%rbx), %eax
+	cmpl $45, %eax
+	je .Lpf_flag
+	cmpl $48, %eax
+	je .Lpf_flag
+	cmpl $43, %eax
+	je .Lpf_flag
+	cmpl $32, %eax
+	jne .Lpf_width
+.Lpf_flag:
+	movb %al, (%r8)
+	incq %r8
+	incq %rbx
+	jmp .Lpf_flags
+.Lpf_width:
+	cmpl $42, %eax
+	jne .Lpf_width_digits
+	movb %al, (%r8)
+	incq %r8
+	incq %rbx
+	call .Lpf_arg
+	movq %rax, (%r9)
+	addq $8, %r9
+.Lpf_width_digits:
+	movzbl (%rbx), %eax
+	leal -48(%rax), %ecx
+	cmpl $9, %ecx
+	ja .Lpf_precision
+	movb %al, (%r8)
+	incq %r8
+	incq %rbx
+	jmp .Lpf_width_digits
+.Lpf_precision:
+	cmpl $46, %eax
+	jne .Lpf_length
+	movb %al, (%r8)
+	incq %r8
+	incq %rbx
+	movzbl (%rbx), %eax
+	cmpl $42, %eax
+	jne .Lpf_precision_digits
+	movb %al, (%r8)
+	incq %r8
+	incq %rbx
+	call .Lpf_arg
+	xorl %ecx, %ecx
+	testq %rax, %rax
+	cmovsq %rcx, %rax
+	movq %rax, (%r9)
+	addq $8, %r9
+.Lpf_precision_digits:
+	movzbl (%rbx), %eax
+	leal -48(%rax), %ecx
+	cmpl $9, %ecx
+	ja .Lpf_length
+	movb %al, (%r8)
+	incq %r8
+	incq %rbx
+	jmp .Lpf_precision_digits
+.Lpf_length:
+	movzbl (%rbx), %eax
+	cmpl $108, %eax
+	jne .Lpf_convert
+	incq %rbx
+	jmp .Lpf_length
+.Lpf_convert:
+	testl %eax, %eax
+	jz .Lpf_trailing
+	incq %rbx
+	cmpl $37, %eax
+	je .Lpf_plain
+	cmpl $99, %eax
+	je .Lpf_argument
+	cmpl $115, %eax
+	je .Lpf_argument
+	cmpl $112, %eax
+	je .Lpf_argument
+	cmpl $100, %eax
+	je .Lpf_long
+	cmpl $105, %eax
+	je .Lpf_long
+	cmpl $117, %eax
+	je .Lpf_long
+	cmpl $120, %eax
+	je .Lpf_long
+	cmpl $88, %eax
+	je .Lpf_long
+	cmpl $111, %eax
+	je .Lpf_long
+	movb $37, -56(%rbp)
+	movb %al, -55(%rbp)
+	movb $0, -54(%rbp)
+	movb $115, (%r8)
+	incq %r8
+	leaq -56(%rbp), %rax
+	movq %rax, (%r9)
+	addq $8, %r9
+	jmp .Lpf_print
+.Lpf_long:
+	movb $108, (%r8)
+	incq %r8
+.Lpf_argument:
+	movb %al, (%r8)
+	incq %r8
+	call .Lpf_arg
+	movq %rax, (%r9)
+	addq $8, %r9
+	jmp .Lpf_print
+.Lpf_plain:
+	movb %al, (%r8)
+	incq %r8
+.Lpf_print:
+	movb $0, (%r8)
+	movq %r15, %rdi
+	movq -80(%rbp), %rsi
+	movq -72(%rbp), %rdx
+	movq -64(%rbp), %rcx
+	xorl %eax, %eax
+	call printf@PLT
+	cltq
+	addq %rax, %r14
+	jmp .Lpf_loop
+.Lpf_trailing:
+	movl $37, %edi
+	call putchar@PLT
+	incq %r14
+.Lpf_done:
+	movq %r14, %rax
+	leaq -40(%rbp), %rsp
+	popq %r15
+	popq %r14
+	popq %r13
+	popq %r12
+	popq %rbx
+	popq %rbp
+	ret
+.Lpf_arg:
+	xorl %eax, %eax
+	testq %r13, %r13
+	jle 1f
+	movq (%r12), %rax
+	subq $8, %r12
+	decq %r13
+1:	ret
+	.section .rodata
+.Lpf_percent:
+	.string \"%\"
+.Lpf_text:
+	.string \"%.*s\"
+	.text
+";
+
+/// Translate a linked program to x86-64 assembly for the GNU assembler
+///
+/// The output follows the VM closely: `AX` lives in `%rax`, the VM stack
+/// is the machine stack (words are 8 bytes and frames have the same
+/// layout, so `LEA n` becomes an offset from `%rbp`), `JSR` and `LEV`
+/// become `call` and `leave; ret`, and the data segment is copied into
+/// `.data` with relocated operands pointing into it. System calls map to
+/// the libc functions of the same name, called with the stack realigned,
+/// and a C `main` passes `argc` and `argv` to the program's `main`. The
+/// result can be assembled and linked with `cc file.s`.
+///
+/// `printf` goes through a small formatter emitted with the program, so
+/// its conversions take 64-bit values as in the VM. Behaviour the VM
+/// checks at run time is left to the machine: division by zero traps.
+/// Programs that call native functions, or that still need linking, are
+/// rejected.
+pub fn emit(program: &ObjectFile) -> Result<String, CompilerError> {
+    if let Some(import) = program.imports.first() {
+        return Err(CompilerError::codegen_error(&format!("'{}' is not defined (link the program first)", import.name), None));
+    }
+    let entry = program.entry
+        .ok_or_else(|| CompilerError::codegen_error("main() not defined", None))?;
+    let code = instr::decode(&program.code)?;
+
+    let mut emitter = Emitter {
+        out: String::new(),
+        data_relocations: program.relocations.iter()
+            .filter(|reloc| reloc.kind == RelocationKind::Data)
+            .map(|reloc| reloc.offset)
+            .collect(),
+    };
+
+    // Every call and jump target gets a label; functions are named in a comment
+    let functions: BTreeMap<usize, &str> = program.symbols.iter()
+        .filter(|sym| sym.kind == SymbolKind::Function)
+        .map(|sym| (sym.value as usize, sym.name.as_str()))
+        .collect();
+    let mut labels: BTreeSet<usize> = code.iter()
+        .filter_map(|instr| match *instr {
+            Instr::Jmp(target) | Instr::Jsr(target) | Instr::Bz(target) | Instr::Bnz(target)
+            | Instr::Tsr(target) => Some(target),
+            _ => None,
+        })
+        .collect();
+    labels.insert(entry);
+    labels.extend(functions.keys());
+
+    emitter.line("# Generated by c4_rust from C4 bytecode");
+    emitter.op(".text");
+    emitter.main_wrapper(entry);
+    for (pc, &instr) in code.iter().enumerate() {
+        if let Some(name) = functions.get(&pc) {
+            emitter.line(&format!("\n# {}", name));
+        }
+        if labels.contains(&pc) {
+            emitter.line(&format!(".L{}:", pc));
+        }
+        emitter.instruction(pc, instr)?;
+    }
+    if code.iter().any(|instr| matches!(instr, Instr::Prtf(_))) {
+        emitter.out.push_str(PRINTF);
+    }
+    emitter.data(&program.data);
+    emitter.op(".section .note.GNU-stack,\"\",@progbits");
+
+    Ok(emitter.out)
+}
+
+struct Emitter {
+    out: String,
+    /// Operand words holding data addresses
+    data_relocations: BTreeSet<usize>,
+}
+
+impl Emitter {
+    fn line(&mut self, text: &str) {
+        writeln!(self.out, "{}", text).unwrap();
+    }
+
+    /// An indented instruction or directive
+    fn op(&mut self, text: &str) {
+        writeln!(self.out, "\t{}", text).unwrap();
+    }
+
+    /// A C `main` that calls the program's `main` as the VM would, with
+    /// `argc` and `argv` pushed in order
+    ///
+    /// `%r12`, which the system calls use, is saved for the C runtime.
+    fn main_wrapper(&mut self, entry: usize) {
+        self.op(".globl main");
+        self.op(".type main, @function");
+        self.line("main:");
+        self.op("pushq %rbp");
+        self.op("movq %rsp, %rbp");
+        self.op("pushq %r12");
+        self.op("pushq %rdi");
+        self.op("pushq %rsi");
+        self.op(&format!("call .L{}", entry));
+        self.op("movq -8(%rbp), %r12");
+        self.op("leave");
+        self.op("ret");
+    }
+
+    /// Load the operand of the instruction at `pc` into a register
+    fn load(&mut self, pc: usize, value: i64, register: &str) {
+        if self.data_relocations.contains(&(pc + 1)) {
+            self.op(&format!("leaq {}+{}(%rip), {}", DATA_LABEL, value, register));
+        } else if i32::try_from(value).is_ok() {
+            self.op(&format!("movq ${}, {}", value, register));
+        } else {
+            self.op(&format!("movabsq ${}, {}", value, register));
+        }
+    }
+
+    /// Pop the left operand into `%rcx` and apply `op` to it and `%rax`
+    fn binary(&mut self, op: &str) {
+        self.op("popq %rcx");
+        self.op(&format!("{} %rcx, %rax", op));
+    }
+
+    /// Pop the left operand and compare it with `%rax`
+    fn compare(&mut self, set: &str) {
+        self.op("popq %rcx");
+        self.op("cmpq %rax, %rcx");
+        self.op(&format!("{} %al", set));
+        self.op("movzbq %al, %rax");
+    }
+
+    /// Call a libc function with the top `argc` stack words as arguments
+    ///
+    /// The arguments stay on the stack for the `ADJ` that follows, as in
+    /// the VM; `%r12` remembers the stack pointer while it is aligned for
+    /// the call.
+    fn call_libc(&mut self, name: &str, argc: usize, returns_int: bool) {
+        let arg = |i: usize| format!("{}(%r12)", (argc - 1 - i) * 8);
+        self.op("movq %rsp, %r12");
+        self.op("andq $-16, %rsp");
+        if argc > ARGUMENT_REGISTERS.len() {
+            let extra = argc - ARGUMENT_REGISTERS.len();
+            if extra % 2 == 1 {
+                self.op("subq $8, %rsp");
+            }
+            for i in (ARGUMENT_REGISTERS.len()..argc).rev() {
+                self.op(&format!("pushq {}", arg(i)));
+            }
+        }
+        for (i, register) in ARGUMENT_REGISTERS.iter().enumerate().take(argc) {
+            self.op(&format!("movq {}, {}", arg(i), register));
+        }
+        if name == "open" {
+            // Permissions for files created with O_CREAT
+            self.op("movl $0644, %edx");
+        }
+        self.op(&format!("call {}@PLT", name));
+        self.op("movq %r12, %rsp");
+        if returns_int {
+            self.op("cltq");
+        }
+    }
+
+    /// Call `c4rt_printf` with the format, the address of the argument
+    /// after it and the number of arguments
+    fn printf(&mut self, argc: usize) {
+        self.op("movq %rsp, %r12");
+        self.op("andq $-16, %rsp");
+        self.op(&format!("movq {}(%r12), %rdi", (argc - 1) * 8));
+        self.op(&format!("leaq {}(%r12), %rsi", (argc as i64 - 2) * 8));
+        self.op(&format!("movq ${}, %rdx", argc - 1));
+        self.op("call c4rt_printf");
+        self.op("movq %r12, %rsp");
+    }
+
+    fn instruction(&mut self, pc: usize, instr: Instr) -> Result<(), CompilerError> {
+        match instr {
+            Instr::Lea(offset) => self.op(&format!("leaq {}(%rbp), %rax", offset * 8)),
+            Instr::Imm(value) => self.load(pc, value, "%rax"),
+            Instr::Jmp(target) => self.op(&format!("jmp .L{}", target)),
+            Instr::Jsr(target) => self.op(&format!("call .L{}", target)),
+            Instr::Bz(target) => {
+                self.op("testq %rax, %rax");
+                self.op(&format!("jz .L{}", target));
+            },
+            Instr::Bnz(target) => {
+                self.op("testq %rax, %rax");
+                self.op(&format!("jnz .L{}", target));
+            },
+            Instr::Ent(locals) => {
+                self.op("pushq %rbp");
+                self.op("movq %rsp, %rbp");
+                if locals != 0 {
+                    self.op(&format!("subq ${}, %rsp", locals * 8));
+                }
+            },
+            Instr::Adj(words) => {
+                if words != 0 {
+                    self.op(&format!("addq ${}, %rsp", words * 8));
+                }
+            },
+            Instr::Lev => {
+                self.op("leave");
+                self.op("ret");
+            },
+            Instr::Tsr(target) => {
+                self.op("leave");
+                self.op(&format!("jmp .L{}", target));
+            },
+            Instr::Li => self.op("movq (%rax), %rax"),
+            Instr::Lc => self.op("movsbq (%rax), %rax"),
+            Instr::Si => {
+                self.op("popq %rcx");
+                self.op("movq %rax, (%rcx)");
+            },
+            Instr::Sc => {
+                self.op("popq %rcx");
+                self.op("movb %al, (%rcx)");
+                self.op("movsbq %al, %rax");
+            },
+            Instr::Psh => self.op("pushq %rax"),
+            Instr::Or => self.binary("orq"),
+            Instr::Xor => self.binary("xorq"),
+            Instr::And => self.binary("andq"),
+            Instr::Eq => self.compare("sete"),
+            Instr::Ne => self.compare("setne"),
+            Instr::Lt => self.compare("setl"),
+            Instr::Gt => self.compare("setg"),
+            Instr::Le => self.compare("setle"),
+            Instr::Ge => self.compare("setge"),
+            Instr::Shl | Instr::Shr => {
+                self.op("movq %rax, %rcx");
+                self.op("popq %rax");
+                self.op(if instr == Instr::Shl { "shlq %cl, %rax" } else { "sarq %cl, %rax" });
+            },
+            Instr::Add => self.binary("addq"),
+            Instr::Sub => {
+                self.op("popq %rcx");
+                self.op("subq %rax, %rcx");
+                self.op("movq %rcx, %rax");
+            },
+            Instr::Mul => self.binary("imulq"),
+            Instr::Div | Instr::Mod => {
+                self.op("movq %rax, %rcx");
+                self.op("popq %rax");
+                self.op("cqto");
+                self.op("idivq %rcx");
+                if instr == Instr::Mod {
+                    self.op("movq %rdx, %rax");
+                }
+            },
+            Instr::Open => self.call_libc("open", 2, true),
+            Instr::Read => self.call_libc("read", 3, false),
+            Instr::Clos => self.call_libc("close", 1, true),
+            Instr::Prtf(argc) => self.printf(argc),
+            Instr::Malc => self.call_libc("malloc", 1, false),
+            Instr::Free => self.call_libc("free", 1, false),
+            Instr::Mset => self.call_libc("memset", 3, false),
+            Instr::Mcmp => self.call_libc("memcmp", 3, true),
+            Instr::Exit => {
+                self.op("movq %rax, %rdi");
+                self.op("andq $-16, %rsp");
+                self.op("call exit@PLT");
+            },
+            Instr::Neg => self.op("negq %rax"),
+            Instr::Lli(offset) => self.op(&format!("movq {}(%rbp), %rax", offset * 8)),
+            Instr::Llc(offset) => self.op(&format!("movsbq {}(%rbp), %rax", offset * 8)),
+            Instr::Pshl(offset) => {
+                self.op(&format!("movq {}(%rbp), %rax", offset * 8));
+                self.op("pushq %rax");
+            },
+            Instr::Pshi(value) => {
+                self.load(pc, value, "%rax");
+                self.op("pushq %rax");
+            },
+            Instr::Addi(value) | Instr::Subi(value) | Instr::Muli(value) => {
+                self.load(pc, value, "%rcx");
+                self.op(match instr {
+                    Instr::Addi(_) => "addq %rcx, %rax",
+                    Instr::Subi(_) => "subq %rcx, %rax",
+                    _ => "imulq %rcx, %rax",
+                });
+            },
+            Instr::Natv(_) => {
+                return Err(CompilerError::codegen_error("native functions cannot be called from compiled code", Some(pc)));
+            },
+            Instr::Operand => {},
+        }
+        Ok(())
+    }
+
+    /// Copy the data segment, 8-byte aligned like the VM's
+    fn data(&mut self, data: &[u8]) {
+        self.line("");
+        self.op(".data");
+        self.op(".balign 8");
+        self.line(&format!("{}:", DATA_LABEL));
+        for chunk in data.chunks(16) {
+            let bytes: Vec<String> = chunk.iter().map(|byte| byte.to_string()).collect();
+            self.op(&format!(".byte {}", bytes.join(", ")));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{compile, compile_with};
+
+    #[test]
+    fn test_emit() {
+        let program = compile(r#"
+            int main() { printf("%d\n", 6 * 7); return 0; }
+        "#);
+        let asm = emit(&program).unwrap();
+        assert!(asm.contains("main:"));
+        assert!(asm.contains(&format!(".L{}:", program.entry.unwrap())));
+        assert!(asm.contains("leaq c4_data+0(%rip), %rax"), "{}", asm);
+        assert!(asm.contains("call c4rt_printf"));
+        assert!(asm.contains("\nc4rt_printf:\n"));
+        assert!(asm.contains("c4_data:\n\t.byte 37, 100, 10, 0"));
+    }
+
+    #[test]
+    fn test_emit_rejects_unlinked_programs() {
+        let object = compile_with("int f(); int main() { return f(); }", |parser| parser.set_separate_compilation(true));
+        assert!(matches!(emit(&object), Err(CompilerError::CodegenError { .. })));
+    }
+}
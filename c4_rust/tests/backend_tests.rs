@@ -0,0 +1,917 @@
+mod common;
+
+use c4_rust::c_source;
+use c4_rust::elf;
+use c4_rust::error::CompilerError;
+use c4_rust::host::MemoryHost;
+use c4_rust::instr::{self, Instr};
+use c4_rust::ir;
+use c4_rust::jit::Jit;
+use c4_rust::llvm;
+use c4_rust::object::{ObjectFile, RelocationKind};
+use c4_rust::parser::Parser;
+use c4_rust::regvm::RegisterVm;
+use c4_rust::vm::{VirtualMachine, VmLimits};
+use c4_rust::wasm;
+use c4_rust::x86_64;
+use common::{compile, compile_with};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+/// Standard input given to every program
+const INPUT: &[u8] = b"some input\n";
+
+/// Programs every backend must run exactly like the VM
+const PROGRAMS: &[(&str, &str)] = &[
+    ("recursion", r#"
+        int fib(int n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+        int main() { printf("%d\n", fib(20)); return fib(10); }
+    "#),
+    ("globals_and_strings", r#"
+        char *greeting;
+        int squares[10];
+        int main() {
+            int i;
+            greeting = "hello";
+            i = 0;
+            while (i < 10) { squares[i] = i * i; i++; }
+            printf("%s %d %c\n", greeting, squares[7], greeting[1]);
+            return 0;
+        }
+    "#),
+    ("arithmetic", r#"
+        int main() {
+            int a; a = -17;
+            printf("%d %d %d %d\n", a / 5, a % 5, 1 << 10, -64 >> 2);
+            printf("%d %d %d %d\n", a < 0, a == -17, !a, ~a);
+            printf("%d %d %d\n", 6 & 3, 6 | 3, 6 ^ 3);
+            return 0;
+        }
+    "#),
+    ("many_printf_arguments", r#"
+        int main() { printf("%d %d %d %d %d %d %d %d %d\n", 1, 2, 3, 4, 5, 6, 7, 8, 9); return 0; }
+    "#),
+    ("memory", r#"
+        int main() {
+            char *p; char buf[16]; int *q;
+            p = malloc(32);
+            memset(p, 'A', 5);
+            p[5] = 0;
+            buf[0] = 'x'; buf[1] = 'y'; buf[2] = 0;
+            q = (int *)p + 1;
+            *q = 1234;
+            printf("%s %s %d %d\n", p, buf, memcmp(p, "AAAAA", 5) == 0, q[0]);
+            free(p);
+            return 0;
+        }
+    "#),
+    ("exit", r#"
+        int main() { printf("before\n"); exit(5); printf("after\n"); return 0; }
+    "#),
+    ("files", r#"
+        int main(int argc, char **argv) {
+            char buf[32]; int n; int i;
+            n = read(0, buf, 31);
+            i = 0;
+            while (i < n) { if (buf[i] >= 'a' && buf[i] <= 'z') buf[i] = buf[i] - 32; i++; }
+            buf[n] = 0;
+            printf("%d %s", n, buf);
+            printf("%d %d %d\n", argc, open("/nonexistent/file", 0), close(99));
+            return 0;
+        }
+    "#),
+];
+
+/// printf conversions, which backends with their own printf must format
+/// exactly like the VM
+const FORMATS: &str = r#"
+    int main() {
+        printf("[%d] [%5d] [%-5d] [%05d] [%+d] [% d] [%.3d] [%.0d]\n", -42, 42, 42, -42, 7, 7, 5, 0);
+        printf("[%x] [%X] [%o] [%u] [%x] [%ld]\n", 255, 255, 8, -1, -1, 1 << 40);
+        printf("[%s] [%8s] [%-8s] [%.2s] [%c] [%%] [%q]\n", "abc", "abc", "abc", "abc", 'z');
+        printf("[%*d] [%-*d] [%.*d] [%p] [%d %d]\n", 6, 3, 4, 9, 4, 12, 4096, 1);
+        printf("[%d] [%i] [%x] [%u] [%o] [%5d]\n", 5000050000, 1 << 62, 1 << 40, -5000050000, 1 << 33, -(1 << 35));
+        return printf("end%");
+    }
+"#;
+
+/// Recursion in tail position, deeper than the VM's stack would allow
+/// without tail calls; compiled with `-O1`, it needs `TSR`
+const TAIL_CALLS: &str = r#"
+    int count(int n, int acc) { if (n == 0) return acc; return count(n - 1, acc + 1); }
+    int is_even(int n);
+    int is_odd(int n) { if (n == 0) return 0; return is_even(n - 1); }
+    int is_even(int n) { if (n == 0) return 1; return is_odd(n - 1); }
+    int twice(int x) { return x + x; }
+    int step(int x, int y) { return twice(y - x); }
+    int main() {
+        printf("%d %d %d\n", count(100000, 0), is_even(1001), is_odd(7));
+        return step(3, 8);
+    }
+"#;
+
+/// Compile with `-O1`
+fn compile_o1(source: &str) -> ObjectFile {
+    let mut parser = Parser::new(source.to_string(), false);
+    parser.init().unwrap();
+    parser.parse().unwrap();
+    parser.optimize();
+    parser.optimize_calls();
+    parser.optimize_dataflow();
+    parser.optimize();
+    parser.fuse();
+    ObjectFile::from_parser(&parser).unwrap()
+}
+
+/// The programs every backend runs: [`PROGRAMS`], and the tail calls
+/// of [`TAIL_CALLS`]
+fn programs() -> impl Iterator<Item = (&'static str, ObjectFile)> {
+    PROGRAMS.iter()
+        .map(|(name, source)| (*name, compile(source)))
+        .chain([("tail_calls", compile_o1(TAIL_CALLS))])
+}
+
+/// Run a program on the VM, returning its output and exit code
+fn run_vm(program: &ObjectFile) -> (String, i64) {
+    let host = MemoryHost::new().with_stdin(INPUT);
+    let mut vm = VirtualMachine::new(program.code.clone(), program.data.clone(), 64 * 1024, false);
+    vm.set_host(Box::new(host.clone()));
+    let exit_code = vm.run(program.entry.unwrap(), &["prog".to_string()]).unwrap();
+    (String::from_utf8(host.stdout()).unwrap(), exit_code)
+}
+
+/// A scratch directory for one test
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("c4_backend_{}_{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Run an executable, returning its output and exit code
+fn run_native(path: &Path) -> (String, i64) {
+    let mut child = Command::new(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    // A program that never reads its input may exit before it is written
+    if let Err(err) = child.stdin.take().unwrap().write_all(INPUT) {
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+    }
+    let output = child.wait_with_output().unwrap();
+    (String::from_utf8_lossy(&output.stdout).into_owned(), output.status.code().unwrap_or(-1) as i64)
+}
+
+/// Whether a C compiler is available to assemble and link with
+fn have_cc() -> bool {
+    have("cc")
+}
+
+/// Whether a tool is installed
+fn have(tool: &str) -> bool {
+    Command::new(tool).arg("--version").output().is_ok_and(|output| output.status.success())
+}
+
+/// Test that x86-64 assembly, assembled and linked, behaves like the VM
+#[test]
+fn test_x86_64_asm_matches_vm() {
+    if !have_cc() {
+        eprintln!("skipping: no cc to assemble with");
+        return;
+    }
+    let dir = scratch_dir("x86_64");
+    for (name, program) in programs().chain([("formats", compile(FORMATS))]) {
+        let asm_path = dir.join(format!("{}.s", name));
+        let exe_path = dir.join(name);
+        fs::write(&asm_path, x86_64::emit(&program).unwrap()).unwrap();
+
+        let status = Command::new("cc").arg(&asm_path).arg("-o").arg(&exe_path).status().unwrap();
+        assert!(status.success(), "{} did not assemble", name);
+        assert_eq!(run_native(&exe_path), run_vm(&program), "{} behaved differently", name);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Test that C translations compile without warnings and behave like the
+/// VM, printf and runtime errors included
+#[test]
+fn test_c_source_matches_vm() {
+    if !have_cc() {
+        eprintln!("skipping: no cc to compile with");
+        return;
+    }
+    let dir = scratch_dir("c_source");
+    let divide = ("division_by_zero", "int main() { int z; z = 0; printf(\"before\\n\"); return 1 / z; }");
+    for (name, program) in programs().chain([("formats", compile(FORMATS)), (divide.0, compile(divide.1))]) {
+        let c_path = dir.join(format!("{}.c", name));
+        let exe_path = dir.join(name);
+        fs::write(&c_path, c_source::emit(&program).unwrap()).unwrap();
+
+        let output = Command::new("cc")
+            .args(["-std=c99", "-Wall", "-Werror"])
+            .arg(&c_path)
+            .arg("-o")
+            .arg(&exe_path)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{} did not compile: {}", name, String::from_utf8_lossy(&output.stderr));
+        if name == divide.0 {
+            assert_eq!(run_native(&exe_path), ("before\n".to_string(), 1));
+        } else {
+            assert_eq!(run_native(&exe_path), run_vm(&program), "{} behaved differently", name);
+        }
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Test that LLVM IR is well formed and, built with the LLVM tools
+/// where they are installed, behaves like the VM
+#[test]
+fn test_llvm_ir_matches_vm() {
+    let runnable = have("llc") && have_cc();
+    let dir = scratch_dir("llvm");
+    for (name, program) in programs().chain([("formats", compile(FORMATS))]) {
+        let ir = llvm::emit(&program).unwrap();
+        assert!(ir.contains("define i32 @main(i32 %argc, i8** %argv) {"), "{}", name);
+        let defines = ir.lines().filter(|line| line.starts_with("define ")).count();
+        let ends = ir.lines().filter(|line| *line == "}").count();
+        assert_eq!(defines, ends, "{}", name);
+        if !have("llvm-as") {
+            continue;
+        }
+
+        let ll_path = dir.join(format!("{}.ll", name));
+        let bc_path = dir.join(format!("{}.bc", name));
+        fs::write(&ll_path, &ir).unwrap();
+        let output = Command::new("llvm-as").arg(&ll_path).arg("-o").arg(&bc_path).output().unwrap();
+        assert!(output.status.success(), "{} did not parse: {}", name, String::from_utf8_lossy(&output.stderr));
+        if !runnable {
+            continue;
+        }
+
+        let obj_path = dir.join(format!("{}.o", name));
+        let exe_path = dir.join(name);
+        let status = Command::new("llc")
+            .args(["-relocation-model=pic", "-filetype=obj"])
+            .arg(&bc_path)
+            .arg("-o")
+            .arg(&obj_path)
+            .status()
+            .unwrap();
+        assert!(status.success(), "{} did not compile", name);
+        let status = Command::new("cc").arg(&obj_path).arg("-o").arg(&exe_path).status().unwrap();
+        assert!(status.success(), "{} did not link", name);
+        assert_eq!(run_native(&exe_path), run_vm(&program), "{} behaved differently", name);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Test that ELF executables behave like the VM, printf included
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[test]
+fn test_elf64_matches_vm() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = scratch_dir("elf64");
+    for (name, program) in programs().chain([("formats", compile(FORMATS))]) {
+        let exe_path = dir.join(name);
+        fs::write(&exe_path, elf::emit(&program).unwrap()).unwrap();
+        fs::set_permissions(&exe_path, fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(run_native(&exe_path), run_vm(&program), "{} behaved differently", name);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Test that programs survive a trip through the IR unchanged, and still
+/// behave the same with their blocks laid out in reverse
+#[test]
+fn test_ir_round_trips_programs() {
+    for (name, source) in PROGRAMS {
+        let program = compile(source);
+        let mut graph = ir::Program::from_object(&program).unwrap();
+        graph.verify().unwrap();
+        let lowered = graph.to_code();
+        assert_eq!(lowered.code, program.code, "{} changed", name);
+        let data: Vec<usize> = program.relocations.iter()
+            .filter(|reloc| reloc.kind == RelocationKind::Data)
+            .map(|reloc| reloc.offset)
+            .collect();
+        let lowered_data: Vec<usize> = lowered.relocations.iter().map(|(offset, _)| *offset).collect();
+        assert_eq!(lowered_data, data, "{} relocations changed", name);
+
+        for func in &mut graph.functions {
+            func.blocks[1..].reverse();
+            let last = func.blocks.len() - 1;
+            let renumber = |id: usize| if id == 0 { 0 } else { last + 1 - id };
+            for block in &mut func.blocks {
+                block.terminator = match block.terminator {
+                    ir::Terminator::Jump(to) => ir::Terminator::Jump(renumber(to)),
+                    ir::Terminator::Branch { zero, nonzero } => {
+                        ir::Terminator::Branch { zero: renumber(zero), nonzero: renumber(nonzero) }
+                    },
+                    terminator => terminator,
+                };
+            }
+        }
+        graph.verify().unwrap();
+        let lowered = graph.to_code();
+        let reordered = ObjectFile {
+            code: lowered.code,
+            entry: program.entry.map(|entry| lowered.map[entry]),
+            ..program.clone()
+        };
+        assert_eq!(run_vm(&reordered), run_vm(&program), "{} behaved differently", name);
+    }
+}
+
+/// Test that the dataflow optimizations leave every program's behaviour
+/// alone, printf formatting and runtime errors included
+#[test]
+fn test_dataflow_preserves_programs() {
+    let compile_dataflow = |source: &str| {
+        let mut parser = Parser::new(source.to_string(), false);
+        parser.init().unwrap();
+        parser.parse().unwrap();
+        parser.optimize();
+        parser.optimize_dataflow();
+        parser.optimize();
+        parser.fuse();
+        ObjectFile::from_parser(&parser).unwrap()
+    };
+    let division_by_zero = "int main() { int a, b; a = 1; b = 0; printf(\"before\\n\"); return a / b; }";
+    let sources = PROGRAMS.iter().copied().chain([("formats", FORMATS), ("division_by_zero", division_by_zero)]);
+    for (name, source) in sources {
+        let (plain, optimized) = (compile(source), compile_dataflow(source));
+        ir::Program::from_object(&optimized).unwrap().verify().unwrap();
+        assert!(optimized.code.len() <= plain.code.len(), "{} grew", name);
+
+        let run = |program: &ObjectFile| {
+            let host = MemoryHost::new().with_stdin(INPUT);
+            let mut vm = VirtualMachine::new(program.code.clone(), program.data.clone(), 64 * 1024, false);
+            vm.set_host(Box::new(host.clone()));
+            // Optimized code takes fewer cycles, so only the message is compared
+            let result = vm.run(program.entry.unwrap(), &["prog".to_string()])
+                .map_err(|err| err.to_string().lines().next().unwrap_or_default().to_string());
+            (String::from_utf8(host.stdout()).unwrap(), result)
+        };
+        assert_eq!(run(&optimized), run(&plain), "{} behaved differently", name);
+    }
+}
+
+/// Test that inlining and tail calls leave every program's behaviour
+/// alone, and let tail recursion run in a stack it would overflow
+#[test]
+fn test_call_optimizations_preserve_programs() {
+    for (name, source) in PROGRAMS.iter().chain([&("formats", FORMATS)]) {
+        let optimized = compile_o1(source);
+        ir::Program::from_object(&optimized).unwrap().verify().unwrap();
+        assert_eq!(run_vm(&optimized), run_vm(&compile(source)), "{} behaved differently", name);
+    }
+
+    let optimized = compile_o1(TAIL_CALLS);
+    let decoded = instr::decode(&optimized.code).unwrap();
+    assert!(decoded.iter().any(|instr| matches!(instr, Instr::Tsr(_))), "no TSR in {:?}", decoded);
+    assert_eq!(run_vm(&optimized), ("100000 0 1\n".to_string(), 10));
+
+    let plain = compile(TAIL_CALLS);
+    let mut vm = VirtualMachine::new(plain.code.clone(), plain.data.clone(), 64 * 1024, false);
+    let err = vm.run(plain.entry.unwrap(), &["prog".to_string()]).unwrap_err();
+    assert!(err.to_string().contains("Stack overflow"), "{}", err);
+}
+
+/// Run a program with the JIT, returning its output and exit code
+fn run_jit(program: &ObjectFile, limits: VmLimits) -> Result<(String, i64), CompilerError> {
+    let host = MemoryHost::new().with_stdin(INPUT);
+    let mut jit = Jit::compile(program, limits)?;
+    jit.set_host(Box::new(host.clone()));
+    let exit_code = jit.run(&["prog".to_string()])?;
+    Ok((String::from_utf8(host.stdout()).unwrap(), exit_code))
+}
+
+/// Test that JIT-compiled programs behave like the VM
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[test]
+fn test_jit_matches_vm() {
+    for (name, program) in programs().chain([("formats", compile(FORMATS))]) {
+        let jitted = run_jit(&program, VmLimits::default()).unwrap();
+        assert_eq!(jitted, run_vm(&program), "{} behaved differently", name);
+    }
+}
+
+/// Test that the JIT stops programs with the VM's errors
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+#[test]
+fn test_jit_errors_match_vm() {
+    let limits = VmLimits { stack_size: 4096, max_heap_bytes: Some(1024), max_output_bytes: Some(16), ..VmLimits::default() };
+    let programs = [
+        "int main() { int z; z = 0; return 7 / z; }",
+        "int main() { int z; z = 0; return 7 % z; }",
+        "int f(int n) { return f(n + 1) + 1; } int main() { return f(0); }",
+        "int main() { malloc(512); malloc(1024); return 0; }",
+        "int main() { printf(\"%d\\n\", 1); printf(\"a long line of output\\n\"); return 0; }",
+    ];
+    for source in programs {
+        let program = compile(source);
+        let mut vm = VirtualMachine::with_limits(program.code.clone(), program.data.clone(), limits, false);
+        vm.set_host(Box::new(MemoryHost::new()));
+        let vm_err = vm.run(program.entry.unwrap(), &["prog".to_string()]).unwrap_err();
+        let jit_err = run_jit(&program, limits).unwrap_err();
+        assert_eq!(
+            std::mem::discriminant(&jit_err),
+            std::mem::discriminant(&vm_err),
+            "{}: {} vs {}", source, jit_err, vm_err,
+        );
+        let first_line = |err: &CompilerError| err.to_string().lines().next().unwrap().to_string();
+        assert_eq!(first_line(&jit_err), first_line(&vm_err), "{}", source);
+    }
+
+    // Wrapping division, which traps on x86-64 if done directly
+    let program = compile("int main() { int m; m = 1 << 63; printf(\"%d %d\\n\", m / -1, m % -1); return 0; }");
+    assert_eq!(run_jit(&program, VmLimits::default()).unwrap(), run_vm(&program));
+}
+
+/// Test that programs the JIT cannot run like the VM are refused
+#[test]
+fn test_jit_refuses_unsupported_programs() {
+    let program = compile("int main() { return 0; }");
+    let limits = VmLimits { max_cycles: Some(100), ..VmLimits::default() };
+    assert!(Jit::compile(&program, limits).is_err());
+
+    let unlinked = compile_with("int helper(); int main() { return helper(); }", |parser| parser.set_separate_compilation(true));
+    assert!(Jit::compile(&unlinked, VmLimits::default()).is_err());
+}
+
+/// Run a program on the register machine, returning its output and exit
+/// code
+fn run_registers(program: &ObjectFile, limits: VmLimits) -> Result<(String, i64), CompilerError> {
+    let host = MemoryHost::new().with_stdin(INPUT);
+    let mut machine = RegisterVm::compile(program, limits, false)?;
+    machine.set_host(Box::new(host.clone()));
+    let exit_code = machine.run(&["prog".to_string()])?;
+    Ok((String::from_utf8(host.stdout()).unwrap(), exit_code))
+}
+
+/// Test that the register machine runs programs exactly like the VM, in
+/// fewer instructions
+#[test]
+fn test_register_vm_matches_vm() {
+    let limits = VmLimits { stack_size: 64 * 1024, ..VmLimits::default() };
+    for (name, program) in programs().chain([("formats", compile(FORMATS)), ("formats_o1", compile_o1(FORMATS))]) {
+        let translated = run_registers(&program, limits).unwrap();
+        assert_eq!(translated, run_vm(&program), "{} behaved differently", name);
+    }
+
+    let program = compile(PROGRAMS[0].1);
+    let mut vm = VirtualMachine::with_limits(program.code.clone(), program.data.clone(), limits, false);
+    vm.set_host(Box::new(MemoryHost::new()));
+    vm.run(program.entry.unwrap(), &[]).unwrap();
+    let mut machine = RegisterVm::compile(&program, limits, false).unwrap();
+    machine.set_host(Box::new(MemoryHost::new()));
+    machine.run(&[]).unwrap();
+    assert!(machine.cycle() < vm.cycle(), "{} vs {} instructions", machine.cycle(), vm.cycle());
+}
+
+/// Test that the register machine stops programs with the VM's errors,
+/// pointing at the same source lines
+#[test]
+fn test_register_vm_errors_match_vm() {
+    let limits = VmLimits { stack_size: 4096, max_heap_bytes: Some(1024), max_output_bytes: Some(16), ..VmLimits::default() };
+    let programs = [
+        "int main() { int z; z = 0; return 7 / z; }",
+        "int main() { int z; z = 0; return 7 % z; }",
+        "int f(int n) { return f(n + 1) + 1; } int main() { return f(0); }",
+        "int main() { malloc(512); malloc(1024); return 0; }",
+        "int main() { printf(\"%d\\n\", 1); printf(\"a long line of output\\n\"); return 0; }",
+        "int main() { int *p; p = 0; p = p - 1; return *p; }",
+    ];
+    for source in programs {
+        let program = compile(source);
+        let mut vm = VirtualMachine::with_limits(program.code.clone(), program.data.clone(), limits, false);
+        vm.set_host(Box::new(MemoryHost::new()));
+        vm.set_debug_info(program.debug.clone());
+        let vm_err = vm.run(program.entry.unwrap(), &["prog".to_string()]).unwrap_err();
+        let err = run_registers(&program, limits).unwrap_err();
+        assert_eq!(std::mem::discriminant(&err), std::mem::discriminant(&vm_err), "{}: {} vs {}", source, err, vm_err);
+        let first_line = |err: &CompilerError| err.to_string().lines().next().unwrap().to_string();
+        assert_eq!(first_line(&err), first_line(&vm_err), "{}", source);
+    }
+
+    // Loops are stopped by the cycle limit, including one through returns
+    // into a frame whose return address was overwritten
+    let returns = "f(int *q) { int x; int *p; p = &x; q[1] = p[1]; q[2] = p[2]; return 0; }
+                   main() { int y; int *q; q = &y; return f(q); }";
+    let cycles = VmLimits { max_cycles: Some(1000), ..VmLimits::default() };
+    let timeout = VmLimits { timeout: Some(Duration::from_millis(20)), ..VmLimits::default() };
+    for source in ["int main() { while (1) {} return 0; }", returns] {
+        let program = compile(source);
+        let err = run_registers(&program, cycles).unwrap_err();
+        assert!(err.to_string().contains("executed more than 1000 instructions"), "{}", err);
+        let err = run_registers(&program, timeout).unwrap_err();
+        assert!(err.to_string().contains("ran for more than"), "{}", err);
+    }
+}
+
+/// Reads the pieces of a WebAssembly binary
+struct WasmReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> WasmReader<'a> {
+    fn byte(&mut self) -> Result<u8, String> {
+        let byte = *self.bytes.get(self.pos).ok_or("unexpected end of module")?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self.bytes.get(self.pos..self.pos + len).ok_or("unexpected end of module")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn uleb(&mut self) -> Result<u64, String> {
+        let (mut value, mut shift) = (0, 0);
+        loop {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+            if shift >= 64 {
+                return Err("LEB128 value too long".to_string());
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Result<i64, String> {
+        let (mut value, mut shift) = (0i64, 0);
+        loop {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as i64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Ok(value);
+            }
+            if shift >= 70 {
+                return Err("LEB128 value too long".to_string());
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let len = self.uleb()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| "name is not UTF-8".to_string())
+    }
+
+    /// A constant expression, `i32.const` or `i64.const` then `end`
+    fn const_expr(&mut self) -> Result<i64, String> {
+        let value = match self.byte()? {
+            0x41 | 0x42 => self.sleb()?,
+            op => return Err(format!("unexpected opcode {:#x} in constant expression", op)),
+        };
+        match self.byte()? {
+            0x0b => Ok(value),
+            _ => Err("constant expression is not terminated".to_string()),
+        }
+    }
+}
+
+/// What a valid module contains
+#[derive(Debug, Default)]
+struct WasmSummary {
+    imports: Vec<String>,
+    exports: Vec<String>,
+    functions: usize,
+}
+
+/// Check the structure of a module: its sections and their order and
+/// sizes, every index, and that each function body is well nested,
+/// branches to labels in scope and uses only known instructions
+fn validate_wasm(bytes: &[u8]) -> Result<WasmSummary, String> {
+    let mut reader = WasmReader { bytes, pos: 0 };
+    if reader.take(8)? != b"\0asm\x01\0\0\0" {
+        return Err("bad magic number or version".to_string());
+    }
+
+    let mut summary = WasmSummary::default();
+    let mut types: Vec<usize> = Vec::new(); // parameter counts
+    let mut function_types: Vec<usize> = Vec::new(); // of every function, imports first
+    let mut imported = 0;
+    let mut globals = 0;
+    let mut memory_pages = None;
+    let mut last_id = 0;
+
+    while reader.pos < bytes.len() {
+        let id = reader.byte()?;
+        let size = reader.uleb()? as usize;
+        let end = reader.pos + size;
+        if end > bytes.len() {
+            return Err(format!("section {} runs past the end", id));
+        }
+        if id != 0 {
+            if id <= last_id {
+                return Err(format!("section {} is out of order", id));
+            }
+            last_id = id;
+        }
+        match id {
+            0 => {
+                reader.name()?;
+                reader.pos = end;
+            },
+            1 => {
+                for _ in 0..reader.uleb()? {
+                    if reader.byte()? != 0x60 {
+                        return Err("type is not a function type".to_string());
+                    }
+                    let params = reader.uleb()? as usize;
+                    for _ in 0..params {
+                        if !matches!(reader.byte()?, 0x7e | 0x7f) {
+                            return Err("unexpected parameter type".to_string());
+                        }
+                    }
+                    for _ in 0..reader.uleb()? {
+                        if !matches!(reader.byte()?, 0x7e | 0x7f) {
+                            return Err("unexpected result type".to_string());
+                        }
+                    }
+                    types.push(params);
+                }
+            },
+            2 => {
+                for _ in 0..reader.uleb()? {
+                    let module = reader.name()?;
+                    let name = reader.name()?;
+                    if reader.byte()? != 0x00 {
+                        return Err(format!("import {}.{} is not a function", module, name));
+                    }
+                    let typ = reader.uleb()? as usize;
+                    function_types.push(*types.get(typ).ok_or("import has an unknown type")?);
+                    summary.imports.push(format!("{}.{}", module, name));
+                }
+                imported = function_types.len();
+            },
+            3 => {
+                for _ in 0..reader.uleb()? {
+                    let typ = reader.uleb()? as usize;
+                    function_types.push(*types.get(typ).ok_or("function has an unknown type")?);
+                }
+            },
+            5 => {
+                if reader.uleb()? != 1 {
+                    return Err("expected one memory".to_string());
+                }
+                let has_max = reader.byte()?;
+                memory_pages = Some(reader.uleb()?);
+                if has_max == 1 {
+                    reader.uleb()?;
+                }
+            },
+            6 => {
+                for _ in 0..reader.uleb()? {
+                    reader.byte()?;
+                    if reader.byte()? > 1 {
+                        return Err("bad global mutability".to_string());
+                    }
+                    reader.const_expr()?;
+                    globals += 1;
+                }
+            },
+            7 => {
+                for _ in 0..reader.uleb()? {
+                    let name = reader.name()?;
+                    let kind = reader.byte()?;
+                    let index = reader.uleb()? as usize;
+                    let valid = match kind {
+                        0 => index < function_types.len(),
+                        2 => index == 0 && memory_pages.is_some(),
+                        3 => index < globals,
+                        _ => false,
+                    };
+                    if !valid || summary.exports.contains(&name) {
+                        return Err(format!("bad export {}", name));
+                    }
+                    summary.exports.push(name);
+                }
+            },
+            10 => {
+                let count = reader.uleb()? as usize;
+                if count != function_types.len() - imported {
+                    return Err("function and code counts differ".to_string());
+                }
+                for function in imported..function_types.len() {
+                    let size = reader.uleb()? as usize;
+                    let body_end = reader.pos + size;
+                    let mut locals = function_types[function];
+                    for _ in 0..reader.uleb()? {
+                        locals += reader.uleb()? as usize;
+                        reader.byte()?;
+                    }
+                    validate_body(&mut reader, function_types.len(), locals, globals)
+                        .map_err(|err| format!("function {}: {}", function, err))?;
+                    if reader.pos != body_end {
+                        return Err(format!("function {} does not fill its body", function));
+                    }
+                }
+            },
+            11 => {
+                for _ in 0..reader.uleb()? {
+                    if reader.uleb()? != 0 {
+                        return Err("expected an active data segment".to_string());
+                    }
+                    let offset = reader.const_expr()? as u64;
+                    let len = reader.uleb()?;
+                    reader.take(len as usize)?;
+                    if offset + len > memory_pages.unwrap_or(0) * 0x10000 {
+                        return Err("data segment does not fit in memory".to_string());
+                    }
+                }
+            },
+            _ => return Err(format!("unexpected section {}", id)),
+        }
+        if reader.pos != end {
+            return Err(format!("section {} has the wrong size", id));
+        }
+    }
+    summary.functions = function_types.len();
+    Ok(summary)
+}
+
+/// Check one function body, up to and including its final `end`
+fn validate_body(reader: &mut WasmReader, functions: usize, locals: usize, globals: usize) -> Result<(), String> {
+    // One entry per open block, loop or if; true for an if
+    let mut labels = vec![false];
+    while !labels.is_empty() {
+        match reader.byte()? {
+            0x02..=0x04 => {
+                let op = reader.bytes[reader.pos - 1];
+                if reader.byte()? != 0x40 {
+                    return Err("expected an empty block type".to_string());
+                }
+                labels.push(op == 0x04);
+            },
+            0x05 => {
+                if labels.last() != Some(&true) {
+                    return Err("else outside an if".to_string());
+                }
+                *labels.last_mut().unwrap() = false;
+            },
+            0x0b => {
+                labels.pop();
+            },
+            0x0c => {
+                if reader.uleb()? as usize >= labels.len() {
+                    return Err("branch to a label out of scope".to_string());
+                }
+            },
+            0x10 => {
+                if reader.uleb()? as usize >= functions {
+                    return Err("call to an unknown function".to_string());
+                }
+            },
+            0x20..=0x22 => {
+                if reader.uleb()? as usize >= locals {
+                    return Err("unknown local".to_string());
+                }
+            },
+            0x23 | 0x24 => {
+                if reader.uleb()? as usize >= globals {
+                    return Err("unknown global".to_string());
+                }
+            },
+            0x28..=0x3e => {
+                reader.uleb()?;
+                reader.uleb()?;
+            },
+            0x41 | 0x42 => {
+                reader.sleb()?;
+            },
+            0x00 | 0x01 | 0x0f | 0x1a | 0x45..=0x8a | 0xa7 | 0xac | 0xad | 0xc0..=0xc4 => {},
+            op => return Err(format!("unknown opcode {:#x}", op)),
+        }
+    }
+    Ok(())
+}
+
+/// Test that WebAssembly modules are well formed, in both formats
+#[test]
+fn test_wasm_modules_are_valid() {
+    for (name, program) in programs().chain([("formats", compile(FORMATS))]) {
+        let module = wasm::Module::from_object(&program).unwrap();
+        let summary = validate_wasm(&module.to_wasm()).unwrap_or_else(|err| panic!("{}: {}", name, err));
+        assert_eq!(summary.imports.len(), 9, "{}", name);
+        assert!(summary.imports.iter().all(|import| import.starts_with("c4.")), "{}", name);
+        assert_eq!(summary.exports, ["memory", "start", "heap_base"], "{}", name);
+
+        // The text has balanced parentheses and the same functions
+        let wat = module.to_wat();
+        let depth = wat.chars().try_fold(0i64, |depth, c| match c {
+            '(' => Some(depth + 1),
+            ')' => (depth > 0).then(|| depth - 1),
+            _ => Some(depth),
+        });
+        assert_eq!(depth, Some(0), "{}", name);
+        let functions = wat.lines()
+            .map(str::trim_start)
+            .filter(|line| line.starts_with("(func $") || line.starts_with("(import "))
+            .count();
+        assert_eq!(functions, summary.functions, "{}", name);
+    }
+}
+
+/// A host for WebAssembly modules, run by Node: the system calls over an
+/// in-memory stdin, with a `printf` that knows only `%d`, `%s` and `%c`
+const NODE_HOST: &str = r#"
+const bytes = require('fs').readFileSync(process.argv[2]);
+const input = Buffer.from(process.argv[3]);
+let memory, heap, inputPos = 0, out = '';
+const mem = () => new Uint8Array(memory.buffer);
+const word = a => new DataView(memory.buffer).getBigInt64(Number(a), true);
+const str = a => { let s = '', i = Number(a); while (mem()[i]) s += String.fromCharCode(mem()[i++]); return s; };
+const c4 = {
+  open: () => -1n,
+  read: (fd, buf, n) => {
+    if (fd != 0n) return -1n;
+    let k = 0;
+    while (k < Number(n) && inputPos < input.length) mem()[Number(buf) + k++] = input[inputPos++];
+    return BigInt(k);
+  },
+  close: () => -1n,
+  printf: (sp, argc) => {
+    const arg = k => word(sp + (argc - 1n - BigInt(k)) * 8n);
+    const fmt = str(arg(0));
+    let s = '', k = 1;
+    for (let i = 0; i < fmt.length; i++) {
+      if (fmt[i] != '%') { s += fmt[i]; continue; }
+      const c = fmt[++i];
+      s += c == 'd' ? arg(k++).toString() : c == 's' ? str(arg(k++)) : String.fromCharCode(Number(arg(k++) & 255n));
+    }
+    out += s;
+    return BigInt(s.length);
+  },
+  malloc: n => {
+    const p = heap;
+    heap += (n + 7n) & ~7n;
+    while (Number(heap) > memory.buffer.byteLength) memory.grow(1);
+    return p;
+  },
+  free: p => p,
+  memset: (d, c, n) => { mem().fill(Number(c & 255n), Number(d), Number(d + n)); return d; },
+  memcmp: (a, b, n) => {
+    for (let i = 0; i < Number(n); i++) {
+      const d = mem()[Number(a) + i] - mem()[Number(b) + i];
+      if (d) return BigInt(d);
+    }
+    return 0n;
+  },
+  exit: code => { throw { code }; },
+};
+WebAssembly.instantiate(bytes, { c4 }).then(({ instance }) => {
+  memory = instance.exports.memory;
+  heap = instance.exports.heap_base.value;
+  const name = c4.malloc(8n);
+  mem().set(Buffer.from('prog\0'), Number(name));
+  const argv = c4.malloc(16n);
+  new DataView(memory.buffer).setBigInt64(Number(argv), name, true);
+  let code;
+  try { code = instance.exports.start(1n, argv); } catch (e) { if (e.code === undefined) throw e; code = e.code; }
+  process.stdout.write(out + '\n' + code);
+});
+"#;
+
+/// Test that WebAssembly modules, run by Node, behave like the VM
+#[test]
+fn test_wasm_matches_vm() {
+    if !Command::new("node").arg("--version").output().is_ok_and(|output| output.status.success()) {
+        eprintln!("skipping: no node to run WebAssembly with");
+        return;
+    }
+    let dir = scratch_dir("wasm");
+    let host_path = dir.join("host.js");
+    fs::write(&host_path, NODE_HOST).unwrap();
+    for (name, program) in programs() {
+        let module_path = dir.join(format!("{}.wasm", name));
+        fs::write(&module_path, wasm::Module::from_object(&program).unwrap().to_wasm()).unwrap();
+
+        let output = Command::new("node")
+            .arg(&host_path)
+            .arg(&module_path)
+            .arg(std::str::from_utf8(INPUT).unwrap())
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}: {}", name, String::from_utf8_lossy(&output.stderr));
+        let output = String::from_utf8(output.stdout).unwrap();
+        let (stdout, exit_code) = output.rsplit_once('\n').unwrap();
+        assert_eq!((stdout.to_string(), exit_code.parse().unwrap()), run_vm(&program), "{} behaved differently", name);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
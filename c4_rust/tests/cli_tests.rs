@@ -0,0 +1,72 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+const PROGRAM: &str = "int main(int argc, char **argv) { printf(\"%d %s\\n\", argc, argv[argc - 1]); return 3; }";
+
+/// A fresh directory for one test's files
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("c4_cli_{}_{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Run `c4_rust` in `dir`
+fn c4_rust(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_c4_rust")).current_dir(dir).args(args).output().unwrap()
+}
+
+/// `-o` may follow the input file when compiling with `-c`
+#[test]
+fn test_output_after_input() {
+    let dir = scratch_dir("output_after_input");
+    fs::write(dir.join("foo.c"), PROGRAM).unwrap();
+
+    let output = c4_rust(&dir, &["-c", "foo.c", "-o", "bar.c4o"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(dir.join("bar.c4o").exists());
+    assert!(!dir.join("foo.c4o").exists());
+
+    // Running still passes everything after the file to the program
+    let output = c4_rust(&dir, &["bar.c4o", "x", "-o"]);
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "3 -o\n");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// `-o` after several inputs is refused before any object is written
+#[test]
+fn test_output_with_several_inputs() {
+    let dir = scratch_dir("output_with_several_inputs");
+    fs::write(dir.join("main.c"), "int twice(int n); int main() { return twice(21); }").unwrap();
+    fs::write(dir.join("lib.c"), "int twice(int n) { return n + n; }").unwrap();
+
+    let output = c4_rust(&dir, &["-c", "main.c", "lib.c", "-o", "both.c4o"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("-o cannot be used with more than one input file"));
+    for name in ["both.c4o", "main.c4o", "lib.c4o", "-o"] {
+        assert!(!dir.join(name).exists(), "{} was written", name);
+    }
+
+    // Without -o each input gets its own object, and they link
+    let output = c4_rust(&dir, &["-c", "main.c", "lib.c"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let output = c4_rust(&dir, &["main.c4o", "lib.c4o", "--"]);
+    assert_eq!(output.status.code(), Some(42));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// `--sandbox` keeps the interpreter under `--jit`, whose unchecked memory
+/// accesses could reach the sandbox itself
+#[test]
+fn test_sandbox_disables_jit() {
+    let dir = scratch_dir("sandbox_disables_jit");
+    fs::create_dir_all(dir.join("box")).unwrap();
+    fs::write(dir.join("wild.c"), "int main() { int *p; p = (int *)123456; *p = 1; printf(\"survived\\n\"); return 0; }").unwrap();
+
+    let output = c4_rust(&dir, &["--jit", "--sandbox", "box", "-d", "wild.c"]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).lines().any(|line| line == "survived"));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("falling back to the interpreter: --sandbox"));
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,24 @@
+//! Helpers shared by the integration tests and the benchmarks
+//!
+//! Benchmarks include this file by path, and each user calls only some of
+//! its functions.
+#![allow(dead_code)]
+
+use c4_rust::object::ObjectFile;
+use c4_rust::parser::Parser;
+
+/// Compile a program, optimized and fused as the CLI does
+pub fn compile(source: &str) -> ObjectFile {
+    compile_with(source, |_| {})
+}
+
+/// [`compile`], after `configure` has set up the parser
+pub fn compile_with(source: &str, configure: impl FnOnce(&mut Parser)) -> ObjectFile {
+    let mut parser = Parser::new(source.to_string(), false);
+    configure(&mut parser);
+    parser.init().expect("init");
+    parser.parse().expect("program should compile");
+    parser.optimize();
+    parser.fuse();
+    ObjectFile::from_parser(&parser).expect("object")
+}
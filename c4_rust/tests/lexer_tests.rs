@@ -1,5 +1,5 @@
 use c4_rust::error::CompilerError;
-use c4_rust::lexer::{Lexer, Token};
+use c4_rust::lexer::Lexer;
 use c4_rust::types::TokenType;
 
 /// Test basic tokenization
@@ -22,7 +22,7 @@ fn test_basic_tokenization() -> Result<(), CompilerError> {
         TokenType::Eof,
     ];
     
-    let expected_values = vec![
+    let expected_values = [
         None,
         None,
         None,
@@ -48,9 +48,9 @@ fn test_basic_tokenization() -> Result<(), CompilerError> {
         None,
     ];
     
-    for i in 0..expected_types.len() {
+    for (i, expected) in expected_types.iter().enumerate() {
         let token = lexer.next_token()?;
-        assert_eq!(token.token_type, expected_types[i], "Token {}: Expected {:?}, got {:?}", i, expected_types[i], token.token_type);
+        assert_eq!(token.token_type, *expected, "Token {}: Expected {:?}, got {:?}", i, expected, token.token_type);
         assert_eq!(token.value, expected_values[i], "Token {}: Value mismatch", i);
         assert_eq!(token.name, expected_names[i], "Token {}: Name mismatch", i);
     }
@@ -77,9 +77,9 @@ fn test_keywords() -> Result<(), CompilerError> {
         TokenType::Eof,
     ];
     
-    for i in 0..expected_types.len() {
+    for (i, expected) in expected_types.iter().enumerate() {
         let token = lexer.next_token()?;
-        assert_eq!(token.token_type, expected_types[i], "Token {}: Expected {:?}, got {:?}", i, expected_types[i], token.token_type);
+        assert_eq!(token.token_type, *expected, "Token {}: Expected {:?}, got {:?}", i, expected, token.token_type);
     }
     
     Ok(())
@@ -91,17 +91,17 @@ fn test_identifiers() -> Result<(), CompilerError> {
     let source = "main _underscore camelCase var123";
     let mut lexer = Lexer::new(source.to_string(), false);
     
-    let expected_names = vec![
+    let expected_names = [
         "main",
         "_underscore",
         "camelCase",
         "var123",
     ];
     
-    for i in 0..expected_names.len() {
+    for (i, expected) in expected_names.iter().enumerate() {
         let token = lexer.next_token()?;
         assert_eq!(token.token_type, TokenType::Id, "Token {} should be an Id", i);
-        assert_eq!(token.name, Some(expected_names[i].to_string()), "Token {}: Expected name '{}', got {:?}", i, expected_names[i], token.name);
+        assert_eq!(token.name, Some(expected.to_string()), "Token {}: Expected name '{}', got {:?}", i, expected, token.name);
     }
     
     Ok(())
@@ -113,17 +113,17 @@ fn test_numeric_literals() -> Result<(), CompilerError> {
     let source = "123 0 0x1A 077";
     let mut lexer = Lexer::new(source.to_string(), false);
     
-    let expected_values = vec![
+    let expected_values = [
         123,    // Decimal
         0,      // Zero
         26,     // Hex (0x1A)
         63,     // Octal (077)
     ];
     
-    for i in 0..expected_values.len() {
+    for (i, expected) in expected_values.iter().enumerate() {
         let token = lexer.next_token()?;
         assert_eq!(token.token_type, TokenType::Num, "Token {} should be a Num", i);
-        assert_eq!(token.value, Some(expected_values[i]), "Token {}: Expected value {}, got {:?}", i, expected_values[i], token.value);
+        assert_eq!(token.value, Some(*expected), "Token {}: Expected value {}, got {:?}", i, expected, token.value);
     }
     
     Ok(())
@@ -188,10 +188,10 @@ fn test_operators() -> Result<(), CompilerError> {
         TokenType::Eof,
     ];
     
-    for i in 0..expected_types.len() {
+    for (i, expected) in expected_types.iter().enumerate() {
         let token = lexer.next_token()?;
-        assert_eq!(token.token_type, expected_types[i], 
-            "Token {}: Expected {:?}, got {:?}", i, expected_types[i], token.token_type);
+        assert_eq!(token.token_type, *expected, 
+            "Token {}: Expected {:?}, got {:?}", i, expected, token.token_type);
     }
     
     Ok(())
@@ -216,10 +216,10 @@ fn test_delimiters() -> Result<(), CompilerError> {
         TokenType::Eof,
     ];
     
-    for i in 0..expected_types.len() {
+    for (i, expected) in expected_types.iter().enumerate() {
         let token = lexer.next_token()?;
-        assert_eq!(token.token_type, expected_types[i], 
-            "Token {}: Expected {:?}, got {:?}", i, expected_types[i], token.token_type);
+        assert_eq!(token.token_type, *expected, 
+            "Token {}: Expected {:?}, got {:?}", i, expected, token.token_type);
     }
     
     Ok(())
@@ -245,9 +245,9 @@ fn test_comments() -> Result<(), CompilerError> {
         None,             // Eof
     ];
     
-    for i in 0..expected_names.len() {
+    for (i, expected) in expected_names.iter().enumerate() {
         let token = lexer.next_token()?;
-        if let Some(expected_name) = &expected_names[i] {
+        if let Some(expected_name) = expected {
             assert_eq!(token.name, Some(expected_name.clone()), 
                 "Token {}: Expected name {:?}, got {:?}", i, expected_name, token.name);
         }
@@ -273,6 +273,23 @@ fn test_lexer_error() {
     assert!(lexer.line() > 0, "Line number should be tracked");
 }
 
+/// Test that non-ASCII text in comments and strings does not throw off
+/// the tokens after it
+#[test]
+fn test_non_ascii_source() -> Result<(), CompilerError> {
+    let source = "// déjà vu\nint x; /* ünïcode */ \"naïve\" count42";
+    let mut lexer = Lexer::new(source.to_string(), false);
+
+    assert_eq!(lexer.next_token()?.token_type, TokenType::Int);
+    assert_eq!(lexer.next_token()?.name, Some("x".to_string()));
+    assert_eq!(lexer.next_token()?.token_type, TokenType::Semicolon);
+    assert_eq!(lexer.next_token()?.name, Some("naïve".to_string()));
+    assert_eq!(lexer.next_token()?.name, Some("count42".to_string()));
+    assert_eq!(lexer.next_token()?.token_type, TokenType::Eof);
+
+    Ok(())
+}
+
 /// Test line and column tracking
 #[test]
 fn test_location_tracking() -> Result<(), CompilerError> {
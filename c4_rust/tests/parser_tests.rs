@@ -1,6 +1,8 @@
 use c4_rust::error::CompilerError;
+use c4_rust::native::Natives;
 use c4_rust::parser::Parser;
-use c4_rust::types::{Opcode, TokenType, Type};
+use c4_rust::types::{InstructionSet, Opcode};
+use c4_rust::vm::VirtualMachine;
 
 /// Test basic parsing of a simple program
 #[test]
@@ -366,16 +368,18 @@ fn test_precedence() -> Result<(), CompilerError> {
     parser.init()?;
     parser.parse()?;
     
-    // The result should be 14, not 20, due to precedence
-    // But this is hard to test directly from the bytecode
-    // We'll check for multiplication and addition
+    // The result should be 14, not 20, due to precedence.
+    // Constant folding computes it at compile time.
     let code = parser.get_code();
     
-    let has_mul = code.contains(&(Opcode::MUL as i64));
-    let has_add = code.contains(&(Opcode::ADD as i64));
+    let mut has_imm_14 = false;
+    for i in 0..code.len() - 1 {
+        if code[i] == Opcode::IMM as i64 && code[i + 1] == 14 {
+            has_imm_14 = true;
+        }
+    }
     
-    assert!(has_mul, "Missing MUL instruction");
-    assert!(has_add, "Missing ADD instruction");
+    assert!(has_imm_14, "Missing IMM 14 instruction");
     
     Ok(())
 }
@@ -394,23 +398,19 @@ fn test_sizeof() -> Result<(), CompilerError> {
     parser.init()?;
     parser.parse()?;
     
-    // sizeof(int) should be 8 and sizeof(char) should be 1
+    // sizeof(int) should be 8 and sizeof(char) should be 1,
+    // and their sum is folded to a single constant
     let code = parser.get_code();
     
-    let mut has_imm_8 = false;
-    let mut has_imm_1 = false;
+    let mut has_imm_9 = false;
     
     for i in 0..code.len() - 1 {
-        if code[i] == Opcode::IMM as i64 {
-            if code[i + 1] == 8 {
-                has_imm_8 = true;
-            } else if code[i + 1] == 1 {
-                has_imm_1 = true;
-            }
+        if code[i] == Opcode::IMM as i64 && code[i + 1] == 9 {
+            has_imm_9 = true;
         }
     }
     
-    assert!(has_imm_8 || has_imm_1, "Missing sizeof values");
+    assert!(has_imm_9, "Missing sizeof values");
     
     Ok(())
 }
@@ -469,6 +469,9 @@ fn test_c4_snippet() -> Result<(), CompilerError> {
     let source = r#"
         enum { CHAR, INT, PTR };
         
+        char *p;
+        int line;
+        
         int next() {
             char *pp;
             int tk;
@@ -507,4 +510,415 @@ fn test_c4_snippet() -> Result<(), CompilerError> {
     }
     
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Compile a program and run it, returning main's result
+///
+/// The program also runs after the `-O1` optimizations, which must not
+/// change the result.
+fn run_source(source: &str) -> Result<i64, CompilerError> {
+    let mut results = Vec::new();
+    for optimized in [false, true] {
+        let mut parser = Parser::new(source.to_string(), false);
+        parser.init()?;
+        parser.parse()?;
+        if optimized {
+            parser.optimize();
+            parser.optimize_calls();
+            parser.optimize_dataflow();
+            parser.optimize();
+            parser.fuse();
+        }
+
+        let entry = parser.get_main_function().unwrap().value as usize;
+        let mut vm = VirtualMachine::new(parser.get_code().to_vec(), parser.get_data().to_vec(), 64 * 1024, false);
+        results.push(vm.run(entry, &[])?);
+    }
+    assert_eq!(results[0], results[1], "the -O1 optimizations changed the result");
+    Ok(results[0])
+}
+
+/// Test local variable initializers
+#[test]
+fn test_local_initializers() -> Result<(), CompilerError> {
+    let source = r#"
+        int main() {
+            int a = 6, *p = &a, b = a * 7;
+            char c = 'x';
+            *p = b;
+            return a + (c == 'x');
+        }
+    "#;
+
+    assert_eq!(run_source(source)?, 43);
+    Ok(())
+}
+
+/// Test declarations after statements and in nested blocks
+#[test]
+fn test_declarations_anywhere() -> Result<(), CompilerError> {
+    let source = r#"
+        int main() {
+            int total = 0;
+            total = total + 1;
+            int i = 0;
+            while (i < 3) {
+                int sq = i * i;
+                total = total + sq;
+                {
+                    int total = 1000;
+                    sq = total;
+                }
+                i++;
+            }
+            int x = 10;
+            if (x) {
+                int x = 20;
+                total = total + x;
+            }
+            return total + x;
+        }
+    "#;
+
+    // 1 + (0 + 1 + 4) + 20 + 10
+    assert_eq!(run_source(source)?, 36);
+    Ok(())
+}
+
+/// Test array initializers, including omitted sizes and zero filling
+#[test]
+fn test_array_initializers() -> Result<(), CompilerError> {
+    let source = r#"
+        int main() {
+            int a[5] = {1, 2, 3};
+            int b[] = {10, 20, 30};
+            char s[] = "hey";
+            char t[6] = {'o', 'k'};
+            return a[0] + a[2] + a[3] + a[4] + b[2] + (s[1] == 'e') + (s[3] == 0) + (t[4] == 0);
+        }
+    "#;
+
+    assert_eq!(run_source(source)?, 1 + 3 + 30 + 3);
+    Ok(())
+}
+
+/// Test that a block's variables go out of scope at its end
+#[test]
+fn test_block_scope_error() {
+    let source = r#"
+        int main() {
+            { int inner = 1; }
+            return inner;
+        }
+    "#;
+
+    match run_source(source) {
+        Err(CompilerError::ParserError { message, .. }) => {
+            assert!(message.contains("inner"), "Unexpected error: {}", message);
+        },
+        other => panic!("Expected undefined variable error, got: {:?}", other),
+    }
+}
+
+/// Test that too many array initializers are rejected
+#[test]
+fn test_array_initializer_overflow() {
+    let source = "int main() { int a[2] = {1, 2, 3}; return 0; }";
+
+    assert!(matches!(run_source(source), Err(CompilerError::ParserError { .. })));
+}
+
+/// Compile a program and return its code segment
+fn compile(source: &str, simplify: bool) -> Result<Vec<i64>, CompilerError> {
+    let mut parser = Parser::new(source.to_string(), false);
+    parser.set_simplify(simplify);
+    parser.init()?;
+    parser.parse()?;
+    Ok(parser.get_code().to_vec())
+}
+
+/// Check whether the code contains `IMM value`
+fn has_imm(code: &[i64], value: i64) -> bool {
+    code.windows(2).any(|w| w[0] == Opcode::IMM as i64 && w[1] == value)
+}
+
+/// Test folding of constant subexpressions
+#[test]
+fn test_constant_folding() -> Result<(), CompilerError> {
+    let code = compile("int main() { return 60 * 60 * 24 + (1 << 4) - -2; }", true)?;
+
+    assert!(has_imm(&code, 86400 + 16 + 2), "Expression should fold to one immediate");
+    assert!(!code.contains(&(Opcode::MUL as i64)), "No MUL should remain");
+    assert!(!code.contains(&(Opcode::PSH as i64)), "No PSH should remain");
+
+    let code = compile("int main() { return !0 + ~5 + (3 > 2) + (0 && 9) + (7 || 0); }", true)?;
+    assert!(has_imm(&code, 1 - 6 + 1 + 7));
+    assert!(!code.contains(&(Opcode::BZ as i64)));
+
+    assert_eq!(run_source("int main() { return 60 * 60 * 24 / 7 % 1000; }")?, 86400 / 7 % 1000);
+
+    // Division by zero is not folded, and is reported at the operator
+    for (source, line, column) in [
+        ("int main() {\n  int x;\n  x = 7 / 0 + 100 * 2;\n  return x;\n}", 3, 9),
+        ("int main() { printf(\"%d\\n\", 7 % 0); return 0; }", 1, 31),
+    ] {
+        let mut parser = Parser::new(source.to_string(), false);
+        parser.init()?;
+        parser.parse()?;
+        let location = parser.get_warnings()[0].location.unwrap();
+        assert_eq!((location.line, location.column), (line, column), "{}", source);
+    }
+    Ok(())
+}
+
+/// Test identity simplification and the switch that disables it
+#[test]
+fn test_identity_simplification() -> Result<(), CompilerError> {
+    let source = "int main() { int x = 3; return (x * 1 + 0) << 0; }";
+
+    let code = compile(source, true)?;
+    assert!(!code.contains(&(Opcode::MUL as i64)), "x * 1 should be simplified");
+    assert!(!code.contains(&(Opcode::SHL as i64)), "x << 0 should be simplified");
+
+    let code = compile(source, false)?;
+    assert!(code.contains(&(Opcode::MUL as i64)), "MUL should be kept when simplification is off");
+    assert!(code.contains(&(Opcode::SHL as i64)), "SHL should be kept when simplification is off");
+
+    assert_eq!(run_source(source)?, 3);
+    assert_eq!(run_source("int main() { int x = 5; return 0 + x * 1 - 0; }")?, 5);
+
+    // A simplified expression is still not an lvalue
+    assert!(compile("int main() { int x; x + 0 = 1; return x; }", true).is_err());
+    Ok(())
+}
+
+/// Test that constant division by zero is left alone and reported
+#[test]
+fn test_division_by_zero_warning() -> Result<(), CompilerError> {
+    let mut parser = Parser::new("int main() { return 1 / 0; }".to_string(), false);
+    parser.init()?;
+    parser.parse()?;
+
+    assert_eq!(parser.get_warnings().len(), 1);
+    assert!(parser.get_warnings()[0].message.contains("zero"));
+    assert!(parser.get_code().contains(&(Opcode::DIV as i64)), "Division by zero must not be folded");
+    Ok(())
+}
+
+/// Test that the peephole optimizer shrinks code without changing results
+#[test]
+fn test_peephole_optimizer() -> Result<(), CompilerError> {
+    let source = r#"
+        int dead(int n) {
+            if (n > 0) return n; else return -n;
+            return 99;
+        }
+
+        int main() {
+            int i = 0, total = 0;
+            while (i < 10) {
+                if (i % 2) total = total + dead(i); else total = total - dead(-i);
+                i++;
+            }
+            return total;
+        }
+    "#;
+
+    let mut plain = Parser::new(source.to_string(), false);
+    plain.init()?;
+    plain.parse()?;
+
+    let mut optimized = Parser::new(source.to_string(), false);
+    optimized.init()?;
+    optimized.parse()?;
+    optimized.optimize();
+
+    assert!(optimized.get_code().len() < plain.get_code().len(), "Optimized code should be smaller");
+
+    let mut results = Vec::new();
+    for parser in [&plain, &optimized] {
+        let entry = parser.get_main_function().unwrap().value as usize;
+        assert_eq!(parser.get_code()[entry], Opcode::ENT as i64, "main should still start with ENT");
+        let mut vm = VirtualMachine::new(parser.get_code().to_vec(), parser.get_data().to_vec(), 64 * 1024, false);
+        results.push(vm.run(entry, &[])?);
+    }
+    assert_eq!(results, vec![5, 5]);
+    Ok(())
+}
+
+/// Test that superinstructions preserve behaviour and can be turned off
+#[test]
+fn test_superinstructions() -> Result<(), CompilerError> {
+    let source = r#"
+        int sum(int *a, int n) {
+            int i = 0, total = 0;
+            while (i < n) { total = total + a[i] * 3 - 1; i++; }
+            return total;
+        }
+
+        int main() {
+            int a[4] = {1, 2, 3, 4};
+            char c = 'A';
+            int n = 4;
+            return sum(a, 4) + -n + (c - 60);
+        }
+    "#;
+
+    let mut results = Vec::new();
+    for instruction_set in [InstructionSet::C4, InstructionSet::Extended] {
+        let mut parser = Parser::new(source.to_string(), false);
+        parser.set_instruction_set(instruction_set);
+        parser.init()?;
+        parser.parse()?;
+        parser.optimize();
+        parser.fuse();
+
+        // Walk the code instruction by instruction
+        let code = parser.get_code();
+        let mut ops = Vec::new();
+        let mut pc = 0;
+        while pc < code.len() {
+            let op = Opcode::from_i64(code[pc]).expect("valid opcode");
+            ops.push(op);
+            pc += if op.has_operand() { 2 } else { 1 };
+        }
+
+        if instruction_set == InstructionSet::C4 {
+            assert!(ops.iter().all(|op| op.is_c4()), "Only C4 opcodes expected: {:?}", ops);
+        } else {
+            for op in [Opcode::LLI, Opcode::PSHL, Opcode::PSHI, Opcode::ADDI, Opcode::MULI] {
+                assert!(ops.contains(&op), "{:?} should be used", op);
+            }
+        }
+
+        let entry = parser.get_main_function().unwrap().value as usize;
+        let mut vm = VirtualMachine::new(code.to_vec(), parser.get_data().to_vec(), 64 * 1024, false);
+        results.push(vm.run(entry, &[])?);
+    }
+    assert_eq!(results, vec![26 - 4 + 5, 26 - 4 + 5]);
+    Ok(())
+}
+
+/// Test that the dataflow optimizations propagate constants, drop dead
+/// stores and unused locals, and leave address-taken locals alone
+#[test]
+fn test_dataflow_optimizer() -> Result<(), CompilerError> {
+    let source = r#"
+        int count(int n) {
+            int unused, limit, i, total;
+            unused = 99;
+            limit = 3;
+            i = 0; total = 0;
+            while (i < limit * n) { total = total + i; i++; }
+            if (limit > 2) return total;
+            return -1;
+        }
+
+        int main() {
+            int x, *p, a[3];
+            p = &x;
+            *p = 5;
+            a[0] = 1; a[1] = 2; a[2] = x;
+            return count(2) + a[0] + a[1] + a[2];
+        }
+    "#;
+
+    for instruction_set in [InstructionSet::C4, InstructionSet::Extended] {
+        let mut results = Vec::new();
+        let mut frames = Vec::new();
+        let mut sizes = Vec::new();
+        for dataflow in [false, true] {
+            let mut parser = Parser::new(source.to_string(), false);
+            parser.set_instruction_set(instruction_set);
+            parser.init()?;
+            parser.parse()?;
+            parser.optimize();
+            if dataflow {
+                parser.optimize_dataflow();
+                parser.optimize();
+            }
+            parser.fuse();
+
+            let code = parser.get_code();
+            let count = parser.get_symbol_table().get("count").unwrap().value as usize;
+            let main = parser.get_main_function().unwrap().value as usize;
+            frames.push((code[count + 1], code[main + 1]));
+            sizes.push(code.len());
+            let mut vm = VirtualMachine::new(code.to_vec(), parser.get_data().to_vec(), 64 * 1024, false);
+            results.push(vm.run(main, &[])?);
+        }
+        assert_eq!(results, vec![15 + 1 + 2 + 5, 15 + 1 + 2 + 5]);
+        // `unused` and `limit` go; x, p and the array stay in main's frame
+        assert_eq!(frames, vec![(4, 5), (2, 5)]);
+        assert!(sizes[1] < sizes[0], "optimized code should be smaller: {:?}", sizes);
+    }
+    Ok(())
+}
+
+/// Test prototypes that let functions be called before their definition
+#[test]
+fn test_prototypes() -> Result<(), CompilerError> {
+    let source = r#"
+        int is_odd(int n);
+        int is_even(int n) { if (n == 0) return 1; return is_odd(n - 1); }
+        int is_odd(int n) { if (n == 0) return 0; return is_even(n - 1); }
+        int main() { return is_even(10) * 10 + is_odd(7); }
+    "#;
+    assert_eq!(run_source(source)?, 11);
+    Ok(())
+}
+
+/// Test that a single file must define everything it declares extern
+#[test]
+fn test_undefined_extern() {
+    for (source, name) in [
+        ("int f(); int main() { return f(); }", "f"),
+        ("extern int g; int main() { return g; }", "g"),
+    ] {
+        match run_source(source) {
+            Err(CompilerError::ParserError { message, .. }) => {
+                assert!(message.contains(name), "Unexpected error: {}", message);
+            },
+            other => panic!("Expected undefined symbol error, got: {:?}", other),
+        }
+    }
+
+    // An extern global conflicting with a function is rejected too
+    assert!(run_source("int f(); extern int f; int main() { return 0; }").is_err());
+}
+
+/// Test that calls to native functions compile to NATV
+#[test]
+fn test_native_calls() -> Result<(), CompilerError> {
+    let mut natives = Natives::new();
+    natives.register("twice", 1, |_, args| Ok(args[0] * 2));
+    natives.register("add3", 3, |_, args| Ok(args.iter().sum()));
+
+    let source = "int main() { return add3(1, 2, twice(4)); }";
+    let mut parser = Parser::new(source.to_string(), false);
+    parser.set_natives(&natives);
+    parser.init()?;
+    parser.parse()?;
+
+    let code = parser.get_code();
+    assert!(code.windows(2).any(|w| w == [Opcode::NATV as i64, 0]), "Expected NATV 0");
+    assert!(code.windows(4).any(|w| w == [Opcode::NATV as i64, 1, Opcode::ADJ as i64, 3]), "Expected NATV 1; ADJ 3");
+
+    let entry = parser.get_main_function().unwrap().value as usize;
+    let mut vm = VirtualMachine::new(code.to_vec(), parser.get_data().to_vec(), 1024, false);
+    vm.set_natives(natives.clone());
+    assert_eq!(vm.run(entry, &[])?, 11);
+
+    // Arity is checked, and C4 bytecode has no way to call natives
+    for (source, instruction_set) in [
+        ("int main() { return twice(1, 2); }", InstructionSet::Extended),
+        ("int main() { return twice(1); }", InstructionSet::C4),
+    ] {
+        let mut parser = Parser::new(source.to_string(), false);
+        parser.set_natives(&natives);
+        parser.set_instruction_set(instruction_set);
+        parser.init()?;
+        assert!(parser.parse().is_err(), "{} should be rejected", source);
+    }
+    Ok(())
+}
@@ -1,6 +1,10 @@
-use c4_rust::error::CompilerError;
+use c4_rust::asm::assemble;
+use c4_rust::error::{CompilerError, ResourceLimit};
+use c4_rust::host::MemoryHost;
+use c4_rust::parser::Parser;
 use c4_rust::types::Opcode;
-use c4_rust::vm::VirtualMachine;
+use c4_rust::vm::{VirtualMachine, VmLimits};
+use std::time::Duration;
 
 /// Test basic VM operations
 #[test]
@@ -174,11 +178,11 @@ fn test_conditional_branching() -> Result<(), CompilerError> {
         Opcode::EQ as i64,           // 1 == 0? (false = 0)
         
         // If result is 0 (condition false), branch to else
-        Opcode::BZ as i64, 11,       // Branch to else path if result is 0
+        Opcode::BZ as i64, 12,       // Branch to else path if result is 0
         
         // Then path (should not be taken)
         Opcode::IMM as i64, 42,      // Load 42
-        Opcode::JMP as i64, 13,      // Jump to end
+        Opcode::JMP as i64, 14,      // Jump to end
         
         // Else path
         Opcode::IMM as i64, 24,      // Load 24
@@ -221,7 +225,7 @@ fn test_function_calls() -> Result<(), CompilerError> {
     // Program to test function calls
     let code = vec![
         // Jump to main
-        Opcode::JMP as i64, 9,       // Jump to main
+        Opcode::JMP as i64, 13,      // Jump to main
         
         // Function 'add': add(a, b) returns a + b
         Opcode::ENT as i64, 0,       // Set up stack frame
@@ -239,13 +243,13 @@ fn test_function_calls() -> Result<(), CompilerError> {
         Opcode::PSH as i64,          // Push 'a'
         Opcode::IMM as i64, 20,      // Load 20 (parameter 'b')
         Opcode::PSH as i64,          // Push 'b'
-        Opcode::JSR as i64, 1,       // Call 'add' function
+        Opcode::JSR as i64, 2,       // Call 'add' function
         Opcode::ADJ as i64, 2,       // Adjust stack (remove parameters)
         Opcode::LEV as i64,          // Return from main
     ];
     
     let mut vm = VirtualMachine::new(code, Vec::new(), 1024, false);
-    let result = vm.run(9, &[])?;    // Start execution at main
+    let result = vm.run(13, &[])?;   // Start execution at main
     
     assert_eq!(result, 30);          // 10 + 20 = 30
     Ok(())
@@ -255,7 +259,7 @@ fn test_function_calls() -> Result<(), CompilerError> {
 #[test]
 fn test_memory_operations() -> Result<(), CompilerError> {
     // Create some initial data
-    let mut data = vec![0; 32];
+    let data = vec![0; 32];
     
     // Program to test memory operations
     let code = vec![
@@ -383,9 +387,9 @@ fn test_vm_errors() {
     let result = vm.run(0, &[]);
     
     assert!(result.is_err(), "VM should detect stack overflow");
-    if let Err(CompilerError::VMError { message, .. }) = result {
-        assert!(message.contains("stack") || message.contains("Stack"),
-                "Error message should mention stack issue");
+    match result {
+        Err(CompilerError::LimitExceeded { limit, .. }) => assert_eq!(limit, ResourceLimit::Stack(1024)),
+        other => panic!("Expected a stack limit error, got {:?}", other),
     }
 }
 
@@ -445,7 +449,7 @@ fn test_recursive_function() -> Result<(), CompilerError> {
     
     let code = vec![
         // Jump to main
-        Opcode::JMP as i64, 18,      // Jump to main
+        Opcode::JMP as i64, 34,      // Jump to main
         
         // Factorial function
         Opcode::ENT as i64, 0,       // Set up stack frame
@@ -456,7 +460,7 @@ fn test_recursive_function() -> Result<(), CompilerError> {
         Opcode::PSH as i64,          // Push 'n'
         Opcode::IMM as i64, 1,       // Load 1
         Opcode::LE as i64,           // n <= 1?
-        Opcode::BZ as i64, 10,       // If not, jump to else
+        Opcode::BZ as i64, 16,       // If not, jump to else
         
         // return 1
         Opcode::IMM as i64, 1,       // Load 1
@@ -472,7 +476,7 @@ fn test_recursive_function() -> Result<(), CompilerError> {
         
         // Call factorial(n-1)
         Opcode::PSH as i64,          // Push n-1
-        Opcode::JSR as i64, 1,       // Call factorial
+        Opcode::JSR as i64, 2,       // Call factorial
         Opcode::ADJ as i64, 1,       // Remove argument
         
         // Multiply n * factorial(n-1)
@@ -486,14 +490,220 @@ fn test_recursive_function() -> Result<(), CompilerError> {
         // Main function
         Opcode::IMM as i64, 5,       // Load 5 (calculate factorial(5))
         Opcode::PSH as i64,          // Push 5
-        Opcode::JSR as i64, 1,       // Call factorial
+        Opcode::JSR as i64, 2,       // Call factorial
         Opcode::ADJ as i64, 1,       // Remove argument
         Opcode::EXIT as i64,         // Exit with result
     ];
     
     let mut vm = VirtualMachine::new(code, Vec::new(), 1024, false);
-    let result = vm.run(18, &[])?;   // Start at main
+    let result = vm.run(34, &[])?;   // Start at main
     
     assert_eq!(result, 120);         // factorial(5) = 120
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Test that TSR reuses the frame, so tail recursion runs in a small stack
+#[test]
+fn test_tail_call() -> Result<(), CompilerError> {
+    // int down(int n) { if (n == 0) return 42; n = n - 1; return down(n); }
+    let code = vec![
+        Opcode::ENT as i64, 0,
+        Opcode::LEA as i64, 2,       // Load 'n'
+        Opcode::LI as i64,
+        Opcode::BNZ as i64, 10,      // If n != 0, recurse
+        Opcode::IMM as i64, 42,
+        Opcode::LEV as i64,          // Return 42
+
+        Opcode::LEA as i64, 2,       // n = n - 1
+        Opcode::PSH as i64,
+        Opcode::LEA as i64, 2,
+        Opcode::LI as i64,
+        Opcode::PSH as i64,
+        Opcode::IMM as i64, 1,
+        Opcode::SUB as i64,
+        Opcode::SI as i64,
+        Opcode::TSR as i64, 0,       // The argument is in place: leave and jump
+
+        // Main function
+        Opcode::IMM as i64, 100000,
+        Opcode::PSH as i64,
+        Opcode::JSR as i64, 0,       // Call down(100000)
+        Opcode::ADJ as i64, 1,
+        Opcode::EXIT as i64,
+    ];
+
+    let mut vm = VirtualMachine::new(code, Vec::new(), 1024, false);
+    assert_eq!(vm.run(23, &[])?, 42);
+    Ok(())
+}
+
+/// Test that malformed bytecode is rejected before anything runs
+#[test]
+fn test_load_time_validation() {
+    // The bad jump is never reached, but is still reported up front
+    let code = vec![
+        Opcode::IMM as i64, 7,
+        Opcode::EXIT as i64,
+        Opcode::JMP as i64, 1,       // Lands on IMM's operand
+    ];
+
+    let mut vm = VirtualMachine::new(code, Vec::new(), 1024, false);
+    match vm.run(0, &[]) {
+        Err(CompilerError::VMError { message, cycle, .. }) => {
+            assert!(message.contains("not an instruction"), "Unexpected message: {}", message);
+            assert_eq!(cycle, None, "Validation happens before execution");
+        },
+        other => panic!("Expected a validation error, got {:?}", other),
+    }
+}
+
+/// Test a program written in assembly, with labels instead of offsets
+#[test]
+fn test_assembled_program() -> Result<(), CompilerError> {
+    // sum = 0; for (i = 10; i; i--) sum += i; return sum;
+    let object = assemble(r#"
+        .entry main
+        main:
+            ENT   2
+            LEA   -1          ; sum = 0
+            PSH
+            IMM   0
+            SI
+            LEA   -2          ; i = 10
+            PSH
+            IMM   10
+            SI
+        .loop:
+            LLI   -2
+            BZ    .done
+            LEA   -1          ; sum += i
+            PSH
+            LLI   -1
+            PSH
+            LLI   -2
+            ADD
+            SI
+            LEA   -2          ; i--
+            PSH
+            LLI   -2
+            SUBI  1
+            SI
+            JMP   .loop
+        .done:
+            LLI   -1
+            EXIT
+    "#)?;
+
+    let mut vm = VirtualMachine::new(object.code, object.data, 1024, false);
+    let result = vm.run(object.entry.unwrap(), &[])?;
+
+    assert_eq!(result, 55);
+    Ok(())
+}
+
+/// Test that runtime errors name the function and line, with a backtrace
+#[test]
+fn test_error_locations() {
+    let source = "int divide(int a, int b) {\n    return a / b;\n}\n\nint main() {\n    int x;\n    x = divide(6, 3);\n    return divide(x, 0);\n}\n";
+    let mut parser = Parser::new(source.to_string(), false);
+    parser.init().unwrap();
+    parser.parse().unwrap();
+    parser.optimize();
+    parser.fuse();
+
+    let entry = parser.get_main_function().unwrap().value as usize;
+    let mut vm = VirtualMachine::new(parser.get_code().to_vec(), parser.get_data().to_vec(), 1024, false);
+    vm.set_debug_info(parser.get_debug_info().clone());
+    let err = vm.run(entry, &[]).unwrap_err();
+    assert!(err.to_string().contains("In divide at line 2:5"), "{}", err);
+    match err {
+        CompilerError::VMError { instruction, backtrace, .. } => {
+            assert_eq!(instruction.as_deref(), Some("DIV"));
+            let frames: Vec<_> = backtrace.iter()
+                .map(|frame| (frame.function.as_deref().unwrap(), frame.location.unwrap().line))
+                .collect();
+            assert_eq!(frames, [("divide", 2), ("main", 8)]);
+        },
+        other => panic!("Expected a division by zero, got {:?}", other),
+    }
+}
+
+/// Each resource limit stops a program with its own error
+#[test]
+fn test_resource_limits() {
+    let run = |source: &str, limits: VmLimits| {
+        let mut parser = Parser::new(source.to_string(), false);
+        parser.init().unwrap();
+        parser.parse().unwrap();
+        let entry = parser.get_main_function().unwrap().value as usize;
+        let mut vm = VirtualMachine::with_limits(parser.get_code().to_vec(), parser.get_data().to_vec(), limits, false);
+        vm.set_host(Box::new(MemoryHost::new()));
+        vm.run(entry, &[])
+    };
+    let limit = |source: &str, limits: VmLimits| match run(source, limits) {
+        Err(CompilerError::LimitExceeded { limit, .. }) => limit,
+        other => panic!("Expected a limit error, got {:?}", other),
+    };
+
+    let forever = "int main() { while (1) {} return 0; }";
+    let cycles = VmLimits { max_cycles: Some(1000), ..VmLimits::default() };
+    assert_eq!(limit(forever, cycles), ResourceLimit::Cycles(1000));
+    let timeout = VmLimits { timeout: Some(Duration::from_millis(20)), ..VmLimits::default() };
+    assert_eq!(limit(forever, timeout), ResourceLimit::Time(Duration::from_millis(20)));
+
+    // Copying f's saved bp and return address over main's makes every
+    // LEV return to main's ADJ; LEV, a loop without a jump
+    let returns = "f(int *q) { int x; int *p; p = &x; q[1] = p[1]; q[2] = p[2]; return 0; }
+                   main() { int y; int *q; q = &y; return f(q); }";
+    assert_eq!(limit(returns, cycles), ResourceLimit::Cycles(1000));
+    assert_eq!(limit(returns, timeout), ResourceLimit::Time(Duration::from_millis(20)));
+
+    let heap = VmLimits { max_heap_bytes: Some(100), ..VmLimits::default() };
+    assert_eq!(run("int main() { malloc(64); malloc(32); return 0; }", heap).unwrap(), 0);
+    assert_eq!(limit("int main() { malloc(64); malloc(64); return 0; }", heap), ResourceLimit::Heap(100));
+
+    let output = VmLimits { max_output_bytes: Some(10), ..VmLimits::default() };
+    let err = run("int main() { printf(\"hello \"); printf(\"world\\n\"); return 0; }", output).unwrap_err();
+    assert!(err.to_string().starts_with("Limit exceeded: wrote more than 10 bytes of output"), "{}", err);
+
+    let stack = VmLimits { stack_size: 100, ..VmLimits::default() };
+    let recurse = "int f(int n) { return f(n + 1); } int main() { return f(0); }";
+    assert_eq!(limit(recurse, stack), ResourceLimit::Stack(100));
+    assert_eq!(run("int main() { return 7; }", cycles).unwrap(), 7);
+}
+
+/// System calls go through the host, which can be kept in memory
+#[test]
+fn test_memory_host() {
+    let source = r#"
+        int main() {
+            char *buf;
+            int fd, n, total;
+            buf = malloc(64);
+            n = read(0, buf, 63);
+            buf[n] = 0;
+            printf("stdin: %s\n", buf);
+
+            fd = open("numbers.txt", 0);
+            total = read(fd, buf, 63);
+            close(fd);
+            printf("file: %d bytes, starts with %c\n", total, buf[0]);
+            printf("missing: %d\n", open("missing.txt", 0));
+            exit(3);
+            return 0;
+        }
+    "#;
+    let mut parser = Parser::new(source.to_string(), false);
+    parser.init().unwrap();
+    parser.parse().unwrap();
+    let entry = parser.get_main_function().unwrap().value as usize;
+
+    let host = MemoryHost::new().with_stdin("typed").with_file("numbers.txt", "42 43 44");
+    let mut vm = VirtualMachine::new(parser.get_code().to_vec(), parser.get_data().to_vec(), 1024, false);
+    vm.set_host(Box::new(host.clone()));
+    assert_eq!(vm.run(entry, &[]).unwrap(), 3);
+
+    let stdout = String::from_utf8(host.stdout()).unwrap();
+    assert_eq!(stdout, "stdin: typed\nfile: 8 bytes, starts with 4\nmissing: -1\n");
+    assert_eq!(host.exit_code(), Some(3));
+}